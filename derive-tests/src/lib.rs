@@ -1,18 +1,38 @@
 #[cfg(test)]
 mod tests {
-    use std::iter;
+    use std::{iter, pin::pin, time::Duration};
 
     use itertools::Itertools;
+    use launchpad::{
+        cache::MemoryCache,
+        futures::{future::join_all, stream, StreamExt},
+        mq::{
+            audit::AuditTrail,
+            consumer::{MessageContext, Processor, ProcessorError},
+            inbox::{Inbox, InboxHandler},
+            interceptor::{OutgoingMessage, PublishInterceptor},
+            outbox::Outbox,
+            quarantine::{PgQuarantineStore, QuarantineStore, QuarantinedMessage},
+            saga::{PgSagaStore, SagaRecord, SagaStore},
+            Envelope, MqError,
+        },
+        page::Page,
+    };
     use launchpad_derive::Entity;
-    use sqlx::{prelude::FromRow, PgPool};
+    use pgvector::Vector;
+    use rocket::{http::Status, local::asynchronous::Client};
+    use serde::{Deserialize, Serialize};
+    use sqlx::{prelude::FromRow, PgConnection, PgPool};
     use tokio::sync::Mutex;
     use uuid::Uuid;
 
     #[allow(unused)]
-    #[derive(Entity, Default, FromRow, Debug)]
-    #[entity(name = my_entity, table_name = "my_entity")]
+    #[derive(Entity, Default, FromRow, Debug, Clone)]
+    #[entity(name = my_entity, table_name = "my_entity", cache, loader, notify)]
+    #[entity(projection(name = MyEntitySummary, fields(entity_id, name)))]
+    #[entity(aggregate(count_by = "color"))]
     struct MyEntity {
-        #[key(name = "id", unique)]
+        #[key(name = "id", primary, locking)]
         #[column(name = "id")]
         #[sqlx(rename = "id")]
         entity_id: Uuid,
@@ -23,20 +43,173 @@ mod tests {
         #[key(name = "name_version", unique)]
         version: i32,
 
-        #[key(name = "color")]
+        #[key(name = "color", locking)]
+        color: String,
+
+        description: String,
+
+        #[column(skip)]
+        #[sqlx(skip)]
+        cached_label: Option<String>,
+    }
+
+    #[allow(unused)]
+    #[derive(Entity, Default, FromRow, Debug)]
+    #[entity(table_name = "my_entity", returning)]
+    struct MyReturningEntity {
+        #[key(primary)]
+        id: Uuid,
+        name: String,
+        version: i32,
         color: String,
+        description: String,
+    }
 
+    #[allow(unused)]
+    #[derive(Entity, Default, FromRow, Debug)]
+    #[entity(table_name = "my_entity", audited)]
+    struct MyAuditedEntity {
+        #[key(primary)]
+        id: Uuid,
+        name: String,
+        version: i32,
+        color: String,
         description: String,
     }
 
+    #[allow(unused)]
+    #[derive(Entity, Default, FromRow, Debug)]
+    #[entity(table_name = "my_tenant_entity", tenant = "tenant_id", error = TestDbError)]
+    struct MyTenantEntity {
+        #[key(name = "name", unique)]
+        tenant_id: Uuid,
+
+        #[key(primary)]
+        id: Uuid,
+
+        #[key(name = "name", unique)]
+        name: String,
+
+        description: String,
+    }
+
+    #[allow(unused)]
+    #[derive(Entity, Default, FromRow, Debug)]
+    #[entity(table_name = "my_tenant_entity", tenant = "tenant_id", returning)]
+    struct MyReturningTenantEntity {
+        tenant_id: Uuid,
+        #[key(primary)]
+        id: Uuid,
+        name: String,
+        description: String,
+    }
+
+    #[allow(unused)]
+    #[derive(Entity, FromRow, Debug, Clone, Serialize, Deserialize)]
+    #[entity(table_name = "my_entity", rocket_routes)]
+    struct MyRoutedEntity {
+        #[key(primary)]
+        id: Uuid,
+        name: String,
+        version: i32,
+        color: String,
+        description: String,
+    }
+
+    #[allow(unused)]
+    #[derive(Entity, FromRow, Debug)]
+    #[entity(table_name = "my_red_entity", view(sql = "select id, name, version from my_entity where color = 'red'"))]
+    struct MyRedEntity {
+        #[key(primary)]
+        id: Uuid,
+        #[key(name = "name")]
+        name: String,
+        version: i32,
+    }
+
+    #[allow(unused)]
+    #[derive(Entity, FromRow, Debug)]
+    #[entity(table_name = "my_owned_entity")]
+    struct MyOwnedEntity {
+        #[key(primary)]
+        id: Uuid,
+        #[key(name = "owner_email", via = "my_owner.email", join = "my_owner.id = my_owned_entity.owner_id", via_type = "String")]
+        owner_id: Uuid,
+        name: String,
+    }
+
+    /// Checked against the database at compile time, from the query data in `.sqlx` when there's
+    /// no `DATABASE_URL`. Regenerate it with `cargo sqlx prepare` once `my_checked_entity` exists.
+    #[allow(unused)]
+    #[derive(Entity, FromRow, Debug, Clone, PartialEq)]
+    #[entity(table_name = "my_checked_entity", checked)]
+    struct MyCheckedEntity {
+        #[key(primary)]
+        id: Uuid,
+        #[key(name = "name", unique)]
+        name: String,
+        description: Option<String>,
+    }
+
+    #[allow(unused)]
+    #[derive(Entity, FromRow, Debug, PartialEq)]
+    #[entity(table_name = "my_camel_entity", rename_all = "camelCase")]
+    #[entity(projection(name = MyCamelEntitySummary, fields(entity_id, display_name)))]
+    #[sqlx(rename_all = "camelCase")]
+    struct MyCamelEntity {
+        #[key(primary)]
+        entity_id: Uuid,
+        #[key(name = "display_name", unique)]
+        display_name: String,
+        created_by: String,
+    }
+
+    #[allow(unused)]
+    #[derive(Entity, FromRow, Debug)]
+    #[entity(table_name = "my_document")]
+    struct MyDocument {
+        #[key(primary)]
+        id: Uuid,
+        embedding: Vector,
+    }
+
+    #[allow(unused)]
+    #[derive(Debug)]
+    struct TestDbError(sqlx::Error);
+
+    impl std::fmt::Display for TestDbError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "database error: {}", self.0)
+        }
+    }
+
+    impl From<sqlx::Error> for TestDbError {
+        fn from(e: sqlx::Error) -> Self {
+            TestDbError(e)
+        }
+    }
+
     #[test]
     fn usage() {
         let _entity = MyEntity::default();
         // impl MyEntityRepo for () {}
     }
 
+    #[test]
+    fn metadata() {
+        assert_eq!(MyEntity::TABLE, "my_entity");
+        assert_eq!(MyEntity::COLUMNS, ["id", "name", "version", "color", "description"]);
+        assert_eq!(MyEntity::KEYS, ["color", "id", "name_version"]);
+        assert_eq!(MyEntity::PRIMARY_KEY, ["id"]);
+        assert_eq!(MyEntity::KEY_NAME_VERSION, ["name", "version"]);
+        const { assert!(MyEntity::KEY_NAME_VERSION_UNIQUE) };
+        const { assert!(!MyEntity::KEY_COLOR_UNIQUE) };
+        assert_eq!(MyEntitySummary::COLUMNS, ["id", "name"]);
+        assert_eq!(MyAuditedEntity::HISTORY_TABLE, "my_entity_history");
+    }
+
     #[tokio::test]
-    async fn integration() -> Result<(), sqlx::Error> {
+    async fn integration() -> Result<(), TestDbError> {
         let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
             PgPool::connect(&pg_url).await?
         } else {
@@ -44,7 +217,7 @@ mod tests {
             return Ok(());
         };
 
-        let result: Result<(), sqlx::Error> = async {
+        let result: Result<(), TestDbError> = async {
             create_table(&pg_pool).await?;
             let (id1, id2, id3) = iter::from_fn(|| Some(Uuid::new_v4())).take(3).collect_tuple().unwrap();
             let data = vec![
@@ -54,6 +227,7 @@ mod tests {
                     version: 1,
                     color: "red".into(),
                     description: "foo red".into(),
+                    cached_label: None,
                 },
                 MyEntity {
                     entity_id: id2,
@@ -61,6 +235,7 @@ mod tests {
                     version: 2,
                     color: "blue".into(),
                     description: "foo blue".into(),
+                    cached_label: None,
                 },
                 MyEntity {
                     entity_id: id3,
@@ -68,6 +243,7 @@ mod tests {
                     version: 1,
                     color: "red".into(),
                     description: "bar red".into(),
+                    cached_label: None,
                 },
             ];
 
@@ -84,9 +260,341 @@ mod tests {
             let e2 = pg_pool.list_my_entity_by_color("red").await?;
             assert_eq!(e2.len(), 2);
 
+            let counts = pg_pool.count_my_entity_grouped_by_color().await?;
+            assert_eq!(counts, [("blue".to_string(), 1), ("red".to_string(), 2)]);
+
+            let repository = MyEntityRepository::new(pg_pool.clone());
+            assert_eq!(repository.find_my_entity_by_id(&id2).await?.map(|e| e.version), Some(2));
+            assert_eq!(repository.with_tx(async |tx| tx.list_my_entity_by_color("red").await).await?.len(), 2);
+
+            let loader = MyEntityLoader::new(pg_pool.clone());
+            let missing = Uuid::new_v4();
+            let loaded = join_all([&id1, &id3, &missing].map(|id| loader.load(id))).await;
+            let loaded = loaded.into_iter().map(|e| e.unwrap().map(|e| e.description)).collect_vec();
+            assert_eq!(loaded, [Some("foo red".into()), Some("bar red".into()), None]);
+
+            MyRedEntity::create_view(&pg_pool).await?;
+            let red = pg_pool.list_my_red_entity_by_name("foo").await?;
+            assert_eq!(red.iter().map(|e| e.id).collect_vec(), [id1]);
+            assert!(pg_pool.find_my_red_entity_by_id(&id2).await?.is_none());
+
+            let filter = MyEntityFilter {
+                color: Some("red".into()),
+                ..Default::default()
+            };
+            let l1 = pg_pool.list_my_entity(&filter, Page::new(1, 0)).await?;
+            let l2 = pg_pool.list_my_entity(&filter, Page::new(1, 0).next()).await?;
+            let paged = l1.iter().chain(&l2).map(|e| e.entity_id).sorted().collect_vec();
+            assert_eq!(paged, [id1, id3].into_iter().sorted().collect_vec());
+            let everything = pg_pool.list_my_entity(&MyEntityFilter::default(), Page::default()).await?;
+            assert_eq!(everything.len(), 3);
+
             let e3 = tx.list_my_entity_by_color("red").await?;
             assert_eq!(e3.len(), 2);
 
+            let conn = Mutex::new(pg_pool.acquire().await?);
+            let e4 = conn.find_my_entity_by_name_version("foo", &2).await?;
+            assert_eq!(e4.unwrap().entity_id, id2);
+
+            let mut raw_conn = pg_pool.acquire().await?;
+            let conn_ref = Mutex::new(&mut *raw_conn);
+            let e5 = conn_ref.list_my_entity_by_color("blue").await?;
+            assert_eq!(e5.len(), 1);
+
+            let e6 = MyEntityRepoExt::with_tx(&pg_pool, async |repo| {
+                let found = repo.find_my_entity_by_id(&id3).await?;
+                Ok::<_, sqlx::Error>(found.map(|e| e.name))
+            })
+            .await?;
+            assert_eq!(e6.as_deref(), Some("bar"));
+
+            // the rollback can't reach the terminated backend, but `f`'s own error still comes back
+            let e7 = MyEntityRepoExt::with_tx(&pg_pool, async |repo| {
+                let _ = sqlx::query("select pg_terminate_backend(pg_backend_pid())").execute(&mut **repo.lock().await).await;
+                Err::<(), _>(sqlx::Error::RowNotFound)
+            })
+            .await;
+            assert!(matches!(e7, Err(sqlx::Error::RowNotFound)), "{e7:?}");
+
+            let s1 = pg_pool.list_my_entity_summary_by_color("red").await?;
+            let names = s1.iter().map(|s| s.name.as_str()).sorted().collect_vec();
+            assert_eq!(names, ["bar", "foo"]);
+
+            let q1 = MyEntityQuery::new()
+                .name_eq("foo")
+                .version_gte(1)
+                .order_by_version_desc()
+                .limit(1)
+                .fetch_all(&pg_pool)
+                .await?;
+            assert_eq!(q1.iter().map(|e| e.entity_id).collect_vec(), [id2]);
+
+            let patch = MyEntityPatch {
+                description: Some("foo green".into()),
+                color: Some("green".into()),
+                ..Default::default()
+            };
+            let updated = pg_pool.update_my_entity_partial(&id1, &patch).await?;
+            assert_eq!(updated, 1);
+            let p1 = pg_pool.find_my_entity_by_id(&id1).await?.unwrap();
+            assert_eq!((p1.color.as_str(), p1.version), ("green", 1));
+            assert_eq!(pg_pool.update_my_entity_partial(&id1, &MyEntityPatch::default()).await?, 0);
+
+            let existing = MyEntity {
+                entity_id: Uuid::new_v4(),
+                name: "foo".into(),
+                version: 2,
+                ..Default::default()
+            };
+            let f1 = pg_pool.find_or_insert_my_entity_by_name_version(&existing).await?;
+            assert_eq!(f1.entity_id, id2);
+
+            let fresh = MyEntity {
+                entity_id: Uuid::new_v4(),
+                name: "baz".into(),
+                version: 1,
+                color: "red".into(),
+                description: "baz red".into(),
+                cached_label: Some("not a column".into()),
+            };
+            let f2 = pg_pool.find_or_insert_my_entity_by_name_version(&fresh).await?;
+            assert_eq!(f2.entity_id, fresh.entity_id);
+            assert!(pg_pool.find_my_entity_by_id(&fresh.entity_id).await?.is_some());
+
+            // a conflict on another unique key isn't mistaken for finding the row
+            let clashing = MyEntity {
+                name: "quux".into(),
+                ..fresh.clone()
+            };
+            let e = pg_pool.find_or_insert_my_entity_by_name_version(&clashing).await.unwrap_err();
+            assert!(e.as_database_error().is_some_and(|e| e.is_unique_violation()), "{e}");
+
+            let mut inserted = MyEntity {
+                entity_id: Uuid::new_v4(),
+                name: "qux".into(),
+                version: 1,
+                color: "blue".into(),
+                description: "qux blue".into(),
+                cached_label: None,
+            };
+            assert_eq!(pg_pool.insert_my_entity(&inserted).await?, 1);
+            inserted.description = "qux updated".into();
+            assert_eq!(pg_pool.update_my_entity(&inserted).await?, 1);
+            inserted.version = 2;
+            assert_eq!(pg_pool.upsert_my_entity(&inserted).await?, 1);
+            let u1 = pg_pool.find_my_entity_by_id(&inserted.entity_id).await?.unwrap();
+            assert_eq!((u1.description.as_str(), u1.version), ("qux updated", 2));
+
+            let r1 = pg_pool
+                .insert_my_returning_entity(&MyReturningEntity {
+                    id: Uuid::new_v4(),
+                    name: "quux".into(),
+                    version: 1,
+                    color: "red".into(),
+                    description: "quux red".into(),
+                })
+                .await?;
+            let r2 = pg_pool
+                .update_my_returning_entity_partial(
+                    &r1.id,
+                    &MyReturningEntityPatch {
+                        version: Some(3),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            assert_eq!(r2.map(|r| (r.name, r.version)), Some(("quux".into(), 3)));
+
+            let client = Client::tracked(
+                rocket::build()
+                    .manage(MyRoutedEntityRepository::new(pg_pool.clone()))
+                    .mount("/entities", MyRoutedEntity::routes()),
+            )
+            .await
+            .expect("valid rocket");
+            let mut routed = MyRoutedEntity {
+                id: Uuid::new_v4(),
+                name: "routed".into(),
+                version: 1,
+                color: "teal".into(),
+                description: "routed teal".into(),
+            };
+            let created = client.post("/entities").json(&routed).dispatch().await;
+            assert_eq!(created.status(), Status::Created);
+            // the duplicate key is logged, and only a 500 goes back
+            let duplicate = client.post("/entities").json(&routed).dispatch().await;
+            assert_eq!(duplicate.status(), Status::InternalServerError);
+
+            let path = format!("/entities/{}", routed.id);
+            let found = client.get(&path).dispatch().await;
+            assert_eq!(found.status(), Status::Ok);
+            assert_eq!(found.into_json::<MyRoutedEntity>().await.map(|e| e.name), Some("routed".into()));
+
+            routed.description = "replaced".into();
+            let replaced = client.put(&path).json(&routed).dispatch().await;
+            assert_eq!(replaced.status(), Status::Ok);
+            let listed = client.get("/entities?limit=100").dispatch().await;
+            let listed = listed.into_json::<Vec<MyRoutedEntity>>().await.unwrap_or_default();
+            assert!(listed.iter().any(|e| e.id == routed.id && e.description == "replaced"));
+
+            assert_eq!(client.delete(&path).dispatch().await.status(), Status::NoContent);
+            assert_eq!(client.delete(&path).dispatch().await.status(), Status::NotFound);
+            assert_eq!(client.get(&path).dispatch().await.status(), Status::NotFound);
+
+            let locker = Mutex::new(pg_pool.begin().await?);
+            let l1 = locker.find_my_entity_by_id_for_update(&id2).await?;
+            assert!(l1.is_some());
+            let l2 = locker.list_my_entity_by_color_for_update_skip_locked("blue", 10).await?;
+            assert!(l2.iter().any(|e| e.entity_id == id2));
+
+            // another transaction skips the rows already locked
+            let other = Mutex::new(pg_pool.begin().await?);
+            let l3 = other.list_my_entity_by_color_for_update_skip_locked("blue", 10).await?;
+            assert!(l3.iter().all(|e| e.entity_id != id2));
+            other.into_inner().rollback().await?;
+            locker.into_inner().rollback().await?;
+
+            let mut a1 = MyAuditedEntity {
+                id: Uuid::new_v4(),
+                name: "audited".into(),
+                version: 1,
+                color: "red".into(),
+                description: "audited red".into(),
+            };
+            assert_eq!(pg_pool.insert_my_audited_entity(&a1).await?, 1);
+            a1.version = 2;
+            assert_eq!(pg_pool.update_my_audited_entity(&a1, "alice").await?, 1);
+            let patch = MyAuditedEntityPatch {
+                color: Some("blue".into()),
+                ..Default::default()
+            };
+            assert_eq!(pg_pool.update_my_audited_entity_partial(&a1.id, &patch, "bob").await?, 1);
+            assert_eq!(pg_pool.delete_my_audited_entity(&a1.id, "carol").await?, 1);
+            assert!(pg_pool.find_my_audited_entity_by_id(&a1.id).await?.is_none());
+
+            let history: Vec<(String, i32, String)> =
+                sqlx::query_as("select actor, version, color from my_entity_history where id = $1 order by changed_at, version, color")
+                    .bind(a1.id)
+                    .fetch_all(&pg_pool)
+                    .await?;
+            assert_eq!(
+                history,
+                [
+                    ("alice".into(), 1, "red".into()),
+                    ("bob".into(), 2, "red".into()),
+                    ("carol".into(), 2, "blue".into())
+                ]
+            );
+
+            assert_eq!(pg_pool.delete_my_entity(&id3).await?, 1);
+            assert_eq!(pg_pool.delete_my_entity(&id3).await?, 0);
+
+            let cached = CachedMyEntityRepo::new(pg_pool.clone(), MemoryCache::new());
+            let c1 = cached.find_my_entity_by_id(&id2).await?.unwrap();
+            sqlx::query("update my_entity set description = 'changed elsewhere' where id = $1")
+                .bind(id2)
+                .execute(&pg_pool)
+                .await?;
+            let c2 = cached.find_my_entity_by_id(&id2).await?.unwrap();
+            assert_eq!(c2.description, c1.description);
+
+            // mutations through the wrapper drop the stale entry
+            let patch = MyEntityPatch {
+                version: Some(5),
+                ..Default::default()
+            };
+            assert_eq!(cached.update_my_entity_partial(&id2, &patch).await?, 1);
+            let c3 = cached.find_my_entity_by_id(&id2).await?.unwrap();
+            assert_eq!((c3.description.as_str(), c3.version), ("changed elsewhere", 5));
+
+            let loaded = (1..=3)
+                .map(|version| MyEntity {
+                    entity_id: Uuid::new_v4(),
+                    name: "copied".into(),
+                    version,
+                    color: "copper".into(),
+                    description: format!("copied {version}"),
+                    cached_label: None,
+                })
+                .collect_vec();
+            assert_eq!(pg_pool.copy_in_my_entity(stream::iter(loaded)).await?, 3);
+            let tx_loaded = MyEntity {
+                entity_id: Uuid::new_v4(),
+                name: "copied".into(),
+                version: 4,
+                color: "copper".into(),
+                ..Default::default()
+            };
+            let copier = Mutex::new(pg_pool.begin().await?);
+            assert_eq!(copier.copy_in_my_entity(stream::iter([tx_loaded])).await?, 1);
+            assert_eq!(copier.list_my_entity_by_color("copper").await?.len(), 4);
+            assert_eq!(pg_pool.list_my_entity_by_color("copper").await?.len(), 3);
+            copier.into_inner().rollback().await?;
+
+            MyEntityChanges::install(&pg_pool).await?;
+            let mut changes = pin!(MyEntityChanges::listen(&pg_pool).await?);
+            let mut next_change = async || {
+                tokio::time::timeout(Duration::from_secs(5), changes.next())
+                    .await
+                    .expect("change notified")
+                    .expect("stream continues")
+            };
+
+            let mut n1 = MyEntity {
+                entity_id: Uuid::new_v4(),
+                name: "notified".into(),
+                version: 1,
+                ..Default::default()
+            };
+            pg_pool.insert_my_entity(&n1).await?;
+            n1.description = "notified".into();
+            pg_pool.update_my_entity(&n1).await?;
+            pg_pool.delete_my_entity(&n1.entity_id).await?;
+
+            assert!(matches!(next_change().await?, MyEntityChange::Inserted(e) if e.entity_id == n1.entity_id));
+            assert!(matches!(next_change().await?, MyEntityChange::Updated(e) if e.description == "notified"));
+            assert!(matches!(next_change().await?, MyEntityChange::Deleted(e) if e.entity_id == n1.entity_id));
+
+            let (tenant1, tenant2) = (Uuid::new_v4(), Uuid::new_v4());
+            let mut t1 = MyTenantEntity {
+                tenant_id: Uuid::new_v4(),
+                id: Uuid::new_v4(),
+                name: "foo".into(),
+                description: "foo".into(),
+            };
+            assert_eq!(pg_pool.insert_my_tenant_entity(&tenant1, &t1).await?, 1);
+            let t2 = MyTenantEntity {
+                id: Uuid::new_v4(),
+                ..Default::default()
+            };
+            let t2 = pg_pool.find_or_insert_my_tenant_entity_by_name(&tenant2, &MyTenantEntity { name: "foo".into(), ..t2 }).await?;
+            assert_eq!(t2.tenant_id, tenant2);
+
+            let found = pg_pool.find_my_tenant_entity_by_name(&tenant1, "foo").await?.unwrap();
+            assert_eq!((found.id, found.tenant_id), (t1.id, tenant1));
+            assert!(pg_pool.find_my_tenant_entity_by_id(&tenant2, &t1.id).await?.is_none());
+
+            // another tenant can't touch the row, even by its primary key
+            t1.description = "updated".into();
+            assert_eq!(pg_pool.update_my_tenant_entity(&tenant2, &t1).await?, 0);
+            assert_eq!(pg_pool.upsert_my_tenant_entity(&tenant2, &t1).await?, 0);
+            let r1 = MyReturningTenantEntity {
+                tenant_id: t1.tenant_id,
+                id: t1.id,
+                name: t1.name.clone(),
+                description: "returned".into(),
+            };
+            assert!(pg_pool.upsert_my_returning_tenant_entity(&tenant2, &r1).await?.is_none());
+            let returned = pg_pool.upsert_my_returning_tenant_entity(&tenant1, &r1).await?;
+            assert_eq!(returned.map(|r| r.description), Some("returned".into()));
+            assert_eq!(pg_pool.update_my_tenant_entity(&tenant1, &t1).await?, 1);
+
+            let l3 = pg_pool.list_my_tenant_entity(&tenant1, &MyTenantEntityFilter::default(), Page::default()).await?;
+            assert_eq!(l3.iter().map(|e| e.id).collect_vec(), [t1.id]);
+
+            let q2 = MyTenantEntityQuery::new(tenant2).name_eq("foo").fetch_all(&pg_pool).await?;
+            assert_eq!(q2.iter().map(|e| e.id).collect_vec(), [t2.id]);
+
             Ok(())
         }
         .await;
@@ -96,6 +604,457 @@ mod tests {
         Ok(result?)
     }
 
+    #[tokio::test]
+    async fn nearest() -> Result<(), sqlx::Error> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        if sqlx::query("create extension if not exists vector").execute(&pg_pool).await.is_err() {
+            eprintln!("pgvector isn't installed. skipping.");
+            return Ok(());
+        }
+
+        sqlx::query("create table my_document (id uuid primary key, embedding vector(2) not null)")
+            .execute(&pg_pool)
+            .await?;
+
+        let result: Result<(), sqlx::Error> = async {
+            let documents = [[0.0, 0.0], [1.0, 1.0], [5.0, 5.0]].map(|v| MyDocument {
+                id: Uuid::new_v4(),
+                embedding: Vector::from(v.to_vec()),
+            });
+            for document in &documents {
+                pg_pool.insert_my_document(document).await?;
+            }
+
+            let nearest = pg_pool
+                .nearest_my_document_by_embedding(&Vector::from(vec![0.9, 0.9]), 2)
+                .await?;
+            assert_eq!(nearest.iter().map(|d| d.id).collect_vec(), [documents[1].id, documents[0].id]);
+            Ok(())
+        }
+        .await;
+
+        sqlx::query("drop table my_document").execute(&pg_pool).await?;
+        result
+    }
+
+    #[tokio::test]
+    async fn joined_key() -> Result<(), sqlx::Error> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        sqlx::raw_sql(
+            "create table my_owner (id uuid primary key, email text not null unique);
+            create table my_owned_entity (id uuid primary key, owner_id uuid not null references my_owner, name text not null);",
+        )
+        .execute(&pg_pool)
+        .await?;
+
+        let result: Result<(), sqlx::Error> = async {
+            let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+            sqlx::query("insert into my_owner (id, email) values ($1, 'alice@example.com'), ($2, 'bob@example.com')")
+                .bind(alice)
+                .bind(bob)
+                .execute(&pg_pool)
+                .await?;
+
+            let owned = [(alice, "foo"), (alice, "bar"), (bob, "baz")].map(|(owner_id, name)| MyOwnedEntity {
+                id: Uuid::new_v4(),
+                owner_id,
+                name: name.into(),
+            });
+            for entity in &owned {
+                pg_pool.insert_my_owned_entity(entity).await?;
+            }
+
+            let alices = pg_pool.list_my_owned_entity_by_owner_email("alice@example.com").await?;
+            assert_eq!(alices.iter().map(|e| &e.name).sorted().collect_vec(), ["bar", "foo"]);
+            assert!(alices.iter().all(|e| e.owner_id == alice));
+            Ok(())
+        }
+        .await;
+
+        sqlx::raw_sql("drop table my_owned_entity; drop table my_owner;").execute(&pg_pool).await?;
+        result
+    }
+
+    #[tokio::test]
+    async fn camel_case_columns() -> Result<(), sqlx::Error> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        sqlx::raw_sql(r#"create table my_camel_entity ("entityId" uuid primary key, "displayName" text not null unique, "createdBy" text not null)"#)
+            .execute(&pg_pool)
+            .await?;
+
+        let result: Result<(), sqlx::Error> = async {
+            let mut entity = MyCamelEntity {
+                entity_id: Uuid::new_v4(),
+                display_name: "foo".into(),
+                created_by: "alice".into(),
+            };
+            pg_pool.insert_my_camel_entity(&entity).await?;
+            assert_eq!(pg_pool.find_my_camel_entity_by_display_name("foo").await?.as_ref(), Some(&entity));
+
+            entity.created_by = "bob".into();
+            assert_eq!(pg_pool.update_my_camel_entity(&entity).await?, 1);
+            assert_eq!(pg_pool.upsert_my_camel_entity(&entity).await?, 1);
+            assert_eq!(pg_pool.find_my_camel_entity_by_entity_id(&entity.entity_id).await?, Some(entity));
+            Ok(())
+        }
+        .await;
+
+        sqlx::query("drop table my_camel_entity").execute(&pg_pool).await?;
+        result
+    }
+
+    #[tokio::test]
+    async fn checked_queries() -> Result<(), sqlx::Error> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        sqlx::query("create table my_checked_entity (id uuid primary key, name text not null unique, description text)")
+            .execute(&pg_pool)
+            .await?;
+
+        let result: Result<(), sqlx::Error> = async {
+            let mut entity = MyCheckedEntity {
+                id: Uuid::new_v4(),
+                name: "foo".into(),
+                description: Some("first".into()),
+            };
+            pg_pool.insert_my_checked_entity(&entity).await?;
+            assert_eq!(pg_pool.find_my_checked_entity_by_name("foo").await?.as_ref(), Some(&entity));
+
+            entity.description = None;
+            assert_eq!(pg_pool.update_my_checked_entity(&entity).await?, 1);
+            assert_eq!(pg_pool.upsert_my_checked_entity(&entity).await?, 1);
+            assert_eq!(pg_pool.find_my_checked_entity_by_id(&entity.id).await?.as_ref(), Some(&entity));
+
+            let found = pg_pool
+                .find_or_insert_my_checked_entity_by_name(&MyCheckedEntity { id: Uuid::new_v4(), ..entity.clone() })
+                .await?;
+            assert_eq!(found, entity);
+
+            assert_eq!(pg_pool.delete_my_checked_entity(&entity.id).await?, 1);
+            assert_eq!(pg_pool.find_my_checked_entity_by_id(&entity.id).await?, None);
+
+            // the insert waits on a conflicting row from a transaction that commits after it started
+            let mut tx = pg_pool.begin().await?;
+            let committed = MyCheckedEntity {
+                id: Uuid::new_v4(),
+                name: "bar".into(),
+                description: None,
+            };
+            sqlx::query("insert into my_checked_entity (id, name) values ($1, $2)")
+                .bind(committed.id)
+                .bind(&committed.name)
+                .execute(&mut *tx)
+                .await?;
+            let racing = tokio::spawn({
+                let pg_pool = pg_pool.clone();
+                let entity = MyCheckedEntity { id: Uuid::new_v4(), ..committed.clone() };
+                async move { pg_pool.find_or_insert_my_checked_entity_by_name(&entity).await }
+            });
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            tx.commit().await?;
+            assert_eq!(racing.await.expect("find_or_insert panicked")?, committed);
+            Ok(())
+        }
+        .await;
+
+        sqlx::query("drop table my_checked_entity").execute(&pg_pool).await?;
+        result
+    }
+
+    #[test]
+    fn routing_key() {
+        use launchpad::mq::routing::RoutingKey;
+
+        #[derive(RoutingKey)]
+        #[routing_key(prefix = "usage")]
+        struct UsageKey {
+            #[routing_key(rename = "person")]
+            person_id: Uuid,
+            item_id: String,
+            #[routing_key(skip)]
+            _amount: i32,
+        }
+
+        let person_id = Uuid::nil();
+        let key = UsageKey {
+            person_id,
+            item_id: "v1.2".into(),
+            _amount: 3,
+        };
+        assert_eq!(key.routing_key(), format!("usage.person.{person_id}.item_id.v1_2"));
+        assert_eq!(UsageKey::pattern().to_string(), "usage.person.*.item_id.*");
+        assert_eq!(
+            String::from(UsageKey::pattern().item_id("a")),
+            "usage.person.*.item_id.a"
+        );
+    }
+
+    #[tokio::test]
+    async fn outbox() -> Result<(), Box<dyn std::error::Error>> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        let outbox = Outbox::new("my_outbox");
+        outbox.create_table(&mut *pg_pool.acquire().await?).await?;
+
+        let result: Result<(), Box<dyn std::error::Error>> = async {
+            let mut tx = pg_pool.begin().await?;
+            outbox.publish(&mut tx, &Envelope::new(String::from("rolled back")), Some("events")).await?;
+            tx.rollback().await?;
+
+            let mut tx = pg_pool.begin().await?;
+            let not_after = chrono::DateTime::from_timestamp(2_000_000_000, 0).unwrap();
+            let envelope = Envelope::new(String::from("committed"))
+                .with_correlation_id("request-1")
+                .with_header("tenant", "acme")
+                .with_version(2)
+                .with_not_after(not_after);
+            outbox.publish(&mut tx, &envelope, Some("events")).await?;
+            tx.commit().await?;
+
+            // read back as the relay publishes it, with nothing of the envelope dropped
+            let mut tx = pg_pool.begin().await?;
+            let unsent = outbox.unsent(&mut tx, 10).await?;
+            tx.rollback().await?;
+            assert_eq!(unsent.len(), 1);
+            let (_, routing_key, relayed) = &unsent[0];
+            assert_eq!(routing_key, "events");
+            assert_eq!(relayed.message, "committed");
+            assert_eq!(relayed.correlation_id(), Some("request-1"));
+            assert_eq!(relayed.headers().get("tenant").map(String::as_str), Some("acme"));
+            assert_eq!(relayed.version(), Some(2));
+            assert_eq!(relayed.not_after(), Some(not_after));
+
+            let rows: Vec<(String, serde_json::Value, String, Option<String>)> =
+                sqlx::query_as("select routing_key, message, message_id, correlation_id from my_outbox where sent_at is null")
+                    .fetch_all(&pg_pool)
+                    .await?;
+            assert_eq!(rows.len(), 1);
+            let (routing_key, message, message_id, correlation_id) = &rows[0];
+            assert_eq!(routing_key, "events");
+            assert_eq!(message, "committed");
+            assert!(Uuid::parse_str(message_id).is_ok());
+            assert_eq!(correlation_id.as_deref(), Some("request-1"));
+            Ok(())
+        }
+        .await;
+
+        sqlx::query("drop table my_outbox").execute(&pg_pool).await?;
+        result
+    }
+
+    #[tokio::test]
+    async fn quarantine() -> Result<(), Box<dyn std::error::Error>> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        let store = PgQuarantineStore::new(pg_pool.clone(), "my_quarantine");
+        store.create_table().await?;
+
+        let result: Result<(), Box<dyn std::error::Error>> = async {
+            let message = |queue: &str| QuarantinedMessage {
+                id: Uuid::new_v4(),
+                queue: queue.into(),
+                routing_key: "order.created".into(),
+                payload: b"{}".to_vec(),
+                content_type: Some("application/json".into()),
+                content_encoding: None,
+                message_id: Some("m-1".into()),
+                correlation_id: None,
+                headers: [("tenant".to_string(), "acme".to_string())].into(),
+                error: "no such customer".into(),
+                attempts: 3,
+                quarantined_at: (std::time::UNIX_EPOCH + Duration::from_secs(1_700_000_000)).into(),
+            };
+            let order = message("orders");
+            let payment = message("payments");
+            store.insert(&order).await?;
+            store.insert(&payment).await?;
+
+            assert_eq!(store.list(Some("orders"), 10).await?, std::slice::from_ref(&order));
+            assert_eq!(store.list(None, 10).await?.len(), 2);
+            assert!(store.remove(order.id).await?);
+            assert_eq!(store.list(None, 10).await?, [payment]);
+            Ok(())
+        }
+        .await;
+
+        sqlx::query("drop table my_quarantine").execute(&pg_pool).await?;
+        result
+    }
+
+    #[tokio::test]
+    async fn saga_store() -> Result<(), Box<dyn std::error::Error>> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        let store = PgSagaStore::new(pg_pool.clone());
+        store.create_table().await?;
+
+        let result: Result<(), Box<dyn std::error::Error>> = async {
+            let at = |secs: u64| (std::time::UNIX_EPOCH + Duration::from_secs(secs)).into();
+            let mut saga = SagaRecord {
+                id: Uuid::new_v4(),
+                name: "place_order".into(),
+                step: 1,
+                state: serde_json::json!({"order": 7}),
+                status: "running".into(),
+                error: None,
+                deadline: Some(at(1_700_000_060)),
+                updated_at: at(1_700_000_000),
+            };
+            store.save(&saga).await?;
+            assert_eq!(store.load(saga.id).await?, Some(saga.clone()));
+            assert_eq!(store.expired("place_order", at(1_700_000_030)).await?, []);
+            assert_eq!(store.expired("place_order", at(1_700_000_090)).await?, [saga.clone()]);
+
+            saga.step = 2;
+            saga.status = "completed".into();
+            saga.deadline = None;
+            store.save(&saga).await?;
+            assert_eq!(store.load(saga.id).await?, Some(saga));
+            assert_eq!(store.expired("place_order", at(1_700_000_090)).await?, []);
+            Ok(())
+        }
+        .await;
+
+        sqlx::query("drop table saga").execute(&pg_pool).await?;
+        result
+    }
+
+    #[tokio::test]
+    async fn audit_trail() -> Result<(), Box<dyn std::error::Error>> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        let audit = AuditTrail::new(pg_pool.clone(), "billing").with_table("my_audit");
+        audit.create_table().await?;
+
+        let result: Result<(), Box<dyn std::error::Error>> = async {
+            let message = |message_id: &str| OutgoingMessage {
+                exchange: "invoices".into(),
+                routing_key: "invoice.created".into(),
+                payload: vec![],
+                properties: lapin::BasicProperties::default().with_message_id(message_id.into()),
+            };
+            audit.published_in(&message("m-1"), &Ok(()), Duration::from_millis(3));
+            audit.published_in(&message("m-2"), &Err(MqError::Cancelled), Duration::from_millis(5));
+
+            // published messages are recorded in the background
+            let mut records = vec![];
+            for _ in 0..50 {
+                records = audit.records("m-1").await?;
+                records.extend(audit.records("m-2").await?);
+                if records.len() == 2 {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(records.len(), 2);
+            assert_eq!((records[0].service.as_str(), records[0].direction.as_str()), ("billing", "published"));
+            assert_eq!(records[0].routing_key, "invoice.created");
+            assert_eq!((records[0].outcome.as_str(), records[0].latency_ms), ("ok", Some(3.0)));
+            assert_eq!((records[1].outcome.as_str(), records[1].error.as_deref()), ("error", Some("Cancelled")));
+
+            assert_eq!(audit.clone().with_retention(Duration::from_secs(3600)).prune().await?, 0);
+            assert_eq!(audit.clone().with_retention(Duration::ZERO).prune().await?, 2);
+            Ok(())
+        }
+        .await;
+
+        sqlx::query("drop table my_audit").execute(&pg_pool).await?;
+        result
+    }
+
+    struct RecordPayment;
+
+    impl InboxHandler for RecordPayment {
+        async fn handle(&mut self, tx: &mut PgConnection, envelope: Envelope<serde_json::Value>) -> Result<(), ProcessorError> {
+            sqlx::query("insert into my_payment (amount) values ($1)")
+                .bind(envelope.message.as_i64())
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| ProcessorError::TemporaryError(e.to_string()))?;
+            if envelope.message.as_i64() < Some(0) {
+                return Err(ProcessorError::PermanentError("negative payment".into()));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn inbox() -> Result<(), Box<dyn std::error::Error>> {
+        let pg_pool = if let Ok(pg_url) = std::env::var("PG_URL") {
+            PgPool::connect(&pg_url).await?
+        } else {
+            eprintln!("No $PG_URL found. skipping.");
+            return Ok(());
+        };
+
+        let inbox = Inbox::new(pg_pool.clone(), "my_inbox");
+        inbox.create_table().await?;
+        sqlx::query("create table my_payment (amount bigint not null)").execute(&pg_pool).await?;
+
+        let result: Result<(), Box<dyn std::error::Error>> = async {
+            let mut processor = inbox.processor(RecordPayment);
+            let payment = |id: &str, amount: i64| Envelope::new(serde_json::json!(amount)).with_message_id(id);
+            let context = MessageContext::default();
+
+            processor.process_envelope(payment("a", 10), &context).await?;
+            processor.process_envelope(payment("a", 10), &context).await?;
+            assert!(processor.process_envelope(payment("b", -5), &context).await.is_err());
+
+            let amounts: Vec<i64> = sqlx::query_scalar("select amount from my_payment").fetch_all(&pg_pool).await?;
+            assert_eq!(amounts, [10]);
+            let processed: Vec<String> = sqlx::query_scalar("select message_id from my_inbox").fetch_all(&pg_pool).await?;
+            assert_eq!(processed, ["a"]);
+            Ok(())
+        }
+        .await;
+
+        sqlx::raw_sql("drop table my_payment; drop table my_inbox;").execute(&pg_pool).await?;
+        result
+    }
+
     async fn create_table(pg_pool: &PgPool) -> Result<(), sqlx::Error> {
         sqlx::query(
             "create table if not exists my_entity (
@@ -109,11 +1068,39 @@ mod tests {
         )
         .execute(pg_pool)
         .await?;
+        sqlx::query(
+            "create table if not exists my_tenant_entity (
+            tenant_id uuid not null,
+            id uuid primary key,
+            name text not null,
+            description text,
+            unique(tenant_id, name)
+        );",
+        )
+        .execute(pg_pool)
+        .await?;
+        sqlx::query(
+            "create table if not exists my_entity_history (
+            id uuid not null,
+            name text not null,
+            version integer not null,
+            color text,
+            description text,
+            actor text not null,
+            changed_at timestamptz not null
+        );",
+        )
+        .execute(pg_pool)
+        .await?;
         Ok(())
     }
 
     async fn drop_table(pg_pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query("drop view if exists my_red_entity").execute(pg_pool).await?;
         sqlx::query("drop table my_entity").execute(pg_pool).await?;
+        sqlx::query("drop function if exists notify_my_entity_changed").execute(pg_pool).await?;
+        sqlx::query("drop table my_tenant_entity").execute(pg_pool).await?;
+        sqlx::query("drop table my_entity_history").execute(pg_pool).await?;
         Ok(())
     }
 
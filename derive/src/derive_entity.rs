@@ -4,9 +4,9 @@ use convert_case::{Case, Casing};
 use darling::{FromDeriveInput, FromField};
 use derive_more::Constructor;
 use itertools::Itertools;
-use proc_macro2::TokenStream;
+use proc_macro2::{Span, TokenStream};
 use quote::{format_ident, quote};
-use syn::{parse_quote, Data, DeriveInput, Fields, Ident, Type};
+use syn::{parse_quote, spanned::Spanned, Data, DeriveInput, Fields, Ident, Type, Visibility};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -22,15 +22,108 @@ pub(crate) enum DeriveEntityError {
 
     #[error("a key must be named, either explicitly or on a named field")]
     MissingKeyName,
+
+    #[error("key name {0} must be usable in an identifier: letters, digits and underscores")]
+    InvalidKeyName(String),
+
+    #[error("keys {0} and {1} would generate the same methods and constants")]
+    DuplicateKeyName(String, String),
+
+    #[error("field {0} has a type that can't be mapped to a column; use an owned type")]
+    UnsupportedType(Ident),
+
+    #[error("generic entities aren't supported")]
+    GenericEntity,
+
+    #[error("projection {0} references unknown field {1}")]
+    UnknownProjectionField(Ident, String),
+
+    #[error("aggregate references unknown field {0}")]
+    UnknownAggregateField(String),
+
+    #[error("only one key can be marked primary, found {0} and {1}")]
+    MultiplePrimaryKeys(String, String),
+
+    #[error("unknown rename_all rule {0}")]
+    UnknownRenameRule(String),
+
+    #[error("key {0} can't include skipped field {1}")]
+    SkippedKeyField(String, Ident),
+
+    #[error("checked queries fill every field from a column, so can't skip field {0}")]
+    CheckedSkippedField(Ident),
+
+    #[error("key {0} {1}")]
+    InvalidJoin(String, &'static str),
+
+    #[error("tenant {0} must name a mapped field")]
+    UnknownTenantField(String),
+
+    #[error("caching requires a primary key")]
+    CacheWithoutPrimaryKey,
+
+    #[error("loader requires a single column primary key")]
+    LoaderWithoutPrimaryKey,
+
+    #[error("rocket_routes {0}")]
+    UnsupportedRoutes(&'static str),
+
+    #[error("views are read-only, so can't be {0}")]
+    ReadOnlyView(&'static str),
+
+    #[error("SynError: {0}")]
+    SynError(#[from] syn::Error),
+
+    #[error("{1}")]
+    Spanned(Span, Box<DeriveEntityError>),
 }
 
-#[derive(Debug, Constructor)]
+impl DeriveEntityError {
+    /// Points the error at the tokens that caused it.
+    pub fn at(self, span: Span) -> Self {
+        match self {
+            spanned @ DeriveEntityError::Spanned(..) => spanned,
+            error => DeriveEntityError::Spanned(span, Box::new(error)),
+        }
+    }
+
+    /// Reports the error where it happened, falling back to `span` for errors without a location.
+    pub fn into_compile_error(self, span: Span) -> TokenStream {
+        match self {
+            DeriveEntityError::DarlingError(e) => e.write_errors(),
+            DeriveEntityError::SynError(e) => e.to_compile_error(),
+            DeriveEntityError::Spanned(span, e) => e.into_compile_error(span),
+            e => syn::Error::new(span, e).to_compile_error(),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub(crate) struct DeriveEntity {
     pub entity: Ident,
+    pub vis: Visibility,
     pub snake_name: Option<Ident>,
     pub table_name: String,
-    pub _columns: Vec<FieldColumn>,
+    pub columns: Vec<FieldColumn>,
     pub keys: Vec<Key>,
+    pub projections: Vec<Projection>,
+    /// Columns rows are counted by, one `count_..._grouped_by_...` method each.
+    pub count_by: Vec<FieldColumn>,
+    pub checked: bool,
+    pub returning: bool,
+    pub audited: bool,
+    pub cache: bool,
+    pub loader: bool,
+    pub notify: bool,
+    pub rocket_routes: bool,
+    /// Read-only: only lookups are generated.
+    pub view: bool,
+    /// Query the view is created from, when its DDL should be generated.
+    pub view_sql: Option<String>,
+    pub tenant: Option<FieldColumn>,
+    /// Error type generated methods return, convertible from `sqlx::Error`.
+    pub error: Type,
+    pub custom_error: bool,
 }
 
 impl DeriveEntity {
@@ -43,13 +136,69 @@ impl DeriveEntity {
             .to_case(Case::Snake);
         format_ident!("{}", name)
     }
+
+    pub fn primary_key(&self) -> Option<&Key> {
+        self.keys.iter().find(|k| k.primary)
+    }
+
+    /// Columns that can change without changing the row's identity or tenant.
+    pub fn value_columns(&self) -> Vec<&FieldColumn> {
+        let primary = self
+            .primary_key()
+            .map(|k| k.components.iter().map(|c| &c.field_name).collect_vec())
+            .unwrap_or_default();
+
+        self.columns
+            .iter()
+            .filter(|c| !primary.contains(&&c.field_name) && !self.is_tenant(c))
+            .collect_vec()
+    }
+
+    /// Converts the `sqlx::Error` of a tail expression into the entity's error type, when it has one.
+    pub fn map_err(&self) -> TokenStream {
+        if self.custom_error {
+            quote! { .map_err(Into::into) }
+        } else {
+            quote! {}
+        }
+    }
+
+    pub fn history_table(&self) -> String {
+        format!("{}_history", self.table_name)
+    }
+
+    pub fn is_tenant(&self, column: &FieldColumn) -> bool {
+        self.tenant
+            .as_ref()
+            .is_some_and(|t| t.field_name == column.field_name)
+    }
+
+    /// The columns a key's lookups filter on: its components, scoped to the tenant when there is one.
+    pub fn lookup<'a>(&'a self, key: &'a Key) -> Vec<&'a FieldColumn> {
+        let tenant = self
+            .tenant
+            .as_ref()
+            .filter(|t| !key.components.iter().any(|c| c.field_name == t.field_name));
+
+        tenant.into_iter().chain(key.components.iter()).collect_vec()
+    }
 }
 
 #[derive(Debug, Default, Constructor)]
 pub(crate) struct Key {
     pub name: String,
     pub unique: bool,
+    pub primary: bool,
+    pub locking: bool,
     pub components: Vec<FieldColumn>,
+    /// Tables joined to reach components on related tables, with their join conditions.
+    pub joins: Vec<(String, String)>,
+}
+
+#[derive(Debug, Constructor)]
+pub(crate) struct Projection {
+    pub name: Ident,
+    pub columns: Vec<FieldColumn>,
 }
 
 #[derive(Debug, Constructor, Clone)]
@@ -59,6 +208,52 @@ pub(crate) struct FieldColumn {
     pub column_name: String,
 }
 
+impl FieldColumn {
+    pub fn is_optional(&self) -> bool {
+        matches!(
+            &self.field_type,
+            Type::Path(p) if p.path.segments.last().is_some_and(|s| s.ident == "Option")
+        )
+    }
+
+    /// The column's type, without the `Option` of a nullable column.
+    pub fn value_type(&self) -> &Type {
+        let inner = match &self.field_type {
+            Type::Path(p) => p.path.segments.last().and_then(|s| match &s.arguments {
+                syn::PathArguments::AngleBracketed(args) if s.ident == "Option" => args.args.first(),
+                _ => None,
+            }),
+            _ => None,
+        };
+
+        match inner {
+            Some(syn::GenericArgument::Type(ty)) => ty,
+            _ => &self.field_type,
+        }
+    }
+
+    /// The column prefixed with `table`, unless it already names one.
+    pub fn qualified(&self, table: &str) -> FieldColumn {
+        if self.column_name.contains('.') {
+            self.clone()
+        } else {
+            FieldColumn {
+                column_name: format!("{table}.{}", self.column_name),
+                ..self.clone()
+            }
+        }
+    }
+
+    /// Whether the column holds a `pgvector::Vector`, which similarity queries are generated for.
+    pub fn is_vector(&self) -> bool {
+        cfg!(feature = "pgvector")
+            && matches!(
+                self.value_type(),
+                Type::Path(p) if p.path.segments.last().is_some_and(|s| s.ident == "Vector")
+            )
+    }
+}
+
 impl TryFrom<DeriveInput> for DeriveEntity {
     type Error = DeriveEntityError;
 
@@ -71,63 +266,250 @@ impl TryFrom<DeriveInput> for DeriveEntity {
 
         let args = args::DeriveInputArgs::from_derive_input(&derive_input)?;
 
+        // generated impls don't carry the entity's generics
+        if !derive_input.generics.params.is_empty() {
+            return Err(DeriveEntityError::GenericEntity.at(derive_input.generics.span()));
+        }
+
         let fields = &ent_struct.fields;
-        let field_columns = field_columns(fields)?;
+        let rename_all = args
+            .rename_all
+            .as_ref()
+            .map(|rule| RenameRule::try_from(rule.as_str()).map_err(|e| e.at(rule.span())))
+            .transpose()?;
+        let field_columns = field_columns(fields, rename_all)?;
+
+        // rows are built by `query_as!`, which has nothing to fill a skipped field with
+        if args.checked {
+            if let Some(skipped) = fields
+                .iter()
+                .filter_map(|f| f.ident.as_ref())
+                .find(|i| !field_columns.contains_key(*i))
+            {
+                return Err(DeriveEntityError::CheckedSkippedField(skipped.clone()).at(skipped.span()));
+            }
+        }
 
         let pks = fields
             .iter()
             .map(|f| {
-                let is_key = f.attrs.iter().any(|a| {
-                    // if the attrs don't have a 'key' ident, stop
-                    let path = a.path();
-                    path.get_ident()
-                        .map(|i| &i.to_string() == "key")
-                        .unwrap_or(false)
-                });
-                if !is_key {
+                // if the attrs don't have a 'key' ident, stop
+                let Some(attr) = f.attrs.iter().find(|a| a.path().is_ident("key")) else {
                     return Ok(None);
-                }
+                };
+                let span = attr.span();
 
-                let f_ident = f.ident.clone().ok_or(DeriveEntityError::MissingKeyName)?;
+                let f_ident = f
+                    .ident
+                    .clone()
+                    .ok_or_else(|| DeriveEntityError::MissingKeyName.at(span))?;
                 let key = args::Key::from_field(f).map_err(DeriveEntityError::from)?;
-                let field_column = field_columns[&f_ident].clone();
-                let key_name = key.name.unwrap_or_else(|| f_ident.to_string());
-                let key_unique = key.unique.unwrap_or(false);
-                Ok(Some((key_name, (field_column, key_unique))))
+                let key_name = key.name.clone().unwrap_or_else(|| f_ident.to_string());
+                if key_name.is_empty() || !key_name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                    return Err(DeriveEntityError::InvalidKeyName(key_name).at(span));
+                }
+
+                // a component on a related table is looked up by that table's column instead of the field
+                let field_column = match (&key.via, &key.join) {
+                    (Some(via), Some(join)) => {
+                        let Some((table, column)) = via.split_once('.') else {
+                            return Err(DeriveEntityError::InvalidJoin(key_name, "via must be a `table.column`").at(span));
+                        };
+                        let field_type = key.via_type.clone().unwrap_or_else(|| f.ty.clone());
+                        let field_name = format_ident!("{}_{}", table, column);
+                        Ok((FieldColumn::new(field_name, field_type, via.clone()), Some((table.to_string(), join.clone()))))
+                    }
+                    (None, None) => field_columns
+                        .get(&f_ident)
+                        .cloned()
+                        .map(|fc| (fc, None))
+                        .ok_or_else(|| DeriveEntityError::SkippedKeyField(key_name.clone(), f_ident.clone()).at(span)),
+                    _ => Err(DeriveEntityError::InvalidJoin(key_name.clone(), "needs both via and join").at(span)),
+                }?;
+                Ok(Some((key_name, (field_column, key, span))))
             })
-            .collect::<Result<Vec<Option<(String, (FieldColumn, bool))>>, DeriveEntityError>>()?
-            .iter()
+            .collect::<Result<Vec<Option<(String, ((FieldColumn, Option<(String, String)>), args::Key, Span))>>, DeriveEntityError>>()?
+            .into_iter()
             .flatten()
-            .cloned()
             .collect_vec();
 
         let keys = utilities::iterable::index(pks)
             .into_iter()
             .map(|(k, v)| {
-                let components = v.iter().map(|(fc, _)| fc.clone()).collect_vec();
-                // assumption: only one key in the named key needs to be marked unique
-                let unique = v.iter().any(|(_, u)| *u);
-                Key::new(k, unique, components)
+                let components = v.iter().map(|((fc, _), ..)| fc.clone()).collect_vec();
+                let joins = v.iter().filter_map(|((_, join), ..)| join.clone()).unique().collect_vec();
+                // assumption: only one key in the named key needs to be marked unique (or primary, or locking)
+                let primary = v.iter().any(|(_, a, _)| a.primary);
+                let unique = primary || v.iter().any(|(_, a, _)| a.unique.unwrap_or(false));
+                let locking = v.iter().any(|(_, a, _)| a.locking);
+                let span = v.iter().map(|(.., span)| *span).next().unwrap_or_else(Span::call_site);
+                // rows are only written and locked through their own table
+                if !joins.is_empty() && (primary || locking) {
+                    return Err(DeriveEntityError::InvalidJoin(k, "through a join can't be primary or locking").at(span));
+                }
+                Ok((Key::new(k, unique, primary, locking, components, joins), span))
             })
+            .collect::<Result<Vec<_>, DeriveEntityError>>()?
+            .into_iter()
+            .sorted_by(|(a, _), (b, _)| a.name.cmp(&b.name))
             .collect_vec();
 
-        let columns = field_columns.into_values().collect_vec();
+        if let Some(((a, _), (b, span))) = keys.iter().filter(|(k, _)| k.primary).next_tuple() {
+            return Err(DeriveEntityError::MultiplePrimaryKeys(a.name.clone(), b.name.clone()).at(*span));
+        }
+
+        // names differing only in case collide once turned into method names and constants
+        if let Some(((a, _), (b, span))) = keys
+            .iter()
+            .tuple_combinations()
+            .find(|((a, _), (b, _))| a.name.to_case(Case::Snake) == b.name.to_case(Case::Snake))
+        {
+            return Err(DeriveEntityError::DuplicateKeyName(a.name.clone(), b.name.clone()).at(*span));
+        }
+
+        let keys = keys.into_iter().map(|(key, _)| key).collect_vec();
+
+        let projections = args
+            .projection
+            .into_iter()
+            .map(|p| {
+                let columns = p
+                    .fields
+                    .iter()
+                    .map(|path| {
+                        path.get_ident()
+                            .and_then(|i| field_columns.get(i))
+                            .cloned()
+                            .ok_or_else(|| {
+                                DeriveEntityError::UnknownProjectionField(
+                                    p.name.clone(),
+                                    quote!(#path).to_string(),
+                                )
+                                .at(path.span())
+                            })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(Projection::new(p.name, columns))
+            })
+            .collect::<Result<Vec<_>, DeriveEntityError>>()?;
+
+        let count_by = args
+            .aggregate
+            .iter()
+            .map(|aggregate| {
+                let field = &aggregate.count_by;
+                field_columns
+                    .values()
+                    .find(|c| c.field_name == **field)
+                    .cloned()
+                    .ok_or_else(|| DeriveEntityError::UnknownAggregateField(field.to_string()).at(field.span()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // keep columns in declaration order
+        let columns = fields
+            .iter()
+            .filter_map(|f| f.ident.as_ref().and_then(|i| field_columns.get(i)))
+            .cloned()
+            .collect_vec();
 
         let table_name = args
             .table_name
             .unwrap_or_else(|| derive_input.ident.to_string());
 
-        Ok(DeriveEntity::new(
-            derive_input.ident,
-            args.name,
+        let tenant = args
+            .tenant
+            .map(|tenant| {
+                columns
+                    .iter()
+                    .find(|c| c.field_name == *tenant)
+                    .cloned()
+                    .ok_or_else(|| DeriveEntityError::UnknownTenantField(tenant.to_string()).at(tenant.span()))
+            })
+            .transpose()?;
+
+        let view = args.view.map(|view| view.unwrap_or_default());
+        if view.is_some() {
+            // none of these make sense without writes, and postgres won't put row triggers on a view
+            for (unsupported, reason) in [
+                (args.audited, "audited"),
+                (args.notify, "notified of changes"),
+                (args.rocket_routes, "served by rocket_routes"),
+            ] {
+                if unsupported {
+                    return Err(DeriveEntityError::ReadOnlyView(reason).at(derive_input.ident.span()));
+                }
+            }
+        }
+
+        Ok(DeriveEntity {
+            entity: derive_input.ident,
+            vis: derive_input.vis,
+            snake_name: args.name,
             table_name,
             columns,
             keys,
-        ))
+            projections,
+            count_by,
+            checked: args.checked,
+            returning: args.returning,
+            audited: args.audited,
+            cache: args.cache,
+            loader: args.loader,
+            notify: args.notify,
+            rocket_routes: args.rocket_routes,
+            view: view.is_some(),
+            view_sql: view.and_then(|view| view.sql),
+            tenant,
+            custom_error: args.error.is_some(),
+            error: args
+                .error
+                .map(|path| Type::Path(syn::TypePath { qself: None, path }))
+                .unwrap_or_else(|| parse_quote!(sqlx::Error)),
+        })
+    }
+}
+
+/// Mirrors the `rename_all` rules accepted by serde and sqlx.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RenameRule {
+    Lower,
+    Upper,
+    Case(Case),
+}
+
+impl TryFrom<&str> for RenameRule {
+    type Error = DeriveEntityError;
+
+    fn try_from(rule: &str) -> Result<Self, Self::Error> {
+        match rule {
+            "lowercase" => Ok(RenameRule::Lower),
+            "UPPERCASE" => Ok(RenameRule::Upper),
+            "PascalCase" => Ok(RenameRule::Case(Case::Pascal)),
+            "camelCase" => Ok(RenameRule::Case(Case::Camel)),
+            "snake_case" => Ok(RenameRule::Case(Case::Snake)),
+            "SCREAMING_SNAKE_CASE" => Ok(RenameRule::Case(Case::UpperSnake)),
+            "kebab-case" => Ok(RenameRule::Case(Case::Kebab)),
+            "SCREAMING-KEBAB-CASE" => Ok(RenameRule::Case(Case::UpperKebab)),
+            _ => Err(DeriveEntityError::UnknownRenameRule(rule.into())),
+        }
+    }
+}
+
+impl RenameRule {
+    pub fn apply(&self, name: &str) -> String {
+        match self {
+            RenameRule::Lower => name.to_lowercase(),
+            RenameRule::Upper => name.to_uppercase(),
+            RenameRule::Case(case) => name.to_case(*case),
+        }
     }
 }
 
-fn field_columns(fields: &Fields) -> Result<HashMap<Ident, FieldColumn>, DeriveEntityError> {
+fn field_columns(
+    fields: &Fields,
+    rename_all: Option<RenameRule>,
+) -> Result<HashMap<Ident, FieldColumn>, DeriveEntityError> {
     fields
         .iter()
         .try_fold(HashMap::default(), |mut mappings, field| {
@@ -138,14 +520,26 @@ fn field_columns(fields: &Fields) -> Result<HashMap<Ident, FieldColumn>, DeriveE
 
             let field_type = field.ty.clone();
 
-            let column = args::Column::from_field(field).ok();
+            let column = args::Column::from_field(field)?;
+            if column.skip {
+                return Ok(mappings);
+            }
+
+            // borrowed and unnameable types can't be decoded into an owned row
+            if matches!(
+                field_type,
+                Type::Reference(_) | Type::ImplTrait(_) | Type::TraitObject(_) | Type::BareFn(_) | Type::Never(_) | Type::Infer(_)
+            ) {
+                return Err(DeriveEntityError::UnsupportedType(field_name.clone()).at(field_type.span()));
+            }
 
             let field_column = FieldColumn::new(
                 field_name.clone(),
                 field_type,
-                column
-                    .map(|c| c.name)
-                    .unwrap_or_else(|| field_name.to_string()),
+                column.name.unwrap_or_else(|| {
+                    let name = field_name.to_string();
+                    rename_all.map(|r| quote_column(r.apply(&name))).unwrap_or(name)
+                }),
             );
 
             mappings.insert(field_name.clone(), field_column);
@@ -155,38 +549,240 @@ fn field_columns(fields: &Fields) -> Result<HashMap<Ident, FieldColumn>, DeriveE
         })
 }
 
+/// Quotes a renamed column unless postgres would read it as written, so `entityId` isn't folded
+/// to `entityid` and `entity-id` isn't a subtraction.
+fn quote_column(name: String) -> String {
+    let plain = name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if plain {
+        name
+    } else {
+        format!("\"{name}\"")
+    }
+}
+
 impl TryFrom<DeriveEntity> for TokenStream {
     type Error = DeriveEntityError;
 
     fn try_from(entity: DeriveEntity) -> Result<Self, Self::Error> {
-        let repo_trait = repo_trait(&entity)?;
+        let EntityImpl {
+            trait_name,
+            locking_trait_name,
+            ..
+        } = EntityImpl::new(&entity);
+
+        let fns = repo_fns(&entity);
+        let repo = repo_trait(&trait_name, &fns)?;
+        let repo_impls = ImplTarget::all()
+            .iter()
+            .chain([&ImplTarget::repository(&entity)])
+            .map(|target| repo_impl(&entity, &trait_name, &fns, target))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // row locks only last as long as a transaction, so there's no point offering them elsewhere
+        let locking_fns = locking_fns(&entity);
+        let locking_repo = if locking_fns.is_empty() {
+            quote! {}
+        } else {
+            let locking_trait = repo_trait(&locking_trait_name, &locking_fns)?;
+            let locking_impl = repo_impl(&entity, &locking_trait_name, &locking_fns, &ImplTarget::transaction())?;
+            quote! {
+                #locking_trait
+                #locking_impl
+            }
+        };
+
+        let repo_ext = repo_ext(&entity)?;
+
+        let cached_repo = cached_repo(&entity, &fns)?;
+
+        let loader = loader(&entity)?;
+
+        let change_feed = change_feed(&entity)?;
 
-        let pgpool_ty: Type = parse_quote!(sqlx::Pool<sqlx::Postgres>);
-        let pgpool_impl = pg_impl(&entity, pgpool_ty)?;
+        let routes = rocket_routes(&entity)?;
 
-        let tx_impl = tx_impl(&entity)?;
+        let projection_structs = projection_structs(&entity)?;
+
+        let query_builder = query_builder(&entity)?;
+
+        let patch_struct = patch_struct(&entity)?;
+
+        let filter_struct = filter_struct(&entity)?;
+
+        let metadata = metadata(&entity)?;
 
         Ok(quote! {
-            #repo_trait
+            #metadata
+
+            #projection_structs
+
+            #patch_struct
+
+            #filter_struct
+
+            #query_builder
+
+            #repo
+
+            #(
+                #repo_impls
+            )*
+
+            #locking_repo
+
+            #repo_ext
+
+            #cached_repo
+
+            #loader
 
-            #pgpool_impl
+            #change_feed
 
-            #tx_impl
+            #routes
         })
     }
 }
 
+/// A type the repo trait is implemented for, along with how to reach an executor from `&self`.
+struct ImplTarget {
+    impl_ty: Type,
+    prelude: TokenStream,
+    executor: TokenStream,
+}
+
+impl ImplTarget {
+    fn all() -> Vec<ImplTarget> {
+        let pool = ImplTarget {
+            impl_ty: parse_quote!(sqlx::Pool<sqlx::Postgres>),
+            prelude: quote! {},
+            executor: quote! { self },
+        };
+
+        let locked_tys: [Type; 2] = [
+            parse_quote!(sqlx::pool::PoolConnection<sqlx::Postgres>),
+            parse_quote!(&mut sqlx::PgConnection),
+        ];
+
+        let locked = locked_tys.into_iter().map(ImplTarget::locked);
+
+        [pool, ImplTarget::transaction()]
+            .into_iter()
+            .chain(locked)
+            .collect_vec()
+    }
+
+    /// The generated `<Entity>Repository`, which owns its pool.
+    fn repository(entity: &DeriveEntity) -> ImplTarget {
+        let repository_name = EntityImpl::new(entity).repository_name;
+        ImplTarget {
+            impl_ty: parse_quote!(#repository_name),
+            prelude: quote! {},
+            executor: quote! { &self.pool },
+        }
+    }
+
+    fn transaction() -> ImplTarget {
+        ImplTarget::locked(parse_quote!(sqlx::Transaction<'_, sqlx::Postgres>))
+    }
+
+    /// Connections need `&mut` access, so they are shared behind a mutex.
+    fn locked(inner: Type) -> ImplTarget {
+        ImplTarget {
+            impl_ty: parse_quote!(tokio::sync::Mutex<#inner>),
+            prelude: quote! {
+                let mut conn = self.lock().await;
+            },
+            executor: quote! { &mut **conn },
+        }
+    }
+}
+
+/// The struct a query maps rows into, and the select list that produces those rows.
+struct Row {
+    ty: Ident,
+    snake_name: Ident,
+    select_list: String,
+    /// The select list with columns qualified by the entity's table, for queries joining others.
+    joined_select_list: String,
+}
+
+impl Row {
+    fn entity(entity: &DeriveEntity) -> Self {
+        let table = &entity.table_name;
+        Self {
+            ty: entity.entity.clone(),
+            snake_name: entity.entity_snake_name(),
+            select_list: Self::select_list(entity, None, ""),
+            joined_select_list: Self::select_list(entity, None, &format!("{table}.")),
+        }
+    }
+
+    fn projection(entity: &DeriveEntity, projection: &Projection) -> Self {
+        let table = &entity.table_name;
+        Self {
+            ty: projection.name.clone(),
+            snake_name: format_ident!("{}", projection.name.to_string().to_case(Case::Snake)),
+            select_list: Self::select_list(entity, Some(&projection.columns), ""),
+            joined_select_list: Self::select_list(entity, Some(&projection.columns), &format!("{table}.")),
+        }
+    }
+
+    /// Selects `columns`, or the whole row when there are none, prefixing each with `qualifier`.
+    fn select_list(entity: &DeriveEntity, columns: Option<&[FieldColumn]>, qualifier: &str) -> String {
+        match columns {
+            // checked queries map columns onto fields by name, so they can't rely on `*`
+            _ if entity.checked => Self::aliased_select_list(columns.unwrap_or(&entity.columns), qualifier),
+            None => format!("{qualifier}*"),
+            Some(columns) => columns.iter().map(|c| format!("{qualifier}{}", c.column_name)).join(", "),
+        }
+    }
+
+    fn aliased_select_list(columns: &[FieldColumn], qualifier: &str) -> String {
+        columns
+            .iter()
+            .map(|c| {
+                if c.field_name == c.column_name {
+                    format!("{qualifier}{}", c.column_name)
+                } else {
+                    format!("{qualifier}{} as \"{}\"", c.column_name, c.field_name)
+                }
+            })
+            .join(", ")
+    }
+
+    /// Like [`Row::aliased_select_list`], but takes nullability from the field types for queries
+    /// where postgres can't infer it.
+    fn nullability_select_list(columns: &[FieldColumn]) -> String {
+        columns
+            .iter()
+            .map(|c| {
+                let nullability = if c.is_optional() { "?" } else { "!" };
+                format!("{} as \"{}{}\"", c.column_name, c.field_name, nullability)
+            })
+            .join(", ")
+    }
+}
+
+/// A generated repo method, declared once on the trait and implemented for every [`ImplTarget`].
+struct RepoFn {
+    signature: TokenStream,
+    body: Box<dyn Fn(&ImplTarget) -> TokenStream>,
+    /// What the method does and through which key, as reported in its tracing span.
+    operation: &'static str,
+    key: Option<String>,
+}
+
 struct KeyFn {
-    _ent: Ident,
     fn_name: Ident,
     fn_rtn: proc_macro2::TokenStream,
     fn_args: Vec<proc_macro2::TokenStream>,
 }
 
 impl KeyFn {
-    fn new(entity: &DeriveEntity, key: &Key) -> Self {
-        let ent = entity.entity.clone();
-        let snake_ent = entity.entity_snake_name();
+    fn new(entity: &DeriveEntity, key: &Key, row: &Row) -> Self {
+        let ty = &row.ty;
+        let snake_ent = &row.snake_name;
 
         let fn_name = if key.unique {
             format_ident!("find_{}_by_{}", snake_ent, key.name)
@@ -194,45 +790,20 @@ impl KeyFn {
             format_ident!("list_{}_by_{}", snake_ent, key.name)
         };
 
+        let error = &entity.error;
         let fn_rtn = if key.unique {
             quote! {
-                Result<Option<#ent>, sqlx::Error>
+                Result<Option<#ty>, #error>
             }
         } else {
             quote! {
-                Result<Vec<#ent>, sqlx::Error>
+                Result<Vec<#ty>, #error>
             }
         };
 
-        fn map_type(ty: &Type) -> Type {
-            match ty {
-                Type::Path(p)
-                    if p.path
-                        .get_ident()
-                        .map(Ident::to_string)
-                        .is_some_and(|s| s.ends_with("String")) =>
-                {
-                    let str_ty: Type = parse_quote!(str);
-                    str_ty
-                }
-                _ => ty.clone(),
-            }
-        }
-
-        let fn_args = key
-            .components
-            .iter()
-            .map(|c| {
-                let name = &c.field_name;
-                let ty = map_type(&c.field_type);
-                quote! {
-                    #name: &#ty
-                }
-            })
-            .collect_vec();
+        let fn_args = lookup_args(&entity.lookup(key));
 
         Self {
-            _ent: ent,
             fn_name,
             fn_rtn,
             fn_args,
@@ -240,190 +811,2034 @@ impl KeyFn {
     }
 }
 
+/// Arguments identifying a key's row(s), borrowed and with `String`s passed as `&str`.
+fn lookup_args(columns: &[&FieldColumn]) -> Vec<TokenStream> {
+    fn map_type(ty: &Type) -> Type {
+        match ty {
+            Type::Path(p)
+                if p.path
+                    .get_ident()
+                    .map(Ident::to_string)
+                    .is_some_and(|s| s.ends_with("String")) =>
+            {
+                let str_ty: Type = parse_quote!(str);
+                str_ty
+            }
+            _ => ty.clone(),
+        }
+    }
+
+    columns
+        .iter()
+        .map(|c| {
+            let name = &c.field_name;
+            let ty = map_type(&c.field_type);
+            quote! {
+                #name: &#ty
+            }
+        })
+        .collect_vec()
+}
+
+/// Binds for [`lookup_args`], in the same order.
+fn lookup_binds(columns: &[&FieldColumn]) -> Vec<TokenStream> {
+    columns
+        .iter()
+        .map(|c| {
+            let f = &c.field_name;
+            quote! { #f }
+        })
+        .collect_vec()
+}
+
+/// The tenant argument taken alongside a whole entity, if the entity is scoped by one.
+fn tenant_args(entity: &DeriveEntity) -> Vec<TokenStream> {
+    lookup_args(&entity.tenant.iter().collect_vec())
+}
+
 struct EntityImpl {
     trait_name: Ident,
+    repository_name: Ident,
+    ext_trait_name: Ident,
+    locking_trait_name: Ident,
 }
 
 impl EntityImpl {
     fn new(entity: &DeriveEntity) -> Self {
         let trait_name = format_ident!("{}Repo", entity.entity);
-        Self { trait_name }
+        let repository_name = format_ident!("{}Repository", entity.entity);
+        let ext_trait_name = format_ident!("{}RepoExt", entity.entity);
+        let locking_trait_name = format_ident!("{}LockingRepo", entity.entity);
+        Self {
+            trait_name,
+            repository_name,
+            ext_trait_name,
+            locking_trait_name,
+        }
     }
 }
 
-fn repo_trait(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
-    let EntityImpl { trait_name } = EntityImpl::new(entity);
-    let key_fns = entity
+fn repo_fns(entity: &DeriveEntity) -> Vec<RepoFn> {
+    let rows = std::iter::once(Row::entity(entity))
+        .chain(entity.projections.iter().map(|p| Row::projection(entity, p)))
+        .collect_vec();
+
+    let key_fns = rows
+        .iter()
+        .flat_map(|row| entity.keys.iter().map(|key| key_fn(entity, key, row)));
+
+    let find_or_insert_fns = entity
         .keys
         .iter()
-        .map(|key| {
-            let KeyFn {
-                fn_name,
-                fn_rtn,
-                fn_args,
-                ..
-            } = KeyFn::new(entity, key);
+        .filter(|key| key.unique && key.joins.is_empty())
+        .map(|key| find_or_insert_fn(entity, key));
+
+    let count_fns = entity.count_by.iter().map(|column| count_by_fn(entity, column));
 
+    let list_fns = std::iter::once(list_fn(entity));
+
+    let nearest_fns = entity
+        .columns
+        .iter()
+        .filter(|c| c.is_vector())
+        .map(|column| nearest_fn(entity, column));
+
+    let lookup_fns = key_fns.chain(list_fns).chain(nearest_fns).chain(count_fns);
+    if entity.view {
+        return lookup_fns.collect_vec();
+    }
+
+    let insert_fns = [insert_fn(entity), copy_in_fn(entity)].into_iter();
+
+    let mutation_fns = entity
+        .primary_key()
+        .into_iter()
+        .flat_map(|pk| {
+            let update_fn = (!entity.value_columns().is_empty()).then(|| update_fn(entity, pk));
+            update_fn.into_iter().chain([
+                upsert_fn(entity, pk),
+                update_partial_fn(entity, pk),
+                delete_fn(entity, pk),
+            ])
+        });
+
+    lookup_fns
+        .chain(find_or_insert_fns)
+        .chain(insert_fns)
+        .chain(mutation_fns)
+        .collect_vec()
+}
+
+fn update_partial_fn(entity: &DeriveEntity, pk: &Key) -> RepoFn {
+    let fn_name = format_ident!("update_{}_partial", entity.entity_snake_name());
+    let patch_name = format_ident!("{}Patch", entity.entity);
+    let lookup = entity.lookup(pk);
+    let fn_args = lookup_args(&lookup);
+    let (actor_args, _) = actor_args(entity);
+    let update = format!("update {} set ", entity.table_name);
+    let entity_returning = entity.returning;
+
+    let assignments = entity
+        .value_columns()
+        .into_iter()
+        .map(|c| {
+            let field = &c.field_name;
+            let assign = format!("{} = ", c.column_name);
+            quote! {
+                if let Some(value) = &patch.#field {
+                    assignments.push(#assign).push_bind_unseparated(value);
+                }
+            }
+        })
+        .collect_vec();
+
+    let conditions = lookup
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            let field = &c.field_name;
+            let condition = format!("{} {} = ", if i == 0 { " where" } else { " and" }, c.column_name);
             quote! {
-                async fn #fn_name(&self, #(#fn_args), *) -> #fn_rtn;
+                builder.push(#condition).push_bind(#field);
             }
         })
         .collect_vec();
 
-    Ok(quote! {
-        pub trait #trait_name {
+    let start = if entity.audited {
+        let columns = entity.columns.iter().map(|c| &c.column_name).join(", ");
+        let previous = format!("with previous as (select {columns} from {}", entity.table_name);
+        let history = format!(
+            "), history as (insert into {} ({columns}, actor, changed_at) select {columns}, ",
+            entity.history_table()
+        );
+        let update = format!(", now() from previous) {update}");
+        quote! {
+            let mut builder = sqlx::QueryBuilder::<sqlx::Postgres>::new(#previous);
             #(
-                #key_fns
+                #conditions
             )*
+            builder.push(#history).push_bind(actor).push(#update);
         }
-    })
-}
+    } else {
+        quote! {
+            let mut builder = sqlx::QueryBuilder::<sqlx::Postgres>::new(#update);
+        }
+    };
+
+    let fn_rtn = mutation_rtn(entity, true);
+
+    let (unchanged, finish) = if entity.returning {
+        let row = Row::entity(entity);
+        let select = format!(
+            "select {} from {} where {}",
+            row.select_list,
+            entity.table_name,
+            where_clause(&lookup, 0)
+        );
+        let select = static_query(entity, &row.ty, &select, &lookup_binds(&lookup));
+        let returning = format!(" returning {}", row.select_list);
+
+        (
+            quote! { #select.fetch_optional },
+            quote! {
+                builder.push(#returning);
+                builder.build_query_as().fetch_optional
+            },
+        )
+    } else {
+        (
+            quote! { return Ok(0); },
+            quote! {
+                builder.build().execute
+            },
+        )
+    };
+
+    let map_err = entity.map_err();
+
+    RepoFn {
+        operation: "update_partial",
+        key: Some(pk.name.clone()),
+        signature: quote! {
+            async fn #fn_name(&self, #(#fn_args,)* patch: &#patch_name #(, #actor_args)*) -> #fn_rtn
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            let unchanged = if entity_returning {
+                quote! { return #unchanged(#executor).await #map_err; }
+            } else {
+                unchanged.clone()
+            };
+            let finish = if entity_returning {
+                quote! { #finish(#executor).await #map_err }
+            } else {
+                quote! {
+                    let result = #finish(#executor).await?;
+                    Ok(result.rows_affected())
+                }
+            };
 
-fn pg_impl(entity: &DeriveEntity, impl_ty: Type) -> Result<TokenStream, DeriveEntityError> {
-    let EntityImpl { trait_name } = EntityImpl::new(entity);
+            quote! {
+                if patch.is_empty() {
+                    #unchanged
+                }
 
-    let key_fns = entity
-        .keys
-        .iter()
-        .map(|key| {
-            let KeyFn {
-                fn_name,
-                fn_rtn,
-                fn_args,
-                ..
-            } = KeyFn::new(entity, key);
+                #start
+                let mut assignments = builder.separated(", ");
+                #(
+                    #assignments
+                )*
 
-            let where_clause = key
-                .components
-                .iter()
-                .enumerate()
-                .map(|(i, c)| format!("{} = ${}", c.column_name, i + 1))
-                .join(" and ");
+                #(
+                    #conditions
+                )*
 
-            let query = format!("select * from {} where {}", entity.table_name, where_clause);
+                #finish
+            }
+        }),
+    }
+}
 
-            let binds = key
-                .components
-                .iter()
-                .map(|c| {
-                    let f = &c.field_name;
-                    quote! {
-                        .bind(&#f)
+/// How a mutation reports its outcome: the persisted row in `returning` mode, otherwise the
+/// number of rows affected.
+fn mutation_rtn(entity: &DeriveEntity, optional: bool) -> TokenStream {
+    let ent = &entity.entity;
+    let error = &entity.error;
+    match (entity.returning, optional) {
+        (true, true) => quote! { Result<Option<#ent>, #error> },
+        (true, false) => quote! { Result<#ent, #error> },
+        (false, _) => quote! { Result<u64, #error> },
+    }
+}
+
+/// Runs a static mutation, returning either the affected row(s) or the number of rows affected.
+fn mutation_body(
+    entity: &DeriveEntity,
+    query: &str,
+    args: &[TokenStream],
+    optional: bool,
+) -> Box<dyn Fn(&ImplTarget) -> TokenStream> {
+    if entity.returning {
+        let row = Row::entity(entity);
+        let query = format!("{query} returning {}", row.select_list);
+        let query = static_query(entity, &row.ty, &query, args);
+        let fetch = if optional {
+            quote! { fetch_optional }
+        } else {
+            quote! { fetch_one }
+        };
+        let map_err = entity.map_err();
+
+        Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                #query
+                .#fetch(#executor)
+                .await
+                #map_err
+            }
+        })
+    } else {
+        let query = static_execute(entity, query, args);
+
+        Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                let result = #query.execute(#executor).await?;
+                Ok(result.rows_affected())
+            }
+        })
+    }
+}
+
+/// Binds for columns of an entity argument; the tenant is always bound from its own argument.
+fn entity_args(entity: &DeriveEntity, columns: &[&FieldColumn]) -> Vec<TokenStream> {
+    columns
+        .iter()
+        .map(|c| {
+            let f = &c.field_name;
+            if entity.is_tenant(c) {
+                quote! { #f }
+            } else if entity.checked {
+                // `query!` borrows its arguments itself, and only sees through an `Option` that
+                // isn't behind a reference
+                quote! { entity.#f }
+            } else {
+                quote! { &entity.#f }
+            }
+        })
+        .collect_vec()
+}
+
+fn insert_fn(entity: &DeriveEntity) -> RepoFn {
+    let ent = &entity.entity;
+    let fn_name = format_ident!("insert_{}", entity.entity_snake_name());
+    let fn_rtn = mutation_rtn(entity, false);
+    let tenant_args = tenant_args(entity);
+
+    let columns = entity.columns.iter().collect_vec();
+    let query = format!(
+        "insert into {} ({}) values ({})",
+        entity.table_name,
+        columns.iter().map(|c| &c.column_name).join(", "),
+        (1..=columns.len()).map(|i| format!("${i}")).join(", ")
+    );
+
+    RepoFn {
+        operation: "insert",
+        key: None,
+        signature: quote! {
+            async fn #fn_name(&self, #(#tenant_args,)* entity: &#ent) -> #fn_rtn
+        },
+        body: mutation_body(entity, &query, &entity_args(entity, &columns), false),
+    }
+}
+
+/// Bulk loads rows with `COPY ... FROM STDIN` in the binary format, each field encoded the same
+/// way sqlx binds it.
+fn copy_in_fn(entity: &DeriveEntity) -> RepoFn {
+    let ent = &entity.entity;
+    let fn_name = format_ident!("copy_in_{}", entity.entity_snake_name());
+    let tenant_args = tenant_args(entity);
+    let error = &entity.error;
+    let map_err = entity.map_err();
+
+    let columns = entity.columns.iter().collect_vec();
+    let statement = format!(
+        "copy {} ({}) from stdin with (format binary)",
+        entity.table_name,
+        columns.iter().map(|c| &c.column_name).join(", ")
+    );
+    let field_count = columns.len() as i16;
+    let fields = columns
+        .iter()
+        .map(|c| {
+            let f = &c.field_name;
+            if entity.is_tenant(c) {
+                quote! { encode_field(&#f, &mut data)?; }
+            } else {
+                quote! { encode_field(&row.#f, &mut data)?; }
+            }
+        })
+        .collect_vec();
+
+    RepoFn {
+        operation: "copy_in",
+        key: None,
+        signature: quote! {
+            async fn #fn_name(&self, #(#tenant_args,)* rows: impl launchpad::futures::Stream<Item = #ent>) -> Result<u64, #error>
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                use launchpad::futures::StreamExt as _;
+                use sqlx::postgres::PgPoolCopyExt as _;
+
+                fn encode_field<'q, T: sqlx::Encode<'q, sqlx::Postgres>>(
+                    value: &T,
+                    data: &mut Vec<u8>,
+                ) -> Result<(), sqlx::Error> {
+                    let mut buf = sqlx::postgres::PgArgumentBuffer::default();
+                    match sqlx::Encode::<sqlx::Postgres>::encode_by_ref(value, &mut buf).map_err(sqlx::Error::Encode)? {
+                        sqlx::encode::IsNull::Yes => data.extend_from_slice(&(-1i32).to_be_bytes()),
+                        sqlx::encode::IsNull::No => {
+                            data.extend_from_slice(&(buf.len() as i32).to_be_bytes());
+                            data.extend_from_slice(&buf);
+                        }
                     }
-                })
-                .collect_vec();
+                    Ok(())
+                }
+
+                // signature, flags and header extension length
+                let mut data = b"PGCOPY\n\xff\r\n\0".to_vec();
+                data.extend_from_slice(&[0; 8]);
+
+                let mut copy = (#executor).copy_in_raw(#statement).await?;
+                let mut rows = std::pin::pin!(rows);
+                let sent: Result<(), sqlx::Error> = async {
+                    while let Some(row) = rows.next().await {
+                        data.extend_from_slice(&#field_count.to_be_bytes());
+                        #(
+                            #fields
+                        )*
+
+                        if data.len() >= 1 << 16 {
+                            copy.send(std::mem::take(&mut data)).await?;
+                        }
+                    }
+
+                    data.extend_from_slice(&(-1i16).to_be_bytes());
+                    copy.send(data).await?;
+                    Ok(())
+                }
+                .await;
+
+                match sent {
+                    Ok(()) => copy.finish().await #map_err,
+                    Err(e) => {
+                        // the server's acknowledgement of the abort isn't interesting next to why we aborted
+                        let _ = copy.abort(e.to_string()).await;
+                        Err(e) #map_err
+                    }
+                }
+            }
+        }),
+    }
+}
+
+fn update_fn(entity: &DeriveEntity, pk: &Key) -> RepoFn {
+    let ent = &entity.entity;
+    let fn_name = format_ident!("update_{}", entity.entity_snake_name());
+    let fn_rtn = mutation_rtn(entity, true);
+    let tenant_args = tenant_args(entity);
+    let lookup = entity.lookup(pk);
+
+    let values = entity.value_columns();
+    let query = format!(
+        "{}update {} set {} where {}",
+        audit_prefix(entity, &where_clause(&lookup, values.len()), values.len() + lookup.len() + 1),
+        entity.table_name,
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("{} = ${}", c.column_name, i + 1))
+            .join(", "),
+        where_clause(&lookup, values.len())
+    );
+
+    let columns = values.into_iter().chain(lookup).collect_vec();
+    let (actor_args, actor_binds) = actor_args(entity);
+    let args = entity_args(entity, &columns).into_iter().chain(actor_binds).collect_vec();
+
+    RepoFn {
+        operation: "update",
+        key: Some(pk.name.clone()),
+        signature: quote! {
+            async fn #fn_name(&self, #(#tenant_args,)* entity: &#ent #(, #actor_args)*) -> #fn_rtn
+        },
+        body: mutation_body(entity, &query, &args, true),
+    }
+}
+
+fn upsert_fn(entity: &DeriveEntity, pk: &Key) -> RepoFn {
+    let ent = &entity.entity;
+    let fn_name = format_ident!("upsert_{}", entity.entity_snake_name());
+    // a conflict with another tenant's row updates nothing, so there may be no row to return
+    let guarded = entity.tenant.is_some();
+    let fn_rtn = mutation_rtn(entity, guarded);
+    let tenant_args = tenant_args(entity);
+
+    let columns = entity.columns.iter().collect_vec();
+    let values = entity.value_columns();
+    // with nothing else to update, touch the key so the row is still returned
+    let assigned = if values.is_empty() {
+        pk.components.iter().collect_vec()
+    } else {
+        values
+    };
+
+    // a conflicting row belonging to another tenant is left untouched
+    let tenant_guard = entity
+        .tenant
+        .as_ref()
+        .map(|t| format!(" where {0}.{1} = excluded.{1}", entity.table_name, t.column_name))
+        .unwrap_or_default();
+
+    let query = format!(
+        "{}insert into {} ({}) values ({}) on conflict ({}) do update set {}{}",
+        audit_prefix(entity, &reused_where_clause(&entity.lookup(pk), &columns), columns.len() + 1),
+        entity.table_name,
+        columns.iter().map(|c| &c.column_name).join(", "),
+        (1..=columns.len()).map(|i| format!("${i}")).join(", "),
+        pk.components.iter().map(|c| &c.column_name).join(", "),
+        assigned
+            .iter()
+            .map(|c| format!("{0} = excluded.{0}", c.column_name))
+            .join(", "),
+        tenant_guard
+    );
+
+    let (actor_args, actor_binds) = actor_args(entity);
+    let args = entity_args(entity, &columns).into_iter().chain(actor_binds).collect_vec();
+
+    RepoFn {
+        operation: "upsert",
+        key: Some(pk.name.clone()),
+        signature: quote! {
+            async fn #fn_name(&self, #(#tenant_args,)* entity: &#ent #(, #actor_args)*) -> #fn_rtn
+        },
+        body: mutation_body(entity, &query, &args, guarded),
+    }
+}
+
+fn delete_fn(entity: &DeriveEntity, pk: &Key) -> RepoFn {
+    let fn_name = format_ident!("delete_{}", entity.entity_snake_name());
+    let fn_rtn = mutation_rtn(entity, true);
+    let lookup = entity.lookup(pk);
+    let fn_args = lookup_args(&lookup);
+    let (actor_args, actor_binds) = actor_args(entity);
+
+    let condition = where_clause(&lookup, 0);
+    let query = format!(
+        "{}delete from {} where {}",
+        audit_prefix(entity, &condition, lookup.len() + 1),
+        entity.table_name,
+        condition
+    );
+    let args = lookup_binds(&lookup).into_iter().chain(actor_binds).collect_vec();
+
+    RepoFn {
+        operation: "delete",
+        key: Some(pk.name.clone()),
+        signature: quote! {
+            async fn #fn_name(&self, #(#fn_args,)* #(#actor_args)*) -> #fn_rtn
+        },
+        body: mutation_body(entity, &query, &args, true),
+    }
+}
+
+/// The `actor` argument audited mutations record in history, with its bind.
+fn actor_args(entity: &DeriveEntity) -> (Vec<TokenStream>, Vec<TokenStream>) {
+    if entity.audited {
+        (vec![quote! { actor: &str }], vec![quote! { actor }])
+    } else {
+        (Vec::default(), Vec::default())
+    }
+}
+
+/// Statement prefix copying the rows matching `condition` into the history table before the
+/// statement changes them. Being part of the same statement, the copy commits or rolls back
+/// together with the change.
+fn audit_prefix(entity: &DeriveEntity, condition: &str, actor: usize) -> String {
+    if !entity.audited {
+        return String::new();
+    }
+
+    let columns = entity.columns.iter().map(|c| &c.column_name).join(", ");
+    format!(
+        "with previous as (select {columns} from {table} where {condition}), \
+        history as (insert into {history} ({columns}, actor, changed_at) select {columns}, ${actor}, now() from previous) ",
+        table = entity.table_name,
+        history = entity.history_table(),
+    )
+}
+
+/// `column = $n` conditions for each of the columns, reusing the placeholders they're bound to in
+/// `bound`.
+fn reused_where_clause(columns: &[&FieldColumn], bound: &[&FieldColumn]) -> String {
+    columns
+        .iter()
+        .map(|k| {
+            let i = bound
+                .iter()
+                .position(|c| c.field_name == k.field_name)
+                .unwrap_or_default();
+            format!("{} = ${}", k.column_name, i + 1)
+        })
+        .join(" and ")
+}
+
+/// `column = $n` conditions for each of the columns, numbered after `offset` other binds.
+fn where_clause(columns: &[&FieldColumn], offset: usize) -> String {
+    columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| format!("{} = ${}", c.column_name, offset + i + 1))
+        .join(" and ")
+}
+
+fn metadata(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    let ent = &entity.entity;
+    let table = &entity.table_name;
+    let columns = entity.columns.iter().map(|c| &c.column_name).collect_vec();
+    let key_names = entity.keys.iter().map(|k| &k.name).collect_vec();
+
+    let key_consts = entity
+        .keys
+        .iter()
+        .map(|key| {
+            let prefix = key.name.to_case(Case::UpperSnake);
+            let columns_name = format_ident!("KEY_{}", prefix);
+            let unique_name = format_ident!("KEY_{}_UNIQUE", prefix);
+            let columns = key.components.iter().map(|c| &c.column_name).collect_vec();
+            let unique = key.unique;
+            let doc = format!("Columns of the `{}` key.", key.name);
+            let unique_doc = format!("Whether the `{}` key identifies at most one row.", key.name);
+
+            quote! {
+                #[doc = #doc]
+                pub const #columns_name: &'static [&'static str] = &[#(#columns),*];
+                #[doc = #unique_doc]
+                pub const #unique_name: bool = #unique;
+            }
+        })
+        .collect_vec();
+
+    let primary_key = entity.primary_key().map(|pk| {
+        let columns = pk.components.iter().map(|c| &c.column_name).collect_vec();
+        quote! {
+            /// Columns of the primary key.
+            pub const PRIMARY_KEY: &'static [&'static str] = &[#(#columns),*];
+        }
+    });
+
+    let history_table = entity.audited.then(|| {
+        let history = entity.history_table();
+        quote! {
+            /// The table previous row states are recorded in.
+            pub const HISTORY_TABLE: &'static str = #history;
+        }
+    });
+
+    let view_ddl = entity.view_sql.as_ref().map(|sql| {
+        let ddl = format!("create or replace view {table} as {sql}");
+        let error = &entity.error;
+        quote! {
+            /// Creates (or replaces) the view rows are read from.
+            pub const VIEW_DDL: &'static str = #ddl;
+
+            pub async fn create_view<'e, E>(executor: E) -> Result<(), #error>
+            where
+                E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+            {
+                sqlx::raw_sql(Self::VIEW_DDL).execute(executor).await?;
+                Ok(())
+            }
+        }
+    });
+
+    let projection_consts = entity
+        .projections
+        .iter()
+        .map(|projection| {
+            let name = &projection.name;
+            let columns = projection.columns.iter().map(|c| &c.column_name).collect_vec();
+            quote! {
+                impl #name {
+                    /// Columns selected for this projection, in field order.
+                    pub const COLUMNS: &'static [&'static str] = &[#(#columns),*];
+                }
+            }
+        })
+        .collect_vec();
+
+    Ok(quote! {
+        impl #ent {
+            /// The table rows are read from and written to.
+            pub const TABLE: &'static str = #table;
+            /// Every mapped column, in field order.
+            pub const COLUMNS: &'static [&'static str] = &[#(#columns),*];
+            /// Names of the declared keys.
+            pub const KEYS: &'static [&'static str] = &[#(#key_names),*];
+            #primary_key
+            #history_table
+            #view_ddl
+            #(
+                #key_consts
+            )*
+        }
+
+        #(
+            #projection_consts
+        )*
+    })
+}
+
+fn patch_struct(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    if entity.primary_key().is_none() || entity.view {
+        return Ok(quote! {});
+    }
+
+    let vis = &entity.vis;
+    let patch_name = format_ident!("{}Patch", entity.entity);
+    let columns = entity.value_columns();
+
+    let fields = columns
+        .iter()
+        .map(|c| {
+            let FieldColumn {
+                field_name,
+                field_type,
+                ..
+            } = c;
+            quote! {
+                pub #field_name: Option<#field_type>
+            }
+        })
+        .collect_vec();
+
+    let field_names = columns.iter().map(|c| &c.field_name).collect_vec();
+
+    let doc = format!(
+        "Changes to apply to a [`{}`]; only the fields which are `Some` are updated.",
+        entity.entity
+    );
+
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(Debug, Default, Clone)]
+        #vis struct #patch_name {
+            #(
+                #fields,
+            )*
+        }
+
+        impl #patch_name {
+            pub fn is_empty(&self) -> bool {
+                true #(&& self.#field_names.is_none())*
+            }
+        }
+    })
+}
+
+fn filter_struct(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    let vis = &entity.vis;
+    let filter_name = format_ident!("{}Filter", entity.entity);
+
+    let fields = filter_columns(entity)
+        .into_iter()
+        .map(|c| {
+            let FieldColumn {
+                field_name,
+                field_type,
+                ..
+            } = c;
+            quote! {
+                pub #field_name: Option<#field_type>
+            }
+        })
+        .collect_vec();
+
+    let doc = format!(
+        "Matches [`{}`] rows equal to every field which is `Some`; an empty filter matches everything.",
+        entity.entity
+    );
 
-            let body = if key.unique {
+    Ok(quote! {
+        #[doc = #doc]
+        #[derive(Debug, Default, Clone)]
+        #vis struct #filter_name {
+            #(
+                #fields,
+            )*
+        }
+    })
+}
+
+fn key_fn(entity: &DeriveEntity, key: &Key, row: &Row) -> RepoFn {
+    let KeyFn {
+        fn_name,
+        fn_rtn,
+        fn_args,
+    } = KeyFn::new(entity, key, row);
+    let lookup = entity.lookup(key);
+    let map_err = entity.map_err();
+
+    let query = if key.joins.is_empty() {
+        format!(
+            "select {} from {} where {}",
+            row.select_list,
+            entity.table_name,
+            where_clause(&lookup, 0)
+        )
+    } else {
+        // the joined tables may share column names with ours
+        let table = &entity.table_name;
+        let qualified = lookup.iter().map(|c| c.qualified(table)).collect_vec();
+        format!(
+            "select {} from {} {} where {}",
+            row.joined_select_list,
+            table,
+            key.joins.iter().map(|(joined, on)| format!("join {joined} on {on}")).join(" "),
+            where_clause(&qualified.iter().collect_vec(), 0)
+        )
+    };
+
+    let fetch = if key.unique {
+        quote! { fetch_optional }
+    } else {
+        quote! { fetch_all }
+    };
+
+    let query = static_query(entity, &row.ty, &query, &lookup_binds(&lookup));
+
+    RepoFn {
+        operation: if key.unique { "find" } else { "list" },
+        key: Some(key.name.clone()),
+        signature: quote! {
+            async fn #fn_name(&self, #(#fn_args), *) -> #fn_rtn
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                #query
+                .#fetch(#executor)
+                .await
+                #map_err
+            }
+        }),
+    }
+}
+
+fn locking_fns(entity: &DeriveEntity) -> Vec<RepoFn> {
+    let row = Row::entity(entity);
+    entity
+        .keys
+        .iter()
+        .filter(|key| key.locking && !entity.view)
+        .flat_map(|key| {
+            [("for_update", "for update"), ("for_update_skip_locked", "for update skip locked")]
+                .map(|(suffix, lock)| locking_fn(entity, key, &row, suffix, lock))
+        })
+        .collect_vec()
+}
+
+fn locking_fn(entity: &DeriveEntity, key: &Key, row: &Row, suffix: &str, lock: &str) -> RepoFn {
+    let KeyFn {
+        fn_name,
+        fn_rtn,
+        mut fn_args,
+    } = KeyFn::new(entity, key, row);
+    let fn_name = format_ident!("{}_{}", fn_name, suffix);
+    let lookup = entity.lookup(key);
+    let map_err = entity.map_err();
+    let mut args = lookup_binds(&lookup);
+
+    // locking every match of a non-unique key is rarely wanted, so lists take a limit
+    let limit = if key.unique {
+        String::new()
+    } else {
+        fn_args.push(quote! { limit: i64 });
+        args.push(quote! { limit });
+        format!(" limit ${}", args.len())
+    };
+
+    let query = format!(
+        "select {} from {} where {}{} {}",
+        row.select_list,
+        entity.table_name,
+        where_clause(&lookup, 0),
+        limit,
+        lock
+    );
+    let query = static_query(entity, &row.ty, &query, &args);
+
+    let fetch = if key.unique {
+        quote! { fetch_optional }
+    } else {
+        quote! { fetch_all }
+    };
+
+    RepoFn {
+        operation: "lock",
+        key: Some(key.name.clone()),
+        signature: quote! {
+            async fn #fn_name(&self, #(#fn_args), *) -> #fn_rtn
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                #query
+                .#fetch(#executor)
+                .await
+                #map_err
+            }
+        }),
+    }
+}
+
+/// Columns a [`filter_struct`] can match on; the tenant is always given separately.
+fn filter_columns(entity: &DeriveEntity) -> Vec<&FieldColumn> {
+    entity.columns.iter().filter(|c| !entity.is_tenant(c)).collect_vec()
+}
+
+/// Lists a page of rows matching every field set on the entity's filter, ordered by primary key
+/// when there is one so pages are stable.
+fn list_fn(entity: &DeriveEntity) -> RepoFn {
+    let ent = &entity.entity;
+    let fn_name = format_ident!("list_{}", entity.entity_snake_name());
+    let filter_name = format_ident!("{}Filter", ent);
+    let error = &entity.error;
+    let map_err = entity.map_err();
+
+    let tenant = entity.tenant.iter().collect_vec();
+    let fn_args = lookup_args(&tenant);
+    let tenant_conditions = tenant
+        .iter()
+        .map(|c| {
+            let f = &c.field_name;
+            let condition = format!("{} = ", c.column_name);
+            quote! {
+                builder.push(separator).push(#condition).push_bind(#f);
+                separator = " and ";
+            }
+        })
+        .collect_vec();
+
+    let conditions = filter_columns(entity)
+        .into_iter()
+        .map(|c| {
+            let f = &c.field_name;
+            let column = &c.column_name;
+            // `Some(None)` on an optional column matches nulls
+            let matches = if c.is_optional() {
                 quote! {
-                    sqlx::query_as(#query)
-                    #(
-                        #binds
-                    )*
-                    .fetch_optional(self)
-                    .await
+                    match value {
+                        Some(value) => builder.push(" = ").push_bind(value),
+                        None => builder.push(" is null"),
+                    };
                 }
             } else {
                 quote! {
-                    sqlx::query_as(#query)
-                    #(
-                        #binds
-                    )*
-                    .fetch_all(self)
-                    .await
+                    builder.push(" = ").push_bind(value);
                 }
             };
-
             quote! {
-                async fn #fn_name(&self, #(#fn_args), *) -> #fn_rtn {
-                    #body
+                if let Some(value) = &filter.#f {
+                    builder.push(separator).push(#column);
+                    #matches
+                    separator = " and ";
                 }
             }
         })
         .collect_vec();
 
+    let select = format!("select * from {}", entity.table_name);
+    let order_by = entity
+        .primary_key()
+        .map(|pk| format!(" order by {}", pk.components.iter().map(|c| &c.column_name).join(", ")))
+        .unwrap_or_default();
+
+    RepoFn {
+        operation: "list",
+        key: None,
+        signature: quote! {
+            async fn #fn_name(&self, #(#fn_args,)* filter: &#filter_name, page: launchpad::page::Page) -> Result<Vec<#ent>, #error>
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                let mut builder = sqlx::QueryBuilder::<sqlx::Postgres>::new(#select);
+                let mut separator = " where ";
+                #(
+                    #tenant_conditions
+                )*
+                #(
+                    #conditions
+                )*
+                let _ = separator;
+
+                builder
+                    .push(#order_by)
+                    .push(" limit ")
+                    .push_bind(page.limit)
+                    .push(" offset ")
+                    .push_bind(page.offset);
+
+                builder.build_query_as().fetch_all(#executor).await #map_err
+            }
+        }),
+    }
+}
+
+/// Lists the `k` rows closest to an embedding by L2 distance, which an ivfflat or hnsw index on
+/// the column can serve.
+fn nearest_fn(entity: &DeriveEntity, column: &FieldColumn) -> RepoFn {
+    let ent = &entity.entity;
+    let row = Row::entity(entity);
+    let fn_name = format_ident!("nearest_{}_by_{}", entity.entity_snake_name(), column.field_name);
+    let vector_type = column.value_type();
+    let error = &entity.error;
+    let map_err = entity.map_err();
+
+    let tenant = entity.tenant.iter().collect_vec();
+    let fn_args = lookup_args(&tenant);
+    let filter = if tenant.is_empty() {
+        String::new()
+    } else {
+        format!(" where {}", where_clause(&tenant, 0))
+    };
+    let query = format!(
+        "select {} from {}{filter} order by {} <-> ${} limit ${}",
+        row.select_list,
+        entity.table_name,
+        column.column_name,
+        tenant.len() + 1,
+        tenant.len() + 2
+    );
+    let args = lookup_binds(&tenant)
+        .into_iter()
+        .chain([quote! { embedding }, quote! { k }])
+        .collect_vec();
+    let query = static_query(entity, &row.ty, &query, &args);
+
+    RepoFn {
+        operation: "nearest",
+        key: None,
+        signature: quote! {
+            async fn #fn_name(&self, #(#fn_args,)* embedding: &#vector_type, k: i64) -> Result<Vec<#ent>, #error>
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                #query
+                .fetch_all(#executor)
+                .await
+                #map_err
+            }
+        }),
+    }
+}
+
+/// Counts rows per distinct value of a column. Checked mode can't describe a tuple row, so the
+/// query is always verified at runtime.
+fn count_by_fn(entity: &DeriveEntity, column: &FieldColumn) -> RepoFn {
+    let FieldColumn {
+        field_name,
+        field_type,
+        column_name,
+    } = column;
+    let fn_name = format_ident!("count_{}_grouped_by_{}", entity.entity_snake_name(), field_name);
+    let error = &entity.error;
+    let map_err = entity.map_err();
+
+    let tenant = entity.tenant.iter().collect_vec();
+    let fn_args = lookup_args(&tenant);
+    let binds = lookup_binds(&tenant);
+    let filter = if tenant.is_empty() {
+        String::new()
+    } else {
+        format!(" where {}", where_clause(&tenant, 0))
+    };
+    let query = format!(
+        "select {column_name}, count(*) from {}{filter} group by {column_name} order by {column_name}",
+        entity.table_name
+    );
+
+    RepoFn {
+        operation: "count",
+        key: None,
+        signature: quote! {
+            async fn #fn_name(&self, #(#fn_args),*) -> Result<Vec<(#field_type, i64)>, #error>
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                sqlx::query_as(#query)
+                #(
+                    .bind(#binds)
+                )*
+                .fetch_all(#executor)
+                .await
+                #map_err
+            }
+        }),
+    }
+}
+
+fn find_or_insert_fn(entity: &DeriveEntity, key: &Key) -> RepoFn {
+    let ent = &entity.entity;
+    let row = Row::entity(entity);
+    let fn_name = format_ident!("find_or_insert_{}_by_{}", entity.entity_snake_name(), key.name);
+    let select_list = if entity.checked {
+        Row::nullability_select_list(&entity.columns)
+    } else {
+        row.select_list
+    };
+
+    let columns = &entity.columns;
+    let column_list = columns.iter().map(|c| &c.column_name).join(", ");
+    let values = (1..=columns.len()).map(|i| format!("${i}")).join(", ");
+
+    // the key's values are already bound for the insert, so reuse their placeholders
+    let lookup = entity.lookup(key);
+    let retry_where_clause = where_clause(&lookup, 0);
+    let where_clause = reused_where_clause(&lookup, &columns.iter().collect_vec());
+    // only a conflict on the key finds the existing row, any other violation is an error. A tenant
+    // scoped key names the tenant field among its own, to match a unique constraint that includes it
+    let conflict_target = key.components.iter().map(|c| &c.column_name).join(", ");
+
+    // a conflicting row isn't visible to the outer select when inserted by the same statement,
+    // so the fresh row comes from the insert's returning clause instead
+    let query = format!(
+        "with inserted as (insert into {table} ({column_list}) values ({values}) on conflict ({conflict_target}) do nothing returning *) \
+        select {select} from inserted \
+        union all select {select} from {table} where {where_clause} \
+        limit 1",
+        table = entity.table_name,
+        select = select_list,
+    );
+    // neither half sees a row that a concurrent transaction committed after the statement started,
+    // but a statement of its own does
+    let retry = format!(
+        "select {select_list} from {table} where {retry_where_clause}",
+        table = entity.table_name,
+    );
+
+    let args = entity_args(entity, &columns.iter().collect_vec());
+    let query = static_query(entity, &row.ty, &query, &args);
+    let retry = static_query(entity, &row.ty, &retry, &entity_args(entity, &lookup));
+    let tenant_args = tenant_args(entity);
+    let error = &entity.error;
+    let map_err = entity.map_err();
+
+    RepoFn {
+        operation: "find_or_insert",
+        key: Some(key.name.clone()),
+        signature: quote! {
+            async fn #fn_name(&self, #(#tenant_args,)* entity: &#ent) -> Result<#ent, #error>
+        },
+        body: Box::new(move |ImplTarget { executor, .. }| {
+            quote! {
+                if let Some(found) = #query.fetch_optional(#executor).await? {
+                    return Ok(found);
+                }
+                #retry
+                .fetch_one(#executor)
+                .await
+                #map_err
+            }
+        }),
+    }
+}
+
+/// A query whose SQL is fixed at expansion time, checked against the database in `checked` mode.
+fn static_query(entity: &DeriveEntity, row_ty: &Ident, query: &str, args: &[TokenStream]) -> TokenStream {
+    if entity.checked {
+        quote! {
+            sqlx::query_as!(#row_ty, #query, #(#args),*)
+        }
+    } else {
+        quote! {
+            sqlx::query_as(#query)
+            #(
+                .bind(#args)
+            )*
+        }
+    }
+}
+
+/// Like [`static_query`], for statements that don't produce rows.
+fn static_execute(entity: &DeriveEntity, query: &str, args: &[TokenStream]) -> TokenStream {
+    if entity.checked {
+        quote! {
+            sqlx::query!(#query, #(#args),*)
+        }
+    } else {
+        quote! {
+            sqlx::query(#query)
+            #(
+                .bind(#args)
+            )*
+        }
+    }
+}
+
+fn repo_trait(trait_name: &Ident, fns: &[RepoFn]) -> Result<TokenStream, DeriveEntityError> {
+    let signatures = fns.iter().map(|RepoFn { signature, .. }| signature).collect_vec();
+
+    Ok(quote! {
+        pub trait #trait_name {
+            #(
+                #signatures;
+            )*
+        }
+    })
+}
+
+fn repo_impl(
+    entity: &DeriveEntity,
+    trait_name: &Ident,
+    fns: &[RepoFn],
+    target: &ImplTarget,
+) -> Result<TokenStream, DeriveEntityError> {
+    let ImplTarget {
+        impl_ty, prelude, ..
+    } = target;
+
+    let fns = fns
+        .iter()
+        .map(|repo_fn| {
+            let RepoFn { signature, body, .. } = repo_fn;
+            let body = body(target);
+            let body = quote! {
+                #prelude
+                #body
+            };
+            let body = if cfg!(feature = "tracing") {
+                traced(entity, repo_fn, body)?
+            } else {
+                body
+            };
+
+            Ok(quote! {
+                #signature {
+                    #body
+                }
+            })
+        })
+        .collect::<Result<Vec<_>, DeriveEntityError>>()?;
+
     Ok(quote! {
         impl #trait_name for #impl_ty {
             #(
-                #key_fns
+                #fns
             )*
         }
     })
 }
 
-fn tx_impl(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
-    let EntityImpl { trait_name } = EntityImpl::new(entity);
+/// Wraps any repo with a read-through cache of rows by primary key, dropping a row's entry whenever
+/// a generated mutation may have changed it.
+fn cached_repo(entity: &DeriveEntity, fns: &[RepoFn]) -> Result<TokenStream, DeriveEntityError> {
+    if !entity.cache {
+        return Ok(quote! {});
+    }
 
-    let key_fns = entity
-        .keys
+    let pk = entity.primary_key().ok_or(DeriveEntityError::CacheWithoutPrimaryKey)?;
+    let ent = &entity.entity;
+    let vis = &entity.vis;
+    let snake_name = entity.entity_snake_name();
+    let EntityImpl { trait_name, .. } = EntityImpl::new(entity);
+    let cached_name = format_ident!("Cached{}", trait_name);
+    let key_name = format_ident!("{}CacheKey", ent);
+
+    let lookup = entity.lookup(pk);
+    let key_types = lookup.iter().map(|c| &c.field_type).collect_vec();
+
+    let find_name = format_ident!("find_{}_by_{}", snake_name, pk.name);
+    let invalidating = [
+        format_ident!("update_{}", snake_name),
+        format_ident!("upsert_{}", snake_name),
+        format_ident!("update_{}_partial", snake_name),
+        format_ident!("delete_{}", snake_name),
+    ];
+
+    let fns = fns
         .iter()
-        .map(|key| {
-            let KeyFn {
-                fn_name,
-                fn_rtn,
-                fn_args,
-                ..
-            } = KeyFn::new(entity, key);
-
-            let where_clause = key
-                .components
+        .map(|RepoFn { signature, .. }| {
+            let sig: syn::Signature = syn::parse2(signature.clone())?;
+            let fn_name = &sig.ident;
+            let args = sig
+                .inputs
                 .iter()
-                .enumerate()
-                .map(|(i, c)| format!("{} = ${}", c.column_name, i + 1))
-                .join(" and ");
+                .filter_map(|arg| match arg {
+                    syn::FnArg::Typed(syn::PatType { pat, .. }) => match pat.as_ref() {
+                        syn::Pat::Ident(pat) => Some(pat.ident.clone()),
+                        _ => None,
+                    },
+                    syn::FnArg::Receiver(_) => None,
+                })
+                .collect_vec();
+
+            // the key comes from the arguments where they name it, otherwise from the entity passed in
+            let key_parts = lookup.iter().map(|c| {
+                let f = &c.field_name;
+                if args.contains(f) {
+                    quote! { ToOwned::to_owned(#f) }
+                } else {
+                    quote! { ToOwned::to_owned(&entity.#f) }
+                }
+            });
+            let key = quote! { (#(#key_parts,)*) };
+            let delegate = quote! { self.repo.#fn_name(#(#args),*).await };
+
+            let body = if *fn_name == find_name {
+                quote! {
+                    let key: #key_name = #key;
+                    if let Some(hit) = self.cache.get(&key).await {
+                        return Ok(Some(hit));
+                    }
+
+                    let found = #delegate?;
+                    if let Some(found) = &found {
+                        self.cache.insert(key, found.clone()).await;
+                    }
+                    Ok(found)
+                }
+            } else if invalidating.contains(fn_name) {
+                quote! {
+                    let key: #key_name = #key;
+                    let result = #delegate;
+                    self.cache.invalidate(&key).await;
+                    result
+                }
+            } else {
+                delegate
+            };
+
+            Ok(quote! {
+                #signature {
+                    #body
+                }
+            })
+        })
+        .collect::<Result<Vec<_>, DeriveEntityError>>()?;
+
+    let key_doc = format!("Identifies a cached `{ent}`: its primary key, preceded by the tenant when scoped.");
+    let doc = format!(
+        "Serves `{find_name}` from a cache before falling back to the wrapped repo. Entries are dropped \
+        on update, upsert and delete made through this wrapper; writes made elsewhere aren't seen."
+    );
+
+    Ok(quote! {
+        #[doc = #key_doc]
+        #vis type #key_name = (#(#key_types,)*);
+
+        #[doc = #doc]
+        #vis struct #cached_name<R, C> {
+            repo: R,
+            cache: C,
+        }
+
+        impl<R, C> #cached_name<R, C> {
+            pub fn new(repo: R, cache: C) -> Self {
+                Self { repo, cache }
+            }
+
+            pub fn into_inner(self) -> R {
+                self.repo
+            }
+        }
+
+        impl<R: #trait_name, C: launchpad::cache::Cache<#key_name, #ent>> #trait_name for #cached_name<R, C> {
+            #(
+                #fns
+            )*
+        }
+    })
+}
 
-            let query = format!("select * from {} where {}", entity.table_name, where_clause);
+/// A loader batching concurrent lookups by primary key into a single query.
+fn loader(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    if !entity.loader {
+        return Ok(quote! {});
+    }
+
+    let Some([pk]) = entity.primary_key().map(|key| key.components.as_slice()) else {
+        return Err(DeriveEntityError::LoaderWithoutPrimaryKey);
+    };
+
+    let ent = &entity.entity;
+    let vis = &entity.vis;
+    let error = &entity.error;
+    let map_err = entity.map_err();
+    let loader_name = format_ident!("{}Loader", ent);
+    let pk_field = &pk.field_name;
+    let pk_type = &pk.field_type;
+
+    let row = Row::entity(entity);
+    let tenant = entity.tenant.as_ref().map(|t| (&t.field_name, &t.field_type, &t.column_name));
+    let query = match tenant {
+        Some((.., tenant_column)) => format!(
+            "select {} from {} where {} = $1 and {} = any($2)",
+            row.select_list, entity.table_name, tenant_column, pk.column_name
+        ),
+        None => format!(
+            "select {} from {} where {} = any($1)",
+            row.select_list, entity.table_name, pk.column_name
+        ),
+    };
+    let (tenant_args, tenant_binds) = match tenant {
+        Some((field, ty, _)) => (vec![quote! { #field: #ty }], vec![quote! { .bind(#field.clone()) }]),
+        None => (vec![], vec![]),
+    };
+
+    let doc = format!("Batches concurrent [`{ent}`] lookups by primary key into a single query.");
+
+    Ok(quote! {
+        #[doc = #doc]
+        #vis struct #loader_name(launchpad::loader::Loader<#pk_type, #ent, #error>);
+
+        impl #loader_name {
+            pub fn new(pool: sqlx::PgPool #(, #tenant_args)*) -> Self {
+                Self(launchpad::loader::Loader::new(move |keys: Vec<#pk_type>| {
+                    let pool = pool.clone();
+                    let query = sqlx::query_as::<_, #ent>(#query) #(#tenant_binds)* .bind(keys);
+                    Box::pin(async move {
+                        let rows = query.fetch_all(&pool).await #map_err?;
+                        Ok(rows.into_iter().map(|row| (row.#pk_field.clone(), row)).collect())
+                    })
+                }))
+            }
+
+            /// Loads along with any other lookups made at the same time.
+            pub async fn load(&self, #pk_field: &#pk_type) -> Result<Option<#ent>, std::sync::Arc<#error>> {
+                self.0.load(#pk_field.clone()).await
+            }
+        }
+    })
+}
+
+/// A trigger notifying `<entity>_changed` listeners with each changed row, and a stream decoding
+/// those notifications back into entities.
+fn change_feed(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    if !entity.notify {
+        return Ok(quote! {});
+    }
+
+    let ent = &entity.entity;
+    let vis = &entity.vis;
+    let table = &entity.table_name;
+    let change_name = format_ident!("{}Change", ent);
+    let error = &entity.error;
+    let map_err = entity.map_err();
+    let changes_name = format_ident!("{}Changes", ent);
+    let channel = format!("{}_changed", entity.entity_snake_name());
+    let function = format!("notify_{channel}");
+
+    // the payload is `<operation>:<row as json>`; postgres turns the json back into a row for us
+    let ddl = format!(
+        "create or replace function {function}() returns trigger as $$ \
+        begin \
+            if tg_op = 'DELETE' then \
+                perform pg_notify('{channel}', tg_op || ':' || row_to_json(old)::text); \
+            else \
+                perform pg_notify('{channel}', tg_op || ':' || row_to_json(new)::text); \
+            end if; \
+            return null; \
+        end; \
+        $$ language plpgsql; \
+        drop trigger if exists {function} on {table}; \
+        create trigger {function} after insert or update or delete on {table} \
+            for each row execute function {function}();"
+    );
+    let decode = format!("select * from json_populate_record(null::{table}, $1::json)");
+
+    let change_doc = format!("A row of `{table}` as it was after an insert or update, or before a delete.");
+    let changes_doc = format!("Row changes to `{table}`, published on `{channel}` once [`{changes_name}::install`] has run.");
+
+    Ok(quote! {
+        #[doc = #change_doc]
+        #vis enum #change_name {
+            Inserted(#ent),
+            Updated(#ent),
+            Deleted(#ent),
+        }
+
+        #[doc = #changes_doc]
+        #vis struct #changes_name;
+
+        impl #changes_name {
+            pub const CHANNEL: &'static str = #channel;
+            /// Creates (or replaces) the notifying trigger function and trigger.
+            pub const TRIGGER_DDL: &'static str = #ddl;
+
+            pub async fn install<'e, E>(executor: E) -> Result<(), #error>
+            where
+                E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+            {
+                sqlx::raw_sql(Self::TRIGGER_DDL).execute(executor).await?;
+                Ok(())
+            }
+
+            /// Listens on a dedicated connection from the pool. Changes committed while the
+            /// connection is being re-established are missed.
+            pub async fn listen(
+                pool: &sqlx::PgPool,
+            ) -> Result<impl launchpad::futures::Stream<Item = Result<#change_name, #error>>, #error> {
+                let mut listener = sqlx::postgres::PgListener::connect_with(pool).await?;
+                listener.listen(Self::CHANNEL).await?;
+
+                Ok(launchpad::futures::stream::unfold(listener, |mut listener| async move {
+                    let change = Self::recv(&mut listener).await #map_err;
+                    Some((change, listener))
+                }))
+            }
+
+            async fn recv(listener: &mut sqlx::postgres::PgListener) -> Result<#change_name, sqlx::Error> {
+                let notification = listener.recv().await?;
+                let (op, row) = notification
+                    .payload()
+                    .split_once(':')
+                    .ok_or_else(|| sqlx::Error::Protocol(format!("malformed change: {}", notification.payload())))?;
+
+                let row = sqlx::query_as::<_, #ent>(#decode)
+                    .bind(row)
+                    .fetch_one(&mut *listener)
+                    .await?;
+
+                match op {
+                    "INSERT" => Ok(#change_name::Inserted(row)),
+                    "UPDATE" => Ok(#change_name::Updated(row)),
+                    "DELETE" => Ok(#change_name::Deleted(row)),
+                    op => Err(sqlx::Error::Protocol(format!("unknown change operation {op}"))),
+                }
+            }
+        }
+    })
+}
+
+/// Runs a method's body inside a `launchpad.entity` span, recording how long it took and how many
+/// rows it returned or affected.
+fn traced(entity: &DeriveEntity, repo_fn: &RepoFn, body: TokenStream) -> Result<TokenStream, DeriveEntityError> {
+    let RepoFn {
+        signature,
+        operation,
+        key,
+        ..
+    } = repo_fn;
+
+    let sig: syn::Signature = syn::parse2(signature.clone())?;
+    let syn::ReturnType::Type(_, output) = &sig.output else {
+        return Ok(body);
+    };
+
+    // every generated method returns a `Result`; what it wraps tells how to count rows
+    let ok_type = match output.as_ref() {
+        Type::Path(path) => path.path.segments.last().and_then(|s| match &s.arguments {
+            syn::PathArguments::AngleBracketed(args) => args.args.first().cloned(),
+            _ => None,
+        }),
+        _ => None,
+    };
+    let rows = match ok_type {
+        Some(syn::GenericArgument::Type(Type::Path(path))) => {
+            match path.path.segments.last().map(|s| s.ident.to_string()).as_deref() {
+                Some("Vec") => quote! { rows.len() as u64 },
+                Some("Option") => quote! { rows.is_some() as u64 },
+                Some("u64") => quote! { *rows },
+                _ => quote! { 1u64 },
+            }
+        }
+        _ => quote! { 1u64 },
+    };
+
+    let table = &entity.table_name;
+    let key = key.as_ref().map(|key| quote! { key = #key, });
+
+    Ok(quote! {
+        use launchpad::tracing::__tracing::Instrument as _;
+
+        let span = launchpad::tracing::__tracing::info_span!(
+            target: "launchpad.entity",
+            "query",
+            table = #table,
+            operation = #operation,
+            #key
+            rows = launchpad::tracing::__tracing::field::Empty,
+            elapsed_ms = launchpad::tracing::__tracing::field::Empty,
+            error = launchpad::tracing::__tracing::field::Empty,
+        );
+        let started = std::time::Instant::now();
+        let result: #output = async { #body }.instrument(span.clone()).await;
+
+        span.record("elapsed_ms", started.elapsed().as_millis() as u64);
+        match &result {
+            Ok(rows) => span.record("rows", #rows),
+            Err(e) => span.record("error", launchpad::tracing::__tracing::field::display(e)),
+        };
+        result
+    })
+}
+
+/// JSON handlers for finding, listing, creating, replacing and deleting rows by primary key,
+/// mounted with `rocket.mount("/path", Entity::routes())` alongside a managed `PgPool`.
+fn rocket_routes(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    if !entity.rocket_routes {
+        return Ok(quote! {});
+    }
 
-            let binds = key
-                .components
+    if !cfg!(feature = "rocket") {
+        return Err(DeriveEntityError::UnsupportedRoutes("requires the rocket feature"));
+    }
+
+    // the routes have nowhere to take a tenant or an actor from
+    if entity.tenant.is_some() || entity.audited {
+        return Err(DeriveEntityError::UnsupportedRoutes("can't serve tenant scoped or audited entities"));
+    }
+
+    let Some((key, [pk])) = entity.primary_key().map(|key| (key, key.components.as_slice())) else {
+        return Err(DeriveEntityError::UnsupportedRoutes("requires a single column primary key"));
+    };
+
+    let ent = &entity.entity;
+    let EntityImpl {
+        trait_name,
+        repository_name,
+        ..
+    } = EntityImpl::new(entity);
+    let snake_name = entity.entity_snake_name();
+    let mod_name = format_ident!("{}_routes", snake_name);
+    let pk_field = &pk.field_name;
+    let pk_type = &pk.field_type;
+    let filter_name = format_ident!("{}Filter", ent);
+
+    let find_fn = format_ident!("find_{}_by_{}", snake_name, key.name);
+    let list_fn = format_ident!("list_{}", snake_name);
+    let insert_fn = format_ident!("insert_{}", snake_name);
+    let update_fn = format_ident!("upsert_{}", snake_name);
+    let delete_fn = format_ident!("delete_{}", snake_name);
+
+    // mutations report the affected row in returning mode, otherwise how many rows were affected
+    let (created, updated, deleted) = if entity.returning {
+        (
+            quote! { Ok((Status::Created, Json(row))) },
+            quote! { Ok(Json(row)) },
+            quote! {
+                match row {
+                    Some(_) => Ok(Status::NoContent),
+                    None => Err(Status::NotFound),
+                }
+            },
+        )
+    } else {
+        (
+            quote! {
+                let _ = row;
+                Ok((Status::Created, Json(entity)))
+            },
+            quote! {
+                let _ = row;
+                Ok(Json(entity))
+            },
+            quote! {
+                match row {
+                    0 => Err(Status::NotFound),
+                    _ => Ok(Status::NoContent),
+                }
+            },
+        )
+    };
+
+    Ok(quote! {
+        #[doc(hidden)]
+        mod #mod_name {
+            use super::*;
+            use ::rocket::{http::Status, serde::json::Json, State};
+
+            fn internal_error<E: std::fmt::Display>(e: E) -> Status {
+                launchpad::rocket::__tracing::error!("serving {} failed: {e}", stringify!(#ent));
+                Status::InternalServerError
+            }
+
+            #[::rocket::get("/<id>")]
+            pub(super) async fn find(repo: &State<#repository_name>, id: #pk_type) -> Result<Json<#ent>, Status> {
+                match #trait_name::#find_fn(repo.inner(), &id).await.map_err(internal_error)? {
+                    Some(row) => Ok(Json(row)),
+                    None => Err(Status::NotFound),
+                }
+            }
+
+            #[::rocket::get("/?<limit>&<offset>")]
+            pub(super) async fn list(
+                repo: &State<#repository_name>,
+                limit: Option<i64>,
+                offset: Option<i64>,
+            ) -> Result<Json<Vec<#ent>>, Status> {
+                let page = launchpad::page::Page::default();
+                let page = launchpad::page::Page::new(limit.unwrap_or(page.limit), offset.unwrap_or(page.offset));
+                let rows = #trait_name::#list_fn(repo.inner(), &#filter_name::default(), page)
+                    .await
+                    .map_err(internal_error)?;
+                Ok(Json(rows))
+            }
+
+            #[::rocket::post("/", data = "<entity>")]
+            pub(super) async fn create(
+                repo: &State<#repository_name>,
+                entity: Json<#ent>,
+            ) -> Result<(Status, Json<#ent>), Status> {
+                let Json(entity) = entity;
+                let row = #trait_name::#insert_fn(repo.inner(), &entity).await.map_err(internal_error)?;
+                #created
+            }
+
+            /// Creates or replaces the row, taking its key from the path rather than the body.
+            #[::rocket::put("/<id>", data = "<entity>")]
+            pub(super) async fn replace(
+                repo: &State<#repository_name>,
+                id: #pk_type,
+                entity: Json<#ent>,
+            ) -> Result<Json<#ent>, Status> {
+                let Json(mut entity) = entity;
+                entity.#pk_field = id;
+                let row = #trait_name::#update_fn(repo.inner(), &entity).await.map_err(internal_error)?;
+                #updated
+            }
+
+            #[::rocket::delete("/<id>")]
+            pub(super) async fn delete(repo: &State<#repository_name>, id: #pk_type) -> Result<Status, Status> {
+                let row = #trait_name::#delete_fn(repo.inner(), &id).await.map_err(internal_error)?;
+                #deleted
+            }
+        }
+
+        impl #ent {
+            /// Handlers for `GET /<id>`, `GET /?<limit>&<offset>`, `POST /`, `PUT /<id>` and `DELETE /<id>`,
+            /// served from the managed repository. Failures are logged and answered with a 500.
+            pub fn routes() -> Vec<::rocket::Route> {
+                ::rocket::routes![
+                    #mod_name::find,
+                    #mod_name::list,
+                    #mod_name::create,
+                    #mod_name::replace,
+                    #mod_name::delete
+                ]
+            }
+        }
+    })
+}
+
+fn projection_structs(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    let vis = &entity.vis;
+    let structs = entity
+        .projections
+        .iter()
+        .map(|projection| {
+            let name = &projection.name;
+            let fields = projection
+                .columns
                 .iter()
                 .map(|c| {
-                    let f = &c.field_name;
+                    let FieldColumn {
+                        field_name,
+                        field_type,
+                        column_name,
+                    } = c;
+                    // the name the column comes back as, without the quotes it's selected with
+                    let column_name = column_name.trim_matches('"');
                     quote! {
-                        .bind(&#f)
+                        #[sqlx(rename = #column_name)]
+                        pub #field_name: #field_type
                     }
                 })
                 .collect_vec();
 
-            let body = if key.unique {
-                quote! {
-                    let mut tx = self.lock().await;
-                    sqlx::query_as(#query)
+            let doc = format!("A partial [`{}`], selecting only some of its columns.", entity.entity);
+
+            quote! {
+                #[doc = #doc]
+                #[derive(Debug, Clone, sqlx::FromRow)]
+                #vis struct #name {
                     #(
-                        #binds
+                        #fields,
                     )*
-                    .fetch_optional(&mut **tx)
-                    .await
                 }
-            } else {
+            }
+        })
+        .collect_vec();
+
+    Ok(quote! {
+        #(
+            #structs
+        )*
+    })
+}
+
+fn query_builder(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    let ent = &entity.entity;
+    let vis = &entity.vis;
+    let query_name = format_ident!("{}Query", ent);
+    let error = &entity.error;
+    let map_err = entity.map_err();
+    let select = format!("select * from {}", entity.table_name);
+
+    let operators = [
+        ("eq", "="),
+        ("ne", "<>"),
+        ("gt", ">"),
+        ("gte", ">="),
+        ("lt", "<"),
+        ("lte", "<="),
+    ];
+
+    let column_fns = entity
+        .columns
+        .iter()
+        .map(|c| {
+            let FieldColumn {
+                field_name,
+                field_type,
+                column_name,
+            } = c;
+
+            let filter_fns = operators.iter().map(|(suffix, op)| {
+                let fn_name = format_ident!("{}_{}", field_name, suffix);
+                let doc = format!("Filters on `{column_name} {op} value`.");
                 quote! {
-                    let mut tx = self.lock().await;
-                    sqlx::query_as(#query)
-                    #(
-                        #binds
-                    )*
-                    .fetch_all(&mut **tx)
-                    .await
+                    #[doc = #doc]
+                    pub fn #fn_name(self, value: impl Into<#field_type>) -> Self {
+                        self.filter(#column_name, #op, value.into())
+                    }
                 }
-            };
+            });
 
-            quote! {
-                async fn #fn_name(&self, #(#fn_args), *) -> #fn_rtn {
-                    #body
+            let order_fns = [("asc", "asc"), ("desc", "desc")].map(|(suffix, dir)| {
+                let fn_name = format_ident!("order_by_{}_{}", field_name, suffix);
+                let order = format!("{column_name} {dir}");
+                quote! {
+                    pub fn #fn_name(mut self) -> Self {
+                        self.order_by.push(#order);
+                        self
+                    }
                 }
+            });
+
+            quote! {
+                #(
+                    #filter_fns
+                )*
+
+                #(
+                    #order_fns
+                )*
             }
         })
         .collect_vec();
 
+    let doc = format!("Builds a parameterized select over `{}`, one filter or ordering at a time.", entity.table_name);
+
+    // a tenant-scoped query can only be started from its tenant
+    let (derive_default, new_fn) = match &entity.tenant {
+        Some(FieldColumn {
+            field_name,
+            field_type,
+            column_name,
+        }) => (
+            quote! {},
+            quote! {
+                pub fn new(#field_name: impl Into<#field_type>) -> Self {
+                    Self {
+                        filters: Vec::default(),
+                        order_by: Vec::default(),
+                        limit: None,
+                        offset: None,
+                    }
+                    .filter(#column_name, "=", #field_name.into())
+                }
+            },
+        ),
+        None => (
+            quote! { #[derive(Default)] },
+            quote! {
+                pub fn new() -> Self {
+                    Self::default()
+                }
+            },
+        ),
+    };
+
     Ok(quote! {
-        impl #trait_name for tokio::sync::Mutex<sqlx::Transaction<'_, sqlx::Postgres>> {
+        #[doc = #doc]
+        #derive_default
+        #vis struct #query_name {
+            filters: Vec<Box<dyn FnOnce(&mut sqlx::QueryBuilder<'static, sqlx::Postgres>) + Send>>,
+            order_by: Vec<&'static str>,
+            limit: Option<i64>,
+            offset: Option<i64>,
+        }
+
+        impl #query_name {
+            #new_fn
+
             #(
-                #key_fns
+                #column_fns
             )*
+
+            pub fn limit(mut self, limit: i64) -> Self {
+                self.limit = Some(limit);
+                self
+            }
+
+            pub fn offset(mut self, offset: i64) -> Self {
+                self.offset = Some(offset);
+                self
+            }
+
+            fn filter<T>(mut self, column: &'static str, op: &'static str, value: T) -> Self
+            where
+                T: sqlx::Encode<'static, sqlx::Postgres> + sqlx::Type<sqlx::Postgres> + Send + 'static,
+            {
+                self.filters.push(Box::new(move |builder| {
+                    builder.push(column).push(" ").push(op).push(" ").push_bind(value);
+                }));
+                self
+            }
+
+            /// Renders the query, binding every filter value in order.
+            pub fn build(self) -> sqlx::QueryBuilder<'static, sqlx::Postgres> {
+                let mut builder = sqlx::QueryBuilder::new(#select);
+
+                for (i, filter) in self.filters.into_iter().enumerate() {
+                    builder.push(if i == 0 { " where " } else { " and " });
+                    filter(&mut builder);
+                }
+
+                if !self.order_by.is_empty() {
+                    builder.push(" order by ").push(self.order_by.join(", "));
+                }
+
+                if let Some(limit) = self.limit {
+                    builder.push(" limit ").push_bind(limit);
+                }
+
+                if let Some(offset) = self.offset {
+                    builder.push(" offset ").push_bind(offset);
+                }
+
+                builder
+            }
+
+            pub async fn fetch_all<'e, E>(self, executor: E) -> Result<Vec<#ent>, #error>
+            where
+                E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+            {
+                let mut builder = self.build();
+                builder.build_query_as().fetch_all(executor).await #map_err
+            }
+
+            pub async fn fetch_optional<'e, E>(self, executor: E) -> Result<Option<#ent>, #error>
+            where
+                E: sqlx::Executor<'e, Database = sqlx::Postgres>,
+            {
+                let mut builder = self.build();
+                builder.build_query_as().fetch_optional(executor).await #map_err
+            }
         }
-    })}
+    })
+}
+
+fn repo_ext(entity: &DeriveEntity) -> Result<TokenStream, DeriveEntityError> {
+    let EntityImpl {
+        trait_name,
+        repository_name,
+        ext_trait_name,
+        ..
+    } = EntityImpl::new(entity);
+    let vis = &entity.vis;
+
+    let doc = format!(
+        "Runs `f` against a new transaction, which implements [`{trait_name}`]. \
+        The transaction is committed when `f` succeeds and rolled back otherwise, \
+        still returning `f`'s error if the rollback fails."
+    );
+    let repository_doc = format!(
+        "Implements [`{trait_name}`] over a pool it owns, so it can be kept in application state \
+        or swapped for another implementation, without bringing every entity's methods into scope on the pool."
+    );
+
+    // a failed rollback mustn't hide the error that made `f` fail
+    let rollback_failed = if cfg!(feature = "tracing") {
+        quote! { launchpad::tracing::__tracing::error!("rolling back after a failed transaction failed: {e}") }
+    } else {
+        quote! { drop(e) }
+    };
+
+    Ok(quote! {
+        pub trait #ext_trait_name {
+            #[doc = #doc]
+            async fn with_tx<T, E, F>(&self, f: F) -> Result<T, E>
+            where
+                F: AsyncFnOnce(&tokio::sync::Mutex<sqlx::Transaction<'static, sqlx::Postgres>>) -> Result<T, E>,
+                E: From<sqlx::Error>;
+        }
+
+        impl #ext_trait_name for sqlx::Pool<sqlx::Postgres> {
+            async fn with_tx<T, E, F>(&self, f: F) -> Result<T, E>
+            where
+                F: AsyncFnOnce(&tokio::sync::Mutex<sqlx::Transaction<'static, sqlx::Postgres>>) -> Result<T, E>,
+                E: From<sqlx::Error>,
+            {
+                let tx = tokio::sync::Mutex::new(self.begin().await?);
+                let result = f(&tx).await;
+                let tx = tx.into_inner();
+                match result {
+                    Ok(_) => tx.commit().await?,
+                    Err(_) => {
+                        if let Err(e) = tx.rollback().await {
+                            #rollback_failed;
+                        }
+                    }
+                }
+                result
+            }
+        }
+
+        #[doc = #repository_doc]
+        #[derive(Debug, Clone)]
+        #vis struct #repository_name {
+            pool: sqlx::PgPool,
+        }
+
+        impl #repository_name {
+            pub fn new(pool: sqlx::PgPool) -> Self {
+                Self { pool }
+            }
+
+            pub fn pool(&self) -> &sqlx::PgPool {
+                &self.pool
+            }
+        }
+
+        impl #ext_trait_name for #repository_name {
+            async fn with_tx<T, E, F>(&self, f: F) -> Result<T, E>
+            where
+                F: AsyncFnOnce(&tokio::sync::Mutex<sqlx::Transaction<'static, sqlx::Postgres>>) -> Result<T, E>,
+                E: From<sqlx::Error>,
+            {
+                #ext_trait_name::with_tx(&self.pool, f).await
+            }
+        }
+    })
+}
 
 pub(super) mod args {
-    use darling::{FromDeriveInput, FromField};
+    use darling::{
+        util::{Override, PathList, SpannedValue},
+        FromDeriveInput, FromField, FromMeta,
+    };
     use syn::Ident;
 
     #[derive(Debug, FromDeriveInput)]
@@ -434,23 +2849,226 @@ pub(super) mod args {
 
         #[darling(default)]
         pub table_name: Option<String>,
+
+        #[darling(multiple)]
+        pub projection: Vec<Projection>,
+
+        #[darling(multiple)]
+        pub aggregate: Vec<Aggregate>,
+
+        /// case convention for column names not set explicitly with `#[column(name = ...)]`
+        #[darling(default)]
+        pub rename_all: Option<SpannedValue<String>>,
+
+        /// field every query is scoped by, taken as an extra argument to each generated method. An upsert
+        /// conflicting with another tenant's row leaves it alone, returning `None` in `returning` mode
+        #[darling(default)]
+        pub tenant: Option<SpannedValue<String>>,
+
+        /// return the persisted row from mutations, rather than the number of rows affected
+        #[darling(default)]
+        pub returning: bool,
+
+        /// copy a row's previous state into `<table>_history` whenever it's updated or deleted
+        #[darling(default)]
+        pub audited: bool,
+
+        /// generate a `Cached<Entity>Repo` wrapper serving primary key lookups from a cache
+        #[darling(default)]
+        pub cache: bool,
+
+        /// generate an `<Entity>Loader` batching concurrent primary key lookups into one query
+        #[darling(default)]
+        pub loader: bool,
+
+        /// generate a trigger publishing row changes over LISTEN/NOTIFY, and a typed listener for them
+        #[darling(default)]
+        pub notify: bool,
+
+        /// generate JSON CRUD routes over the primary key, served from a managed `<Entity>Repository`
+        #[darling(default)]
+        pub rocket_routes: bool,
+
+        /// map a read-only view: `view` alone, or `view(sql = "select ...")` to also generate its DDL
+        #[darling(default)]
+        pub view: Option<Override<View>>,
+
+        /// error type returned by generated methods instead of `sqlx::Error`, which must convert into it
+        /// (and implement `Display`, for tracing)
+        #[darling(default)]
+        pub error: Option<syn::Path>,
+
+        /// use `sqlx::query_as!` so key lookups are verified against the database at compile time
+        #[darling(default)]
+        pub checked: bool,
     }
 
-    #[derive(Debug, FromField)]
+    #[derive(Debug, FromMeta)]
+    pub(crate) struct Projection {
+        pub name: Ident,
+        pub fields: PathList,
+    }
+
+    #[derive(Debug, Default, FromMeta)]
+    pub(crate) struct View {
+        /// query the view selects rows from
+        #[darling(default)]
+        pub sql: Option<String>,
+    }
+
+    #[derive(Debug, FromMeta)]
+    pub(crate) struct Aggregate {
+        /// field to count rows grouped by
+        pub count_by: SpannedValue<String>,
+    }
+
+    #[derive(Debug, Clone, FromField)]
     #[darling(attributes(key))]
     pub(crate) struct Key {
         pub name: Option<String>,
 
         #[darling(default)]
         pub unique: Option<bool>,
+
+        /// the key identifying a row for updates; implies `unique`
+        #[darling(default)]
+        pub primary: bool,
+
+        /// generate `select ... for update` variants for use within a transaction
+        #[darling(default)]
+        pub locking: bool,
+
+        /// look the key up by a column on a related table, `table.column`, instead of the field
+        #[darling(default)]
+        pub via: Option<String>,
+
+        /// condition joining the `via` table onto the entity's, e.g. `users.id = my_entity.owner_id`
+        #[darling(default)]
+        pub join: Option<String>,
+
+        /// type of the `via` column, when it differs from the field's
+        #[darling(default)]
+        pub via_type: Option<syn::Type>,
     }
 
     #[derive(Debug, FromField)]
     #[darling(attributes(column))]
     pub(crate) struct Column {
-        pub name: String,
+        #[darling(default)]
+        pub name: Option<String>,
+
+        /// leave the field out of generated SQL; pair with `#[sqlx(skip)]` so `FromRow` ignores it too
+        #[darling(default)]
+        pub skip: bool,
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use syn::{parse_quote, DeriveInput};
+
+    use super::{quote_column, DeriveEntity, DeriveEntityError, RenameRule};
+
+    fn error(input: DeriveInput) -> DeriveEntityError {
+        match DeriveEntity::try_from(input).unwrap_err() {
+            DeriveEntityError::Spanned(_, e) => *e,
+            e => e,
+        }
+    }
+
+    #[test]
+    fn test_rename_rules() {
+        let rename = |rule: &str| RenameRule::try_from(rule).unwrap().apply("entity_id");
+        assert_eq!(rename("camelCase"), "entityId");
+        assert_eq!(rename("PascalCase"), "EntityId");
+        assert_eq!(rename("SCREAMING_SNAKE_CASE"), "ENTITY_ID");
+        assert_eq!(rename("kebab-case"), "entity-id");
+        assert_eq!(rename("UPPERCASE"), "ENTITY_ID");
+        assert!(RenameRule::try_from("Title Case").is_err());
+        assert_eq!(quote_column(rename("snake_case")), "entity_id");
+        assert_eq!(quote_column(rename("camelCase")), "\"entityId\"");
+        assert_eq!(quote_column(rename("kebab-case")), "\"entity-id\"");
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let e = error(parse_quote! {
+            struct E {
+                #[key(name = "by-name")]
+                name: String,
+            }
+        });
+        assert!(matches!(e, DeriveEntityError::InvalidKeyName(name) if name == "by-name"));
+
+        let e = error(parse_quote! {
+            struct E {
+                #[key(name = "Name")]
+                name: String,
+                #[key(name = "name")]
+                other: String,
+            }
+        });
+        assert!(matches!(e, DeriveEntityError::DuplicateKeyName(..)));
+
+        let e = error(parse_quote! {
+            struct E {
+                name: &'static str,
+            }
+        });
+        assert!(matches!(e, DeriveEntityError::UnsupportedType(field) if field == "name"));
+
+        let e = error(parse_quote! {
+            struct E<T> {
+                value: T,
+            }
+        });
+        assert!(matches!(e, DeriveEntityError::GenericEntity));
+
+        let e = error(parse_quote! {
+            #[entity(view, audited)]
+            struct E {
+                name: String,
+            }
+        });
+        assert!(matches!(e, DeriveEntityError::ReadOnlyView("audited")));
+
+        let e = error(parse_quote! {
+            struct E {
+                #[key(name = "owner_email", via = "users.email")]
+                owner_id: i64,
+            }
+        });
+        assert!(matches!(e, DeriveEntityError::InvalidJoin(name, _) if name == "owner_email"));
+
+        let e = error(parse_quote! {
+            #[entity(checked)]
+            struct E {
+                #[key(primary)]
+                id: i64,
+                #[column(skip)]
+                label: String,
+            }
+        });
+        assert!(matches!(e, DeriveEntityError::CheckedSkippedField(field) if field == "label"));
+
+        let input: DeriveInput = parse_quote! {
+            #[entity(view, audited)]
+            struct E {
+                name: String,
+            }
+        };
+        let e = DeriveEntity::try_from(input);
+        assert!(matches!(e, Err(DeriveEntityError::Spanned(_, e)) if matches!(*e, DeriveEntityError::ReadOnlyView("audited"))));
+
+        let input: DeriveInput = parse_quote! {
+            #[entity(rename_all = "Title Case")]
+            struct E {
+                name: String,
+            }
+        };
+        let e = DeriveEntity::try_from(input).unwrap_err();
+        let compile_error = e.into_compile_error(proc_macro2::Span::call_site()).to_string();
+        assert!(compile_error.contains("compile_error"));
+        assert!(compile_error.contains("unknown rename_all rule"));
+    }
+}
@@ -0,0 +1,156 @@
+use darling::{ast, FromDeriveInput, FromField};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote};
+use syn::{DeriveInput, Generics, Ident, Visibility};
+
+#[derive(Debug, FromDeriveInput)]
+#[darling(attributes(routing_key), supports(struct_named))]
+pub(crate) struct DeriveRoutingKey {
+    ident: Ident,
+    vis: Visibility,
+    generics: Generics,
+    data: ast::Data<(), RoutingKeyField>,
+
+    /// a literal first segment, e.g. the kind of message
+    #[darling(default)]
+    prefix: Option<String>,
+}
+
+#[derive(Debug, FromField)]
+#[darling(attributes(routing_key))]
+pub(crate) struct RoutingKeyField {
+    ident: Option<Ident>,
+
+    /// the segment's name in the key; the field's name by default
+    #[darling(default)]
+    rename: Option<String>,
+
+    /// leave the field out of the key
+    #[darling(default)]
+    skip: bool,
+}
+
+impl DeriveRoutingKey {
+    pub(crate) fn expand(input: DeriveInput) -> darling::Result<TokenStream> {
+        let derive = DeriveRoutingKey::from_derive_input(&input)?;
+        if !derive.generics.params.is_empty() {
+            return Err(darling::Error::custom("generic routing keys aren't supported").with_span(&derive.generics));
+        }
+        let DeriveRoutingKey { ident, vis, data, prefix, .. } = derive;
+
+        let fields = data
+            .take_struct()
+            .expect("only named structs are supported")
+            .fields
+            .into_iter()
+            .filter(|f| !f.skip)
+            .map(|f| {
+                let ident = f.ident.expect("only named structs are supported");
+                let name = f.rename.unwrap_or_else(|| ident.to_string());
+                if name.is_empty() || name.contains(['.', '*', '#']) {
+                    return Err(darling::Error::custom("segment names can't be empty or contain '.', '*' or '#'")
+                        .with_span(&ident));
+                }
+                Ok((ident, name))
+            })
+            .collect::<darling::Result<Vec<_>>>()?;
+        if fields.is_empty() {
+            return Err(darling::Error::custom("a routing key needs at least one field").with_span(&ident));
+        }
+
+        let pattern = format_ident!("{}Pattern", ident);
+        let prefix = match prefix {
+            Some(prefix) => quote! { Some(#prefix) },
+            None => quote! { None },
+        };
+        let idents = fields.iter().map(|(ident, _)| ident).collect::<Vec<_>>();
+        let names = fields.iter().map(|(_, name)| name).collect::<Vec<_>>();
+        let docs = names
+            .iter()
+            .map(|name| format!("Matches only keys whose `{name}` segment is `value`."));
+        let pattern_doc = format!("A binding pattern for [`{ident}`] keys, matching any value in segments not narrowed down.");
+
+        Ok(quote! {
+            impl launchpad::mq::routing::RoutingKey for #ident {
+                type Pattern = #pattern;
+
+                fn routing_key(&self) -> String {
+                    let mut segments = launchpad::mq::routing::Segments::new(#prefix);
+                    #(segments.push(#names, Some(&self.#idents));)*
+                    segments.finish()
+                }
+
+                fn pattern() -> #pattern {
+                    #pattern::default()
+                }
+            }
+
+            #[doc = #pattern_doc]
+            #[derive(Debug, Clone, Default, PartialEq, Eq)]
+            #vis struct #pattern {
+                #(#idents: Option<String>,)*
+            }
+
+            impl #pattern {
+                #(
+                    #[doc = #docs]
+                    pub fn #idents(mut self, value: impl std::fmt::Display) -> Self {
+                        self.#idents = Some(value.to_string());
+                        self
+                    }
+                )*
+            }
+
+            impl std::fmt::Display for #pattern {
+                fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                    let mut segments = launchpad::mq::routing::Segments::new(#prefix);
+                    #(segments.push(#names, self.#idents.as_ref());)*
+                    f.write_str(&segments.finish())
+                }
+            }
+
+            impl From<#pattern> for String {
+                fn from(pattern: #pattern) -> String {
+                    pattern.to_string()
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use syn::parse_quote;
+
+    use super::*;
+
+    fn error(input: DeriveInput) -> String {
+        DeriveRoutingKey::expand(input).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn invalid_routing_keys() {
+        assert!(error(parse_quote! {
+            struct K {
+                #[routing_key(rename = "person.id")]
+                person_id: String,
+            }
+        })
+        .contains("can't be empty or contain"));
+
+        assert!(error(parse_quote! {
+            struct K {
+                #[routing_key(skip)]
+                person_id: String,
+            }
+        })
+        .contains("at least one field"));
+
+        assert!(error(parse_quote! {
+            struct K<T> {
+                person_id: T,
+            }
+        })
+        .contains("generic"));
+    }
+}
@@ -1,17 +1,32 @@
+#[cfg(feature = "pgsqlx")]
 use derive_entity::DeriveEntity;
 use proc_macro::TokenStream;
 use syn::{parse_macro_input, DeriveInput};
 
+#[cfg(feature = "pgsqlx")]
 mod derive_entity;
+#[cfg(feature = "mq")]
+mod derive_routing_key;
 
 #[cfg(feature = "pgsqlx")]
 #[proc_macro_derive(Entity, attributes(entity, key, column))]
 pub fn derive_sql(input: TokenStream) -> TokenStream {
     let derive_input: DeriveInput = parse_macro_input!(input as DeriveInput);
-    let derive_entity = DeriveEntity::try_from(derive_input)
-        .expect("failed to understand macro arguments");
-    let entity: proc_macro2::TokenStream = derive_entity.try_into()
-        .expect("could create token stream");
+    // errors without a more precise location point at the entity's name
+    let span = derive_input.ident.span();
+
+    DeriveEntity::try_from(derive_input)
+        .and_then(proc_macro2::TokenStream::try_from)
+        .unwrap_or_else(|e| e.into_compile_error(span))
+        .into()
+}
+
+#[cfg(feature = "mq")]
+#[proc_macro_derive(RoutingKey, attributes(routing_key))]
+pub fn derive_routing_key(input: TokenStream) -> TokenStream {
+    let derive_input: DeriveInput = parse_macro_input!(input as DeriveInput);
 
-    entity.into()
-}
\ No newline at end of file
+    derive_routing_key::DeriveRoutingKey::expand(derive_input)
+        .unwrap_or_else(|e| e.write_errors())
+        .into()
+}
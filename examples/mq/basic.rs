@@ -1,7 +1,7 @@
 use std::time::Duration;
 
 use launchpad::mq::{
-    consumer::{Processor, ProcessorError},
+    consumer::{MessageContext, Processor, ProcessorError},
     create_channel,
     setup::{ExchangeBuilder, ExchangeType},
     ChannelOps, CreateChannelConfigFromEnv, Envelope, Exchange, Queue,
@@ -32,8 +32,8 @@ pub async fn main() -> anyhow::Result<()> {
 
 struct LoggingProcessor;
 impl Processor for LoggingProcessor {
-    async fn process(&mut self, value: Value) -> Result<(), ProcessorError> {
-        info!("received: {:?}", value);
+    async fn process(&mut self, value: Value, context: &MessageContext) -> Result<(), ProcessorError> {
+        info!("received {:?}: {:?}", context.routing_key, value);
         Ok(())
     }
 }
@@ -2,12 +2,16 @@
 mod basic;
 #[cfg(feature = "mq")]
 mod streaming;
+#[cfg(feature = "yaml")]
+mod topology;
 
 #[cfg(feature = "mq")]
 #[tokio::main]
 async fn main() -> anyhow::Result<()>{
     basic::main()?;
     streaming::main()?;
+    #[cfg(feature = "yaml")]
+    topology::main()?;
     Ok(())
 }
 
@@ -3,6 +3,7 @@ use std::time::Duration;
 use derive_more::derive::Constructor;
 use launchpad::mq::{
     create_channel,
+    routing::RoutingKey,
     setup::{ExchangeBuilder, ExchangeType, QueueOptions},
     ChannelOps, CreateChannelConfigFromEnv, Envelope, Exchange, Queue,
 };
@@ -31,9 +32,19 @@ pub async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+#[derive(Debug, Serialize, Deserialize, Constructor, RoutingKey)]
+struct Payload {
+    #[routing_key(skip)]
+    value: String,
+    person_id: String,
+    item_id: String,
+}
+
 async fn topology() -> anyhow::Result<()> {
     use launchpad::mq::setup::{Binding, Exchange, Queue, Topology, TopologyBuilder, TopologyOps};
 
+    let mine = Payload::pattern().person_id("me").to_string();
+    let all = Payload::pattern().to_string();
     let topology = Topology::builder()
         .with_queue(Queue::new(
             "my-queue",
@@ -52,12 +63,12 @@ async fn topology() -> anyhow::Result<()> {
         .with_binding(Binding::ToQueue {
             src_exchange_name: "streaming-exchange",
             target_queue_name: "my-queue",
-            routing_key: Some("person_id.me.item_id.*"),
+            routing_key: Some(mine.as_str()),
         })
         .with_binding(Binding::ToQueue {
             src_exchange_name: "streaming-exchange",
             target_queue_name: "all",
-            routing_key: Some("person_id.*.item_id.*"),
+            routing_key: Some(all.as_str()),
         })
         .build();
 
@@ -85,12 +96,6 @@ async fn consumer() -> anyhow::Result<()> {
 async fn producer() -> anyhow::Result<()> {
     let channel = create_channel(CreateChannelConfigFromEnv).await?;
     let producer = channel.create_producer(Exchange::new("streaming-exchange"));
-    #[derive(Debug, Serialize, Deserialize, Constructor)]
-    struct Payload {
-        value: String,
-        person_id: String,
-        item_id: String,
-    }
     let messages = [
         Payload::new("a".into(), "me".into(), "id1".into()),
         Payload::new("b".into(), "you".into(), "id2".into()),
@@ -101,10 +106,7 @@ async fn producer() -> anyhow::Result<()> {
 
     for message in messages {
         info!("sending message: {:?}", message);
-        let rk = format!(
-            "person_id.{}.item_id.{}",
-            message.message.person_id, message.message.item_id
-        );
+        let rk = message.message.routing_key();
         producer.publish(message, Some(rk)).await?;
     }
     info!("producing complete");
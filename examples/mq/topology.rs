@@ -0,0 +1,16 @@
+use launchpad::mq::{
+    create_channel,
+    setup::{Topology, TopologyOps},
+    CreateChannelConfigFromEnv,
+};
+use tracing::info;
+
+#[tokio::main]
+pub async fn main() -> anyhow::Result<()> {
+    let topology = Topology::from_path(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/mq/topology.yaml"))?;
+    info!("applying {:?}", topology);
+
+    let channel = create_channel(CreateChannelConfigFromEnv).await?;
+    channel.apply_topology(topology).await?;
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use std::{collections::HashMap, hash::Hash, sync::RwLock};
+
+/// A store of values by key, used by the `CachedXRepo` wrappers generated for `#[entity(cache)]`.
+pub trait Cache<K, V> {
+    async fn get(&self, key: &K) -> Option<V>;
+    async fn insert(&self, key: K, value: V);
+    async fn invalidate(&self, key: &K);
+}
+
+/// An unbounded in-process cache. Entries live until invalidated.
+#[derive(Debug)]
+pub struct MemoryCache<K, V> {
+    entries: RwLock<HashMap<K, V>>,
+}
+
+impl<K, V> MemoryCache<K, V> {
+    pub fn new() -> Self {
+        MemoryCache {
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K, V> Default for MemoryCache<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq, V: Clone> Cache<K, V> for MemoryCache<K, V> {
+    async fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        entries.get(key).cloned()
+    }
+
+    async fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        entries.insert(key, value);
+    }
+
+    async fn invalidate(&self, key: &K) {
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        entries.remove(key);
+    }
+}
+
+#[cfg(feature = "moka")]
+impl<K, V> Cache<K, V> for moka::future::Cache<K, V>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    async fn get(&self, key: &K) -> Option<V> {
+        moka::future::Cache::get(self, key).await
+    }
+
+    async fn insert(&self, key: K, value: V) {
+        moka::future::Cache::insert(self, key, value).await
+    }
+
+    async fn invalidate(&self, key: &K) {
+        moka::future::Cache::invalidate(self, key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_cache() {
+        let cache = MemoryCache::new();
+        assert_eq!(cache.get(&1).await, None);
+
+        cache.insert(1, "one").await;
+        assert_eq!(cache.get(&1).await, Some("one"));
+
+        cache.invalidate(&1).await;
+        assert_eq!(cache.get(&1).await, None);
+    }
+}
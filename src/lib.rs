@@ -1,5 +1,9 @@
 #![allow(async_fn_in_trait)]
 
+// lets code generated by the derives, which names `launchpad::`, be used in the crate itself
+extern crate self as launchpad;
+
+pub use futures;
 pub use utilities;
 
 #[cfg(feature = "mq")]
@@ -8,8 +12,20 @@ pub mod mq;
 #[cfg(feature = "tracing")]
 pub mod tracing;
 
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
 #[cfg(feature = "rocket")]
 pub mod rocket;
 
+#[cfg(feature = "cache")]
+pub mod cache;
+
+#[cfg(feature = "pgsqlx")]
+pub mod loader;
+
+#[cfg(feature = "pgsqlx")]
+pub mod page;
+
 #[cfg(feature = "pgsqlx")]
 pub use launchpad_derive::Entity;
\ No newline at end of file
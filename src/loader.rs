@@ -0,0 +1,129 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::{channel::oneshot, future::BoxFuture};
+
+type Fetch<K, V, E> = Box<dyn Fn(Vec<K>) -> BoxFuture<'static, Result<Vec<(K, V)>, E>> + Send + Sync>;
+type Waiters<V, E> = Vec<oneshot::Sender<Result<Option<V>, Arc<E>>>>;
+
+/// Batches loads made at the same time into a single fetch, used by the `XLoader`s generated for
+/// `#[entity(loader)]`.
+///
+/// Loads polled together, e.g. by `join_all` or from tasks spawned together, are collected until
+/// they next yield and then fetched at once. Errors are shared between every load in the batch.
+pub struct Loader<K, V, E> {
+    fetch: Fetch<K, V, E>,
+    pending: Mutex<HashMap<K, Waiters<V, E>>>,
+}
+
+impl<K, V, E> Loader<K, V, E>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    /// `fetch` returns the rows found for a batch of keys, with their keys. Keys without a row
+    /// load as `None`.
+    pub fn new(fetch: impl Fn(Vec<K>) -> BoxFuture<'static, Result<Vec<(K, V)>, E>> + Send + Sync + 'static) -> Self {
+        Loader {
+            fetch: Box::new(fetch),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn load(&self, key: K) -> Result<Option<V>, Arc<E>> {
+        loop {
+            let (tx, rx) = oneshot::channel();
+            self.pending().entry(key.clone()).or_default().push(tx);
+
+            // let the loads polled alongside this one join the batch; whichever gets here first
+            // fetches it
+            YieldNow(false).await;
+            let batch = std::mem::take(&mut *self.pending());
+            if !batch.is_empty() {
+                self.dispatch(batch).await;
+            }
+
+            // a load fetching our batch was dropped part way through, so queue up again
+            if let Ok(result) = rx.await {
+                return result;
+            }
+        }
+    }
+
+    async fn dispatch(&self, batch: HashMap<K, Waiters<V, E>>) {
+        let keys = batch.keys().cloned().collect();
+        match (self.fetch)(keys).await {
+            Ok(rows) => {
+                let mut rows: HashMap<K, V> = rows.into_iter().collect();
+                for (key, waiters) in batch {
+                    let row = rows.remove(&key);
+                    for waiter in waiters {
+                        let _ = waiter.send(Ok(row.clone()));
+                    }
+                }
+            }
+            Err(e) => {
+                let e = Arc::new(e);
+                for waiter in batch.into_values().flatten() {
+                    let _ = waiter.send(Err(e.clone()));
+                }
+            }
+        }
+    }
+
+    fn pending(&self) -> std::sync::MutexGuard<'_, HashMap<K, Waiters<V, E>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Returns to the executor once, so other futures get polled before we carry on.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::{future::join_all, FutureExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn batches_concurrent_loads() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let loader = {
+            let fetches = fetches.clone();
+            Loader::new(move |keys: Vec<u32>| {
+                fetches.fetch_add(1, Ordering::SeqCst);
+                async move { Ok::<_, ()>(keys.into_iter().filter(|k| k % 2 == 0).map(|k| (k, k * 10)).collect()) }
+                    .boxed()
+            })
+        };
+
+        let loaded = join_all([1, 2, 4, 2].map(|k| loader.load(k))).await;
+        assert_eq!(loaded, [Ok(None), Ok(Some(20)), Ok(Some(40)), Ok(Some(20))]);
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        assert_eq!(loader.load(6).await, Ok(Some(60)));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,189 @@
+//! Metrics for Prometheus to scrape, recorded through the [`metrics`](::metrics) facade, so
+//! [`counter!`], [`gauge!`] and [`histogram!`] anywhere in the application, and in launchpad
+//! itself, e.g. with the `mq-metrics` feature, end up in the same place.
+//!
+//! Install a recorder once at startup with [`Prometheus::builder`], then serve what it renders,
+//! either from a Rocket route, see [`launchpad::rocket::metrics`](crate::rocket::metrics), or
+//! from a listener of its own, see [`MetricsBuilder::with_listener`].
+
+use std::{future::Future, net::SocketAddr, time::Instant};
+
+use metrics_exporter_prometheus::{BuildError, PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+use thiserror::Error;
+
+pub use ::metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram, Histogram, Unit};
+
+/// The buckets histograms are recorded in unless told otherwise, suiting durations in seconds,
+/// from 5 milliseconds to 10 seconds.
+pub const DEFAULT_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Debug, Error)]
+pub enum MetricsError {
+    #[error("Configuration error: {0}")]
+    ConfigurationError(String),
+
+    #[error("Install error: {0}")]
+    InstallError(#[from] BuildError),
+}
+
+/// Configures the recorder [`MetricsBuilder::install`] installs.
+#[derive(Debug, Clone)]
+pub struct MetricsBuilder {
+    buckets: Vec<f64>,
+    global_labels: Vec<(String, String)>,
+    listener: Option<SocketAddr>,
+}
+
+impl MetricsBuilder {
+    /// Histograms are recorded in [`DEFAULT_BUCKETS`], and nothing is served until told to.
+    pub fn new() -> Self {
+        MetricsBuilder {
+            buckets: DEFAULT_BUCKETS.to_vec(),
+            global_labels: Vec::new(),
+            listener: None,
+        }
+    }
+
+    /// Records every histogram in buckets with these upper bounds, rather than
+    /// [`DEFAULT_BUCKETS`].
+    pub fn with_buckets(mut self, buckets: &[f64]) -> Self {
+        self.buckets = buckets.to_vec();
+        self
+    }
+
+    /// Labels every metric with `name` of `value`, e.g. the service's name. Labels recorded with a
+    /// metric take precedence.
+    pub fn with_global_label(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.global_labels.push((name.into(), value.into()));
+        self
+    }
+
+    /// Serves the metrics at `/metrics`, or any other path, on `address`, for services without an
+    /// HTTP server of their own, or to keep them off its port.
+    pub fn with_listener(mut self, address: impl Into<SocketAddr>) -> Self {
+        self.listener = Some(address.into());
+        self
+    }
+
+    /// Installs the recorder globally, and starts the listener if there is one, returning a
+    /// handle rendering what's recorded. Must be called from within a Tokio runtime, at most once.
+    pub fn install(self) -> Result<Prometheus, MetricsError> {
+        let listener = self.listener;
+        let builder = self.prometheus_builder()?;
+        let recorder = match listener {
+            Some(address) => {
+                let (recorder, exporter) = builder.with_http_listener(address).build()?;
+                tokio::spawn(exporter);
+                recorder
+            }
+            None => {
+                let recorder = builder.build_recorder();
+                let handle = recorder.handle();
+                // drains histograms, as the listener would
+                tokio::spawn(async move {
+                    loop {
+                        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                        handle.run_upkeep();
+                    }
+                });
+                recorder
+            }
+        };
+        let handle = recorder.handle();
+        ::metrics::set_global_recorder(recorder).map_err(BuildError::from)?;
+        Ok(Prometheus { handle })
+    }
+
+    /// A recorder to install as the caller sees fit, e.g. alongside others, leaving upkeep to the
+    /// caller too. See [`PrometheusHandle::run_upkeep`].
+    pub fn build_recorder(self) -> Result<PrometheusRecorder, MetricsError> {
+        Ok(self.prometheus_builder()?.build_recorder())
+    }
+
+    fn prometheus_builder(self) -> Result<PrometheusBuilder, MetricsError> {
+        let mut builder = PrometheusBuilder::new()
+            .set_buckets(&self.buckets)
+            .map_err(|e| MetricsError::ConfigurationError(e.to_string()))?;
+        for (name, value) in self.global_labels {
+            builder = builder.add_global_label(name, value);
+        }
+        Ok(builder)
+    }
+}
+
+impl Default for MetricsBuilder {
+    fn default() -> Self {
+        MetricsBuilder::new()
+    }
+}
+
+/// The installed recorder, rendering what's recorded for Prometheus to scrape. Clones render
+/// the same metrics.
+#[derive(Debug, Clone)]
+pub struct Prometheus {
+    handle: PrometheusHandle,
+}
+
+impl Prometheus {
+    pub fn builder() -> MetricsBuilder {
+        MetricsBuilder::new()
+    }
+
+    /// Everything recorded so far, in Prometheus' text format.
+    pub fn render(&self) -> String {
+        self.handle.render()
+    }
+
+    pub fn handle(&self) -> &PrometheusHandle {
+        &self.handle
+    }
+}
+
+impl From<PrometheusHandle> for Prometheus {
+    fn from(handle: PrometheusHandle) -> Self {
+        Prometheus { handle }
+    }
+}
+
+/// Runs `future`, recording how long it took, in seconds, to `histogram`:
+///
+/// ```no_run
+/// # async fn charge() {}
+/// use launchpad::metrics::{histogram, time};
+///
+/// # async fn run() {
+/// time(histogram!("payment_duration_seconds", "provider" => "stripe"), charge()).await;
+/// # }
+/// ```
+pub async fn time<T>(histogram: Histogram, future: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let output = future.await;
+    histogram.record(started.elapsed().as_secs_f64());
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn renders_for_prometheus() {
+        let recorder = Prometheus::builder()
+            .with_buckets(&[0.5, 1.0])
+            .with_global_label("service", "billing")
+            .build_recorder()
+            .unwrap();
+        let prometheus = Prometheus::from(recorder.handle());
+
+        ::metrics::with_local_recorder(&recorder, || {
+            counter!("payments_total", "outcome" => "ok").increment(2);
+            histogram!("payment_duration_seconds").record(0.7);
+        });
+        let rendered = prometheus.render();
+
+        assert!(rendered.contains(r#"payments_total{service="billing",outcome="ok"} 2"#), "{rendered}");
+        assert!(rendered.contains(r#"payment_duration_seconds_bucket{service="billing",le="0.5"} 0"#), "{rendered}");
+        assert!(rendered.contains(r#"payment_duration_seconds_bucket{service="billing",le="1"} 1"#), "{rendered}");
+        assert!(Prometheus::builder().with_buckets(&[]).build_recorder().is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! An audit trail of the messages a service consumes and publishes, kept in Postgres, to answer
+//! whether a service received a message, and what became of it.
+
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use lapin::message::Delivery;
+use serde_json::Value;
+use sqlx::PgPool;
+use tracing::warn;
+
+use super::{
+    consumer::ProcessorError,
+    interceptor::{OutgoingMessage, PublishInterceptor},
+    middleware::{ConsumerMiddleware, Next},
+    Envelope, MqError,
+};
+
+/// The table [`AuditTrail`] records to unless told otherwise.
+pub const DEFAULT_AUDIT_TABLE: &str = "mq_audit";
+
+/// A message consumed or published, as recorded.
+#[derive(Debug, Clone, PartialEq, sqlx::FromRow)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub service: String,
+    /// `consumed` or `published`.
+    pub direction: String,
+    pub exchange: String,
+    pub routing_key: String,
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+    /// `ok`, or for consumed messages, `temporary_error` or `permanent_error`, or for published
+    /// ones, `error`.
+    pub outcome: String,
+    pub error: Option<String>,
+    /// How long processing, or publishing, took.
+    pub latency_ms: Option<f64>,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// Records every message a service consumes, as [`ConsumerMiddleware`], and publishes, as a
+/// [`PublishInterceptor`], with its routing key, id, outcome and latency.
+///
+/// Consumed messages are recorded before they're acked, and a message whose record can't be
+/// written fails temporarily, so every message processed is recorded at least once; one processed
+/// again after a failure is recorded again. Add it as the first middleware to see what the others
+/// make of each message. Published messages are recorded in the background, as interceptors can't
+/// wait, so a record may be lost if the service stops just after publishing.
+#[derive(Debug, Clone)]
+pub struct AuditTrail {
+    pool: PgPool,
+    table: String,
+    service: String,
+    retention: Duration,
+}
+
+impl AuditTrail {
+    /// Records what `service` consumes and publishes to [`DEFAULT_AUDIT_TABLE`], keeping records
+    /// for 90 days unless told otherwise.
+    pub fn new(pool: PgPool, service: impl Into<String>) -> Self {
+        AuditTrail {
+            pool,
+            table: DEFAULT_AUDIT_TABLE.into(),
+            service: service.into(),
+            retention: Duration::from_secs(90 * 24 * 60 * 60),
+        }
+    }
+
+    pub fn with_table(mut self, table: impl Into<String>) -> Self {
+        self.table = table.into();
+        self
+    }
+
+    /// How long records are kept by [`AuditTrail::prune`].
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    pub async fn create_table(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "create table if not exists {table} (
+                id bigserial primary key,
+                service text not null,
+                direction text not null,
+                exchange text not null,
+                routing_key text not null,
+                message_id text,
+                correlation_id text,
+                outcome text not null,
+                error text,
+                latency_ms double precision,
+                recorded_at timestamptz not null default now()
+            )",
+            table = self.table
+        ))
+        .execute(&self.pool)
+        .await?;
+        for column in ["message_id", "recorded_at"] {
+            sqlx::query(&format!(
+                "create index if not exists {table}_{column} on {table} ({column})",
+                table = self.table
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Every record of the message with `message_id`, by any service, oldest first.
+    pub async fn records(&self, message_id: &str) -> Result<Vec<AuditRecord>, sqlx::Error> {
+        sqlx::query_as(&format!("select * from {} where message_id = $1 order by id", self.table))
+            .bind(message_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Deletes records older than the retention period, returning how many. Run it every so often,
+    /// e.g. daily.
+    pub async fn prune(&self) -> Result<u64, sqlx::Error> {
+        let cutoff = Utc::now() - self.retention;
+        let pruned = sqlx::query(&format!("delete from {} where recorded_at < $1", self.table))
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(pruned)
+    }
+
+    async fn record(&self, entry: Entry) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "insert into {} (service, direction, exchange, routing_key, message_id, correlation_id, outcome, error, latency_ms)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            self.table
+        ))
+        .bind(&self.service)
+        .bind(entry.direction)
+        .bind(entry.exchange)
+        .bind(entry.routing_key)
+        .bind(entry.message_id)
+        .bind(entry.correlation_id)
+        .bind(entry.outcome)
+        .bind(entry.error)
+        .bind(entry.latency.as_secs_f64() * 1000.0)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+/// A record about to be written.
+struct Entry {
+    direction: &'static str,
+    exchange: String,
+    routing_key: String,
+    message_id: Option<String>,
+    correlation_id: Option<String>,
+    outcome: &'static str,
+    error: Option<String>,
+    latency: Duration,
+}
+
+impl ConsumerMiddleware for AuditTrail {
+    fn handle<'a>(
+        &'a self,
+        delivery: &'a Delivery,
+        envelope: Envelope<Value>,
+        next: Next<'a>,
+    ) -> LocalBoxFuture<'a, Result<(), ProcessorError>> {
+        Box::pin(async move {
+            let message_id = envelope.message_id().map(String::from);
+            let correlation_id = envelope.correlation_id().map(String::from);
+            let started = Instant::now();
+            let result = next.run(envelope).await;
+
+            let (outcome, error) = match &result {
+                Ok(()) => ("ok", None),
+                Err(ProcessorError::TemporaryError(e)) => ("temporary_error", Some(e.clone())),
+                Err(ProcessorError::PermanentError(e)) => ("permanent_error", Some(e.clone())),
+            };
+            let entry = Entry {
+                direction: "consumed",
+                exchange: delivery.exchange.to_string(),
+                routing_key: delivery.routing_key.to_string(),
+                message_id,
+                correlation_id,
+                outcome,
+                error,
+                latency: started.elapsed(),
+            };
+            if let Err(e) = self.record(entry).await {
+                warn!("recording consumed message in the audit trail failed: {e}");
+                return Err(ProcessorError::TemporaryError(format!("audit trail: {e}")));
+            }
+            result
+        })
+    }
+}
+
+impl PublishInterceptor for AuditTrail {
+    fn intercept(&self, _message: &mut OutgoingMessage) -> Result<(), MqError> {
+        Ok(())
+    }
+
+    fn published_in(&self, message: &OutgoingMessage, result: &Result<(), MqError>, elapsed: Duration) {
+        let properties = &message.properties;
+        let entry = Entry {
+            direction: "published",
+            exchange: message.exchange.clone(),
+            routing_key: message.routing_key.clone(),
+            message_id: properties.message_id().as_ref().map(|id| id.to_string()),
+            correlation_id: properties.correlation_id().as_ref().map(|id| id.to_string()),
+            outcome: if result.is_ok() { "ok" } else { "error" },
+            error: result.as_ref().err().map(|e| e.to_string()),
+            latency: elapsed,
+        };
+        let audit = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = audit.record(entry).await {
+                warn!("recording published message in the audit trail failed: {e}");
+            }
+        });
+    }
+}
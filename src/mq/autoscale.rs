@@ -0,0 +1,189 @@
+//! Watching how deep a queue is, and scaling how many messages a consumer processes at once to
+//! match, so workers neither sit idle with spare capacity nor drown under a backlog.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use lapin::{options::QueueDeclareOptions, types::FieldTable, Channel};
+
+use super::{consumer::ConsumerStream, MqError};
+
+/// How deep a queue was when it was sampled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepthSample {
+    pub queue: String,
+
+    /// Messages waiting to be delivered, not counting those delivered but not yet acked.
+    pub messages: u32,
+
+    pub consumers: u32,
+    pub sampled_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    Channel(Channel),
+    #[cfg(feature = "mq-management")]
+    Management(super::management::ManagementClient),
+}
+
+/// Samples a queue's depth every so often, by declaring it passively on a channel or asking the
+/// management API.
+///
+/// A passive declare is cheap, but closes the channel if the queue doesn't exist, so give it a
+/// channel of its own unless the queue is sure to be there.
+#[derive(Debug, Clone)]
+pub struct QueueDepthMonitor {
+    source: Source,
+    queue: String,
+    interval: Duration,
+}
+
+impl QueueDepthMonitor {
+    /// Samples every 5 seconds unless set otherwise.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+    pub fn new(channel: Channel, queue: impl Into<String>) -> Self {
+        QueueDepthMonitor {
+            source: Source::Channel(channel),
+            queue: queue.into(),
+            interval: Self::DEFAULT_INTERVAL,
+        }
+    }
+
+    /// Asks the management API instead, which doesn't need a channel, but lags the broker by its
+    /// statistics interval, 5 seconds by default.
+    #[cfg(feature = "mq-management")]
+    pub fn from_management(client: super::management::ManagementClient, queue: impl Into<String>) -> Self {
+        QueueDepthMonitor {
+            source: Source::Management(client),
+            queue: queue.into(),
+            interval: Self::DEFAULT_INTERVAL,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub async fn sample(&self) -> Result<DepthSample, MqError> {
+        let (messages, consumers) = match &self.source {
+            Source::Channel(channel) => {
+                let queue = channel
+                    .queue_declare(
+                        &self.queue,
+                        QueueDeclareOptions {
+                            passive: true,
+                            ..Default::default()
+                        },
+                        FieldTable::default(),
+                    )
+                    .await?;
+                (queue.message_count(), queue.consumer_count())
+            }
+            #[cfg(feature = "mq-management")]
+            Source::Management(client) => {
+                let queue = client
+                    .queue_info(&self.queue)
+                    .await?
+                    .ok_or_else(|| MqError::ManagementError(format!("no queue {:?}", self.queue)))?;
+                (queue.messages_ready, queue.consumers)
+            }
+        };
+
+        Ok(DepthSample {
+            queue: self.queue.clone(),
+            messages,
+            consumers,
+            sampled_at: Utc::now(),
+        })
+    }
+
+    /// A sample straight away, then one every interval. Samples that fail are yielded as errors,
+    /// without ending the stream.
+    pub fn samples(&self) -> ConsumerStream<Result<DepthSample, MqError>> {
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        Box::pin(futures::stream::unfold(
+            (self.clone(), interval),
+            |(monitor, mut interval)| async move {
+                interval.tick().await;
+                let sample = monitor.sample().await;
+                Some((sample, (monitor, interval)))
+            },
+        ))
+    }
+}
+
+/// Scales how many messages are processed at once between `min` and `max`, with a worker for
+/// every so many messages waiting. See
+/// [`Consumer::consume_autoscaled`](super::consumer::Consumer::consume_autoscaled).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Autoscale {
+    min: usize,
+    max: usize,
+    messages_per_worker: u32,
+}
+
+impl Autoscale {
+    /// A worker for every 10 messages waiting unless set otherwise. `min` is raised to at least 1.
+    pub fn new(min: usize, max: usize) -> Self {
+        let min = min.max(1);
+        Autoscale {
+            min,
+            max: max.max(min),
+            messages_per_worker: 10,
+        }
+    }
+
+    pub fn with_messages_per_worker(mut self, messages_per_worker: u32) -> Self {
+        self.messages_per_worker = messages_per_worker.max(1);
+        self
+    }
+
+    pub fn min(&self) -> usize {
+        self.min
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// How many messages to process at once with `sample`'s backlog.
+    pub fn concurrency(&self, sample: &DepthSample) -> usize {
+        let workers = sample.messages.div_ceil(self.messages_per_worker) as usize;
+        workers.clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(messages: u32) -> DepthSample {
+        DepthSample {
+            queue: "orders".into(),
+            messages,
+            consumers: 1,
+            sampled_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn scales_with_the_backlog() {
+        let autoscale = Autoscale::new(2, 8).with_messages_per_worker(5);
+
+        assert_eq!(autoscale.concurrency(&sample(0)), 2);
+        assert_eq!(autoscale.concurrency(&sample(11)), 3);
+        assert_eq!(autoscale.concurrency(&sample(40)), 8);
+        assert_eq!(autoscale.concurrency(&sample(10_000)), 8);
+    }
+
+    #[test]
+    fn keeps_limits_sensible() {
+        let autoscale = Autoscale::new(0, 0);
+        assert_eq!((autoscale.min(), autoscale.max()), (1, 1));
+        assert_eq!(autoscale.concurrency(&sample(100)), 1);
+    }
+}
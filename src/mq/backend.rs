@@ -0,0 +1,99 @@
+use std::future::{self, Future};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{
+    codec::Codec,
+    consumer::{Consumer, Processor},
+    producer::Producer,
+    testing::{MemoryConsumer, MemoryProducer},
+    Envelope, MqError,
+};
+
+/// Publishes envelopes to whichever broker is behind it: RabbitMQ, Kafka or an
+/// [`InMemoryBroker`](super::testing::InMemoryBroker). Code written against it can be handed any of them.
+pub trait Publisher {
+    async fn publish_envelope<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError>;
+}
+
+/// Consumes envelopes from whichever broker is behind it. See [`Publisher`].
+pub trait Subscriber {
+    async fn subscribe_until<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, shutdown: impl Future<Output = ()>) -> Result<(), MqError>;
+
+    async fn subscribe<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P) -> Result<(), MqError> {
+        self.subscribe_until(processor, future::pending()).await
+    }
+}
+
+impl<C: Codec> Publisher for Producer<'_, C> {
+    async fn publish_envelope<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+        self.publish(envelope, routing_key).await
+    }
+}
+
+impl<C: Codec> Subscriber for Consumer<'_, C> {
+    async fn subscribe_until<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, shutdown: impl Future<Output = ()>) -> Result<(), MqError> {
+        self.consume_until(processor, shutdown).await
+    }
+}
+
+impl Publisher for MemoryProducer<'_> {
+    async fn publish_envelope<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+        self.publish(envelope, routing_key).await
+    }
+}
+
+impl Subscriber for MemoryConsumer<'_> {
+    async fn subscribe_until<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, shutdown: impl Future<Output = ()>) -> Result<(), MqError> {
+        self.consume_until(processor, shutdown).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mq::{
+        consumer::{MessageContext, ProcessorError},
+        setup::{Binding, Exchange, ExchangeType, Queue, Topology, TopologyBuilder, TopologyOps},
+        testing::InMemoryBroker,
+        ChannelOps,
+    };
+
+    struct Collect(Vec<Value>);
+
+    impl Processor for Collect {
+        async fn process(&mut self, value: Value, _context: &MessageContext) -> Result<(), ProcessorError> {
+            self.0.push(value);
+            Ok(())
+        }
+    }
+
+    // written once, against any backend
+    async fn announce(publisher: &impl Publisher) -> Result<(), MqError> {
+        publisher.publish_envelope(Envelope::new(json!("hello")), Some("greetings")).await
+    }
+
+    #[tokio::test]
+    async fn backend_agnostic() {
+        let broker = InMemoryBroker::new();
+        let topology = Topology::builder()
+            .with_exchange(Exchange::new("events", ExchangeType::Direct, true))
+            .with_queue(Queue::new("greetings", vec![]))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "events",
+                target_queue_name: "greetings",
+                routing_key: Some("greetings"),
+            })
+            .build();
+        broker.apply_topology(topology).await.unwrap();
+
+        announce(&broker.clone().create_producer("events".into())).await.unwrap();
+
+        let consumer = broker.clone().create_consumer("test", "greetings".into());
+        let mut collect = Collect(vec![]);
+        consumer.subscribe_until(&mut collect, async {}).await.unwrap();
+        assert_eq!(collect.0, [json!("hello")]);
+    }
+}
@@ -0,0 +1,247 @@
+//! Publishing and subscribing to events by type, with the exchanges, queues and bindings between
+//! them declared by the bus rather than wired up by hand in every service.
+
+use std::future::{self, Future};
+
+use futures::{
+    future::{try_join_all, LocalBoxFuture, Shared},
+    FutureExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use uuid::Uuid;
+
+use super::{
+    backend::{Publisher, Subscriber},
+    consumer::Processor,
+    setup::{self, Binding, ExchangeType, QueueOptions, Topology},
+    ChannelOps, Envelope, Exchange, MqError, Queue,
+};
+
+/// Something that happened, published to its exchange for any service interested to subscribe to.
+///
+/// Events are routed by name, so subscribers get every event of the type. To let them narrow that
+/// down, add segments to the routing key, e.g. with a
+/// [`RoutingKey`](super::routing::RoutingKey): the pattern subscribers bind with matches any key
+/// starting with the name.
+pub trait Event: Serialize + DeserializeOwned + 'static {
+    /// The exchange events of this type are published to, declared as a durable topic exchange.
+    const EXCHANGE: &'static str;
+
+    /// Names the event, e.g. `order.created`.
+    const NAME: &'static str;
+
+    fn routing_key(&self) -> String {
+        Self::NAME.into()
+    }
+
+    /// The pattern subscribers' queues are bound with.
+    fn pattern() -> String {
+        format!("{}.#", Self::NAME)
+    }
+}
+
+type Shutdown = Shared<LocalBoxFuture<'static, ()>>;
+type Subscription<B> = Box<dyn FnOnce(B, Shutdown) -> LocalBoxFuture<'static, Result<(), MqError>>>;
+
+/// Publishes [`Event`]s, and hands those a service subscribes to to their handlers.
+///
+/// Each subscription gets a durable queue named `<service>.<event name>`, so a service's instances
+/// share its events between them, while every other service subscribing gets them too. Subscribe
+/// to each type once: a second handler would share the queue, and so the events, with the first.
+///
+/// ```no_run
+/// # use launchpad::mq::{bus::{Event, EventBus}, consumer::{MessageContext, Processor, ProcessorError}};
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Serialize, Deserialize)]
+/// struct OrderCreated {
+///     order_id: u64,
+/// }
+///
+/// impl Event for OrderCreated {
+///     const EXCHANGE: &'static str = "orders";
+///     const NAME: &'static str = "order.created";
+/// }
+///
+/// struct SendConfirmation;
+///
+/// impl Processor<OrderCreated> for SendConfirmation {
+///     async fn process(&mut self, order: OrderCreated, _context: &MessageContext) -> Result<(), ProcessorError> {
+///         Ok(())
+///     }
+/// }
+///
+/// # async fn run(channel: lapin::Channel) -> Result<(), launchpad::mq::MqError> {
+/// let mut bus = EventBus::new(channel, "notifications");
+/// bus.subscribe::<OrderCreated>(SendConfirmation);
+/// bus.run().await
+/// # }
+/// ```
+pub struct EventBus<B> {
+    broker: B,
+    service: String,
+    topology: Topology<String>,
+    subscriptions: Vec<Subscription<B>>,
+}
+
+impl<B> EventBus<B>
+where
+    B: ChannelOps + setup::TopologyOps + Clone + 'static,
+    for<'a> B::Producer<'a>: Publisher,
+    for<'a> B::Consumer<'a>: Subscriber,
+{
+    /// A bus on `broker`, e.g. a [`Channel`](lapin::Channel), subscribing as `service`.
+    pub fn new(broker: B, service: impl Into<String>) -> Self {
+        EventBus {
+            broker,
+            service: service.into(),
+            topology: Topology::new(),
+            subscriptions: Vec::new(),
+        }
+    }
+
+    /// Hands every `E` to `handler`, once [`EventBus::run`] is.
+    pub fn subscribe<E: Event>(&mut self, handler: impl Processor<E> + 'static) -> &mut Self {
+        let queue = self.queue_name::<E>();
+        if !self.topology.exchanges.iter().any(|e| e.name == E::EXCHANGE) {
+            self.topology
+                .exchanges
+                .push(setup::Exchange::new(E::EXCHANGE.into(), ExchangeType::Topic, true));
+        }
+        self.topology
+            .queues
+            .push(setup::Queue::new(queue.clone(), vec![QueueOptions::Persistence(true)]));
+        self.topology.bindings.push(Binding::ToQueue {
+            src_exchange_name: E::EXCHANGE.into(),
+            target_queue_name: queue.clone(),
+            routing_key: Some(E::pattern()),
+        });
+
+        let mut handler = handler;
+        self.subscriptions.push(Box::new(move |broker, shutdown| {
+            async move {
+                let consumer = broker.create_consumer(&queue, Queue::new(&queue));
+                consumer.subscribe_until::<E, _>(&mut handler, shutdown).await
+            }
+            .boxed_local()
+        }));
+        self
+    }
+
+    /// The queue the service's `E`s wait in.
+    pub fn queue_name<E: Event>(&self) -> String {
+        format!("{}.{}", self.service, E::NAME)
+    }
+
+    /// Declares the exchanges, queues and bindings subscriptions so far need. Done by
+    /// [`EventBus::run`], but needed beforehand for events published meanwhile to be kept.
+    pub async fn declare(&self) -> Result<(), MqError> {
+        self.broker.apply_topology(self.topology.clone()).await
+    }
+
+    pub async fn publish<E: Event>(&self, event: E) -> Result<(), MqError> {
+        let routing_key = event.routing_key();
+        let envelope = Envelope::new(event).with_message_id(Uuid::new_v4().to_string());
+        self.broker
+            .clone()
+            .create_producer(Exchange::new(E::EXCHANGE))
+            .publish_envelope(envelope, Some(&routing_key))
+            .await
+    }
+
+    /// Declares the subscriptions' topology, then consumes their queues, all at once, until one of
+    /// them fails.
+    pub async fn run(self) -> Result<(), MqError> {
+        self.run_until(future::pending()).await
+    }
+
+    /// Like [`EventBus::run`], until `shutdown` completes, when every subscription finishes the
+    /// event it's handling.
+    pub async fn run_until(self, shutdown: impl Future<Output = ()> + 'static) -> Result<(), MqError> {
+        self.declare().await?;
+        let shutdown = shutdown.boxed_local().shared();
+        let subscriptions = self
+            .subscriptions
+            .into_iter()
+            .map(|subscription| subscription(self.broker.clone(), shutdown.clone()));
+        try_join_all(subscriptions).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::mq::{
+        consumer::{MessageContext, ProcessorError},
+        testing::InMemoryBroker,
+    };
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct OrderCreated {
+        order_id: u64,
+        region: String,
+    }
+
+    impl Event for OrderCreated {
+        const EXCHANGE: &'static str = "orders";
+        const NAME: &'static str = "order.created";
+
+        fn routing_key(&self) -> String {
+            format!("{}.{}", Self::NAME, self.region)
+        }
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct OrderCancelled {
+        order_id: u64,
+    }
+
+    impl Event for OrderCancelled {
+        const EXCHANGE: &'static str = "orders";
+        const NAME: &'static str = "order.cancelled";
+    }
+
+    struct Record(Rc<RefCell<Vec<String>>>);
+
+    impl Processor<OrderCreated> for Record {
+        async fn process(&mut self, order: OrderCreated, context: &MessageContext) -> Result<(), ProcessorError> {
+            self.0.borrow_mut().push(format!("created {} via {}", order.order_id, context.routing_key));
+            Ok(())
+        }
+    }
+
+    impl Processor<OrderCancelled> for Record {
+        async fn process(&mut self, order: OrderCancelled, _context: &MessageContext) -> Result<(), ProcessorError> {
+            self.0.borrow_mut().push(format!("cancelled {}", order.order_id));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_by_type() {
+        let broker = InMemoryBroker::new();
+        let handled = Rc::new(RefCell::new(vec![]));
+        let mut bus = EventBus::new(broker.clone(), "billing");
+        bus.subscribe::<OrderCreated>(Record(handled.clone()))
+            .subscribe::<OrderCancelled>(Record(handled.clone()));
+        bus.declare().await.unwrap();
+
+        let created = OrderCreated {
+            order_id: 1,
+            region: "eu".into(),
+        };
+        bus.publish(created).await.unwrap();
+        bus.publish(OrderCancelled { order_id: 1 }).await.unwrap();
+        assert_eq!(broker.queue_len("billing.order.created"), 1);
+        assert_eq!(broker.queue_len("billing.order.cancelled"), 1);
+
+        bus.run_until(async {}).await.unwrap();
+        let mut handled = handled.take();
+        handled.sort();
+        assert_eq!(handled, ["cancelled 1", "created 1 via order.created.eu"]);
+    }
+}
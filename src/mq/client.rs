@@ -0,0 +1,89 @@
+//! One place to get producers and consumers from, each on a connection of their own.
+
+use lapin::Channel;
+
+use super::{
+    connection::MqConnectionManager,
+    consumer::Consumer,
+    pool::ChannelPool,
+    producer::Producer,
+    setup::Topology,
+    CreateChannelConfig, Exchange, MqError, Queue,
+};
+
+/// How many channels producers share by default. See [`MqClient::with_max_publish_channels`].
+pub const DEFAULT_PUBLISH_CHANNELS: usize = 8;
+
+/// Keeps separate connections for publishing and consuming, as RabbitMQ recommends. When the
+/// broker applies flow control, on a memory or disk alarm, it blocks the connections publishing;
+/// consumers on a connection of their own keep draining queues and acking meanwhile, rather than
+/// stalling along with the publishers.
+///
+/// Both connections are managed by an [`MqConnectionManager`], so they're made on first use and
+/// replaced when lost. They're named `<name> (publish)` and `<name> (consume)` after the config's
+/// connection name, or just `publish` and `consume`, to tell them apart in the management UI.
+/// Clones share the connections.
+#[derive(Clone)]
+pub struct MqClient {
+    publishing: MqConnectionManager,
+    consuming: MqConnectionManager,
+    publish_channels: ChannelPool,
+    max_publish_channels: usize,
+}
+
+impl MqClient {
+    pub fn new<C: CreateChannelConfig>(config: C) -> Result<Self, MqError> {
+        let config = config.connection_config()?;
+        Ok(MqClient::from_managers(
+            MqConnectionManager::new(config.clone().for_role("publish"))?,
+            MqConnectionManager::new(config.for_role("consume"))?,
+            DEFAULT_PUBLISH_CHANNELS,
+        ))
+    }
+
+    fn from_managers(publishing: MqConnectionManager, consuming: MqConnectionManager, max_publish_channels: usize) -> Self {
+        MqClient {
+            publish_channels: ChannelPool::new(publishing.clone(), max_publish_channels),
+            publishing,
+            consuming,
+            max_publish_channels,
+        }
+    }
+
+    /// Applies `topology` whenever either connection is made.
+    pub fn with_topology<Name: Into<String>>(self, topology: Topology<Name>) -> Self {
+        let topology = topology.into_owned();
+        MqClient::from_managers(
+            self.publishing.with_topology(topology.clone()),
+            self.consuming.with_topology(topology),
+            self.max_publish_channels,
+        )
+    }
+
+    /// How many producers may publish at once, each on a channel of its own. Defaults to
+    /// [`DEFAULT_PUBLISH_CHANNELS`].
+    pub fn with_max_publish_channels(self, max_publish_channels: usize) -> Self {
+        MqClient::from_managers(self.publishing, self.consuming, max_publish_channels)
+    }
+
+    /// A producer publishing on the publishing connection, with a channel from a pool shared by
+    /// every producer from the client.
+    pub fn producer<'a>(&self, exchange: Exchange<'a>) -> Producer<'a> {
+        Producer::pooled(self.publish_channels.clone(), exchange)
+    }
+
+    /// A consumer with a channel of its own on the consuming connection.
+    pub async fn consumer<'a>(&self, consumer_tag: &'a str, queue: Queue<'a>) -> Result<Consumer<'a>, MqError> {
+        Ok(Consumer::new(self.consuming.channel().await?, consumer_tag, queue))
+    }
+
+    /// A new channel on the publishing connection.
+    pub async fn publish_channel(&self) -> Result<Channel, MqError> {
+        self.publishing.channel().await
+    }
+
+    /// A new channel on the consuming connection.
+    pub async fn consume_channel(&self) -> Result<Channel, MqError> {
+        self.consuming.channel().await
+    }
+}
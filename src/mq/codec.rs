@@ -0,0 +1,143 @@
+use lapin::BasicProperties;
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{compression, MqError};
+
+/// How messages are written to and read from the wire.
+///
+/// Producers record the codec's content type on each message, and consumers reject messages whose
+/// content type they can't decode. Messages without one are assumed to be in the consumer's codec.
+pub trait Codec: Clone + Send + Sync + 'static {
+    /// The MIME type messages are published with.
+    fn content_type(&self) -> &'static str;
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, MqError>;
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, MqError>;
+
+    /// Checks the content type a message was delivered with, then decompresses and decodes it.
+    fn decode_delivery<T: DeserializeOwned>(&self, properties: &BasicProperties, data: &[u8]) -> Result<T, MqError> {
+        if let Some(content_type) = properties.content_type() {
+            if content_type.as_str() != self.content_type() {
+                return Err(MqError::UnexpectedContentType(
+                    self.content_type().into(),
+                    content_type.to_string(),
+                ));
+            }
+        }
+        let data = compression::decompress(properties.content_encoding().as_ref().map(|e| e.as_str()), data)?;
+        self.decode(&data)
+    }
+}
+
+/// The default codec.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Json;
+
+impl Codec for Json {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, MqError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, MqError> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// MessagePack, with structs written as maps so they decode the same as JSON would.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePack;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePack {
+    fn content_type(&self) -> &'static str {
+        "application/msgpack"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, MqError> {
+        rmp_serde::to_vec_named(value).map_err(|e| MqError::CodecError(e.to_string()))
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, MqError> {
+        rmp_serde::from_slice(data).map_err(|e| MqError::CodecError(e.to_string()))
+    }
+}
+
+#[cfg(feature = "cbor")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cbor;
+
+#[cfg(feature = "cbor")]
+impl Codec for Cbor {
+    fn content_type(&self) -> &'static str {
+        "application/cbor"
+    }
+
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, MqError> {
+        let mut data = Vec::new();
+        ciborium::into_writer(value, &mut data).map_err(|e| MqError::CodecError(e.to_string()))?;
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> Result<T, MqError> {
+        ciborium::from_reader(data).map_err(|e| MqError::CodecError(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::mq::Envelope;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: f64,
+    }
+
+    fn round_trip<C: Codec>(codec: C) {
+        let reading = Reading {
+            sensor: "t1".into(),
+            value: 21.5,
+        };
+        let data = codec.encode(&Envelope::new(json!(reading))).unwrap();
+        let properties = BasicProperties::default().with_content_type(codec.content_type().into());
+
+        let envelope: Envelope<Value> = codec.decode_delivery(&properties, &data).unwrap();
+        assert_eq!(serde_json::from_value::<Reading>(envelope.message).unwrap(), reading);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(Json);
+    }
+
+    #[cfg(feature = "msgpack")]
+    #[test]
+    fn msgpack_round_trip() {
+        round_trip(MessagePack);
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trip() {
+        round_trip(Cbor);
+    }
+
+    #[test]
+    fn rejects_other_content_types() {
+        let properties = BasicProperties::default().with_content_type("application/msgpack".into());
+        let result = Json.decode_delivery::<Value>(&properties, b"{}");
+
+        assert!(matches!(result, Err(MqError::UnexpectedContentType(..))));
+        assert!(Json.decode_delivery::<Value>(&BasicProperties::default(), b"{}").is_ok());
+    }
+}
@@ -0,0 +1,144 @@
+use std::borrow::Cow;
+
+use super::MqError;
+
+/// Compresses payloads larger than `threshold` bytes before they're published, recording the
+/// algorithm as the message's content encoding. Consumers decompress whatever encoding a message
+/// arrives with, whether or not they compress themselves.
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    pub encoding: Encoding,
+    pub threshold: usize,
+}
+
+impl Compression {
+    pub fn new(encoding: Encoding, threshold: usize) -> Self {
+        Compression { encoding, threshold }
+    }
+
+    /// The payload, compressed if it's over the threshold, along with the encoding used.
+    pub(crate) fn compress(&self, data: Vec<u8>) -> Result<(Vec<u8>, Option<Encoding>), MqError> {
+        if data.len() <= self.threshold {
+            return Ok((data, None));
+        }
+        Ok((self.encoding.compress(&data)?, Some(self.encoding)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+impl Encoding {
+    /// The `content_encoding` messages are published with.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => "gzip",
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name {
+            #[cfg(feature = "gzip")]
+            "gzip" => Some(Encoding::Gzip),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, MqError> {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => {
+                use std::io::Write;
+
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).map_err(compression_error)?;
+                encoder.finish().map_err(compression_error)
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::encode_all(data, 0).map_err(compression_error),
+        }
+    }
+
+    #[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, MqError> {
+        match *self {
+            #[cfg(feature = "gzip")]
+            Encoding::Gzip => {
+                use std::io::Read;
+
+                let mut decoded = Vec::new();
+                flate2::read::GzDecoder::new(data)
+                    .read_to_end(&mut decoded)
+                    .map_err(compression_error)?;
+                Ok(decoded)
+            }
+            #[cfg(feature = "zstd")]
+            Encoding::Zstd => zstd::decode_all(data).map_err(compression_error),
+        }
+    }
+}
+
+/// Decompresses a payload delivered with `content_encoding`. Messages in an encoding this build
+/// doesn't support fail rather than being handed to the codec as is.
+pub(crate) fn decompress<'d>(content_encoding: Option<&str>, data: &'d [u8]) -> Result<Cow<'d, [u8]>, MqError> {
+    match content_encoding {
+        None | Some("") | Some("identity") => Ok(Cow::Borrowed(data)),
+        Some(name) => match Encoding::from_name(name) {
+            Some(encoding) => Ok(Cow::Owned(encoding.decompress(data)?)),
+            None => Err(MqError::CodecError(format!("unsupported content encoding {name:?}"))),
+        },
+    }
+}
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn compression_error(e: std::io::Error) -> MqError {
+    MqError::CodecError(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    fn round_trip(encoding: Encoding) {
+        let compression = Compression::new(encoding, 64);
+        let payload = "a large and very repetitive document ".repeat(100).into_bytes();
+
+        let (compressed, used) = compression.compress(payload.clone()).unwrap();
+        assert_eq!(used, Some(encoding));
+        assert!(compressed.len() < payload.len());
+        assert_eq!(decompress(Some(encoding.name()), &compressed).unwrap(), payload);
+
+        let (small, used) = compression.compress(b"{}".to_vec()).unwrap();
+        assert_eq!((small.as_slice(), used), (&b"{}"[..], None));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn gzip_round_trip() {
+        round_trip(Encoding::Gzip);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        round_trip(Encoding::Zstd);
+    }
+
+    #[test]
+    fn unsupported_encoding() {
+        assert!(decompress(Some("br"), b"data").is_err());
+        assert_eq!(decompress(None, b"data").unwrap(), &b"data"[..]);
+    }
+}
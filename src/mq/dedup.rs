@@ -0,0 +1,216 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use tracing::debug;
+
+use super::{
+    consumer::{MessageContext, Processor, ProcessorError},
+    Envelope,
+};
+
+/// Remembers the ids of messages that have been processed: in memory with [`MemoryDedupStore`] or
+/// a moka cache, or in Postgres with `PgDedupStore`. Other shared stores, e.g. Redis, implement it
+/// themselves.
+pub trait DedupStore {
+    async fn contains(&self, message_id: &str) -> Result<bool, ProcessorError>;
+    async fn insert(&self, message_id: &str) -> Result<(), ProcessorError>;
+}
+
+impl<S: DedupStore> DedupStore for Arc<S> {
+    async fn contains(&self, message_id: &str) -> Result<bool, ProcessorError> {
+        S::contains(self, message_id).await
+    }
+
+    async fn insert(&self, message_id: &str) -> Result<(), ProcessorError> {
+        S::insert(self, message_id).await
+    }
+}
+
+/// Skips messages whose `message_id` has already been processed, acking them without calling the
+/// inner processor. Ids are only recorded once processing succeeds, so failed messages are still
+/// retried. Messages without an id are always processed.
+///
+/// Duplicates delivered at the same time to concurrent processors can both get through; the guard
+/// is for redeliveries, e.g. after a consumer dies before its ack reaches the broker.
+pub struct DeduplicatingProcessor<P, S> {
+    processor: P,
+    store: S,
+}
+
+impl<P, S> DeduplicatingProcessor<P, S> {
+    pub fn new(processor: P, store: S) -> Self {
+        DeduplicatingProcessor { processor, store }
+    }
+}
+
+impl<M, P: Processor<M>, S: DedupStore> Processor<M> for DeduplicatingProcessor<P, S> {
+    async fn process(&mut self, message: M, context: &MessageContext) -> Result<(), ProcessorError> {
+        self.processor.process(message, context).await
+    }
+
+    async fn process_envelope(&mut self, envelope: Envelope<M>, context: &MessageContext) -> Result<(), ProcessorError> {
+        let Some(message_id) = envelope.message_id().map(str::to_string) else {
+            return self.processor.process_envelope(envelope, context).await;
+        };
+
+        if self.store.contains(&message_id).await? {
+            debug!("skipping duplicate message {message_id}");
+            return Ok(());
+        }
+
+        self.processor.process_envelope(envelope, context).await?;
+        self.store.insert(&message_id).await
+    }
+}
+
+/// Remembers `capacity` message ids in memory, forgetting the least recently seen first, so a
+/// message redelivered over and over stays remembered.
+#[derive(Debug)]
+pub struct MemoryDedupStore {
+    capacity: usize,
+    seen: Mutex<(HashSet<String>, VecDeque<String>)>,
+}
+
+impl MemoryDedupStore {
+    pub fn new(capacity: usize) -> Self {
+        MemoryDedupStore {
+            capacity,
+            seen: Mutex::new((HashSet::with_capacity(capacity), VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    fn seen(&self) -> std::sync::MutexGuard<'_, (HashSet<String>, VecDeque<String>)> {
+        self.seen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl DedupStore for MemoryDedupStore {
+    async fn contains(&self, message_id: &str) -> Result<bool, ProcessorError> {
+        let (ids, order) = &mut *self.seen();
+        if !ids.contains(message_id) {
+            return Ok(false);
+        }
+        // duplicates are rare, so moving a hit to the back can afford the scan
+        if let Some(seen) = order.iter().position(|id| id == message_id).and_then(|i| order.remove(i)) {
+            order.push_back(seen);
+        }
+        Ok(true)
+    }
+
+    async fn insert(&self, message_id: &str) -> Result<(), ProcessorError> {
+        let (ids, order) = &mut *self.seen();
+        if self.capacity == 0 || !ids.insert(message_id.to_string()) {
+            return Ok(());
+        }
+        order.push_back(message_id.to_string());
+        if order.len() > self.capacity {
+            if let Some(oldest) = order.pop_front() {
+                ids.remove(&oldest);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "moka")]
+impl DedupStore for moka::future::Cache<String, ()> {
+    async fn contains(&self, message_id: &str) -> Result<bool, ProcessorError> {
+        Ok(moka::future::Cache::contains_key(self, message_id))
+    }
+
+    async fn insert(&self, message_id: &str) -> Result<(), ProcessorError> {
+        moka::future::Cache::insert(self, message_id.to_string(), ()).await;
+        Ok(())
+    }
+}
+
+/// Remembers message ids in a Postgres table, so they're shared between consumers and survive
+/// restarts. Old ids are left for the application to clear out by `processed_at`.
+#[cfg(feature = "pgsqlx")]
+#[derive(Debug, Clone)]
+pub struct PgDedupStore {
+    pool: sqlx::PgPool,
+    table: String,
+}
+
+#[cfg(feature = "pgsqlx")]
+impl PgDedupStore {
+    pub fn new(pool: sqlx::PgPool, table: impl Into<String>) -> Self {
+        PgDedupStore {
+            pool,
+            table: table.into(),
+        }
+    }
+
+    pub async fn create_table(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "create table if not exists {} (message_id text primary key, processed_at timestamptz not null default now())",
+            self.table
+        ))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "pgsqlx")]
+impl DedupStore for PgDedupStore {
+    async fn contains(&self, message_id: &str) -> Result<bool, ProcessorError> {
+        sqlx::query_scalar(&format!("select exists(select 1 from {} where message_id = $1)", self.table))
+            .bind(message_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|e| ProcessorError::TemporaryError(e.to_string()))
+    }
+
+    async fn insert(&self, message_id: &str) -> Result<(), ProcessorError> {
+        sqlx::query(&format!("insert into {} (message_id) values ($1) on conflict do nothing", self.table))
+            .bind(message_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| ProcessorError::TemporaryError(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counting(usize);
+
+    impl Processor for Counting {
+        async fn process(&mut self, _value: Value, _context: &MessageContext) -> Result<(), ProcessorError> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_duplicates() {
+        let mut processor = DeduplicatingProcessor::new(Counting::default(), MemoryDedupStore::new(2));
+        let message = |id: &str| Envelope::new(json!({})).with_message_id(id);
+        let context = MessageContext::default();
+
+        processor.process_envelope(message("a"), &context).await.unwrap();
+        processor.process_envelope(message("b"), &context).await.unwrap();
+        processor.process_envelope(message("a"), &context).await.unwrap();
+        assert_eq!(processor.processor.0, 2);
+
+        // "b" is forgotten once "c" takes its place, having been seen less recently than "a"
+        processor.process_envelope(message("c"), &context).await.unwrap();
+        processor.process_envelope(message("a"), &context).await.unwrap();
+        assert_eq!(processor.processor.0, 3);
+        processor.process_envelope(message("b"), &context).await.unwrap();
+        assert_eq!(processor.processor.0, 4);
+
+        processor.process_envelope(Envelope::new(json!({})), &context).await.unwrap();
+        processor.process_envelope(Envelope::new(json!({})), &context).await.unwrap();
+        assert_eq!(processor.processor.0, 6);
+    }
+}
@@ -0,0 +1,223 @@
+//! Publishing messages to be delivered later, e.g. to retry something in ten minutes, or send a
+//! reminder tomorrow.
+
+use std::{collections::HashSet, sync::Mutex, time::Duration};
+
+use lapin::{
+    options::{ExchangeBindOptions, ExchangeDeclareOptions},
+    types::{AMQPValue, FieldTable},
+    Channel, ExchangeKind,
+};
+
+use super::{
+    interceptor::OutgoingMessage,
+    setup::{self, Binding, ExchangeType, QueueOptions, Topology, TopologyBuilder, TopologyOps},
+    MqError,
+};
+
+/// Header the delayed message exchange plugin reads each message's delay from, in milliseconds.
+pub const DELAY_HEADER: &str = "x-delay";
+
+/// The exchange type the delayed message exchange plugin adds.
+pub const DELAYED_MESSAGE_EXCHANGE: &str = "x-delayed-message";
+
+/// How [`Producer::publish_delayed`](super::producer::Producer::publish_delayed) holds messages
+/// back, set with
+/// [`Producer::with_delayed_delivery`](super::producer::Producer::with_delayed_delivery). What it
+/// goes through is declared the first time it's needed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DelayedDelivery {
+    /// With RabbitMQ's delayed message exchange plugin: messages wait in `<exchange>.delayed`, an
+    /// exchange of the plugin's type bound to the producer's exchange. Takes any delay, but the
+    /// plugin can't route messages until they're due, so the broker returns them all to producers
+    /// publishing with `mandatory`.
+    Plugin,
+
+    /// Without the plugin: each delay gets a queue, `<exchange>.delay.<milliseconds>`, whose
+    /// messages expire after the delay and are dead lettered to the producer's exchange with the
+    /// routing key they were published with. As that's a queue per distinct delay, stick to a
+    /// handful of them.
+    #[default]
+    DeadLetter,
+}
+
+impl DelayedDelivery {
+    /// The plugin if the broker has it, otherwise dead lettering.
+    #[cfg(feature = "mq-management")]
+    pub async fn detect(client: &super::management::ManagementClient) -> Result<Self, MqError> {
+        #[derive(serde::Deserialize)]
+        struct Overview {
+            #[serde(default)]
+            exchange_types: Vec<ExchangeTypeInfo>,
+        }
+        #[derive(serde::Deserialize)]
+        struct ExchangeTypeInfo {
+            name: String,
+        }
+
+        let overview: Option<Overview> = client.get(&["overview"]).await?;
+        let plugin = overview
+            .map(|o| o.exchange_types.iter().any(|t| t.name == DELAYED_MESSAGE_EXCHANGE))
+            .unwrap_or_default();
+        Ok(if plugin {
+            DelayedDelivery::Plugin
+        } else {
+            DelayedDelivery::DeadLetter
+        })
+    }
+
+    /// Where messages for `exchange` wait out `delay`.
+    pub fn exchange_name(&self, exchange: &str, delay: Duration) -> String {
+        // the default exchange can't be part of a name starting with "amq."
+        let exchange = if exchange.is_empty() { "default" } else { exchange };
+        match self {
+            DelayedDelivery::Plugin => format!("{exchange}.delayed"),
+            DelayedDelivery::DeadLetter => format!("{exchange}.delay.{}", delay.as_millis()),
+        }
+    }
+
+    /// The queue and exchange messages for `exchange` wait out `delay` in, without the plugin.
+    pub fn dead_letter_topology(exchange: &str, delay: Duration) -> Result<Topology<String>, MqError> {
+        let name = DelayedDelivery::DeadLetter.exchange_name(exchange, delay);
+        Ok(Topology::builder()
+            .with_queue(setup::Queue::new(
+                name.clone(),
+                vec![
+                    QueueOptions::Persistence(true),
+                    QueueOptions::MessageTTL(millis(delay)?),
+                    QueueOptions::DeadLetterExchange(exchange.into()),
+                ],
+            ))
+            .with_exchange(setup::Exchange::new(name.clone(), ExchangeType::Topic, true))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: name.clone(),
+                target_queue_name: name,
+                routing_key: Some("#".into()),
+            })
+            .build())
+    }
+
+    /// `message`, readdressed to wait out `delay` before going to its exchange.
+    pub(crate) fn delayed(&self, message: &OutgoingMessage, delay: Duration) -> Result<OutgoingMessage, MqError> {
+        let mut delayed = message.clone();
+        delayed.exchange = self.exchange_name(&message.exchange, delay);
+        if *self == DelayedDelivery::Plugin {
+            let mut headers = delayed.properties.headers().clone().unwrap_or_default();
+            headers.insert(DELAY_HEADER.into(), AMQPValue::LongLongInt(millis(delay)?.into()));
+            delayed.properties = delayed.properties.with_headers(headers);
+        }
+        Ok(delayed)
+    }
+
+    /// Declares what messages for `exchange` wait out `delay` in, unless `declared` says it's
+    /// already been done.
+    pub(crate) async fn declare(
+        &self,
+        channel: &Channel,
+        declared: &Mutex<HashSet<String>>,
+        exchange: &str,
+        delay: Duration,
+    ) -> Result<(), MqError> {
+        let name = self.exchange_name(exchange, delay);
+        if declared.lock().unwrap_or_else(|e| e.into_inner()).contains(&name) {
+            return Ok(());
+        }
+
+        match self {
+            DelayedDelivery::Plugin => {
+                if exchange.is_empty() {
+                    return Err(MqError::ConfigurationError(
+                        "the default exchange can't take delayed messages from the plugin".into(),
+                    ));
+                }
+                let mut arguments = FieldTable::default();
+                arguments.insert("x-delayed-type".into(), AMQPValue::LongString("topic".into()));
+                channel
+                    .exchange_declare(
+                        &name,
+                        ExchangeKind::Custom(DELAYED_MESSAGE_EXCHANGE.into()),
+                        ExchangeDeclareOptions {
+                            durable: true,
+                            ..Default::default()
+                        },
+                        arguments,
+                    )
+                    .await?;
+                channel
+                    .exchange_bind(exchange, &name, "#", ExchangeBindOptions::default(), FieldTable::default())
+                    .await?;
+            }
+            DelayedDelivery::DeadLetter => {
+                channel
+                    .apply_topology(DelayedDelivery::dead_letter_topology(exchange, delay)?)
+                    .await?
+            }
+        }
+
+        declared.lock().unwrap_or_else(|e| e.into_inner()).insert(name);
+        Ok(())
+    }
+}
+
+fn millis(delay: Duration) -> Result<u32, MqError> {
+    u32::try_from(delay.as_millis())
+        .map_err(|_| MqError::ConfigurationError(format!("delays can't be longer than {}ms", u32::MAX)))
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin::BasicProperties;
+
+    use super::*;
+
+    fn message() -> OutgoingMessage {
+        OutgoingMessage {
+            exchange: "reminders".into(),
+            routing_key: "signup.incomplete".into(),
+            payload: vec![],
+            properties: BasicProperties::default(),
+        }
+    }
+
+    #[test]
+    fn delays_with_the_plugin() {
+        let delayed = DelayedDelivery::Plugin
+            .delayed(&message(), Duration::from_secs(90))
+            .unwrap();
+
+        assert_eq!(delayed.exchange, "reminders.delayed");
+        assert_eq!(delayed.routing_key, "signup.incomplete");
+        assert_eq!(
+            delayed.properties.headers().as_ref().unwrap().inner().get(DELAY_HEADER),
+            Some(&AMQPValue::LongLongInt(90_000))
+        );
+    }
+
+    #[test]
+    fn delays_by_dead_lettering() {
+        let delay = Duration::from_secs(600);
+        let delayed = DelayedDelivery::DeadLetter.delayed(&message(), delay).unwrap();
+        assert_eq!(delayed.exchange, "reminders.delay.600000");
+        assert_eq!(delayed.properties.headers(), &None);
+
+        let topology = DelayedDelivery::dead_letter_topology("reminders", delay).unwrap();
+        let arguments = topology.queues[0].arguments();
+        assert_eq!(
+            arguments.inner().get("x-message-ttl"),
+            Some(&AMQPValue::LongUInt(600_000))
+        );
+        assert_eq!(
+            arguments.inner().get("x-dead-letter-exchange"),
+            Some(&AMQPValue::ShortString("reminders".into()))
+        );
+        // without a dead letter routing key, messages keep the one they were published with
+        assert_eq!(arguments.inner().get("x-dead-letter-routing-key"), None);
+    }
+
+    #[test]
+    fn refuses_delays_too_long_for_the_broker() {
+        let delay = Duration::from_millis(u32::MAX as u64 + 1);
+        assert!(DelayedDelivery::Plugin.delayed(&message(), delay).is_err());
+        assert!(DelayedDelivery::dead_letter_topology("reminders", delay).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Encrypts message payloads with AES-256-GCM, so sensitive messages are never readable on the broker.
+//!
+//! An [`Encryptor`] added to a producer encrypts each payload after compression and marks its content
+//! type with [`ENCRYPTED_SUFFIX`]; consumers set up with
+//! [`Consumer::with_decryption`](super::consumer::Consumer::with_decryption) decrypt messages carrying
+//! the suffix before decoding them, and take others as they are. Add a
+//! [`Signer`](super::signing::Signer) after the encryptor if both are wanted.
+
+use std::{borrow::Cow, collections::HashMap, env, sync::Arc};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng},
+    Aes256Gcm, Key, KeyInit, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lapin::{types::AMQPValue, BasicProperties};
+
+use super::{
+    interceptor::{OutgoingMessage, PublishInterceptor},
+    MqError,
+};
+
+/// Appended to the content type of encrypted messages, e.g. `application/json+aes256gcm`.
+pub const ENCRYPTED_SUFFIX: &str = "+aes256gcm";
+/// The header naming the key a message was encrypted with.
+pub const KEY_ID_HEADER: &str = "x-encryption-key";
+
+const NONCE_LEN: usize = 12;
+
+/// Supplies encryption keys by id, so keys can be rotated while messages encrypted with the old
+/// one are still queued. Keys held elsewhere, e.g. data keys from a KMS, should be fetched up front
+/// and cached, as lookups happen on every message.
+pub trait KeyProvider: Send + Sync {
+    /// The id of the key new messages are encrypted with.
+    fn current_key_id(&self) -> &str;
+
+    fn key(&self, key_id: &str) -> Option<[u8; 32]>;
+}
+
+impl<K: KeyProvider + ?Sized> KeyProvider for Arc<K> {
+    fn current_key_id(&self) -> &str {
+        K::current_key_id(self)
+    }
+
+    fn key(&self, key_id: &str) -> Option<[u8; 32]> {
+        K::key(self, key_id)
+    }
+}
+
+/// A single key.
+#[derive(Clone)]
+pub struct StaticKey {
+    id: String,
+    key: [u8; 32],
+}
+
+impl StaticKey {
+    pub fn new(id: impl Into<String>, key: [u8; 32]) -> Self {
+        StaticKey { id: id.into(), key }
+    }
+
+    /// Reads a base64 encoded 32 byte key from the environment variable `var`.
+    pub fn from_env(id: impl Into<String>, var: &str) -> Result<Self, MqError> {
+        let encoded = env::var(var).map_err(|_| MqError::ConfigurationError(format!("{var} must be set")))?;
+        let key = STANDARD
+            .decode(encoded.trim())
+            .ok()
+            .and_then(|key| <[u8; 32]>::try_from(key).ok())
+            .ok_or_else(|| MqError::ConfigurationError(format!("{var} must be a base64 encoded 32 byte key")))?;
+        Ok(StaticKey::new(id, key))
+    }
+}
+
+impl KeyProvider for StaticKey {
+    fn current_key_id(&self) -> &str {
+        &self.id
+    }
+
+    fn key(&self, key_id: &str) -> Option<[u8; 32]> {
+        (key_id == self.id).then_some(self.key)
+    }
+}
+
+/// Several keys, encrypting with the current one and decrypting with any of them.
+#[derive(Clone)]
+pub struct KeyRing {
+    current: String,
+    keys: HashMap<String, [u8; 32]>,
+}
+
+impl KeyRing {
+    pub fn new(current: StaticKey) -> Self {
+        KeyRing {
+            current: current.id.clone(),
+            keys: HashMap::from([(current.id, current.key)]),
+        }
+    }
+
+    /// Keeps `key` around for decrypting messages encrypted before it was retired.
+    pub fn with_retired(mut self, key: StaticKey) -> Self {
+        self.keys.entry(key.id).or_insert(key.key);
+        self
+    }
+}
+
+impl KeyProvider for KeyRing {
+    fn current_key_id(&self) -> &str {
+        &self.current
+    }
+
+    fn key(&self, key_id: &str) -> Option<[u8; 32]> {
+        self.keys.get(key_id).copied()
+    }
+}
+
+/// Encrypts every message a producer publishes with the provider's current key.
+pub struct Encryptor<K> {
+    keys: K,
+}
+
+impl<K: KeyProvider> Encryptor<K> {
+    pub fn new(keys: K) -> Self {
+        Encryptor { keys }
+    }
+}
+
+impl<K: KeyProvider> PublishInterceptor for Encryptor<K> {
+    fn intercept(&self, message: &mut OutgoingMessage) -> Result<(), MqError> {
+        let key_id = self.keys.current_key_id();
+        let key = self
+            .keys
+            .key(key_id)
+            .ok_or_else(|| MqError::ConfigurationError(format!("no encryption key {key_id:?}")))?;
+
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+            .encrypt(&nonce, message.payload.as_slice())
+            .map_err(|_| MqError::CodecError("encrypting payload failed".into()))?;
+        message.payload = [nonce.as_slice(), &ciphertext].concat();
+
+        let content_type = message.properties.content_type().as_ref().map(|c| c.to_string()).unwrap_or_default();
+        let mut headers = message.properties.headers().clone().unwrap_or_default();
+        headers.insert(KEY_ID_HEADER.into(), AMQPValue::LongString(key_id.into()));
+        message.properties = message
+            .properties
+            .clone()
+            .with_content_type(format!("{content_type}{ENCRYPTED_SUFFIX}").into())
+            .with_headers(headers);
+        Ok(())
+    }
+}
+
+/// Decrypts a payload if its content type says it's encrypted, returning the properties it would
+/// have had unencrypted.
+pub(crate) fn decrypt<'d>(
+    keys: Option<&dyn KeyProvider>,
+    properties: &'d BasicProperties,
+    data: &'d [u8],
+) -> Result<(Cow<'d, BasicProperties>, Cow<'d, [u8]>), MqError> {
+    let content_type = properties.content_type().as_ref().map(|c| c.as_str()).unwrap_or_default();
+    let Some(content_type) = content_type.strip_suffix(ENCRYPTED_SUFFIX) else {
+        return Ok((Cow::Borrowed(properties), Cow::Borrowed(data)));
+    };
+
+    let keys = keys.ok_or_else(|| MqError::CodecError("message is encrypted, but no keys are configured".into()))?;
+    let key_id = match properties.headers().as_ref().and_then(|h| h.inner().get(KEY_ID_HEADER)) {
+        Some(AMQPValue::LongString(s)) => s.to_string(),
+        Some(AMQPValue::ShortString(s)) => s.to_string(),
+        _ => return Err(MqError::CodecError("encrypted message doesn't name its key".into())),
+    };
+    let key = keys
+        .key(&key_id)
+        .ok_or_else(|| MqError::CodecError(format!("unknown encryption key {key_id:?}")))?;
+
+    if data.len() < NONCE_LEN {
+        return Err(MqError::CodecError("encrypted payload is truncated".into()));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let plaintext = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| MqError::CodecError("decrypting payload failed".into()))?;
+
+    let properties = properties.clone().with_content_type(content_type.into());
+    Ok((Cow::Owned(properties), Cow::Owned(plaintext)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypted(keys: impl KeyProvider) -> OutgoingMessage {
+        let mut message = OutgoingMessage {
+            exchange: "people".into(),
+            routing_key: "person.created".into(),
+            payload: br#"{"message":{"email":"alice@example.com"}}"#.to_vec(),
+            properties: BasicProperties::default().with_content_type("application/json".into()),
+        };
+        Encryptor::new(keys).intercept(&mut message).unwrap();
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let old = StaticKey::new("2024", [1; 32]);
+        let new = StaticKey::new("2025", [2; 32]);
+        let message = encrypted(old.clone());
+        assert!(!message.payload.windows(5).any(|w| w == b"alice"));
+
+        let ring = KeyRing::new(new.clone()).with_retired(old);
+        let (properties, payload) = decrypt(Some(&ring), &message.properties, &message.payload).unwrap();
+        assert_eq!(properties.content_type().as_ref().unwrap().as_str(), "application/json");
+        assert_eq!(&*payload, br#"{"message":{"email":"alice@example.com"}}"#);
+
+        assert!(decrypt(Some(&new), &message.properties, &message.payload).is_err());
+        assert!(decrypt(None, &message.properties, &message.payload).is_err());
+
+        let mut tampered = message.payload.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(Some(&ring), &message.properties, &tampered).is_err());
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let properties = BasicProperties::default().with_content_type("application/json".into());
+        let (_, payload) = decrypt(None, &properties, b"{}").unwrap();
+        assert_eq!(&*payload, b"{}");
+    }
+}
@@ -0,0 +1,66 @@
+//! Skipping messages a consumer isn't interested in, see
+//! [`Consumer::with_filter`](super::consumer::Consumer::with_filter).
+
+use std::collections::BTreeMap;
+
+use super::routing;
+
+/// Which messages a consumer processes. The rest are acked as soon as they're delivered, without
+/// being decoded, so a queue can be bound with a broad pattern and each consumer pick out its
+/// share, rather than declaring a narrow binding for each.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageFilter {
+    /// Messages with a routing key matching any of the patterns, as a topic binding would: `*`
+    /// matches exactly one word and `#` any number of words.
+    RoutingKey(Vec<String>),
+    /// Messages with a string header `name` of `value`.
+    Header { name: String, value: String },
+}
+
+impl MessageFilter {
+    pub fn routing_key(pattern: impl Into<String>) -> Self {
+        MessageFilter::RoutingKey(vec![pattern.into()])
+    }
+
+    pub fn routing_keys(patterns: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        MessageFilter::RoutingKey(patterns.into_iter().map(Into::into).collect())
+    }
+
+    pub fn header(name: impl Into<String>, value: impl Into<String>) -> Self {
+        MessageFilter::Header {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
+    /// Whether a message with `routing_key` and string `headers` passes.
+    pub fn matches(&self, routing_key: &str, headers: &BTreeMap<String, String>) -> bool {
+        match self {
+            MessageFilter::RoutingKey(patterns) => patterns
+                .iter()
+                .any(|pattern| routing::pattern_matches(pattern, routing_key)),
+            MessageFilter::Header { name, value } => headers.get(name) == Some(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_routing_keys_and_headers() {
+        let no_headers = BTreeMap::new();
+        let locations = MessageFilter::routing_keys(["location.*.eu", "location.#.priority"]);
+        assert!(locations.matches("location.updated.eu", &no_headers));
+        assert!(locations.matches("location.updated.us.priority", &no_headers));
+        assert!(!locations.matches("location.updated.us", &no_headers));
+        assert!(!MessageFilter::routing_keys(Vec::<String>::new()).matches("location.updated.eu", &no_headers));
+
+        let tenant = MessageFilter::header("tenant", "acme");
+        let headers = BTreeMap::from([("tenant".to_string(), "acme".to_string())]);
+        assert!(tenant.matches("anything", &headers));
+        assert!(!tenant.matches("anything", &no_headers));
+        assert!(!MessageFilter::header("tenant", "other").matches("anything", &headers));
+    }
+}
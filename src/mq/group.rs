@@ -0,0 +1,294 @@
+//! Consumer groups: replicas of a service sharing one queue, each taking its turn, and knowing
+//! who else is in the group.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use futures::{future, FutureExt};
+use lapin::Channel;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use super::{
+    backend::{Publisher, Subscriber},
+    consumer::{Consumer, ConsumerOptions, MessageContext, Processor, ProcessorError},
+    setup::{self, Binding, ExchangeType, QueueOptions, Topology, TopologyBuilder, TopologyOps},
+    ChannelOps, Envelope, Exchange, MqError, Queue,
+};
+
+/// A member of a [`ConsumerGroup`], as last heard from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub id: String,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// What members tell each other about themselves.
+#[derive(Debug, Serialize, Deserialize)]
+struct Heartbeat {
+    member_id: String,
+    #[serde(default)]
+    leaving: bool,
+}
+
+type Members = Arc<Mutex<BTreeMap<String, DateTime<Utc>>>>;
+
+/// Replicas of a service consuming a queue together, as a group: the queue is named after the
+/// group, and the broker hands its messages to one member at a time, each taking another only once
+/// it's done with the last, so a slow member doesn't sit on messages the others could be handling.
+///
+/// Members make themselves known by sending heartbeats to the rest of the group, through an
+/// exchange named `<group>.presence` and a queue of their own, `<group>.presence.<member id>`,
+/// while [`ConsumerGroup::run_presence_until`] runs. A member's queue expires once nothing has
+/// read it for three heartbeats, so the members that crashed leave nothing behind.
+///
+/// ```no_run
+/// # use launchpad::mq::{consumer::{MessageContext, Processor, ProcessorError}, group::ConsumerGroup};
+/// # struct Track;
+/// # impl Processor for Track {
+/// #     async fn process(&mut self, _: serde_json::Value, _: &MessageContext) -> Result<(), ProcessorError> { Ok(()) }
+/// # }
+/// # async fn run(channel: lapin::Channel, shutdown: impl std::future::Future<Output = ()>) -> Result<(), launchpad::mq::MqError> {
+/// let group = ConsumerGroup::new(channel, "tracking", std::env::var("HOSTNAME").unwrap_or_default());
+/// group.declare().await?;
+/// let consumer = group.consumer();
+/// let mut track = Track;
+/// let shutdown = futures::FutureExt::shared(Box::pin(shutdown));
+/// let (presence, consuming) = tokio::join!(
+///     group.run_presence_until(shutdown.clone()),
+///     consumer.consume_until(&mut track, shutdown),
+/// );
+/// presence.and(consuming)
+/// # }
+/// ```
+pub struct ConsumerGroup<B> {
+    broker: B,
+    name: String,
+    member_id: String,
+    consumer_tag: String,
+    prefetch_count: u16,
+    heartbeat_interval: Duration,
+    members: Members,
+}
+
+impl<B> ConsumerGroup<B>
+where
+    B: ChannelOps + TopologyOps + Clone,
+    for<'a> B::Producer<'a>: Publisher,
+    for<'a> B::Consumer<'a>: Subscriber,
+{
+    /// Joins the group `name` on `broker` as `member_id`, which must be unique within the group,
+    /// e.g. the host or pod name.
+    pub fn new(broker: B, name: impl Into<String>, member_id: impl Into<String>) -> Self {
+        let name = name.into();
+        let member_id = member_id.into();
+        ConsumerGroup {
+            broker,
+            consumer_tag: format!("{name}.{member_id}"),
+            name,
+            member_id,
+            prefetch_count: 1,
+            heartbeat_interval: Duration::from_secs(5),
+            members: Members::default(),
+        }
+    }
+
+    /// How many messages each member is handed at a time. Defaults to 1, for the fairest dispatch;
+    /// raise it for quick messages, where waiting for the next one adds up.
+    pub fn with_prefetch_count(mut self, prefetch_count: u16) -> Self {
+        self.prefetch_count = prefetch_count;
+        self
+    }
+
+    /// How often members send heartbeats. Defaults to 5 seconds; a member missing three is taken
+    /// to have left.
+    pub fn with_heartbeat_interval(mut self, heartbeat_interval: Duration) -> Self {
+        self.heartbeat_interval = heartbeat_interval;
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn member_id(&self) -> &str {
+        &self.member_id
+    }
+
+    /// The queue the group shares, named after it.
+    pub fn queue_name(&self) -> &str {
+        &self.name
+    }
+
+    /// The shared queue, and the member's presence exchange and queue. Bind the shared queue to
+    /// whatever the group consumes separately.
+    pub fn topology(&self) -> Topology<String> {
+        let presence_exchange = self.presence_exchange();
+        let presence_queue = self.presence_queue();
+        let expiry = u32::try_from(self.member_timeout().as_millis()).unwrap_or(u32::MAX);
+        Topology::builder()
+            .with_exchange(setup::Exchange::new(presence_exchange.clone(), ExchangeType::Topic, true))
+            .with_queue(setup::Queue::new(self.name.clone(), vec![QueueOptions::Persistence(true)]))
+            .with_queue(setup::Queue::new(
+                presence_queue.clone(),
+                vec![
+                    QueueOptions::Persistence(false),
+                    QueueOptions::AutoExpire(expiry),
+                    QueueOptions::MessageTTL(expiry),
+                ],
+            ))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: presence_exchange,
+                target_queue_name: presence_queue,
+                routing_key: Some("#".into()),
+            })
+            .build()
+    }
+
+    pub async fn declare(&self) -> Result<(), MqError> {
+        self.broker.apply_topology(self.topology()).await
+    }
+
+    /// The members heard from within the last three heartbeats, this one included once it's
+    /// heard from itself, by id.
+    pub fn members(&self) -> Vec<Member> {
+        let cutoff = Utc::now() - self.member_timeout();
+        lock(&self.members)
+            .iter()
+            .filter(|(_, last_seen)| **last_seen >= cutoff)
+            .map(|(id, last_seen)| Member {
+                id: id.clone(),
+                last_seen: *last_seen,
+            })
+            .collect()
+    }
+
+    /// Sends heartbeats, and listens for the other members', until `shutdown` completes, when the
+    /// member tells the others it's leaving. Needs the topology [`ConsumerGroup::declare`]s.
+    pub async fn run_presence_until(&self, shutdown: impl Future<Output = ()>) -> Result<(), MqError> {
+        let shutdown = shutdown.boxed_local().shared();
+        let presence_exchange = self.presence_exchange();
+        let presence_queue = self.presence_queue();
+
+        let heartbeats = async {
+            let producer = self.broker.clone().create_producer(Exchange::new(&presence_exchange));
+            let heartbeat = |leaving| {
+                let heartbeat = Heartbeat {
+                    member_id: self.member_id.clone(),
+                    leaving,
+                };
+                producer.publish_envelope(Envelope::new(heartbeat), Some(&self.member_id))
+            };
+            let mut shutdown = shutdown.clone();
+            loop {
+                if let Err(e) = heartbeat(false).await {
+                    warn!("sending heartbeat to consumer group {} failed: {e}", self.name);
+                }
+                let sleep = tokio::time::sleep(self.heartbeat_interval);
+                if let future::Either::Right(_) = future::select(sleep.boxed_local(), &mut shutdown).await {
+                    break;
+                }
+            }
+            heartbeat(true).await
+        };
+        let listening = async {
+            let consumer = self
+                .broker
+                .clone()
+                .create_consumer(&self.consumer_tag, Queue::new(&presence_queue));
+            let mut presence = Presence(self.members.clone());
+            consumer.subscribe_until::<Heartbeat, _>(&mut presence, shutdown.clone()).await
+        };
+        let result = future::try_join(heartbeats, listening).await;
+        lock(&self.members).remove(&self.member_id);
+        result.map(|_| ())
+    }
+
+    fn presence_exchange(&self) -> String {
+        format!("{}.presence", self.name)
+    }
+
+    fn presence_queue(&self) -> String {
+        format!("{}.presence.{}", self.name, self.member_id)
+    }
+
+    fn member_timeout(&self) -> Duration {
+        self.heartbeat_interval * 3
+    }
+}
+
+impl ConsumerGroup<Channel> {
+    /// A consumer of the shared queue, handed [`ConsumerGroup::with_prefetch_count`] messages at a
+    /// time, and tagged `<group>.<member id>`, so it's recognisable in the management UI.
+    pub fn consumer(&self) -> Consumer<'_> {
+        Consumer::new(self.broker.clone(), &self.consumer_tag, Queue::new(&self.name)).with_options(ConsumerOptions {
+            prefetch_count: Some(self.prefetch_count),
+            ..ConsumerOptions::default()
+        })
+    }
+}
+
+/// Keeps track of the members heard from.
+struct Presence(Members);
+
+impl Processor<Heartbeat> for Presence {
+    async fn process(&mut self, heartbeat: Heartbeat, _context: &MessageContext) -> Result<(), ProcessorError> {
+        let mut members = lock(&self.0);
+        if heartbeat.leaving {
+            members.remove(&heartbeat.member_id);
+        } else {
+            members.insert(heartbeat.member_id, Utc::now());
+        }
+        Ok(())
+    }
+}
+
+fn lock(members: &Members) -> MutexGuard<'_, BTreeMap<String, DateTime<Utc>>> {
+    members.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mq::testing::InMemoryBroker;
+
+    fn ids(group: &ConsumerGroup<InMemoryBroker>) -> Vec<String> {
+        group.members().into_iter().map(|member| member.id).collect()
+    }
+
+    #[tokio::test]
+    async fn members_come_and_go() {
+        let broker = InMemoryBroker::new();
+        let group = |member_id| {
+            ConsumerGroup::new(broker.clone(), "tracking", member_id).with_heartbeat_interval(Duration::from_secs(1))
+        };
+        let (a, b) = (group("a"), group("b"));
+        a.declare().await.unwrap();
+        b.declare().await.unwrap();
+        assert_eq!(broker.queue_len("tracking"), 0);
+
+        let sleep = |ms| tokio::time::sleep(Duration::from_millis(ms));
+        let checking = async {
+            sleep(50).await;
+            let both = (ids(&a), ids(&b));
+            sleep(100).await;
+            (both, ids(&a))
+        };
+        let (presence_a, presence_b, (both, after_b_left)) = tokio::join!(
+            a.run_presence_until(sleep(200)),
+            b.run_presence_until(sleep(100)),
+            checking,
+        );
+        presence_a.unwrap();
+        presence_b.unwrap();
+
+        assert_eq!(both, (vec!["a".to_string(), "b".into()], vec!["a".to_string(), "b".into()]));
+        assert_eq!(after_b_left, ["a"]);
+        assert!(ids(&a).is_empty());
+    }
+}
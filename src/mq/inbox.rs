@@ -0,0 +1,99 @@
+use serde_json::Value;
+use sqlx::{PgConnection, PgPool};
+use tracing::debug;
+
+use super::{
+    consumer::{MessageContext, Processor, ProcessorError},
+    Envelope,
+};
+
+/// The table [`Inbox::default_table`] records processed messages in.
+pub const DEFAULT_INBOX_TABLE: &str = "inbox";
+
+/// Handles a message inside the transaction its processing is recorded in.
+pub trait InboxHandler {
+    async fn handle(&mut self, tx: &mut PgConnection, envelope: Envelope<Value>) -> Result<(), ProcessorError>;
+}
+
+/// Records the ids of processed messages in a table, in the same transaction as the handler's own
+/// writes, so each message's writes are committed exactly once however often it's delivered.
+///
+/// The id is claimed before the handler runs, so a duplicate delivered while the original is still
+/// being processed waits for it, then is skipped if it commits. Messages without an id are processed
+/// unguarded.
+#[derive(Debug, Clone)]
+pub struct Inbox {
+    pool: PgPool,
+    table: String,
+}
+
+impl Inbox {
+    pub fn new(pool: PgPool, table: impl Into<String>) -> Self {
+        Inbox {
+            pool,
+            table: table.into(),
+        }
+    }
+
+    pub fn default_table(pool: PgPool) -> Self {
+        Inbox::new(pool, DEFAULT_INBOX_TABLE)
+    }
+
+    pub async fn create_table(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "create table if not exists {} (message_id text primary key, processed_at timestamptz not null default now())",
+            self.table
+        ))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// A processor running `handler` for each message not already in the inbox.
+    pub fn processor<H: InboxHandler>(&self, handler: H) -> InboxProcessor<H> {
+        InboxProcessor {
+            inbox: self.clone(),
+            handler,
+        }
+    }
+}
+
+pub struct InboxProcessor<H> {
+    inbox: Inbox,
+    handler: H,
+}
+
+impl<H: InboxHandler> Processor for InboxProcessor<H> {
+    async fn process(&mut self, value: Value, context: &MessageContext) -> Result<(), ProcessorError> {
+        self.process_envelope(Envelope::new(value), context).await
+    }
+
+    async fn process_envelope(&mut self, envelope: Envelope<Value>, _context: &MessageContext) -> Result<(), ProcessorError> {
+        let mut tx = self.inbox.pool.begin().await.map_err(temporary)?;
+
+        if let Some(message_id) = envelope.message_id() {
+            let claimed = sqlx::query(&format!(
+                "insert into {} (message_id) values ($1) on conflict do nothing",
+                self.inbox.table
+            ))
+            .bind(message_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(temporary)?
+            .rows_affected();
+
+            if claimed == 0 {
+                debug!("skipping already processed message {message_id}");
+                return Ok(());
+            }
+        }
+
+        // dropping the transaction on failure rolls back the handler's writes and the claim
+        self.handler.handle(&mut tx, envelope).await?;
+        tx.commit().await.map_err(temporary)
+    }
+}
+
+fn temporary(e: sqlx::Error) -> ProcessorError {
+    ProcessorError::TemporaryError(e.to_string())
+}
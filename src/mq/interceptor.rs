@@ -0,0 +1,56 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use lapin::BasicProperties;
+
+use super::MqError;
+
+/// A message about to be published, once encoded and compressed.
+#[derive(Debug, Clone)]
+pub struct OutgoingMessage {
+    pub exchange: String,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    pub properties: BasicProperties,
+}
+
+/// Sees every message a [`Producer`](super::producer::Producer) publishes, added with
+/// [`Producer::with_interceptor`](super::producer::Producer::with_interceptor).
+///
+/// Interceptors run in the order they were added, just before the message is sent, and may change
+/// anything about it: stamp headers, sign or encrypt the payload, or refuse to publish it at all by
+/// returning an error.
+pub trait PublishInterceptor: Send + Sync {
+    fn intercept(&self, message: &mut OutgoingMessage) -> Result<(), MqError>;
+
+    /// Called once the broker has taken the message, or publishing has failed, e.g. to record metrics.
+    fn published(&self, _message: &OutgoingMessage, _result: &Result<(), MqError>) {}
+
+    /// Like [`PublishInterceptor::published`], along with how long publishing took, confirms
+    /// included. Calls [`PublishInterceptor::published`] unless implemented.
+    fn published_in(&self, message: &OutgoingMessage, result: &Result<(), MqError>, _elapsed: Duration) {
+        self.published(message, result);
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Interceptors(Vec<Arc<dyn PublishInterceptor>>);
+
+impl Interceptors {
+    pub(crate) fn push(&mut self, interceptor: Arc<dyn PublishInterceptor>) {
+        self.0.push(interceptor);
+    }
+
+    pub(crate) fn intercept(&self, message: &mut OutgoingMessage) -> Result<(), MqError> {
+        self.0.iter().try_for_each(|i| i.intercept(message))
+    }
+
+    pub(crate) fn published(&self, message: &OutgoingMessage, result: &Result<(), MqError>, elapsed: Duration) {
+        self.0.iter().for_each(|i| i.published_in(message, result, elapsed));
+    }
+}
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} interceptors", self.0.len())
+    }
+}
@@ -0,0 +1,367 @@
+//! Kafka behind the same [`Publisher`] and [`Subscriber`] traits as RabbitMQ. Topics play the part of
+//! exchanges, with the routing key as the message key, and consumer groups the part of queues.
+
+use std::{future::Future, pin::pin, time::Duration};
+
+use lapin::{
+    types::{AMQPValue, FieldTable},
+    BasicProperties,
+};
+use rdkafka::{
+    admin::{AdminClient, AdminOptions, NewTopic, TopicReplication},
+    client::DefaultClientContext,
+    consumer::{CommitMode, Consumer as _, StreamConsumer},
+    message::{Header, Headers, Message, OwnedHeaders},
+    producer::{FutureProducer, FutureRecord},
+    types::RDKafkaErrorCode,
+    ClientConfig, Offset,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use super::{
+    backend::{Publisher, Subscriber},
+    codec::{Codec, Json},
+    consumer::{process_as, MessageContext, Processor, ProcessorError},
+    setup::{Binding, Exchange, Queue, TeardownOptions, TopologyOps},
+    Envelope, MqError,
+};
+
+const MESSAGE_ID: &str = "message-id";
+const CORRELATION_ID: &str = "correlation-id";
+const CONTENT_TYPE: &str = "content-type";
+const CONTENT_ENCODING: &str = "content-encoding";
+
+/// Publishes to a topic, keyed by routing key so messages with the same key stay in order.
+pub struct KafkaProducer<C: Codec = Json> {
+    producer: FutureProducer,
+    topic: String,
+    timeout: Duration,
+    codec: C,
+}
+
+impl KafkaProducer {
+    pub fn new(brokers: &str, topic: impl Into<String>) -> Result<Self, MqError> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", brokers);
+        Self::from_config(&config, topic)
+    }
+
+    pub fn from_config(config: &ClientConfig, topic: impl Into<String>) -> Result<Self, MqError> {
+        Ok(KafkaProducer {
+            producer: config.create()?,
+            topic: topic.into(),
+            timeout: Duration::from_secs(30),
+            codec: Json,
+        })
+    }
+}
+
+impl<C: Codec> KafkaProducer<C> {
+    pub fn with_codec<D: Codec>(self, codec: D) -> KafkaProducer<D> {
+        KafkaProducer {
+            producer: self.producer,
+            topic: self.topic,
+            timeout: self.timeout,
+            codec,
+        }
+    }
+
+    /// How long a message may wait in the local queue for room to send. Defaults to 30s.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Publishes `envelope`, returning once the brokers have acknowledged it.
+    pub async fn publish<M: Serialize>(&self, envelope: Envelope<M>, key: Option<&str>) -> Result<(), MqError> {
+        let payload = self.codec.encode(&envelope)?;
+        let properties = envelope.properties().with_content_type(self.codec.content_type().into());
+
+        let mut record = FutureRecord::<str, [u8]>::to(&self.topic)
+            .payload(&payload)
+            .headers(kafka_headers(&properties));
+        if let Some(key) = key {
+            record = record.key(key);
+        }
+        if let Some(timestamp) = envelope.timestamp() {
+            record = record.timestamp(timestamp.timestamp_millis());
+        }
+
+        self.producer
+            .send(record, self.timeout)
+            .await
+            .map_err(|(e, _)| MqError::from(e))?;
+        Ok(())
+    }
+}
+
+impl<C: Codec> Publisher for KafkaProducer<C> {
+    async fn publish_envelope<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+        self.publish(envelope, routing_key).await
+    }
+}
+
+/// Consumes topics as a member of a consumer group, committing each message's offset once it's
+/// processed.
+///
+/// Kafka can't requeue a single message, so one failing temporarily is retried from its offset after
+/// `retry_delay`, holding up the rest of its partition until it succeeds. Messages failing permanently
+/// are committed and skipped.
+pub struct KafkaConsumer<C: Codec = Json> {
+    consumer: StreamConsumer,
+    retry_delay: Duration,
+    codec: C,
+}
+
+impl KafkaConsumer {
+    pub fn new(brokers: &str, group_id: &str, topics: &[&str]) -> Result<Self, MqError> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", brokers).set("group.id", group_id);
+        Self::from_config(&config, topics)
+    }
+
+    /// Offsets are committed by the consumer, so `enable.auto.commit` is always turned off.
+    pub fn from_config(config: &ClientConfig, topics: &[&str]) -> Result<Self, MqError> {
+        let consumer: StreamConsumer = config.clone().set("enable.auto.commit", "false").create()?;
+        consumer.subscribe(topics)?;
+        Ok(KafkaConsumer {
+            consumer,
+            retry_delay: Duration::from_secs(1),
+            codec: Json,
+        })
+    }
+}
+
+impl<C: Codec> KafkaConsumer<C> {
+    pub fn with_codec<D: Codec>(self, codec: D) -> KafkaConsumer<D> {
+        KafkaConsumer {
+            consumer: self.consumer,
+            retry_delay: self.retry_delay,
+            codec,
+        }
+    }
+
+    /// How long to wait before retrying a message that failed temporarily. Defaults to 1s.
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub async fn consume_until<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, shutdown: impl Future<Output = ()>) -> Result<(), MqError> {
+        let mut shutdown = pin!(shutdown);
+        loop {
+            let message = tokio::select! {
+                _ = &mut shutdown => break,
+                message = self.consumer.recv() => message?,
+            };
+
+            let properties = amqp_properties(message.headers());
+            let result = match self
+                .codec
+                .decode_delivery::<Envelope<Value>>(&properties, message.payload().unwrap_or_default())
+            {
+                Ok(envelope) => {
+                    let key = message.key().map(String::from_utf8_lossy).unwrap_or_default();
+                    let context = MessageContext {
+                        delivery_tag: message.offset().max(0) as u64,
+                        ..MessageContext::new(key, &properties)
+                    };
+                    process_as(processor, envelope.with_properties(&properties), &context).await
+                }
+                Err(e) => Err(ProcessorError::PermanentError(e.to_string())),
+            };
+
+            match result {
+                Err(ProcessorError::TemporaryError(e)) => {
+                    warn!("message failed temporarily, retrying in {:?}: {e}", self.retry_delay);
+                    self.consumer
+                        .seek(message.topic(), message.partition(), Offset::Offset(message.offset()), self.retry_delay)?;
+                    tokio::time::sleep(self.retry_delay).await;
+                }
+                result => {
+                    match result {
+                        Ok(_) => debug!("message successful"),
+                        Err(e) => warn!("message failed permanently, skipping: {e}"),
+                    }
+                    self.consumer.commit_message(&message, CommitMode::Async)?;
+                }
+            }
+        }
+
+        debug!("shutting down kafka consumer");
+        Ok(())
+    }
+}
+
+impl<C: Codec> Subscriber for KafkaConsumer<C> {
+    async fn subscribe_until<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, shutdown: impl Future<Output = ()>) -> Result<(), MqError> {
+        self.consume_until(processor, shutdown).await
+    }
+}
+
+/// Creates a topic for each exchange in a topology. Queues are consumer groups, which Kafka creates
+/// as consumers join, and bindings are the topics a group subscribes to, so neither is declared.
+pub struct KafkaAdmin {
+    admin: AdminClient<DefaultClientContext>,
+    partitions: i32,
+    replication: i32,
+}
+
+impl KafkaAdmin {
+    pub fn new(brokers: &str) -> Result<Self, MqError> {
+        let mut config = ClientConfig::new();
+        config.set("bootstrap.servers", brokers);
+        Ok(KafkaAdmin {
+            admin: config.create()?,
+            partitions: 1,
+            replication: 1,
+        })
+    }
+
+    /// How many partitions and replicas topics are created with. Defaults to 1 of each.
+    pub fn with_partitions(mut self, partitions: i32, replication: i32) -> Self {
+        self.partitions = partitions;
+        self.replication = replication;
+        self
+    }
+}
+
+impl TopologyOps for KafkaAdmin {
+    async fn with_queue<Name: Into<String> + Clone>(&self, queue: &Queue<Name>) -> Result<(), MqError> {
+        debug!("consumer group {} is created when consumers join", queue.name.clone().into());
+        Ok(())
+    }
+
+    async fn with_exchange<Name: Into<String> + Clone>(&self, exchange: &Exchange<Name>) -> Result<(), MqError> {
+        let name: String = exchange.name.clone().into();
+        let topic = NewTopic::new(&name, self.partitions, TopicReplication::Fixed(self.replication));
+        for result in self.admin.create_topics([&topic], &AdminOptions::new()).await? {
+            match result {
+                Ok(_) | Err((_, RDKafkaErrorCode::TopicAlreadyExists)) => {}
+                Err((topic, code)) => {
+                    return Err(MqError::ConfigurationError(format!("creating topic {topic} failed: {code}")))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn with_binding<Name: Into<String> + Clone>(&self, _binding: &Binding<Name>) -> Result<(), MqError> {
+        Ok(())
+    }
+
+    async fn delete_queue<Name: Into<String> + Clone>(
+        &self,
+        queue: &Queue<Name>,
+        _options: TeardownOptions,
+    ) -> Result<(), MqError> {
+        debug!("consumer group {} expires once its consumers leave", queue.name.clone().into());
+        Ok(())
+    }
+
+    /// Deletes the topic. Kafka can't tell whether a topic is in use, so any check in `options` is refused.
+    async fn delete_exchange<Name: Into<String> + Clone>(
+        &self,
+        exchange: &Exchange<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError> {
+        let name: String = exchange.name.clone().into();
+        if options.if_unused || options.if_empty {
+            return Err(MqError::ConfigurationError(format!("can't check whether topic {name} is in use")));
+        }
+        for result in self.admin.delete_topics(&[&name], &AdminOptions::new()).await? {
+            match result {
+                Ok(_) | Err((_, RDKafkaErrorCode::UnknownTopicOrPartition)) => {}
+                Err((topic, code)) => {
+                    return Err(MqError::ConfigurationError(format!("deleting topic {topic} failed: {code}")))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn unbind<Name: Into<String> + Clone>(&self, _binding: &Binding<Name>) -> Result<(), MqError> {
+        Ok(())
+    }
+}
+
+/// Carries the properties the AMQP side of an envelope is made from as Kafka headers.
+fn kafka_headers(properties: &BasicProperties) -> OwnedHeaders {
+    let mut headers = OwnedHeaders::new();
+    let named = [
+        (MESSAGE_ID, properties.message_id()),
+        (CORRELATION_ID, properties.correlation_id()),
+        (CONTENT_TYPE, properties.content_type()),
+        (CONTENT_ENCODING, properties.content_encoding()),
+    ];
+    for (key, value) in named {
+        if let Some(value) = value {
+            headers = headers.insert(Header {
+                key,
+                value: Some(value.as_str()),
+            });
+        }
+    }
+
+    for (key, value) in properties.headers().iter().flat_map(|h| h.inner()) {
+        let value = match value {
+            AMQPValue::LongString(s) => s.to_string(),
+            AMQPValue::ShortString(s) => s.to_string(),
+            _ => continue,
+        };
+        headers = headers.insert(Header {
+            key: key.as_str(),
+            value: Some(&value),
+        });
+    }
+    headers
+}
+
+fn amqp_properties(headers: Option<&impl Headers>) -> BasicProperties {
+    let mut properties = BasicProperties::default();
+    let mut others = FieldTable::default();
+    for header in headers.into_iter().flat_map(|h| h.iter()) {
+        let Some(value) = header.value.and_then(|v| std::str::from_utf8(v).ok()) else {
+            continue;
+        };
+        properties = match header.key {
+            MESSAGE_ID => properties.with_message_id(value.into()),
+            CORRELATION_ID => properties.with_correlation_id(value.into()),
+            CONTENT_TYPE => properties.with_content_type(value.into()),
+            CONTENT_ENCODING => properties.with_content_encoding(value.into()),
+            key => {
+                others.insert(key.into(), AMQPValue::LongString(value.into()));
+                properties
+            }
+        };
+    }
+    if !others.inner().is_empty() {
+        properties = properties.with_headers(others);
+    }
+    properties
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_round_trip() {
+        let envelope = Envelope::new(String::from("order"))
+            .with_message_id("m-1")
+            .with_correlation_id("c-1")
+            .with_header("tenant", "acme");
+        let properties = envelope.properties().with_content_type("application/json".into());
+
+        let headers = kafka_headers(&properties);
+        let received = amqp_properties(Some(&headers));
+        assert_eq!(received, properties);
+
+        let received = Envelope::new(String::new()).with_properties(&received);
+        assert_eq!(received.message_id(), Some("m-1"));
+        assert_eq!(received.correlation_id(), Some("c-1"));
+        assert_eq!(received.header("tenant"), Some("acme"));
+    }
+}
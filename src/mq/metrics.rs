@@ -0,0 +1,208 @@
+//! Metrics for publishing and consuming, recorded through the [`metrics`] facade, so they go to
+//! whichever recorder the application installs, e.g. a Prometheus exporter. Nothing is recorded
+//! until one is.
+//!
+//! Publishing is labelled by `exchange`, consuming by `queue`. Consuming covers
+//! [`Consumer::consume`](super::consumer::Consumer::consume) and its variants; messages from the
+//! consumer's streams are settled by the caller, so aren't counted.
+
+use std::time::Instant;
+
+use metrics::{counter, gauge, histogram};
+
+use super::MqError;
+
+/// Messages published, labelled with an `outcome` of `ok` or `error`.
+pub const PUBLISHED: &str = "mq_published_total";
+/// Seconds from publishing a message until the broker has it, or confirms it with confirms on.
+pub const PUBLISH_DURATION: &str = "mq_publish_duration_seconds";
+/// Messages the broker nacked, or returned as unroutable, labelled with the `reason`.
+pub const CONFIRM_FAILURES: &str = "mq_confirm_failures_total";
+/// Messages delivered to a consumer.
+pub const CONSUMED: &str = "mq_consumed_total";
+/// Seconds spent processing each message, middleware included.
+pub const PROCESSING_DURATION: &str = "mq_processing_duration_seconds";
+/// Messages being processed right now.
+pub const IN_FLIGHT: &str = "mq_in_flight";
+/// Messages settled, labelled with an `outcome` of `ack`, `nack`, `requeue`, `retry`,
+/// `quarantine`, `expired`, for messages dropped unprocessed past their
+/// [`not_after`](super::Envelope::with_not_after), or `filtered`, for those skipped by a
+/// [`MessageFilter`](super::filter::MessageFilter).
+pub const SETTLED: &str = "mq_settled_total";
+/// Messages waiting in each queue, as last reported by the management API.
+pub const QUEUE_MESSAGES: &str = "mq_queue_messages";
+/// Consumers of each queue, as last reported by the management API.
+pub const QUEUE_CONSUMERS: &str = "mq_queue_consumers";
+
+/// How a consumed message was settled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Settlement {
+    Ack,
+    Nack,
+    Requeue,
+    Retry,
+    Quarantine,
+    Expired,
+    Filtered,
+}
+
+impl Settlement {
+    fn label(self) -> &'static str {
+        match self {
+            Settlement::Ack => "ack",
+            Settlement::Nack => "nack",
+            Settlement::Requeue => "requeue",
+            Settlement::Retry => "retry",
+            Settlement::Quarantine => "quarantine",
+            Settlement::Expired => "expired",
+            Settlement::Filtered => "filtered",
+        }
+    }
+}
+
+/// Records a publish to `exchange`, begun at `started`, once it's finished.
+pub(crate) fn published(exchange: &str, started: Instant, result: &Result<(), MqError>) {
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    counter!(PUBLISHED, "exchange" => exchange.to_string(), "outcome" => outcome).increment(1);
+    histogram!(PUBLISH_DURATION, "exchange" => exchange.to_string()).record(started.elapsed().as_secs_f64());
+
+    let reason = match result {
+        Err(MqError::Nacked(..)) => "nacked",
+        Err(MqError::Unroutable(..)) => "unroutable",
+        _ => return,
+    };
+    counter!(CONFIRM_FAILURES, "exchange" => exchange.to_string(), "reason" => reason).increment(1);
+}
+
+pub(crate) fn settled(queue: &str, settlement: Settlement) {
+    counter!(SETTLED, "queue" => queue.to_string(), "outcome" => settlement.label()).increment(1);
+}
+
+/// Records how deep `queue` is, and how many consumers it has.
+#[cfg(feature = "mq-management")]
+pub(crate) fn queue_depth(queue: &super::management::QueueInfo) {
+    gauge!(QUEUE_MESSAGES, "queue" => queue.name.clone()).set(queue.messages_ready);
+    gauge!(QUEUE_CONSUMERS, "queue" => queue.name.clone()).set(queue.consumers);
+}
+
+/// A message from `queue` being processed, counted in flight until dropped.
+pub(crate) struct Processing {
+    queue: String,
+    started: Instant,
+}
+
+impl Processing {
+    pub(crate) fn start(queue: &str) -> Self {
+        counter!(CONSUMED, "queue" => queue.to_string()).increment(1);
+        gauge!(IN_FLIGHT, "queue" => queue.to_string()).increment(1.0);
+        Processing {
+            queue: queue.to_string(),
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for Processing {
+    fn drop(&mut self) {
+        gauge!(IN_FLIGHT, "queue" => self.queue.clone()).decrement(1.0);
+        histogram!(PROCESSING_DURATION, "queue" => self.queue.clone()).record(self.started.elapsed().as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use metrics_util::{
+        debugging::{DebugValue, DebuggingRecorder},
+        MetricKind,
+    };
+
+    use super::*;
+
+    /// The value of every metric recorded by `f`, by name and labels. Whatever `f` returns is
+    /// dropped after the values are taken.
+    fn record<T>(f: impl FnOnce() -> T) -> Vec<(String, Vec<String>, DebugValue)> {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _kept = metrics::with_local_recorder(&recorder, f);
+
+        let mut values = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let (kind, key) = key.into_parts();
+                let name = key.name().to_string();
+                let labels = key.labels().map(|l| format!("{}={}", l.key(), l.value())).collect();
+                // histograms are checked by how many were recorded, as their timings vary
+                let value = match (kind, value) {
+                    (MetricKind::Histogram, DebugValue::Histogram(values)) => DebugValue::Counter(values.len() as u64),
+                    (_, value) => value,
+                };
+                (name, labels, value)
+            })
+            .collect::<Vec<_>>();
+        values.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        values
+    }
+
+    #[test]
+    fn records_publishing() {
+        let values = record(|| {
+            published("orders", Instant::now(), &Ok(()));
+            published("orders", Instant::now(), &Err(MqError::Nacked("orders".into(), "created".into())));
+        });
+
+        assert_eq!(
+            values,
+            [
+                (CONFIRM_FAILURES.into(), vec!["exchange=orders".into(), "reason=nacked".into()], DebugValue::Counter(1)),
+                (PUBLISH_DURATION.into(), vec!["exchange=orders".into()], DebugValue::Counter(2)),
+                (PUBLISHED.into(), vec!["exchange=orders".into(), "outcome=error".into()], DebugValue::Counter(1)),
+                (PUBLISHED.into(), vec!["exchange=orders".into(), "outcome=ok".into()], DebugValue::Counter(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_consuming() {
+        let values = record(|| {
+            let processing = Processing::start("orders");
+            let still_processing = Processing::start("orders");
+            drop(processing);
+            settled("orders", Settlement::Requeue);
+            still_processing
+        });
+
+        assert_eq!(
+            values,
+            [
+                (CONSUMED.into(), vec!["queue=orders".into()], DebugValue::Counter(2)),
+                (IN_FLIGHT.into(), vec!["queue=orders".into()], DebugValue::Gauge(1.0.into())),
+                (PROCESSING_DURATION.into(), vec!["queue=orders".into()], DebugValue::Counter(1)),
+                (SETTLED.into(), vec!["queue=orders".into(), "outcome=requeue".into()], DebugValue::Counter(1)),
+            ]
+        );
+    }
+
+    #[cfg(feature = "mq-management")]
+    #[test]
+    fn records_queue_depth() {
+        let queue: crate::mq::management::QueueInfo = serde_json::from_value(serde_json::json!({
+            "name": "orders",
+            "durable": true,
+            "auto_delete": false,
+            "messages_ready": 12,
+            "consumers": 3,
+        }))
+        .unwrap();
+        let values = record(|| queue_depth(&queue));
+
+        assert_eq!(
+            values,
+            [
+                (QUEUE_CONSUMERS.into(), vec!["queue=orders".into()], DebugValue::Gauge(3.0.into())),
+                (QUEUE_MESSAGES.into(), vec!["queue=orders".into()], DebugValue::Gauge(12.0.into())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,216 @@
+use std::{marker::PhantomData, sync::Arc, time::Instant};
+
+use futures::future::LocalBoxFuture;
+use lapin::message::Delivery;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use super::{
+    consumer::{process_as, MessageContext, Processor, ProcessorError},
+    Envelope,
+};
+
+/// Runs around the processor for every message a [`Consumer`](super::consumer::Consumer) receives,
+/// added with [`Consumer::with_middleware`](super::consumer::Consumer::with_middleware).
+///
+/// Middleware sees the delivery and the decoded envelope, and calls `next` to carry on down the
+/// chain to the processor, or doesn't, to handle the message itself. Whatever it returns decides
+/// whether the message is acked, requeued or rejected.
+pub trait ConsumerMiddleware: Send + Sync {
+    fn handle<'a>(
+        &'a self,
+        delivery: &'a Delivery,
+        envelope: Envelope<Value>,
+        next: Next<'a>,
+    ) -> LocalBoxFuture<'a, Result<(), ProcessorError>>;
+}
+
+/// The rest of the middleware chain, ending with the processor.
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn ConsumerMiddleware>],
+    delivery: &'a Delivery,
+    processor: Box<dyn ErasedProcessor + 'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new<M: DeserializeOwned + 'a, P: Processor<M>>(
+        middleware: &'a [Arc<dyn ConsumerMiddleware>],
+        delivery: &'a Delivery,
+        processor: &'a mut P,
+    ) -> Self {
+        Next {
+            middleware,
+            delivery,
+            processor: Box::new(Typed {
+                processor,
+                message: PhantomData,
+            }),
+        }
+    }
+
+    pub async fn run(self, envelope: Envelope<Value>) -> Result<(), ProcessorError> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => {
+                let next = Next {
+                    middleware: rest,
+                    delivery: self.delivery,
+                    processor: self.processor,
+                };
+                first.handle(self.delivery, envelope, next).await
+            }
+            None => {
+                let mut processor = self.processor;
+                let context = MessageContext::from_delivery(self.delivery);
+                processor.process_envelope_boxed(envelope, &context).await
+            }
+        }
+    }
+}
+
+/// [`Processor`] made object safe, so the chain doesn't need a type parameter per processor.
+trait ErasedProcessor {
+    fn process_envelope_boxed<'a>(
+        &'a mut self,
+        envelope: Envelope<Value>,
+        context: &'a MessageContext,
+    ) -> LocalBoxFuture<'a, Result<(), ProcessorError>>;
+}
+
+/// A processor of `M`, taking the envelopes the chain passes along.
+struct Typed<'a, P, M> {
+    processor: &'a mut P,
+    message: PhantomData<fn() -> M>,
+}
+
+impl<M: DeserializeOwned, P: Processor<M>> ErasedProcessor for Typed<'_, P, M> {
+    fn process_envelope_boxed<'a>(
+        &'a mut self,
+        envelope: Envelope<Value>,
+        context: &'a MessageContext,
+    ) -> LocalBoxFuture<'a, Result<(), ProcessorError>> {
+        Box::pin(process_as(&mut *self.processor, envelope, context))
+    }
+}
+
+/// Logs how long each message took to process, and how it went.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Logging;
+
+impl ConsumerMiddleware for Logging {
+    fn handle<'a>(
+        &'a self,
+        delivery: &'a Delivery,
+        envelope: Envelope<Value>,
+        next: Next<'a>,
+    ) -> LocalBoxFuture<'a, Result<(), ProcessorError>> {
+        Box::pin(async move {
+            let started = Instant::now();
+            let result = next.run(envelope).await;
+            match &result {
+                Ok(_) => debug!("processed message from {:?} in {:?}", delivery.routing_key.as_str(), started.elapsed()),
+                Err(e) => warn!(
+                    "processing message from {:?} failed after {:?}: {e}",
+                    delivery.routing_key.as_str(),
+                    started.elapsed()
+                ),
+            }
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use lapin::{acker::Acker, BasicProperties};
+    use serde_json::json;
+
+    use super::*;
+
+    struct Record(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl ConsumerMiddleware for Record {
+        fn handle<'a>(
+            &'a self,
+            _delivery: &'a Delivery,
+            envelope: Envelope<Value>,
+            next: Next<'a>,
+        ) -> LocalBoxFuture<'a, Result<(), ProcessorError>> {
+            Box::pin(async move {
+                self.1.lock().unwrap().push(format!("{} before", self.0));
+                let result = next.run(envelope).await;
+                self.1.lock().unwrap().push(format!("{} after: {}", self.0, result.is_ok()));
+                result
+            })
+        }
+    }
+
+    /// Rejects messages that aren't objects, without troubling the processor.
+    struct RequireObject;
+
+    impl ConsumerMiddleware for RequireObject {
+        fn handle<'a>(
+            &'a self,
+            _delivery: &'a Delivery,
+            envelope: Envelope<Value>,
+            next: Next<'a>,
+        ) -> LocalBoxFuture<'a, Result<(), ProcessorError>> {
+            Box::pin(async move {
+                if !envelope.message.is_object() {
+                    return Err(ProcessorError::PermanentError("not an object".into()));
+                }
+                next.run(envelope).await
+            })
+        }
+    }
+
+    struct Count(usize);
+
+    impl Processor for Count {
+        async fn process(&mut self, _value: Value, _context: &MessageContext) -> Result<(), ProcessorError> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+
+    fn delivery() -> Delivery {
+        Delivery {
+            delivery_tag: 1,
+            exchange: "events".into(),
+            routing_key: "order.created".into(),
+            redelivered: false,
+            properties: BasicProperties::default(),
+            data: vec![],
+            acker: Acker::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_in_order() {
+        let log = Arc::new(Mutex::new(vec![]));
+        let middleware: Vec<Arc<dyn ConsumerMiddleware>> = vec![
+            Arc::new(Record("outer", log.clone())),
+            Arc::new(RequireObject),
+            Arc::new(Record("inner", log.clone())),
+        ];
+        let delivery = delivery();
+        let mut processor = Count(0);
+
+        Next::new(&middleware, &delivery, &mut processor)
+            .run(Envelope::new(json!({})))
+            .await
+            .unwrap();
+        let rejected = Next::new(&middleware, &delivery, &mut processor)
+            .run(Envelope::new(json!(1)))
+            .await;
+
+        assert!(rejected.is_err());
+        assert_eq!(processor.0, 1);
+        assert_eq!(
+            *log.lock().unwrap(),
+            ["outer before", "inner before", "inner after: true", "outer after: true", "outer before", "outer after: false"]
+        );
+    }
+}
@@ -1,12 +1,60 @@
+#[cfg(feature = "pgsqlx")]
+pub mod audit;
+pub mod autoscale;
+pub mod backend;
+pub mod bus;
+pub mod client;
+pub mod codec;
+pub mod compression;
+pub mod connection;
 pub mod consumer;
+pub mod dedup;
+pub mod delay;
+#[cfg(feature = "encryption")]
+pub mod encryption;
+pub mod filter;
+pub mod group;
+#[cfg(feature = "pgsqlx")]
+pub mod inbox;
+pub mod interceptor;
+#[cfg(feature = "mq-kafka")]
+pub mod kafka;
+#[cfg(feature = "mq-management")]
+pub mod management;
+#[cfg(feature = "mq-metrics")]
+pub mod metrics;
+pub mod middleware;
+#[cfg(feature = "pgsqlx")]
+pub mod outbox;
+pub mod pool;
 pub mod producer;
+pub mod quarantine;
+pub mod rate_limit;
+pub mod replay;
+pub mod retry;
+pub mod routing;
+pub mod rpc;
+pub mod saga;
+#[cfg(feature = "schema")]
+pub mod schema;
 pub mod setup;
+#[cfg(feature = "signing")]
+pub mod signing;
+#[cfg(feature = "mq-sqs")]
+pub mod sqs;
+pub mod testing;
+pub mod upcast;
 
-use std::env;
+use std::{collections::BTreeMap, env};
 
+use chrono::{DateTime, Utc};
+use connection::ConnectionConfig;
 use consumer::Consumer;
 use derive_more::{Constructor, From};
-use lapin::{Channel, Connection, ConnectionProperties};
+use lapin::{
+    types::{AMQPValue, FieldTable, ShortString},
+    BasicProperties, Channel,
+};
 use producer::Producer;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
@@ -21,10 +69,59 @@ pub enum MqError {
 
     #[error("Serde JSON Error: {0}")]
     SerdeJsonError(#[from] serde_json::Error),
+
+    #[error("Nacked: broker didn't accept message for exchange {0:?}, routing key {1:?}")]
+    Nacked(String, String),
+
+    #[error("Unroutable: no queue bound for exchange {0:?}, routing key {1:?}: {2}")]
+    Unroutable(String, String, String),
+
+    #[error("RPC Timeout: no reply within {0:?}")]
+    RpcTimeout(std::time::Duration),
+
+    #[error("RPC Reply Lost: the client stopped receiving replies before this call's came")]
+    RpcReplyLost,
+
+    #[error("Timeout: gave up after {0:?}")]
+    Timeout(std::time::Duration),
+
+    #[error("Cancelled")]
+    Cancelled,
+
+    #[error("Codec Error: {0}")]
+    CodecError(String),
+
+    #[error("Unexpected Content Type: expected {0:?}, got {1:?}")]
+    UnexpectedContentType(String, String),
+
+    #[cfg(feature = "pgsqlx")]
+    #[error("Database Error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[cfg(feature = "mq-management")]
+    #[error("Management API Error: {0}")]
+    ManagementError(String),
+
+    #[cfg(feature = "mq-kafka")]
+    #[error("Kafka Error: {0}")]
+    KafkaError(#[from] rdkafka::error::KafkaError),
+
+    #[cfg(feature = "mq-sqs")]
+    #[error("AWS Error: {0}")]
+    AwsError(String),
+
+    #[cfg(feature = "schema")]
+    #[error("Schema Error: {0}")]
+    SchemaError(String),
 }
 
 pub trait CreateChannelConfig {
     fn rabbitmq_url(&self) -> Result<String, MqError>;
+
+    /// How to connect. Defaults to [`CreateChannelConfig::rabbitmq_url`] with nothing else set.
+    fn connection_config(&self) -> Result<ConnectionConfig, MqError> {
+        Ok(ConnectionConfig::new(self.rabbitmq_url()?))
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -35,22 +132,40 @@ impl CreateChannelConfig for CreateChannelConfigFromEnv {
         env::var("RABBITMQ_URL")
             .map_err(|_| MqError::ConfigurationError("RABBITMQ_URL must be set".into()))
     }
+
+    /// See [`ConnectionConfig::from_env`].
+    fn connection_config(&self) -> Result<ConnectionConfig, MqError> {
+        ConnectionConfig::from_env()
+    }
 }
 
 pub async fn create_channel<C: CreateChannelConfig>(config: C) -> Result<Channel, MqError> {
-    let rabbitmq_url = config.rabbitmq_url()?;
-    let connection = Connection::connect(&rabbitmq_url, ConnectionProperties::default()).await?;
+    let connection = config.connection_config()?.connect().await?;
+    let channel = connection.create_channel().await?;
+
+    Ok(channel)
+}
+
+/// Like [`create_channel`], on `vhost` rather than the one configured.
+pub async fn create_vhost_channel<C: CreateChannelConfig>(config: C, vhost: &str) -> Result<Channel, MqError> {
+    let connection = config.connection_config()?.with_vhost(vhost).connect().await?;
     let channel = connection.create_channel().await?;
 
     Ok(channel)
 }
 
 pub trait ChannelOps {
-    fn create_producer(self, exchange: Exchange<'_>) -> Producer<'_>;
-    fn create_consumer<'a>(self, consumer_tag: &'a str, queue: Queue<'a>) -> Consumer<'a>;
+    type Producer<'a>;
+    type Consumer<'a>;
+
+    fn create_producer(self, exchange: Exchange<'_>) -> Self::Producer<'_>;
+    fn create_consumer<'a>(self, consumer_tag: &'a str, queue: Queue<'a>) -> Self::Consumer<'a>;
 }
 
 impl ChannelOps for Channel {
+    type Producer<'a> = Producer<'a>;
+    type Consumer<'a> = Consumer<'a>;
+
     fn create_producer<'a>(self, exchange: Exchange<'a>) -> Producer<'a> {
         Producer::new(self, exchange)
     }
@@ -70,9 +185,32 @@ pub struct Exchange<'a> {
     pub name: &'a str,
 }
 
+/// A message, along with metadata carried in its AMQP properties rather than its body.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Envelope<M> {
     pub message: M,
+
+    /// The version of the message's shape, carried in the body so it survives any transport. See
+    /// [`upcast::Upcaster`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u32>,
+
+    /// When the message stops being worth processing. Also carried in the body, as it's the
+    /// consumer that checks it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    not_after: Option<DateTime<Utc>>,
+
+    #[serde(skip)]
+    message_id: Option<String>,
+
+    #[serde(skip)]
+    correlation_id: Option<String>,
+
+    #[serde(skip)]
+    timestamp: Option<DateTime<Utc>>,
+
+    #[serde(skip)]
+    headers: BTreeMap<String, String>,
 }
 
 impl<M> Envelope<M>
@@ -81,6 +219,212 @@ where
     M: DeserializeOwned,
 {
     pub fn new(message: M) -> Self {
-        Envelope { message }
+        Envelope {
+            message,
+            version: None,
+            not_after: None,
+            message_id: None,
+            correlation_id: None,
+            timestamp: None,
+            headers: BTreeMap::new(),
+        }
+    }
+}
+
+impl<M> Envelope<M> {
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = Some(version);
+        self
+    }
+
+    /// Has consumers drop the message, acking it unprocessed, if they get to it after
+    /// `not_after`, e.g. for a location update a newer one will have replaced by then.
+    pub fn with_not_after(mut self, not_after: DateTime<Utc>) -> Self {
+        self.not_after = Some(not_after);
+        self
+    }
+
+    pub fn with_message_id(mut self, message_id: impl Into<String>) -> Self {
+        self.message_id = Some(message_id.into());
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: impl Into<String>) -> Self {
+        self.correlation_id = Some(correlation_id.into());
+        self
+    }
+
+    /// AMQP timestamps only have a resolution of seconds; anything finer is dropped on publish.
+    pub fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.insert(name.into(), value.into());
+        self
+    }
+
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn not_after(&self) -> Option<DateTime<Utc>> {
+        self.not_after
+    }
+
+    /// Whether the message was past its [`Envelope::not_after`] at `now`.
+    pub fn is_expired_at(&self, now: DateTime<Utc>) -> bool {
+        self.not_after.is_some_and(|not_after| not_after < now)
+    }
+
+    pub fn message_id(&self) -> Option<&str> {
+        self.message_id.as_deref()
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        self.timestamp
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    /// The AMQP properties the envelope's metadata is published with.
+    pub(crate) fn properties(&self) -> BasicProperties {
+        let mut properties = BasicProperties::default();
+        if let Some(message_id) = &self.message_id {
+            properties = properties.with_message_id(message_id.as_str().into());
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            properties = properties.with_correlation_id(correlation_id.as_str().into());
+        }
+        if let Some(timestamp) = self.timestamp {
+            properties = properties.with_timestamp(timestamp.timestamp().max(0) as u64);
+        }
+        if !self.headers.is_empty() {
+            let headers = self
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    (
+                        ShortString::from(name.as_str()),
+                        AMQPValue::LongString(value.as_str().into()),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>();
+            properties = properties.with_headers(FieldTable::from(headers));
+        }
+        properties
+    }
+
+    /// Fills in metadata from the properties a message was delivered with. Headers that aren't
+    /// strings are left out.
+    pub(crate) fn with_properties(mut self, properties: &BasicProperties) -> Self {
+        self.message_id = properties.message_id().as_ref().map(|id| id.to_string());
+        self.correlation_id = properties
+            .correlation_id()
+            .as_ref()
+            .map(|id| id.to_string());
+        self.timestamp = properties
+            .timestamp()
+            .and_then(|ts| DateTime::from_timestamp(ts as i64, 0));
+        self.headers = string_headers(properties);
+        self
+    }
+}
+
+/// The headers in `properties` with string values.
+pub(crate) fn string_headers(properties: &BasicProperties) -> BTreeMap<String, String> {
+    properties
+        .headers()
+        .as_ref()
+        .map(|headers| {
+            headers
+                .inner()
+                .iter()
+                .filter_map(|(name, value)| {
+                    let value = match value {
+                        AMQPValue::LongString(s) => s.to_string(),
+                        AMQPValue::ShortString(s) => s.to_string(),
+                        _ => return None,
+                    };
+                    Some((name.to_string(), value))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+impl Envelope<serde_json::Value> {
+    /// Deserializes the message as `M`, keeping its metadata.
+    pub fn deserialize<M: DeserializeOwned>(self) -> Result<Envelope<M>, serde_json::Error> {
+        Ok(Envelope {
+            message: serde_json::from_value(self.message)?,
+            version: self.version,
+            not_after: self.not_after,
+            message_id: self.message_id,
+            correlation_id: self.correlation_id,
+            timestamp: self.timestamp,
+            headers: self.headers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn envelope_metadata_round_trips_through_properties() {
+        let timestamp = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let sent = Envelope::new("payload".to_string())
+            .with_message_id("m-1")
+            .with_correlation_id("c-1")
+            .with_timestamp(timestamp)
+            .with_header("tenant", "acme");
+
+        // metadata stays out of the body, so existing consumers are unaffected
+        assert_eq!(
+            serde_json::to_string(&sent).unwrap(),
+            r#"{"message":"payload"}"#
+        );
+
+        let received = Envelope::new(String::new()).with_properties(&sent.properties());
+        assert_eq!(received.message_id(), Some("m-1"));
+        assert_eq!(received.correlation_id(), Some("c-1"));
+        assert_eq!(received.timestamp(), Some(timestamp));
+        assert_eq!(received.header("tenant"), Some("acme"));
+    }
+
+    #[test]
+    fn envelope_version_is_in_the_body() {
+        let sent = Envelope::new(1).with_version(2);
+        assert_eq!(serde_json::to_string(&sent).unwrap(), r#"{"message":1,"version":2}"#);
+
+        let unversioned: Envelope<i32> = serde_json::from_str(r#"{"message":1}"#).unwrap();
+        assert_eq!(unversioned.version(), None);
+    }
+
+    #[test]
+    fn envelope_expiry_is_in_the_body() {
+        let not_after = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let sent = Envelope::new(1).with_not_after(not_after);
+        let json = serde_json::to_string(&sent).unwrap();
+        assert_eq!(json, r#"{"message":1,"not_after":"2023-11-14T22:13:20Z"}"#);
+
+        let received: Envelope<i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(received.not_after(), Some(not_after));
+        assert!(!received.is_expired_at(not_after));
+        assert!(received.is_expired_at(not_after + chrono::Duration::milliseconds(1)));
+        assert!(!Envelope::new(1).is_expired_at(not_after));
     }
 }
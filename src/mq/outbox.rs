@@ -0,0 +1,260 @@
+use std::{collections::BTreeMap, future::Future, pin::pin, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{types::Json, PgConnection, PgPool};
+use thiserror::Error;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::{
+    codec::{Codec, Json as JsonCodec},
+    producer::Producer,
+    Envelope, MqError,
+};
+
+/// The table [`publish_via_outbox`] writes to.
+pub const DEFAULT_OUTBOX_TABLE: &str = "outbox";
+
+#[derive(Debug, Error)]
+pub enum OutboxError {
+    #[error("Database Error: {0}")]
+    DatabaseError(#[from] sqlx::Error),
+
+    #[error("MQ Error: {0}")]
+    MqError(#[from] MqError),
+}
+
+/// Messages written to a table in the same transaction as the changes they announce, then
+/// published by an [`OutboxRelay`] once the transaction commits. A message is published if and
+/// only if its transaction commits, at least once: consumers should expect the odd duplicate,
+/// e.g. with a [`DeduplicatingProcessor`](super::dedup::DeduplicatingProcessor).
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    table: String,
+}
+
+impl Outbox {
+    pub fn new(table: impl Into<String>) -> Self {
+        Outbox { table: table.into() }
+    }
+
+    pub async fn create_table(&self, executor: &mut PgConnection) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "create table if not exists {table} (
+                id bigserial primary key,
+                routing_key text not null,
+                message jsonb not null,
+                message_id text not null,
+                correlation_id text,
+                headers jsonb not null,
+                version bigint,
+                not_after timestamptz,
+                created_at timestamptz not null,
+                sent_at timestamptz
+            )",
+            table = self.table
+        ))
+        .execute(&mut *executor)
+        .await?;
+        sqlx::query(&format!(
+            "create index if not exists {table}_unsent on {table} (id) where sent_at is null",
+            table = self.table
+        ))
+        .execute(executor)
+        .await?;
+        Ok(())
+    }
+
+    /// Writes `envelope` to the outbox on `tx`, giving it a message id if it doesn't have one.
+    pub async fn publish<M: Serialize>(
+        &self,
+        tx: &mut PgConnection,
+        envelope: &Envelope<M>,
+        routing_key: Option<&str>,
+    ) -> Result<(), OutboxError> {
+        let message_id = envelope
+            .message_id()
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+        sqlx::query(&format!(
+            "insert into {} (routing_key, message, message_id, correlation_id, headers, version, not_after, created_at)
+            values ($1, $2, $3, $4, $5, $6, $7, $8)",
+            self.table
+        ))
+        .bind(routing_key.unwrap_or_default())
+        .bind(Json(&envelope.message))
+        .bind(message_id)
+        .bind(envelope.correlation_id())
+        .bind(Json(envelope.headers()))
+        .bind(envelope.version().map(i64::from))
+        .bind(envelope.not_after())
+        .bind(envelope.timestamp().unwrap_or_else(Utc::now))
+        .execute(tx)
+        .await?;
+        Ok(())
+    }
+
+    /// Up to `limit` unsent messages, oldest first, with their ids and routing keys, as they were
+    /// written. They're locked until `tx` ends, and skipped by others doing the same meanwhile.
+    pub async fn unsent(
+        &self,
+        tx: &mut PgConnection,
+        limit: i64,
+    ) -> Result<Vec<(i64, String, Envelope<Value>)>, sqlx::Error> {
+        let rows: Vec<OutboxRow> = sqlx::query_as(&format!(
+            "select id, routing_key, message, message_id, correlation_id, headers, version, not_after, created_at
+            from {} where sent_at is null order by id limit $1 for update skip locked",
+            self.table
+        ))
+        .bind(limit)
+        .fetch_all(tx)
+        .await?;
+
+        let unsent = rows
+            .into_iter()
+            .map(|(id, routing_key, message, message_id, correlation_id, headers, version, not_after, created_at)| {
+                let mut envelope = Envelope::new(message.0)
+                    .with_message_id(message_id)
+                    .with_timestamp(created_at);
+                if let Some(correlation_id) = correlation_id {
+                    envelope = envelope.with_correlation_id(correlation_id);
+                }
+                for (name, value) in headers.0 {
+                    envelope = envelope.with_header(name, value);
+                }
+                if let Some(version) = version.and_then(|version| u32::try_from(version).ok()) {
+                    envelope = envelope.with_version(version);
+                }
+                if let Some(not_after) = not_after {
+                    envelope = envelope.with_not_after(not_after);
+                }
+                (id, routing_key, envelope)
+            })
+            .collect();
+        Ok(unsent)
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Outbox::new(DEFAULT_OUTBOX_TABLE)
+    }
+}
+
+/// Writes `envelope` to the default outbox on `tx`. See [`Outbox`].
+pub async fn publish_via_outbox<M: Serialize>(
+    tx: &mut PgConnection,
+    envelope: &Envelope<M>,
+    routing_key: Option<&str>,
+) -> Result<(), OutboxError> {
+    Outbox::default().publish(tx, envelope, routing_key).await
+}
+
+type OutboxRow = (
+    i64,
+    String,
+    Json<Value>,
+    String,
+    Option<String>,
+    Json<BTreeMap<String, String>>,
+    Option<i64>,
+    Option<DateTime<Utc>>,
+    DateTime<Utc>,
+);
+
+/// Publishes messages from an [`Outbox`] in the order they were written, a batch at a time, waiting
+/// for the broker to confirm them before marking them sent. Several relays can drain one outbox; each claims its own rows.
+pub struct OutboxRelay<'a, C: Codec = JsonCodec> {
+    pool: PgPool,
+    outbox: Outbox,
+    producer: Producer<'a, C>,
+    batch_size: i64,
+    poll_interval: Duration,
+}
+
+impl<'a, C: Codec> OutboxRelay<'a, C> {
+    /// A relay publishing with `producer`, which is switched to confirm mode.
+    pub fn new(pool: PgPool, outbox: Outbox, producer: Producer<'a, C>) -> Self {
+        OutboxRelay {
+            pool,
+            outbox,
+            producer: producer.with_confirms(),
+            batch_size: 100,
+            poll_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// How many messages are published per transaction, at least one. Defaults to 100.
+    pub fn with_batch_size(mut self, batch_size: i64) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// How long to wait before checking again once the outbox is empty. Defaults to 1s.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Publishes up to a batch of unsent messages, returning how many were sent. Messages the broker
+    /// confirmed are marked sent even if others failed; those are retried with a later batch, so
+    /// may arrive after messages written after them.
+    pub async fn relay_batch(&self) -> Result<usize, OutboxError> {
+        let mut tx = self.pool.begin().await?;
+        let (ids, envelopes): (Vec<_>, Vec<_>) = self
+            .outbox
+            .unsent(&mut tx, self.batch_size)
+            .await?
+            .into_iter()
+            .map(|(id, routing_key, envelope)| (id, (envelope, Some(routing_key))))
+            .unzip();
+
+        let mut sent = Vec::with_capacity(ids.len());
+        let mut result = Ok(());
+        for (id, published) in ids.into_iter().zip(self.producer.publish_batch(envelopes).await?) {
+            match published {
+                Ok(()) => sent.push(id),
+                Err(e) => result = result.and(Err(e.into())),
+            }
+        }
+
+        if !sent.is_empty() {
+            sqlx::query(&format!("update {} set sent_at = now() where id = any($1)", self.outbox.table))
+                .bind(&sent)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+
+        result.map(|_| sent.len())
+    }
+
+    /// Relays messages until `shutdown` completes, polling while the outbox is empty. Failures are
+    /// logged and retried after the poll interval. Shutdown is checked between batches, so a full
+    /// outbox is drained no further once it completes.
+    pub async fn run_until(&self, shutdown: impl Future<Output = ()>) {
+        let mut shutdown = pin!(shutdown);
+        loop {
+            let wait = match self.relay_batch().await {
+                Ok(sent) if sent as i64 == self.batch_size => Duration::ZERO,
+                Ok(sent) => {
+                    debug!("relayed {sent} messages from the outbox");
+                    self.poll_interval
+                }
+                Err(e) => {
+                    warn!("relaying the outbox failed: {e}");
+                    self.poll_interval
+                }
+            };
+
+            tokio::select! {
+                biased;
+                _ = &mut shutdown => return,
+                _ = tokio::time::sleep(wait) => {}
+            }
+        }
+    }
+}
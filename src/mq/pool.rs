@@ -0,0 +1,106 @@
+use std::{
+    fmt,
+    ops::Deref,
+    sync::{Arc, Mutex},
+};
+
+use lapin::Channel;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::{connection::MqConnectionManager, MqError};
+
+/// A bounded set of channels over a managed connection, created as they're first needed.
+///
+/// Channels aren't safe to publish on concurrently without serializing, so publishers sharing one
+/// wait on each other; checking a channel out of a pool lets up to `max_channels` publish at once.
+#[derive(Clone)]
+pub struct ChannelPool {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    manager: MqConnectionManager,
+    idle: Mutex<Vec<Channel>>,
+    permits: Arc<Semaphore>,
+}
+
+impl ChannelPool {
+    pub fn new(manager: MqConnectionManager, max_channels: usize) -> Self {
+        ChannelPool {
+            inner: Arc::new(Inner {
+                manager,
+                idle: Mutex::new(Vec::with_capacity(max_channels)),
+                permits: Arc::new(Semaphore::new(max_channels)),
+            }),
+        }
+    }
+
+    /// Waits for a channel to be free, reusing an idle one or opening another. The channel goes
+    /// back to the pool when the returned guard is dropped.
+    pub async fn get(&self) -> Result<PooledChannel, MqError> {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("pool semaphore is never closed");
+
+        // channels closed by the broker, or left behind by a lost connection, are discarded
+        let idle = {
+            let mut idle = self.idle();
+            std::iter::from_fn(|| idle.pop()).find(|c| c.status().connected())
+        };
+        let channel = match idle {
+            Some(channel) => channel,
+            None => self.inner.manager.channel().await?,
+        };
+
+        Ok(PooledChannel {
+            channel: Some(channel),
+            pool: self.inner.clone(),
+            _permit: permit,
+        })
+    }
+
+    /// Channels open and waiting to be checked out.
+    pub fn idle_channels(&self) -> usize {
+        self.idle().len()
+    }
+
+    fn idle(&self) -> std::sync::MutexGuard<'_, Vec<Channel>> {
+        self.inner.idle.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl fmt::Debug for ChannelPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelPool")
+            .field("idle_channels", &self.idle_channels())
+            .field("available", &self.inner.permits.available_permits())
+            .finish()
+    }
+}
+
+/// A channel checked out of a [`ChannelPool`].
+pub struct PooledChannel {
+    channel: Option<Channel>,
+    pool: Arc<Inner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl Deref for PooledChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Channel {
+        self.channel.as_ref().expect("channel is only taken on drop")
+    }
+}
+
+impl Drop for PooledChannel {
+    fn drop(&mut self) {
+        if let Some(channel) = self.channel.take().filter(|c| c.status().connected()) {
+            self.pool.idle.lock().unwrap_or_else(|e| e.into_inner()).push(channel);
+        }
+    }
+}
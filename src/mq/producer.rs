@@ -1,31 +1,531 @@
-use super::*;
-use lapin::{options::BasicPublishOptions, BasicProperties, Channel};
+use std::{
+    collections::HashSet,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::{
+    codec::{Codec, Json},
+    compression::Compression,
+    delay::DelayedDelivery,
+    interceptor::{Interceptors, OutgoingMessage, PublishInterceptor},
+    pool::ChannelPool,
+    rate_limit::{RateLimit, RateLimiter},
+    *,
+};
+use lapin::{
+    options::{BasicPublishOptions, ConfirmSelectOptions},
+    publisher_confirm::{Confirmation, PublisherConfirm},
+    BasicProperties, Channel,
+};
 use serde::Serialize;
 
 type ProducerResult<T> = Result<T, MqError>;
 
-#[derive(Debug, Clone, Constructor)]
-pub struct Producer<'a> {
-    channel: Channel,
+#[derive(Debug, Clone)]
+pub struct Producer<'a, C: Codec = Json> {
+    channels: Channels,
     exchange: Exchange<'a>,
+    options: ProducerOptions,
+    interceptors: Interceptors,
+    codec: C,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    delayed_delivery: DelayedDelivery,
+    /// Where delayed messages wait, once declared.
+    delays_declared: Arc<Mutex<HashSet<String>>>,
 }
 
-impl Producer<'_> {
-    pub async fn publish<M: Serialize, R: Into<String>>(&self, envelope: Envelope<M>, routing_key: Option<R>) -> ProducerResult<()> {
-        let payload = serde_json::to_string(&envelope)?;
-        let routing_key = routing_key.map(|r| r.into()).unwrap_or("".into());
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProducerOptions {
+    /// Put the channel in confirm mode and wait for the broker to acknowledge each message, which
+    /// it does once the message is routed (and persisted, if durable), before `publish` returns.
+    pub confirms: bool,
+
+    /// Have the broker return messages no queue is bound to receive, failing the publish with
+    /// [`MqError::Unroutable`]. Returns are only reported alongside confirms, so this implies `confirms`.
+    pub mandatory: bool,
+
+    /// Compress large payloads. Consumers decompress them transparently.
+    pub compression: Option<Compression>,
+}
+
+/// Options for a single message. The defaults leave everything to the queue.
+#[derive(Debug, Clone, Default)]
+pub struct PublishOptions {
+    /// Discard the message if it's still queued after this long. Queues may set a shorter TTL of
+    /// their own with [`QueueOptions::MessageTTL`](super::setup::QueueOptions::MessageTTL).
+    pub expiration: Option<Duration>,
+
+    /// Have the broker write the message to disk (delivery mode 2), so it survives a restart of a
+    /// durable queue, or keep it in memory only (delivery mode 1).
+    pub persistent: Option<bool>,
+
+    /// Delivered ahead of lower priority messages on queues declared with
+    /// [`QueueOptions::MaxPriority`](super::setup::QueueOptions::MaxPriority), up to that maximum.
+    pub priority: Option<u8>,
+
+    /// Sent alongside the envelope's own headers, replacing any with the same name.
+    pub headers: BTreeMap<String, String>,
+}
+
+impl PublishOptions {
+    pub(crate) fn apply(&self, mut properties: BasicProperties) -> BasicProperties {
+        if let Some(expiration) = self.expiration {
+            properties = properties.with_expiration(expiration.as_millis().to_string().into());
+        }
+        if let Some(persistent) = self.persistent {
+            properties = properties.with_delivery_mode(if persistent { 2 } else { 1 });
+        }
+        if let Some(priority) = self.priority {
+            properties = properties.with_priority(priority);
+        }
+        if !self.headers.is_empty() {
+            let mut headers = properties.headers().clone().unwrap_or_default();
+            for (name, value) in &self.headers {
+                headers.insert(name.as_str().into(), AMQPValue::LongString(value.as_str().into()));
+            }
+            properties = properties.with_headers(headers);
+        }
+        properties
+    }
+}
+
+impl ProducerOptions {
+    fn confirms(&self) -> bool {
+        self.confirms || self.mandatory
+    }
+}
+
+/// Where a producer publishes from.
+#[derive(Debug, Clone)]
+enum Channels {
+    Single(Channel),
+    Pooled(ChannelPool),
+}
+
+impl<'a> Producer<'a> {
+    pub fn new(channel: Channel, exchange: Exchange<'a>) -> Self {
+        Producer {
+            channels: Channels::Single(channel),
+            exchange,
+            options: ProducerOptions::default(),
+            interceptors: Interceptors::default(),
+            codec: Json,
+            rate_limiter: None,
+            delayed_delivery: DelayedDelivery::default(),
+            delays_declared: Arc::default(),
+        }
+    }
+
+    /// Publishes on a channel checked out of `pool` for each message, so clones of the producer
+    /// can publish concurrently.
+    pub fn pooled(pool: ChannelPool, exchange: Exchange<'a>) -> Self {
+        Producer {
+            channels: Channels::Pooled(pool),
+            exchange,
+            options: ProducerOptions::default(),
+            interceptors: Interceptors::default(),
+            codec: Json,
+            rate_limiter: None,
+            delayed_delivery: DelayedDelivery::default(),
+            delays_declared: Arc::default(),
+        }
+    }
+}
+
+impl<'a, C: Codec> Producer<'a, C> {
+    /// Encodes messages with `codec` rather than JSON. Consumers need the same codec to read them.
+    pub fn with_codec<D: Codec>(self, codec: D) -> Producer<'a, D> {
+        Producer {
+            channels: self.channels,
+            exchange: self.exchange,
+            options: self.options,
+            interceptors: self.interceptors,
+            codec,
+            rate_limiter: self.rate_limiter,
+            delayed_delivery: self.delayed_delivery,
+            delays_declared: self.delays_declared,
+        }
+    }
 
+    pub fn with_options(mut self, options: ProducerOptions) -> Self {
+        self.options = options;
         self
-            .channel
+    }
+
+    /// Runs `interceptor` on every message just before it's published, after any added before it.
+    pub fn with_interceptor(mut self, interceptor: impl PublishInterceptor + 'static) -> Self {
+        self.interceptors.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Holds publishing to `limit`, waiting before each message until it's within the limit.
+    /// Clones made afterwards share the limit.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limiter = Some(Arc::new(RateLimiter::new(limit, Instant::now())));
+        self
+    }
+
+    /// Delays messages from [`Producer::publish_delayed`] with `delayed_delivery`, rather than by
+    /// dead lettering.
+    pub fn with_delayed_delivery(mut self, delayed_delivery: DelayedDelivery) -> Self {
+        self.delayed_delivery = delayed_delivery;
+        self
+    }
+
+    /// Turns on [`ProducerOptions::confirms`], keeping the other options.
+    pub fn with_confirms(mut self) -> Self {
+        self.options.confirms = true;
+        self
+    }
+
+    pub async fn publish<M: Serialize, R: Into<String>>(
+        &self,
+        envelope: Envelope<M>,
+        routing_key: Option<R>,
+    ) -> ProducerResult<()> {
+        self.publish_with(envelope, routing_key, &PublishOptions::default()).await
+    }
+
+    pub async fn publish_with<M: Serialize, R: Into<String>>(
+        &self,
+        envelope: Envelope<M>,
+        routing_key: Option<R>,
+        options: &PublishOptions,
+    ) -> ProducerResult<()> {
+        let message = self.outgoing(envelope, routing_key, options)?;
+        let started = Instant::now();
+        let result = self.send(&message).await;
+        self.published(&message, started, &result);
+        result
+    }
+
+    /// Like [`Producer::publish`], but gives up with [`MqError::Timeout`] at `deadline`, e.g. while
+    /// the broker blocks publishers under flow control, or a channel can't be had. A message given
+    /// up on may still have reached the broker, so publish it again only if duplicates are fine.
+    pub async fn publish_with_deadline<M: Serialize, R: Into<String>>(
+        &self,
+        envelope: Envelope<M>,
+        routing_key: Option<R>,
+        deadline: Instant,
+    ) -> ProducerResult<()> {
+        let message = self.outgoing(envelope, routing_key, &PublishOptions::default())?;
+        let started = Instant::now();
+        let result = tokio::time::timeout_at(deadline.into(), self.send(&message))
+            .await
+            .unwrap_or_else(|_| Err(MqError::Timeout(deadline.saturating_duration_since(started))));
+        self.published(&message, started, &result);
+        result
+    }
+
+    /// Like [`Producer::publish`], but gives up with [`MqError::Cancelled`] once `cancel`
+    /// completes, e.g. with a `CancellationToken`'s `cancelled()`. As with
+    /// [`Producer::publish_with_deadline`], a message given up on may still have been published.
+    pub async fn publish_until<M: Serialize, R: Into<String>>(
+        &self,
+        envelope: Envelope<M>,
+        routing_key: Option<R>,
+        cancel: impl Future<Output = ()>,
+    ) -> ProducerResult<()> {
+        let message = self.outgoing(envelope, routing_key, &PublishOptions::default())?;
+        let started = Instant::now();
+        let result = tokio::select! {
+            result = self.send(&message) => result,
+            _ = cancel => Err(MqError::Cancelled),
+        };
+        self.published(&message, started, &result);
+        result
+    }
+
+    /// Like [`Producer::publish`], but the message isn't delivered until `delay` has passed. With
+    /// confirms on, the broker confirms it once it's waiting, not once it's delivered.
+    pub async fn publish_delayed<M: Serialize, R: Into<String>>(
+        &self,
+        envelope: Envelope<M>,
+        routing_key: Option<R>,
+        delay: Duration,
+    ) -> ProducerResult<()> {
+        let message = self.outgoing(envelope, routing_key, &PublishOptions::default())?;
+        let started = Instant::now();
+        let result = self.send_delayed(&message, delay).await;
+        self.published(&message, started, &result);
+        result
+    }
+
+    /// Publishes every envelope before waiting on any, then, with confirms on, waits for the broker
+    /// to confirm them all. Returns a result per envelope, in order; the outer result fails only if
+    /// no channel could be had.
+    pub async fn publish_batch<M: Serialize, R: Into<String>>(
+        &self,
+        envelopes: impl IntoIterator<Item = (Envelope<M>, Option<R>)>,
+    ) -> ProducerResult<Vec<ProducerResult<()>>> {
+        let pooled;
+        let channel = match &self.channels {
+            Channels::Single(channel) => channel,
+            Channels::Pooled(pool) => {
+                pooled = pool.get().await?;
+                &*pooled
+            }
+        };
+        self.select_confirms(channel).await?;
+
+        let started = Instant::now();
+        let mut pending = Vec::new();
+        for (envelope, routing_key) in envelopes {
+            let sent = match self.outgoing(envelope, routing_key, &PublishOptions::default()) {
+                Ok(message) => {
+                    let confirm = self.basic_publish(channel, &message).await;
+                    Ok((message, confirm))
+                }
+                Err(e) => Err(e),
+            };
+            pending.push(sent);
+        }
+
+        let mut results = Vec::with_capacity(pending.len());
+        for sent in pending {
+            let result = match sent {
+                Ok((message, confirm)) => {
+                    let result = match confirm {
+                        Ok(confirm) => self.confirmed(&message, confirm).await,
+                        Err(e) => Err(e),
+                    };
+                    self.published(&message, started, &result);
+                    result
+                }
+                Err(e) => Err(e),
+            };
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// Tells the interceptors how publishing a message went, and records it in the metrics.
+    fn published(&self, message: &OutgoingMessage, started: Instant, result: &ProducerResult<()>) {
+        self.interceptors.published(message, result, started.elapsed());
+        #[cfg(feature = "mq-metrics")]
+        metrics::published(&message.exchange, started, result);
+    }
+
+    /// Encodes and compresses a message, then hands it to the interceptors.
+    fn outgoing<M: Serialize, R: Into<String>>(
+        &self,
+        envelope: Envelope<M>,
+        routing_key: Option<R>,
+        options: &PublishOptions,
+    ) -> ProducerResult<OutgoingMessage> {
+        let payload = self.codec.encode(&envelope)?;
+        let mut properties = options
+            .apply(envelope.properties())
+            .with_content_type(self.codec.content_type().into());
+        let payload = match &self.options.compression {
+            Some(compression) => {
+                let (payload, encoding) = compression.compress(payload)?;
+                if let Some(encoding) = encoding {
+                    properties = properties.with_content_encoding(encoding.name().into());
+                }
+                payload
+            }
+            None => payload,
+        };
+        #[cfg(feature = "tracing")]
+        let properties = with_trace_context(properties);
+        let routing_key = routing_key.map(|r| r.into()).unwrap_or("".into());
+
+        let mut message = OutgoingMessage {
+            exchange: self.exchange.name.into(),
+            routing_key,
+            payload,
+            properties,
+        };
+        self.interceptors.intercept(&mut message)?;
+        Ok(message)
+    }
+
+    async fn send(&self, message: &OutgoingMessage) -> ProducerResult<()> {
+        let pooled;
+        let channel = match &self.channels {
+            Channels::Single(channel) => channel,
+            Channels::Pooled(pool) => {
+                pooled = pool.get().await?;
+                &*pooled
+            }
+        };
+
+        self.select_confirms(channel).await?;
+        let confirm = self.basic_publish(channel, message).await?;
+        self.confirmed(message, confirm).await
+    }
+
+    async fn send_delayed(&self, message: &OutgoingMessage, delay: Duration) -> ProducerResult<()> {
+        let pooled;
+        let channel = match &self.channels {
+            Channels::Single(channel) => channel,
+            Channels::Pooled(pool) => {
+                pooled = pool.get().await?;
+                &*pooled
+            }
+        };
+
+        self.delayed_delivery
+            .declare(channel, &self.delays_declared, &message.exchange, delay)
+            .await?;
+        let message = self.delayed_delivery.delayed(message, delay)?;
+        self.select_confirms(channel).await?;
+        let confirm = self.basic_publish(channel, &message).await?;
+        self.confirmed(&message, confirm).await
+    }
+
+    async fn select_confirms(&self, channel: &Channel) -> ProducerResult<()> {
+        if self.options.confirms() && !channel.status().confirm() {
+            channel
+                .confirm_select(ConfirmSelectOptions::default())
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn basic_publish(&self, channel: &Channel, message: &OutgoingMessage) -> ProducerResult<PublisherConfirm> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(message.payload.len()).await;
+        }
+        let confirm = channel
             .basic_publish(
-                self.exchange.name,
-                &routing_key,
-                BasicPublishOptions::default(),
-                payload.as_bytes(),
-                BasicProperties::default(),
+                &message.exchange,
+                &message.routing_key,
+                BasicPublishOptions {
+                    mandatory: self.options.mandatory,
+                    ..Default::default()
+                },
+                &message.payload,
+                message.properties.clone(),
             )
             .await?;
+        Ok(confirm)
+    }
+
+    /// Waits for the broker to confirm a message, when confirms are on.
+    async fn confirmed(&self, message: &OutgoingMessage, confirm: PublisherConfirm) -> ProducerResult<()> {
+        if self.options.confirms() {
+            match confirm.await? {
+                Confirmation::Ack(Some(returned)) => {
+                    return Err(MqError::Unroutable(
+                        message.exchange.clone(),
+                        message.routing_key.clone(),
+                        returned.reply_text.to_string(),
+                    ))
+                }
+                Confirmation::Nack(_) => {
+                    return Err(MqError::Nacked(message.exchange.clone(), message.routing_key.clone()))
+                }
+                Confirmation::Ack(None) | Confirmation::NotRequested => {}
+            }
+        }
 
         Ok(())
     }
 }
+
+/// Adds a `traceparent` header for the current span, so consumers continue its trace, unless the
+/// message already names one.
+#[cfg(feature = "tracing")]
+pub(crate) fn with_trace_context(properties: BasicProperties) -> BasicProperties {
+    use crate::tracing::context::{TraceContext, TRACEPARENT};
+
+    let Some(context) = TraceContext::current() else {
+        return properties;
+    };
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    if headers.inner().contains_key(TRACEPARENT) {
+        return properties;
+    }
+    headers.insert(TRACEPARENT.into(), AMQPValue::LongString(context.traceparent().into()));
+    properties.with_headers(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mq::connection::{ConnectionConfig, MqConnectionManager};
+
+    #[test]
+    fn publish_options_properties() {
+        let options = PublishOptions {
+            expiration: Some(Duration::from_secs(30)),
+            persistent: Some(false),
+            priority: Some(5),
+            headers: BTreeMap::from([("source".to_string(), "telemetry".to_string())]),
+        };
+        let envelope = Envelope::new(String::from("reading")).with_header("tenant", "acme");
+        let properties = options.apply(envelope.properties());
+
+        assert_eq!(properties.expiration().as_ref().map(|e| e.as_str()), Some("30000"));
+        assert_eq!(*properties.delivery_mode(), Some(1));
+        assert_eq!(*properties.priority(), Some(5));
+
+        let headers = properties.headers().as_ref().unwrap().inner();
+        assert_eq!(headers.get("source"), Some(&AMQPValue::LongString("telemetry".into())));
+        assert_eq!(headers.get("tenant"), Some(&AMQPValue::LongString("acme".into())));
+    }
+
+    struct Stamp;
+
+    impl PublishInterceptor for Stamp {
+        fn intercept(&self, message: &mut OutgoingMessage) -> Result<(), MqError> {
+            if message.routing_key == "forbidden" {
+                return Err(MqError::ConfigurationError("forbidden".into()));
+            }
+            message.properties = message.properties.clone().with_app_id("billing".into());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interceptors_see_outgoing_messages() {
+        let manager = MqConnectionManager::new(CreateChannelConfigFromUrl).unwrap();
+        let producer = Producer::pooled(ChannelPool::new(manager, 1), "events".into()).with_interceptor(Stamp);
+
+        let message = producer
+            .outgoing(Envelope::new(String::from("hello")), Some("greeting"), &PublishOptions::default())
+            .unwrap();
+        assert_eq!(message.exchange, "events");
+        assert_eq!(message.routing_key, "greeting");
+        assert_eq!(message.properties.app_id().as_ref().map(|a| a.as_str()), Some("billing"));
+        assert_eq!(message.payload, br#"{"message":"hello"}"#);
+
+        let refused = producer.outgoing(Envelope::new(String::from("hello")), Some("forbidden"), &PublishOptions::default());
+        assert!(refused.is_err());
+    }
+
+    #[tokio::test]
+    async fn gives_up_publishing() {
+        // nothing listens there, so the producer is stuck reconnecting
+        let manager = MqConnectionManager::new(ConnectionConfig::new("amqp://127.0.0.1:1")).unwrap();
+        let producer = Producer::pooled(ChannelPool::new(manager, 1), "events".into());
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let late = producer.publish_with_deadline(Envelope::new(1), Some("tick"), deadline).await;
+        assert!(matches!(late, Err(MqError::Timeout(timeout)) if timeout <= Duration::from_millis(50)));
+
+        let cancelled = producer
+            .publish_until(Envelope::new(1), Some("tick"), tokio::time::sleep(Duration::from_millis(50)))
+            .await;
+        assert!(matches!(cancelled, Err(MqError::Cancelled)));
+    }
+
+    struct CreateChannelConfigFromUrl;
+
+    impl CreateChannelConfig for CreateChannelConfigFromUrl {
+        fn rabbitmq_url(&self) -> Result<String, MqError> {
+            Ok("amqp://localhost:5672".into())
+        }
+    }
+
+    #[test]
+    fn default_publish_options_leave_properties_alone() {
+        let properties = PublishOptions::default().apply(BasicProperties::default());
+
+        assert_eq!(properties, BasicProperties::default());
+    }
+}
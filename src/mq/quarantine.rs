@@ -0,0 +1,389 @@
+//! Keeps messages that failed for good, so they can be looked at and republished once whatever
+//! failed them is fixed, rather than shovelled out of a dead letter queue by hand.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use futures::future::LocalBoxFuture;
+use lapin::{
+    message::Delivery,
+    options::BasicPublishOptions,
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, FieldTable, ShortString},
+    BasicProperties, Channel,
+};
+use uuid::Uuid;
+
+use super::{retry, string_headers, MqError};
+
+/// A delivery that failed permanently, or ran out of retries, as it was received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedMessage {
+    pub id: Uuid,
+
+    /// The queue the message was consumed from, and is republished to.
+    pub queue: String,
+    pub routing_key: String,
+    pub payload: Vec<u8>,
+    pub content_type: Option<String>,
+    pub content_encoding: Option<String>,
+    pub message_id: Option<String>,
+    pub correlation_id: Option<String>,
+
+    /// The message's headers with string values. Others, like the retry attempt, are left out, so
+    /// a republished message starts over.
+    pub headers: BTreeMap<String, String>,
+    pub error: String,
+    pub attempts: u32,
+    pub quarantined_at: DateTime<Utc>,
+}
+
+impl QuarantinedMessage {
+    pub(crate) fn new(queue: &str, delivery: &Delivery, error: &str) -> Self {
+        let properties = &delivery.properties;
+        QuarantinedMessage {
+            id: Uuid::new_v4(),
+            queue: queue.into(),
+            routing_key: delivery.routing_key.to_string(),
+            payload: delivery.data.clone(),
+            content_type: properties.content_type().as_ref().map(|s| s.to_string()),
+            content_encoding: properties.content_encoding().as_ref().map(|s| s.to_string()),
+            message_id: properties.message_id().as_ref().map(|s| s.to_string()),
+            correlation_id: properties.correlation_id().as_ref().map(|s| s.to_string()),
+            headers: string_headers(properties),
+            error: error.into(),
+            attempts: retry::attempt(delivery),
+            quarantined_at: Utc::now(),
+        }
+    }
+
+    /// The properties the message is republished with.
+    pub(crate) fn properties(&self) -> BasicProperties {
+        let mut properties = BasicProperties::default();
+        if let Some(content_type) = &self.content_type {
+            properties = properties.with_content_type(content_type.as_str().into());
+        }
+        if let Some(content_encoding) = &self.content_encoding {
+            properties = properties.with_content_encoding(content_encoding.as_str().into());
+        }
+        if let Some(message_id) = &self.message_id {
+            properties = properties.with_message_id(message_id.as_str().into());
+        }
+        if let Some(correlation_id) = &self.correlation_id {
+            properties = properties.with_correlation_id(correlation_id.as_str().into());
+        }
+        if !self.headers.is_empty() {
+            let headers = self
+                .headers
+                .iter()
+                .map(|(name, value)| (ShortString::from(name.as_str()), AMQPValue::LongString(value.as_str().into())))
+                .collect::<BTreeMap<_, _>>();
+            properties = properties.with_headers(FieldTable::from(headers));
+        }
+        properties
+    }
+}
+
+/// Where a [`Consumer`](super::consumer::Consumer) with a quarantine keeps the messages it gives
+/// up on, added with [`Consumer::with_quarantine`](super::consumer::Consumer::with_quarantine).
+pub trait QuarantineStore {
+    async fn insert(&self, message: &QuarantinedMessage) -> Result<(), MqError>;
+
+    /// Up to `limit` messages, oldest first, only those from `queue` if given.
+    async fn list(&self, queue: Option<&str>, limit: usize) -> Result<Vec<QuarantinedMessage>, MqError>;
+
+    /// Forgets a message, returning whether there was one with the id.
+    async fn remove(&self, id: Uuid) -> Result<bool, MqError>;
+
+    /// Publishes `message` back to the queue it was consumed from, through the default exchange,
+    /// then removes it. On a channel in confirm mode, it's only removed once the broker confirms it.
+    async fn republish(&self, channel: &Channel, message: &QuarantinedMessage) -> Result<(), MqError> {
+        let confirmation = channel
+            .basic_publish(
+                "",
+                &message.queue,
+                BasicPublishOptions::default(),
+                &message.payload,
+                message.properties(),
+            )
+            .await?
+            .await?;
+        if let Confirmation::Nack(_) = confirmation {
+            return Err(MqError::Nacked(String::new(), message.queue.clone()));
+        }
+        self.remove(message.id).await?;
+        Ok(())
+    }
+}
+
+impl<S: QuarantineStore> QuarantineStore for Arc<S> {
+    async fn insert(&self, message: &QuarantinedMessage) -> Result<(), MqError> {
+        S::insert(self, message).await
+    }
+
+    async fn list(&self, queue: Option<&str>, limit: usize) -> Result<Vec<QuarantinedMessage>, MqError> {
+        S::list(self, queue, limit).await
+    }
+
+    async fn remove(&self, id: Uuid) -> Result<bool, MqError> {
+        S::remove(self, id).await
+    }
+}
+
+/// [`QuarantineStore`] made object safe, so consumers don't need a type parameter for one.
+pub(crate) trait ErasedQuarantineStore: Send + Sync {
+    fn insert_boxed<'a>(&'a self, message: &'a QuarantinedMessage) -> LocalBoxFuture<'a, Result<(), MqError>>;
+}
+
+impl<S: QuarantineStore + Send + Sync> ErasedQuarantineStore for S {
+    fn insert_boxed<'a>(&'a self, message: &'a QuarantinedMessage) -> LocalBoxFuture<'a, Result<(), MqError>> {
+        Box::pin(self.insert(message))
+    }
+}
+
+/// Keeps quarantined messages in memory, for tests and tools that don't outlive them.
+#[derive(Debug, Default)]
+pub struct MemoryQuarantineStore {
+    messages: Mutex<Vec<QuarantinedMessage>>,
+}
+
+impl MemoryQuarantineStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn messages(&self) -> std::sync::MutexGuard<'_, Vec<QuarantinedMessage>> {
+        self.messages.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl QuarantineStore for MemoryQuarantineStore {
+    async fn insert(&self, message: &QuarantinedMessage) -> Result<(), MqError> {
+        self.messages().push(message.clone());
+        Ok(())
+    }
+
+    async fn list(&self, queue: Option<&str>, limit: usize) -> Result<Vec<QuarantinedMessage>, MqError> {
+        Ok(self
+            .messages()
+            .iter()
+            .filter(|m| queue.is_none_or(|queue| m.queue == queue))
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn remove(&self, id: Uuid) -> Result<bool, MqError> {
+        let mut messages = self.messages();
+        let before = messages.len();
+        messages.retain(|m| m.id != id);
+        Ok(messages.len() < before)
+    }
+}
+
+#[cfg(feature = "pgsqlx")]
+type QuarantineRow = (
+    Uuid,
+    String,
+    String,
+    Vec<u8>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    sqlx::types::Json<BTreeMap<String, String>>,
+    String,
+    i32,
+    DateTime<Utc>,
+);
+
+/// Keeps quarantined messages in a Postgres table, shared between consumers and the tools that
+/// list and republish them.
+#[cfg(feature = "pgsqlx")]
+#[derive(Debug, Clone)]
+pub struct PgQuarantineStore {
+    pool: sqlx::PgPool,
+    table: String,
+}
+
+#[cfg(feature = "pgsqlx")]
+impl PgQuarantineStore {
+    pub fn new(pool: sqlx::PgPool, table: impl Into<String>) -> Self {
+        PgQuarantineStore {
+            pool,
+            table: table.into(),
+        }
+    }
+
+    pub async fn create_table(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            "create table if not exists {} (
+                id uuid primary key,
+                queue text not null,
+                routing_key text not null,
+                payload bytea not null,
+                content_type text,
+                content_encoding text,
+                message_id text,
+                correlation_id text,
+                headers jsonb not null,
+                error text not null,
+                attempts integer not null,
+                quarantined_at timestamptz not null
+            )",
+            self.table
+        ))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "pgsqlx")]
+impl QuarantineStore for PgQuarantineStore {
+    async fn insert(&self, message: &QuarantinedMessage) -> Result<(), MqError> {
+        sqlx::query(&format!(
+            "insert into {} (id, queue, routing_key, payload, content_type, content_encoding, message_id,
+                correlation_id, headers, error, attempts, quarantined_at)
+            values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12)",
+            self.table
+        ))
+        .bind(message.id)
+        .bind(&message.queue)
+        .bind(&message.routing_key)
+        .bind(&message.payload)
+        .bind(&message.content_type)
+        .bind(&message.content_encoding)
+        .bind(&message.message_id)
+        .bind(&message.correlation_id)
+        .bind(sqlx::types::Json(&message.headers))
+        .bind(&message.error)
+        .bind(i32::try_from(message.attempts).unwrap_or(i32::MAX))
+        .bind(message.quarantined_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn list(&self, queue: Option<&str>, limit: usize) -> Result<Vec<QuarantinedMessage>, MqError> {
+        let rows: Vec<QuarantineRow> = sqlx::query_as(&format!(
+            "select id, queue, routing_key, payload, content_type, content_encoding, message_id,
+                correlation_id, headers, error, attempts, quarantined_at
+            from {} where $1::text is null or queue = $1 order by quarantined_at, id limit $2",
+            self.table
+        ))
+        .bind(queue)
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(
+                |(
+                    id,
+                    queue,
+                    routing_key,
+                    payload,
+                    content_type,
+                    content_encoding,
+                    message_id,
+                    correlation_id,
+                    headers,
+                    error,
+                    attempts,
+                    quarantined_at,
+                )| QuarantinedMessage {
+                    id,
+                    queue,
+                    routing_key,
+                    payload,
+                    content_type,
+                    content_encoding,
+                    message_id,
+                    correlation_id,
+                    headers: headers.0,
+                    error,
+                    attempts: attempts.max(0) as u32,
+                    quarantined_at,
+                },
+            )
+            .collect())
+    }
+
+    async fn remove(&self, id: Uuid) -> Result<bool, MqError> {
+        let removed = sqlx::query(&format!("delete from {} where id = $1", self.table))
+            .bind(id)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin::acker::Acker;
+
+    use super::*;
+    use crate::mq::retry::ATTEMPT_HEADER;
+
+    fn delivery(routing_key: &str) -> Delivery {
+        let mut headers = FieldTable::default();
+        headers.insert("tenant".into(), AMQPValue::LongString("acme".into()));
+        headers.insert(ATTEMPT_HEADER.into(), AMQPValue::LongUInt(3));
+        Delivery {
+            delivery_tag: 1,
+            exchange: "events".into(),
+            routing_key: routing_key.into(),
+            redelivered: false,
+            properties: BasicProperties::default()
+                .with_content_type("application/json".into())
+                .with_message_id("m-1".into())
+                .with_headers(headers),
+            data: b"{}".to_vec(),
+            acker: Acker::default(),
+        }
+    }
+
+    #[test]
+    fn keeps_what_republishing_needs() {
+        let message = QuarantinedMessage::new("orders", &delivery("order.created"), "no such customer");
+
+        assert_eq!(message.routing_key, "order.created");
+        assert_eq!(message.attempts, 3);
+        assert_eq!(message.payload, b"{}");
+
+        let properties = message.properties();
+        assert_eq!(properties.content_type().as_ref().map(|s| s.as_str()), Some("application/json"));
+        assert_eq!(properties.message_id().as_ref().map(|s| s.as_str()), Some("m-1"));
+        // the attempt isn't carried over, so a republished message gets its retries back
+        assert_eq!(
+            string_headers(&properties),
+            BTreeMap::from([("tenant".to_string(), "acme".to_string())])
+        );
+        assert!(properties.headers().as_ref().unwrap().inner().get(ATTEMPT_HEADER).is_none());
+    }
+
+    #[tokio::test]
+    async fn lists_and_removes() {
+        let store = MemoryQuarantineStore::new();
+        let order = QuarantinedMessage::new("orders", &delivery("order.created"), "failed");
+        let payment = QuarantinedMessage::new("payments", &delivery("payment.taken"), "failed");
+        store.insert(&order).await.unwrap();
+        store.insert(&payment).await.unwrap();
+
+        let ids = |messages: Vec<QuarantinedMessage>| messages.into_iter().map(|m| m.id).collect::<Vec<_>>();
+
+        assert_eq!(ids(store.list(None, 10).await.unwrap()), [order.id, payment.id]);
+        assert_eq!(ids(store.list(Some("payments"), 10).await.unwrap()), [payment.id]);
+        assert_eq!(ids(store.list(None, 1).await.unwrap()), [order.id]);
+
+        assert!(store.remove(order.id).await.unwrap());
+        assert!(!store.remove(order.id).await.unwrap());
+        assert_eq!(ids(store.list(None, 10).await.unwrap()), [payment.id]);
+    }
+}
@@ -0,0 +1,131 @@
+//! Token buckets holding a producer to a rate, so bulk publishing can't swamp the broker or the
+//! consumers downstream.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How fast a [`Producer`](super::producer::Producer) may publish, added with
+/// [`Producer::with_rate_limit`](super::producer::Producer::with_rate_limit). Either limit may be
+/// used alone; unset ones don't apply. Bursts of up to a second's worth go out without waiting.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    pub messages_per_second: Option<u32>,
+
+    /// Counted by payload, after encoding and compression.
+    pub bytes_per_second: Option<u64>,
+}
+
+/// A [`RateLimit`]'s buckets, shared by clones of the producer.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    buckets: Mutex<(Option<Bucket>, Option<Bucket>)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit, now: Instant) -> Self {
+        RateLimiter {
+            buckets: Mutex::new((
+                limit.messages_per_second.map(|rate| Bucket::new(rate as f64, now)),
+                limit.bytes_per_second.map(|rate| Bucket::new(rate as f64, now)),
+            )),
+        }
+    }
+
+    /// Waits until a message of `bytes` may be published.
+    pub(crate) async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes, Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes a message's tokens, returning how long to wait before they're actually there. Tokens
+    /// are taken up front, so concurrent publishers queue up behind each other.
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let (messages, byte_count) = &mut *self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let messages = messages.as_mut().map_or(Duration::ZERO, |b| b.take(1.0, now));
+        let bytes = byte_count.as_mut().map_or(Duration::ZERO, |b| b.take(bytes as f64, now));
+        messages.max(bytes)
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: f64, now: Instant) -> Self {
+        Bucket {
+            rate,
+            tokens: rate,
+            updated: now,
+        }
+    }
+
+    /// Takes `n` tokens, going into debt if there aren't enough, and returns how long until the
+    /// debt is paid off.
+    fn take(&mut self, n: f64, now: Instant) -> Duration {
+        if self.rate <= 0.0 {
+            return Duration::MAX;
+        }
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - n;
+        self.updated = now;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_messages() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(
+            RateLimit {
+                messages_per_second: Some(2),
+                ..Default::default()
+            },
+            start,
+        );
+
+        // a second's worth goes straight out, then each waits its turn
+        assert_eq!(limiter.reserve(0, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(0, start), Duration::ZERO);
+        assert_eq!(limiter.reserve(0, start), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(0, start), Duration::from_secs(1));
+
+        // once the backlog has gone out, the bucket fills up again
+        assert_eq!(limiter.reserve(0, start + Duration::from_secs(3)), Duration::ZERO);
+    }
+
+    #[test]
+    fn limits_bytes() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(
+            RateLimit {
+                messages_per_second: Some(100),
+                bytes_per_second: Some(1000),
+            },
+            start,
+        );
+
+        assert_eq!(limiter.reserve(1000, start), Duration::ZERO);
+        // larger than a second's worth, so it waits for what it's short
+        assert_eq!(limiter.reserve(1500, start), Duration::from_millis(1500));
+        assert_eq!(
+            limiter.reserve(1000, start + Duration::from_secs(2)),
+            Duration::from_millis(500)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Replaying dead lettered messages once whatever made them fail is fixed, see
+//! [`Consumer::replay_dead_letters`](super::consumer::Consumer::replay_dead_letters).
+
+use std::collections::BTreeMap;
+
+use lapin::{
+    types::{AMQPValue, FieldTable},
+    BasicProperties,
+};
+
+use super::string_headers;
+
+/// Header counting how many times a message has been replayed from a dead letter queue.
+pub const REPLAY_COUNT_HEADER: &str = "x-replay-count";
+
+/// A message in a dead letter queue, as shown to the filter choosing which to replay. Where it
+/// came from is read from the headers the broker adds when dead lettering it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// The routing key the message was first published with.
+    pub routing_key: String,
+
+    /// The exchange the message was first published to.
+    pub exchange: Option<String>,
+
+    /// The queue the message was first dead lettered from.
+    pub queue: Option<String>,
+
+    /// Why it was first dead lettered: `rejected`, `expired`, `maxlen` or `delivery_limit`.
+    pub reason: Option<String>,
+
+    /// How many times it's been replayed before.
+    pub replay_count: u32,
+
+    /// The message's headers with string values.
+    pub headers: BTreeMap<String, String>,
+    pub payload: Vec<u8>,
+}
+
+impl DeadLetter {
+    /// Reads a message delivered from a dead letter queue with `routing_key`.
+    pub(crate) fn new(routing_key: &str, properties: &BasicProperties, payload: &[u8]) -> Self {
+        let headers = string_headers(properties);
+        let first_death = |name: &str| headers.get(&format!("x-first-death-{name}")).cloned();
+        let queue = first_death("queue");
+
+        let table = properties.headers().clone().unwrap_or_default();
+        let deaths = match table.inner().get("x-death") {
+            Some(AMQPValue::FieldArray(deaths)) => deaths
+                .as_slice()
+                .iter()
+                .filter_map(|death| match death {
+                    AMQPValue::FieldTable(death) => Some(death.clone()),
+                    _ => None,
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        // most recent first, so the last is the first death, unless it's one the queue matches
+        let first = deaths
+            .iter()
+            .find(|death| queue.is_some() && text(death, "queue") == queue)
+            .or(deaths.last());
+        let original_key = first.and_then(|death| match death.inner().get("routing-keys") {
+            Some(AMQPValue::FieldArray(keys)) => keys.as_slice().iter().find_map(|key| match key {
+                AMQPValue::LongString(key) => Some(key.to_string()),
+                AMQPValue::ShortString(key) => Some(key.to_string()),
+                _ => None,
+            }),
+            _ => None,
+        });
+
+        DeadLetter {
+            routing_key: original_key.unwrap_or_else(|| routing_key.into()),
+            exchange: first_death("exchange").or_else(|| first.and_then(|death| text(death, "exchange"))),
+            reason: first_death("reason").or_else(|| first.and_then(|death| text(death, "reason"))),
+            queue,
+            replay_count: replay_count(&table),
+            headers,
+            payload: payload.to_vec(),
+        }
+    }
+}
+
+fn text(table: &FieldTable, name: &str) -> Option<String> {
+    match table.inner().get(name) {
+        Some(AMQPValue::LongString(s)) => Some(s.to_string()),
+        Some(AMQPValue::ShortString(s)) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+fn replay_count(headers: &FieldTable) -> u32 {
+    match headers.inner().get(REPLAY_COUNT_HEADER) {
+        Some(AMQPValue::LongUInt(n)) => *n,
+        Some(AMQPValue::LongLongInt(n)) => u32::try_from(*n).unwrap_or_default(),
+        Some(AMQPValue::LongString(s)) => s.to_string().parse().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// The properties to replay a message with: its own, with the replay count incremented.
+pub(crate) fn replay_properties(properties: &BasicProperties) -> BasicProperties {
+    let mut headers = properties.headers().clone().unwrap_or_default();
+    let count = replay_count(&headers) + 1;
+    headers.insert(REPLAY_COUNT_HEADER.into(), AMQPValue::LongUInt(count));
+    properties.clone().with_headers(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin::types::FieldArray;
+
+    use super::*;
+
+    fn death(queue: &str, reason: &str, routing_key: &str) -> AMQPValue {
+        let mut death = FieldTable::default();
+        death.insert("queue".into(), AMQPValue::LongString(queue.into()));
+        death.insert("reason".into(), AMQPValue::LongString(reason.into()));
+        death.insert("exchange".into(), AMQPValue::LongString("orders".into()));
+        death.insert(
+            "routing-keys".into(),
+            AMQPValue::FieldArray(FieldArray::from(vec![AMQPValue::LongString(routing_key.into())])),
+        );
+        AMQPValue::FieldTable(death)
+    }
+
+    #[test]
+    fn reads_where_a_dead_letter_came_from() {
+        let mut headers = FieldTable::default();
+        headers.insert(
+            "x-death".into(),
+            AMQPValue::FieldArray(FieldArray::from(vec![
+                death("orders.retry.1", "expired", "orders"),
+                death("orders", "rejected", "order.created"),
+            ])),
+        );
+        headers.insert("x-first-death-queue".into(), AMQPValue::LongString("orders".into()));
+        headers.insert("x-first-death-reason".into(), AMQPValue::LongString("rejected".into()));
+        headers.insert("tenant".into(), AMQPValue::LongString("acme".into()));
+        let properties = BasicProperties::default().with_headers(headers);
+
+        let dead_letter = DeadLetter::new("orders.dlq", &properties, b"{}");
+        assert_eq!(dead_letter.routing_key, "order.created");
+        assert_eq!(dead_letter.exchange.as_deref(), Some("orders"));
+        assert_eq!(dead_letter.queue.as_deref(), Some("orders"));
+        assert_eq!(dead_letter.reason.as_deref(), Some("rejected"));
+        assert_eq!(dead_letter.replay_count, 0);
+        assert_eq!(dead_letter.headers.get("tenant").map(String::as_str), Some("acme"));
+
+        let replayed = replay_properties(&replay_properties(&properties));
+        assert_eq!(DeadLetter::new("orders.dlq", &replayed, b"{}").replay_count, 2);
+    }
+
+    #[test]
+    fn keeps_the_routing_key_without_death_headers() {
+        let dead_letter = DeadLetter::new("order.created", &BasicProperties::default(), b"{}");
+        assert_eq!(dead_letter.routing_key, "order.created");
+        assert_eq!(dead_letter.queue, None);
+    }
+}
@@ -0,0 +1,193 @@
+use std::time::Duration;
+
+use lapin::{
+    message::Delivery,
+    options::{BasicAckOptions, BasicNackOptions, BasicPublishOptions},
+    publisher_confirm::Confirmation,
+    types::{AMQPValue, FieldTable},
+    Channel,
+};
+
+use tracing::warn;
+
+use super::{setup, MqError};
+
+/// Header counting how many times a message has been delivered for processing, starting at 1.
+pub const ATTEMPT_HEADER: &str = "x-retry-attempt";
+
+/// How temporarily failed messages are retried.
+///
+/// Each retry waits in its own queue, `<queue>.retry.<attempt>`, which dead letters back to the
+/// consumed queue once the delay has passed. Those queues have to exist: include
+/// [`RetryPolicy::retry_queues`] in the topology. Once attempts run out, messages are rejected
+/// without requeueing, so they're dead lettered if the consumed queue has a dead letter exchange.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    multiplier: u32,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Doubles the delay after each attempt, up to an hour.
+    pub fn exponential(max_attempts: u32, initial_delay: Duration) -> Self {
+        RetryPolicy {
+            max_attempts,
+            initial_delay,
+            multiplier: 2,
+            max_delay: Duration::from_secs(60 * 60),
+        }
+    }
+
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// How long to wait after a failed `attempt` before the next, or `None` once there are no more.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt == 0 || attempt >= self.max_attempts {
+            return None;
+        }
+
+        let factor = self.multiplier.saturating_pow(attempt - 1);
+        Some(self.initial_delay.saturating_mul(factor).min(self.max_delay))
+    }
+
+    pub fn retry_queue_name(queue: &str, attempt: u32) -> String {
+        format!("{queue}.retry.{attempt}")
+    }
+
+    /// The queues retries of messages from `queue` wait in, one per attempt that can be retried.
+    pub fn retry_queues(&self, queue: &str) -> Vec<setup::Queue<String>> {
+        (1..self.max_attempts)
+            .filter_map(|attempt| Some((attempt, self.delay(attempt)?)))
+            .map(|(attempt, delay)| {
+                setup::Queue::new(
+                    Self::retry_queue_name(queue, attempt),
+                    vec![
+                        setup::QueueOptions::Persistence(true),
+                        setup::QueueOptions::MessageTTL(delay.as_millis().min(u32::MAX as u128) as u32),
+                        // the default exchange routes straight back to the queue by name
+                        setup::QueueOptions::DeadLetterExchange("".into()),
+                        setup::QueueOptions::DeadLetterRoutingKey(queue.into()),
+                    ],
+                )
+            })
+            .collect()
+    }
+
+    /// Schedules a temporarily failed delivery from `queue` for another attempt, or rejects it
+    /// once attempts are exhausted. The delivery is acked once its retry is published; with
+    /// confirms on the channel, only once the broker confirms it was routed to the retry queue.
+    /// Without confirms, a retry the broker can't route is lost. A retry that fails is logged and
+    /// the delivery requeued, so consuming carries on.
+    pub(crate) async fn retry(&self, channel: &Channel, queue: &str, delivery: &Delivery) -> Result<(), MqError> {
+        let attempt = attempt(delivery);
+        if self.delay(attempt).is_none() {
+            delivery
+                .nack(BasicNackOptions {
+                    multiple: false,
+                    requeue: false,
+                })
+                .await?;
+            return Ok(());
+        }
+
+        let mut headers = delivery.properties.headers().clone().unwrap_or_default();
+        headers.insert(ATTEMPT_HEADER.into(), AMQPValue::LongUInt(attempt + 1));
+        let properties = delivery.properties.clone().with_headers(headers);
+
+        let retry_queue = Self::retry_queue_name(queue, attempt);
+        let published = async {
+            let confirm = channel
+                .basic_publish(
+                    "",
+                    &retry_queue,
+                    BasicPublishOptions {
+                        mandatory: true,
+                        ..Default::default()
+                    },
+                    &delivery.data,
+                    properties,
+                )
+                .await?;
+            match confirm.await? {
+                Confirmation::Ack(Some(returned)) => Err(MqError::Unroutable(
+                    String::new(),
+                    retry_queue.clone(),
+                    returned.reply_text.to_string(),
+                )),
+                Confirmation::Nack(_) => Err(MqError::Nacked(String::new(), retry_queue.clone())),
+                Confirmation::Ack(None) | Confirmation::NotRequested => Ok(()),
+            }
+        };
+
+        match published.await {
+            Ok(()) => {
+                delivery.ack(BasicAckOptions::default()).await?;
+                Ok(())
+            }
+            Err(e) => {
+                // put back for another go rather than lost, e.g. if the retry queue is missing
+                warn!("scheduling a retry in {retry_queue} failed, requeueing the message: {e}");
+                delivery
+                    .nack(BasicNackOptions {
+                        multiple: false,
+                        requeue: true,
+                    })
+                    .await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Which attempt a delivery is, according to its [`ATTEMPT_HEADER`].
+pub fn attempt(delivery: &Delivery) -> u32 {
+    delivery
+        .properties
+        .headers()
+        .as_ref()
+        .map_or(1, attempt_from_headers)
+}
+
+pub(crate) fn attempt_from_headers(headers: &FieldTable) -> u32 {
+    match headers.inner().get(ATTEMPT_HEADER) {
+        Some(AMQPValue::LongUInt(n)) => *n,
+        Some(AMQPValue::LongLongInt(n)) => u32::try_from(*n).unwrap_or(1),
+        Some(AMQPValue::LongString(s)) => s.to_string().parse().unwrap_or(1),
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy::exponential(4, Duration::from_secs(1)).with_max_delay(Duration::from_secs(3));
+        let delays = (1..=4).map(|attempt| policy.delay(attempt)).collect::<Vec<_>>();
+        assert_eq!(
+            delays,
+            [Some(Duration::from_secs(1)), Some(Duration::from_secs(2)), Some(Duration::from_secs(3)), None]
+        );
+        assert_eq!(policy.retry_queues("orders").len(), 3);
+    }
+
+    #[test]
+    fn reads_attempt_header() {
+        let mut headers = FieldTable::default();
+        assert_eq!(attempt_from_headers(&headers), 1);
+
+        headers.insert(ATTEMPT_HEADER.into(), AMQPValue::LongUInt(3));
+        assert_eq!(attempt_from_headers(&headers), 3);
+    }
+}
@@ -0,0 +1,72 @@
+//! Routing keys made of named segments, e.g. `person_id.42.item_id.7`, so the keys producers
+//! publish with and the patterns queues are bound with can't drift apart.
+
+use std::fmt::Display;
+
+pub use launchpad_derive::RoutingKey;
+
+/// A routing key rendered from a message's fields, along with a builder for binding patterns
+/// matching it. Usually derived, with a segment per field in declaration order:
+///
+/// ```
+/// use launchpad::mq::routing::RoutingKey;
+///
+/// #[derive(RoutingKey)]
+/// #[routing_key(prefix = "item")]
+/// struct ItemKey {
+///     person_id: String,
+///     item_id: u64,
+/// }
+///
+/// let key = ItemKey { person_id: "me".into(), item_id: 7 };
+/// assert_eq!(key.routing_key(), "item.person_id.me.item_id.7");
+/// assert_eq!(ItemKey::pattern().person_id("me").to_string(), "item.person_id.me.item_id.*");
+/// ```
+///
+/// Fields are renamed with `#[routing_key(rename = "...")]`, or left out with `#[routing_key(skip)]`.
+pub trait RoutingKey {
+    type Pattern: Display + Default;
+
+    fn routing_key(&self) -> String;
+
+    /// A pattern matching every key, to be narrowed down segment by segment.
+    fn pattern() -> Self::Pattern;
+}
+
+/// Joins segments into a key or pattern.
+#[derive(Debug, Clone, Default)]
+pub struct Segments(Vec<String>);
+
+impl Segments {
+    pub fn new(prefix: Option<&str>) -> Self {
+        Segments(prefix.into_iter().map(String::from).collect())
+    }
+
+    /// Adds `name.value`, or `name.*` to match any value. Dots in the value would split it in two,
+    /// so they're replaced with underscores.
+    pub fn push(&mut self, name: &str, value: Option<impl Display>) {
+        self.0.push(name.into());
+        self.0.push(value.map_or("*".into(), |v| v.to_string().replace('.', "_")));
+    }
+
+    pub fn finish(self) -> String {
+        self.0.join(".")
+    }
+}
+
+/// Whether `routing_key` matches `pattern` the way a topic binding would: `*` matches exactly one
+/// word and `#` any number of words.
+pub(crate) fn pattern_matches(pattern: &str, routing_key: &str) -> bool {
+    let pattern = pattern.split('.').collect::<Vec<_>>();
+    let words = routing_key.split('.').collect::<Vec<_>>();
+    words_match(&pattern, &words)
+}
+
+fn words_match(pattern: &[&str], words: &[&str]) -> bool {
+    match (pattern.first(), words.first()) {
+        (None, None) => true,
+        (Some(&"#"), _) => words_match(&pattern[1..], words) || (!words.is_empty() && words_match(pattern, &words[1..])),
+        (Some(p), Some(w)) if *p == "*" || p == w => words_match(&pattern[1..], &words[1..]),
+        _ => false,
+    }
+}
@@ -0,0 +1,324 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use futures::{channel::oneshot, StreamExt};
+use lapin::{
+    options::{BasicConsumeOptions, BasicPublishOptions, QueueDeclareOptions},
+    types::FieldTable,
+    BasicProperties, Channel,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, warn, Instrument};
+use uuid::Uuid;
+
+use super::{
+    consumer::{delivery_span, handle_message_result, ProcessorError},
+    Envelope, MqError,
+};
+
+/// Sends requests and waits for their replies, which come back on a queue exclusive to the client.
+pub struct RpcClient {
+    channel: Channel,
+    exchange: String,
+    routing_key: String,
+    reply_queue: String,
+    pending: PendingCalls,
+}
+
+impl RpcClient {
+    /// A client sending requests to `exchange` with `routing_key`, where an [`RpcServer`] is consuming.
+    pub async fn new(channel: Channel, exchange: &str, routing_key: &str) -> Result<Self, MqError> {
+        let reply_queue = channel
+            .queue_declare(
+                "",
+                QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        let reply_queue = reply_queue.name().to_string();
+        let dispatch_queue = reply_queue.clone();
+
+        let mut replies = channel
+            .basic_consume(
+                &reply_queue,
+                "",
+                BasicConsumeOptions {
+                    no_ack: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+
+        let pending = PendingCalls::default();
+        let dispatch = pending.clone();
+        tokio::spawn(async move {
+            while let Some(delivery) = replies.next().await {
+                match delivery {
+                    Ok(delivery) => dispatch.reply(&delivery.properties, delivery.data),
+                    Err(e) => warn!("receiving replies on {dispatch_queue} failed: {e}"),
+                }
+            }
+            warn!("stopped receiving replies, failing the calls waiting for them");
+            dispatch.close();
+        });
+
+        Ok(RpcClient {
+            channel,
+            exchange: exchange.into(),
+            routing_key: routing_key.into(),
+            reply_queue,
+            pending,
+        })
+    }
+
+    /// Sends `payload` and waits up to `timeout` for the reply. Fails with
+    /// [`MqError::RpcReplyLost`] straight away once the client has stopped receiving replies.
+    pub async fn call<M, R>(&self, payload: M, timeout: Duration) -> Result<R, MqError>
+    where
+        M: Serialize + DeserializeOwned,
+        R: DeserializeOwned,
+    {
+        let (correlation_id, body, properties) = request(payload, &self.reply_queue)?;
+        #[cfg(feature = "tracing")]
+        let properties = super::producer::with_trace_context(properties);
+
+        let replied = self.pending.expect(&correlation_id);
+        let result = async {
+            self.channel
+                .basic_publish(&self.exchange, &self.routing_key, BasicPublishOptions::default(), &body, properties)
+                .await?;
+            wait_for_reply(replied?, timeout).await
+        }
+        .await;
+
+        self.pending.forget(&correlation_id);
+        result
+    }
+}
+
+/// Encodes a request for `payload`, returning its correlation id along with it.
+fn request<M: Serialize + DeserializeOwned>(payload: M, reply_queue: &str) -> Result<(String, Vec<u8>, BasicProperties), MqError> {
+    let correlation_id = Uuid::new_v4().to_string();
+    let envelope = Envelope::new(payload).with_correlation_id(correlation_id.clone());
+    let body = serde_json::to_vec(&envelope)?;
+    let properties = envelope.properties().with_reply_to(reply_queue.into());
+    Ok((correlation_id, body, properties))
+}
+
+/// Waits up to `timeout` for a reply and decodes it.
+async fn wait_for_reply<R: DeserializeOwned>(replied: oneshot::Receiver<Vec<u8>>, timeout: Duration) -> Result<R, MqError> {
+    match tokio::time::timeout(timeout, replied).await {
+        Ok(Ok(reply)) => Ok(serde_json::from_slice::<Envelope<R>>(&reply)?.message),
+        Ok(Err(_)) => Err(MqError::RpcReplyLost),
+        Err(_) => Err(MqError::RpcTimeout(timeout)),
+    }
+}
+
+type Replies = HashMap<String, oneshot::Sender<Vec<u8>>>;
+
+/// The calls waiting for replies, by correlation id. Once closed, because replies stopped coming,
+/// calls fail straight away rather than waiting out their timeout.
+#[derive(Clone)]
+struct PendingCalls {
+    waiting: Arc<Mutex<Option<Replies>>>,
+}
+
+impl Default for PendingCalls {
+    fn default() -> Self {
+        PendingCalls {
+            waiting: Arc::new(Mutex::new(Some(HashMap::new()))),
+        }
+    }
+}
+
+impl PendingCalls {
+    fn expect(&self, correlation_id: &str) -> Result<oneshot::Receiver<Vec<u8>>, MqError> {
+        let (reply, replied) = oneshot::channel();
+        match self.lock().as_mut() {
+            Some(waiting) => waiting.insert(correlation_id.into(), reply),
+            None => return Err(MqError::RpcReplyLost),
+        };
+        Ok(replied)
+    }
+
+    /// Hands a reply to the call waiting for it, if it's still waiting.
+    fn reply(&self, properties: &BasicProperties, body: Vec<u8>) {
+        let correlation_id = properties.correlation_id().as_ref().map(|id| id.to_string());
+        let waiting = correlation_id.and_then(|id| self.lock().as_mut()?.remove(&id));
+        match waiting {
+            Some(reply) => {
+                let _ = reply.send(body);
+            }
+            // the caller gave up waiting
+            None => debug!("discarding reply nobody is waiting for"),
+        }
+    }
+
+    fn forget(&self, correlation_id: &str) {
+        if let Some(waiting) = self.lock().as_mut() {
+            waiting.remove(correlation_id);
+        }
+    }
+
+    /// Fails the calls waiting, and those made from now on.
+    fn close(&self) {
+        *self.lock() = None;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Replies>> {
+        self.waiting.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Handles requests sent by an [`RpcClient`], returning the reply to send back.
+pub trait RpcHandler {
+    type Request: DeserializeOwned;
+    type Response: Serialize + DeserializeOwned;
+
+    async fn handle(&mut self, request: Self::Request) -> Result<Self::Response, ProcessorError>;
+}
+
+/// Serves requests from a queue, replying to each on the queue named by its `reply_to`.
+pub struct RpcServer<'a> {
+    channel: Channel,
+    consumer_tag: &'a str,
+    queue: &'a str,
+}
+
+impl<'a> RpcServer<'a> {
+    pub fn new(channel: Channel, consumer_tag: &'a str, queue: &'a str) -> Self {
+        RpcServer {
+            channel,
+            consumer_tag,
+            queue,
+        }
+    }
+
+    /// Serves requests until the consumer is cancelled. Requests are only acked once their reply is
+    /// sent; failures are nacked like any other processor's, and the caller is left to time out.
+    pub async fn serve<H: RpcHandler>(&self, handler: &mut H) -> Result<(), MqError> {
+        let mut requests = self
+            .channel
+            .basic_consume(self.queue, self.consumer_tag, BasicConsumeOptions::default(), FieldTable::default())
+            .await?;
+
+        while let Some(delivery) = requests.next().await {
+            let delivery = delivery?;
+            let span = delivery_span(self.queue, &delivery);
+            let result = match respond(handler, &delivery.data, &delivery.properties).instrument(span).await {
+                Ok(Some((reply_to, body, properties))) => {
+                    // replies go through the default exchange, straight to the caller's queue
+                    self.channel
+                        .basic_publish("", &reply_to, BasicPublishOptions::default(), &body, properties)
+                        .await?;
+                    Ok(())
+                }
+                Ok(None) => Ok(()),
+                Err(e) => Err(e),
+            };
+
+            handle_message_result(&delivery, &result).await?;
+        }
+
+        warn!("no request, finishing");
+        Ok(())
+    }
+}
+
+/// Handles an encoded request, returning the reply to send and the queue to send it to, or `None`
+/// if the request has no `reply_to`.
+async fn respond<H: RpcHandler>(
+    handler: &mut H,
+    data: &[u8],
+    properties: &BasicProperties,
+) -> Result<Option<(String, Vec<u8>, BasicProperties)>, ProcessorError> {
+    let request = serde_json::from_slice::<Envelope<H::Request>>(data)
+        .map_err(|e| ProcessorError::PermanentError(e.to_string()))?;
+    let response = handler.handle(request.message).await?;
+
+    let Some(reply_to) = properties.reply_to().as_ref().map(|r| r.to_string()) else {
+        warn!("request has no reply_to, dropping the response");
+        return Ok(None);
+    };
+    let body = serde_json::to_vec(&Envelope::new(response))
+        .map_err(|e| ProcessorError::PermanentError(format!("can't encode the response: {e}")))?;
+    let mut reply_properties = BasicProperties::default();
+    if let Some(correlation_id) = properties.correlation_id().clone() {
+        reply_properties = reply_properties.with_correlation_id(correlation_id);
+    }
+    Ok(Some((reply_to, body, reply_properties)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mq::{
+        setup::{self, TopologyOps},
+        testing::InMemoryBroker,
+    };
+
+    struct Double;
+
+    impl RpcHandler for Double {
+        type Request = i32;
+        type Response = i32;
+
+        async fn handle(&mut self, request: i32) -> Result<i32, ProcessorError> {
+            Ok(request * 2)
+        }
+    }
+
+    #[tokio::test]
+    async fn replies_to_the_caller() {
+        let broker = InMemoryBroker::new();
+        for queue in ["doubler", "replies"] {
+            broker.with_queue(&setup::Queue::new(queue, vec![])).await.unwrap();
+        }
+        let pending = PendingCalls::default();
+
+        let (correlation_id, body, properties) = request(21, "replies").unwrap();
+        let replied = pending.expect(&correlation_id).unwrap();
+        let (_, other_body, other_properties) = request(1, "replies").unwrap();
+        broker.publish_raw("", "doubler", other_body, other_properties).unwrap();
+        broker.publish_raw("", "doubler", body, properties).unwrap();
+
+        for message in broker.messages("doubler") {
+            let (reply_to, body, properties) = respond(&mut Double, &message.body, &message.properties).await.unwrap().unwrap();
+            broker.publish_raw("", &reply_to, body, properties).unwrap();
+        }
+        // the reply to a call nobody is waiting on is dropped
+        for message in broker.messages("replies") {
+            pending.reply(&message.properties, message.body);
+        }
+
+        assert_eq!(wait_for_reply::<i32>(replied, Duration::from_secs(1)).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn times_out_without_a_reply() {
+        let pending = PendingCalls::default();
+        let replied = pending.expect("unanswered").unwrap();
+
+        let e = wait_for_reply::<i32>(replied, Duration::from_millis(10)).await.unwrap_err();
+        assert!(matches!(e, MqError::RpcTimeout(_)), "{e}");
+    }
+
+    #[tokio::test]
+    async fn fails_calls_once_replies_stop() {
+        let pending = PendingCalls::default();
+        let replied = pending.expect("waiting").unwrap();
+        pending.close();
+
+        let e = wait_for_reply::<i32>(replied, Duration::from_secs(60)).await.unwrap_err();
+        assert!(matches!(e, MqError::RpcReplyLost), "{e}");
+        assert!(matches!(pending.expect("later"), Err(MqError::RpcReplyLost)));
+    }
+}
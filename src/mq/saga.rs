@@ -0,0 +1,528 @@
+//! Sagas: workflows spanning services, run as a state machine that sends a command, waits for the
+//! reply, and moves on to the next step, undoing what it's done if a step fails or its reply
+//! doesn't come in time.
+
+use std::{
+    collections::HashMap,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use super::{
+    backend::Publisher,
+    consumer::{MessageContext, Processor, ProcessorError},
+    Envelope, MqError,
+};
+
+/// A workflow's state, and how it moves from step to step. It's stored as JSON between steps, so
+/// keep in it whatever later steps, or compensation, need to know.
+pub trait Saga: Serialize + DeserializeOwned {
+    /// Tells this kind of saga apart from others in the store.
+    const NAME: &'static str;
+
+    /// The replies the saga's commands are answered with.
+    type Reply: DeserializeOwned;
+
+    /// The first step.
+    fn start(&mut self) -> Step;
+
+    /// The step after the last one's `reply`.
+    fn on_reply(&mut self, reply: Self::Reply, context: &MessageContext) -> Step;
+
+    /// The step after the last one has gone unanswered for its timeout. Fails the saga by default.
+    fn on_timeout(&mut self) -> Step {
+        Step::Fail("timed out waiting for a reply".into())
+    }
+
+    /// Commands undoing what the saga has done so far, sent once it fails.
+    fn compensate(&mut self) -> Vec<Command> {
+        Vec::new()
+    }
+}
+
+/// What a saga does next.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Sends `command`, then waits up to `timeout` for its reply.
+    Send { command: Command, timeout: Duration },
+    Complete,
+    /// Sends the saga's compensating commands, and gives up.
+    Fail(String),
+}
+
+/// A message a saga publishes, correlated with the saga so the reply finds its way back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub routing_key: String,
+    pub message: Value,
+}
+
+impl Command {
+    pub fn new(routing_key: impl Into<String>, message: Value) -> Self {
+        Command {
+            routing_key: routing_key.into(),
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SagaStatus {
+    /// Waiting for the reply to a command.
+    Running,
+    Completed,
+    /// Failed, with its compensating commands sent.
+    Compensated,
+}
+
+impl SagaStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SagaStatus::Running => "running",
+            SagaStatus::Completed => "completed",
+            SagaStatus::Compensated => "compensated",
+        }
+    }
+
+    pub fn parse(status: &str) -> Option<Self> {
+        [SagaStatus::Running, SagaStatus::Completed, SagaStatus::Compensated]
+            .into_iter()
+            .find(|s| s.as_str() == status)
+    }
+}
+
+/// A saga as it's stored between steps.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "pgsqlx", derive(crate::Entity, sqlx::FromRow))]
+#[cfg_attr(feature = "pgsqlx", entity(table_name = "saga"))]
+pub struct SagaRecord {
+    #[cfg_attr(feature = "pgsqlx", key(primary))]
+    pub id: Uuid,
+    pub name: String,
+    /// Counts the steps taken, so a late reply to an earlier step isn't taken for the current one's.
+    pub step: i32,
+    pub state: Value,
+    /// A [`SagaStatus`].
+    pub status: String,
+    /// Why the saga failed.
+    pub error: Option<String>,
+    /// When the current step times out.
+    pub deadline: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Keeps sagas between steps.
+pub trait SagaStore {
+    async fn load(&self, id: Uuid) -> Result<Option<SagaRecord>, MqError>;
+
+    /// Inserts or replaces the saga with `record`'s id.
+    async fn save(&self, record: &SagaRecord) -> Result<(), MqError>;
+
+    /// Running sagas called `name` whose step timed out before `now`.
+    async fn expired(&self, name: &str, now: DateTime<Utc>) -> Result<Vec<SagaRecord>, MqError>;
+}
+
+impl<S: SagaStore> SagaStore for Arc<S> {
+    async fn load(&self, id: Uuid) -> Result<Option<SagaRecord>, MqError> {
+        S::load(self, id).await
+    }
+
+    async fn save(&self, record: &SagaRecord) -> Result<(), MqError> {
+        S::save(self, record).await
+    }
+
+    async fn expired(&self, name: &str, now: DateTime<Utc>) -> Result<Vec<SagaRecord>, MqError> {
+        S::expired(self, name, now).await
+    }
+}
+
+/// Runs sagas of type `S`: starts them, and as a [`Processor`] of the queue their replies arrive
+/// on, takes them a step further with each reply. Replies are matched to sagas by correlation id,
+/// which services answering commands have to copy from the command to the reply.
+///
+/// Timeouts are only noticed by [`SagaRunner::check_timeouts`], so call it every so often. Run one
+/// consumer of replies at a time: two steps of the same saga taken at once would overwrite each
+/// other.
+pub struct SagaRunner<S, P, St> {
+    publisher: P,
+    store: St,
+    saga: PhantomData<fn() -> S>,
+}
+
+impl<S: Saga, P: Publisher, St: SagaStore> SagaRunner<S, P, St> {
+    pub fn new(publisher: P, store: St) -> Self {
+        SagaRunner {
+            publisher,
+            store,
+            saga: PhantomData,
+        }
+    }
+
+    /// Starts `saga`, returning its id.
+    pub async fn start(&self, mut saga: S) -> Result<Uuid, MqError> {
+        let id = Uuid::new_v4();
+        let step = saga.start();
+        self.advance(id, 0, saga, step).await?;
+        info!("started saga {} {id}", S::NAME);
+        Ok(id)
+    }
+
+    /// Takes every saga whose step has timed out on to its next step, returning how many.
+    pub async fn check_timeouts(&self) -> Result<usize, MqError> {
+        let expired = self.store.expired(S::NAME, Utc::now()).await?;
+        for record in &expired {
+            warn!("saga {} {} timed out at step {}", S::NAME, record.id, record.step);
+            let mut saga: S = serde_json::from_value(record.state.clone())?;
+            let step = saga.on_timeout();
+            self.advance(record.id, record.step, saga, step).await?;
+        }
+        Ok(expired.len())
+    }
+
+    /// Takes `step`, after `taken` steps, saving the saga as it then stands before sending
+    /// anything, so a reply, however quick, finds it waiting. Should sending fail, the step times
+    /// out as if unanswered.
+    async fn advance(&self, id: Uuid, taken: i32, mut saga: S, step: Step) -> Result<(), MqError> {
+        let step_number = taken + 1;
+        let (status, deadline, error, commands) = match step {
+            Step::Send { command, timeout } => (SagaStatus::Running, Some(Utc::now() + timeout), None, vec![command]),
+            Step::Complete => (SagaStatus::Completed, None, None, vec![]),
+            Step::Fail(error) => {
+                warn!("saga {} {id} failed, compensating: {error}", S::NAME);
+                let commands = saga.compensate();
+                (SagaStatus::Compensated, None, Some(error), commands)
+            }
+        };
+
+        self.store
+            .save(&SagaRecord {
+                id,
+                name: S::NAME.into(),
+                step: step_number,
+                state: serde_json::to_value(&saga)?,
+                status: status.as_str().into(),
+                error,
+                deadline,
+                updated_at: Utc::now(),
+            })
+            .await?;
+        for command in commands {
+            self.send(id, step_number, command).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&self, id: Uuid, step: i32, command: Command) -> Result<(), MqError> {
+        let envelope = Envelope::new(command.message)
+            .with_message_id(Uuid::new_v4().to_string())
+            .with_correlation_id(correlation_id(id, step));
+        self.publisher.publish_envelope(envelope, Some(&command.routing_key)).await
+    }
+}
+
+/// Names the saga and the step a command was sent for, `<saga id>.<step>`.
+fn correlation_id(id: Uuid, step: i32) -> String {
+    format!("{id}.{step}")
+}
+
+fn parse_correlation_id(correlation_id: &str) -> Option<(Uuid, i32)> {
+    let (id, step) = correlation_id.rsplit_once('.')?;
+    Some((Uuid::parse_str(id).ok()?, step.parse().ok()?))
+}
+
+impl<S: Saga, P: Publisher, St: SagaStore> Processor for SagaRunner<S, P, St> {
+    async fn process(&mut self, _message: Value, _context: &MessageContext) -> Result<(), ProcessorError> {
+        Err(ProcessorError::PermanentError("saga replies need a correlation id".into()))
+    }
+
+    async fn process_envelope(&mut self, envelope: Envelope<Value>, context: &MessageContext) -> Result<(), ProcessorError> {
+        let Some((id, step)) = envelope.correlation_id().and_then(parse_correlation_id) else {
+            return Err(ProcessorError::PermanentError("reply isn't correlated with a saga".into()));
+        };
+        let temporary = |e: MqError| ProcessorError::TemporaryError(e.to_string());
+
+        let Some(record) = self.store.load(id).await.map_err(temporary)? else {
+            warn!("no saga {id}, dropping its reply");
+            return Ok(());
+        };
+        if record.name != S::NAME
+            || record.step != step
+            || SagaStatus::parse(&record.status) != Some(SagaStatus::Running)
+        {
+            debug!("saga {id} isn't waiting for step {step}, dropping its reply");
+            return Ok(());
+        }
+
+        let mut saga: S = serde_json::from_value(record.state)
+            .map_err(|e| ProcessorError::PermanentError(format!("saga {id} can't be read: {e}")))?;
+        let reply: S::Reply = serde_json::from_value(envelope.message)
+            .map_err(|e| ProcessorError::PermanentError(format!("unexpected reply: {e}")))?;
+        let next = saga.on_reply(reply, context);
+        self.advance(id, record.step, saga, next).await.map_err(temporary)
+    }
+}
+
+/// Keeps sagas in memory, e.g. for tests.
+#[derive(Debug, Default)]
+pub struct MemorySagaStore {
+    sagas: Mutex<HashMap<Uuid, SagaRecord>>,
+}
+
+impl MemorySagaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn sagas(&self) -> std::sync::MutexGuard<'_, HashMap<Uuid, SagaRecord>> {
+        self.sagas.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl SagaStore for MemorySagaStore {
+    async fn load(&self, id: Uuid) -> Result<Option<SagaRecord>, MqError> {
+        Ok(self.sagas().get(&id).cloned())
+    }
+
+    async fn save(&self, record: &SagaRecord) -> Result<(), MqError> {
+        self.sagas().insert(record.id, record.clone());
+        Ok(())
+    }
+
+    async fn expired(&self, name: &str, now: DateTime<Utc>) -> Result<Vec<SagaRecord>, MqError> {
+        Ok(self
+            .sagas()
+            .values()
+            .filter(|r| r.name == name && r.status == SagaStatus::Running.as_str())
+            .filter(|r| r.deadline.is_some_and(|deadline| deadline < now))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Keeps sagas in the `saga` table, so they survive restarts and any instance can take the next step.
+#[cfg(feature = "pgsqlx")]
+#[derive(Debug, Clone)]
+pub struct PgSagaStore {
+    pool: sqlx::PgPool,
+}
+
+#[cfg(feature = "pgsqlx")]
+impl PgSagaStore {
+    pub fn new(pool: sqlx::PgPool) -> Self {
+        PgSagaStore { pool }
+    }
+
+    pub async fn create_table(&self) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "create table if not exists saga (
+                id uuid primary key,
+                name text not null,
+                step int not null,
+                state jsonb not null,
+                status text not null,
+                error text,
+                deadline timestamptz,
+                updated_at timestamptz not null
+            )",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("create index if not exists saga_running on saga (name, deadline) where status = 'running'")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "pgsqlx")]
+impl SagaStore for PgSagaStore {
+    async fn load(&self, id: Uuid) -> Result<Option<SagaRecord>, MqError> {
+        Ok(self.pool.find_saga_record_by_id(&id).await?)
+    }
+
+    async fn save(&self, record: &SagaRecord) -> Result<(), MqError> {
+        self.pool.upsert_saga_record(record).await?;
+        Ok(())
+    }
+
+    async fn expired(&self, name: &str, now: DateTime<Utc>) -> Result<Vec<SagaRecord>, MqError> {
+        Ok(sqlx::query_as(
+            "select * from saga where name = $1 and status = 'running' and deadline < $2 order by deadline",
+        )
+        .bind(name)
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mq::{
+        setup::{Binding, Exchange, ExchangeType, Queue, Topology, TopologyBuilder, TopologyOps},
+        testing::InMemoryBroker,
+        ChannelOps,
+    };
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct PlaceOrder {
+        order: u32,
+        charged: bool,
+    }
+
+    #[derive(Debug, Deserialize)]
+    struct Reply {
+        ok: bool,
+    }
+
+    impl Saga for PlaceOrder {
+        const NAME: &'static str = "place_order";
+        type Reply = Reply;
+
+        fn start(&mut self) -> Step {
+            Step::Send {
+                command: Command::new("payment.charge", json!({"order": self.order})),
+                timeout: Duration::from_secs(60),
+            }
+        }
+
+        fn on_reply(&mut self, reply: Reply, _context: &MessageContext) -> Step {
+            match (self.charged, reply.ok) {
+                (false, true) => {
+                    self.charged = true;
+                    Step::Send {
+                        command: Command::new("shipping.ship", json!({"order": self.order})),
+                        timeout: Duration::ZERO,
+                    }
+                }
+                (true, true) => Step::Complete,
+                (_, false) => Step::Fail("declined".into()),
+            }
+        }
+
+        fn compensate(&mut self) -> Vec<Command> {
+            match self.charged {
+                true => vec![Command::new("payment.refund", json!({"order": self.order}))],
+                false => vec![],
+            }
+        }
+    }
+
+    async fn broker() -> InMemoryBroker {
+        let broker = InMemoryBroker::new();
+        let topology = Topology::builder()
+            .with_exchange(Exchange::new("orders", ExchangeType::Topic, true))
+            .with_queue(Queue::new("commands", vec![]))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "orders",
+                target_queue_name: "commands",
+                routing_key: Some("#"),
+            })
+            .build();
+        broker.apply_topology(topology).await.unwrap();
+        broker
+    }
+
+    /// Replies to the last command sent.
+    async fn reply(runner: &mut SagaRunner<PlaceOrder, impl Publisher, impl SagaStore>, broker: &InMemoryBroker, ok: bool) {
+        let commands = broker.envelopes::<Value>("commands").unwrap();
+        let correlation_id = commands.last().unwrap().correlation_id().unwrap();
+        let reply = Envelope::new(json!({"ok": ok})).with_correlation_id(correlation_id);
+        runner.process_envelope(reply, &MessageContext::default()).await.unwrap();
+    }
+
+    fn routing_keys(broker: &InMemoryBroker) -> Vec<String> {
+        broker.messages("commands").into_iter().map(|m| m.routing_key).collect()
+    }
+
+    #[tokio::test]
+    async fn runs_steps_on_replies() {
+        let broker = broker().await;
+        let store = Arc::new(MemorySagaStore::new());
+        let mut runner = SagaRunner::new(broker.clone().create_producer("orders".into()), store.clone());
+
+        let id = runner.start(PlaceOrder { order: 7, charged: false }).await.unwrap();
+        reply(&mut runner, &broker, true).await;
+        // a duplicate of the first reply is for a step the saga has moved on from
+        let first = broker.envelopes::<Value>("commands").unwrap()[0].correlation_id().unwrap().to_string();
+        runner
+            .process_envelope(Envelope::new(json!({"ok": false})).with_correlation_id(first), &MessageContext::default())
+            .await
+            .unwrap();
+        reply(&mut runner, &broker, true).await;
+
+        assert_eq!(routing_keys(&broker), ["payment.charge", "shipping.ship"]);
+        let record = store.load(id).await.unwrap().unwrap();
+        assert_eq!(record.status, "completed");
+        assert_eq!(record.step, 3);
+    }
+
+    /// Answers every charge as it's published, before the saga publishing it moves on.
+    struct ChargesAtOnce<P> {
+        publisher: P,
+        replies: tokio::sync::Mutex<SagaRunner<PlaceOrder, P, Arc<MemorySagaStore>>>,
+    }
+
+    impl<P: Publisher> Publisher for ChargesAtOnce<P> {
+        async fn publish_envelope<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+            let correlation_id = envelope.correlation_id().map(String::from);
+            self.publisher.publish_envelope(envelope, routing_key).await?;
+            if routing_key == Some("payment.charge") {
+                let reply = Envelope::new(json!({"ok": true})).with_correlation_id(correlation_id.unwrap());
+                let mut replies = self.replies.lock().await;
+                replies.process_envelope(reply, &MessageContext::default()).await.unwrap();
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn takes_replies_faster_than_its_own_steps() {
+        let broker = broker().await;
+        let store = Arc::new(MemorySagaStore::new());
+        let producer = || broker.clone().create_producer("orders".into());
+        let replies = SagaRunner::new(producer(), store.clone());
+        let publisher = ChargesAtOnce {
+            publisher: producer(),
+            replies: tokio::sync::Mutex::new(replies),
+        };
+        let runner = SagaRunner::new(publisher, store.clone());
+
+        let id = runner.start(PlaceOrder { order: 7, charged: false }).await.unwrap();
+
+        assert_eq!(routing_keys(&broker), ["payment.charge", "shipping.ship"]);
+        let record = store.load(id).await.unwrap().unwrap();
+        assert_eq!(record.step, 2);
+        assert_eq!(SagaStatus::parse(&record.status), Some(SagaStatus::Running));
+    }
+
+    #[tokio::test]
+    async fn compensates_on_timeout() {
+        let broker = broker().await;
+        let store = Arc::new(MemorySagaStore::new());
+        let mut runner = SagaRunner::new(broker.clone().create_producer("orders".into()), store.clone());
+
+        let id = runner.start(PlaceOrder { order: 7, charged: false }).await.unwrap();
+        assert_eq!(runner.check_timeouts().await.unwrap(), 0);
+        // shipping has no time at all to reply
+        reply(&mut runner, &broker, true).await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(runner.check_timeouts().await.unwrap(), 1);
+
+        assert_eq!(routing_keys(&broker), ["payment.charge", "shipping.ship", "payment.refund"]);
+        let record = store.load(id).await.unwrap().unwrap();
+        assert_eq!(SagaStatus::parse(&record.status), Some(SagaStatus::Compensated));
+        assert_eq!(record.error.as_deref(), Some("timed out waiting for a reply"));
+    }
+}
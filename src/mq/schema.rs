@@ -0,0 +1,182 @@
+//! Validating messages against a JSON Schema on both sides of the broker, so a message breaking
+//! the contract between teams is caught where it's published, or rejected where it's consumed,
+//! rather than failing to deserialize deep inside a processor.
+
+use std::{fs, path::Path, sync::Arc};
+
+use futures::future::LocalBoxFuture;
+use jsonschema::Validator;
+use lapin::message::Delivery;
+use serde_json::Value;
+
+use super::{
+    codec::{Codec, Json},
+    consumer::ProcessorError,
+    interceptor::{OutgoingMessage, PublishInterceptor},
+    middleware::{ConsumerMiddleware, Next},
+    Envelope, MqError,
+};
+
+/// Validates messages against a JSON Schema.
+///
+/// As [`ConsumerMiddleware`], invalid messages fail permanently, without reaching the processor.
+/// As a [`PublishInterceptor`], invalid messages aren't published, failing with
+/// [`MqError::SchemaError`]; it reads messages with its codec, so add it before anything that
+/// changes the payload, like encryption.
+#[derive(Clone)]
+pub struct SchemaValidation<C: Codec = Json> {
+    validator: Arc<Validator>,
+    codec: C,
+}
+
+impl SchemaValidation {
+    pub fn new(schema: &Value) -> Result<Self, MqError> {
+        let validator =
+            jsonschema::validator_for(schema).map_err(|e| MqError::SchemaError(format!("invalid schema: {e}")))?;
+        Ok(SchemaValidation {
+            validator: Arc::new(validator),
+            codec: Json,
+        })
+    }
+
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, MqError> {
+        let path = path.as_ref();
+        let schema = fs::read(path)
+            .map_err(|e| MqError::ConfigurationError(format!("reading schema {}: {e}", path.display())))?;
+        SchemaValidation::new(&serde_json::from_slice(&schema)?)
+    }
+
+    /// Fetches the schema from `url`, e.g. a schema registry's.
+    pub async fn from_url(url: &str) -> Result<Self, MqError> {
+        let fetch = |e: reqwest::Error| MqError::SchemaError(format!("fetching schema from {url}: {e}"));
+        let schema: Value = reqwest::get(url)
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(fetch)?
+            .json()
+            .await
+            .map_err(fetch)?;
+        SchemaValidation::new(&schema)
+    }
+}
+
+impl<C: Codec> SchemaValidation<C> {
+    /// Reads messages being published with `codec` rather than JSON.
+    pub fn with_codec<D: Codec>(self, codec: D) -> SchemaValidation<D> {
+        SchemaValidation {
+            validator: self.validator,
+            codec,
+        }
+    }
+
+    /// Checks `message` against the schema, describing every way it doesn't match.
+    pub fn validate(&self, message: &Value) -> Result<(), String> {
+        let errors = self
+            .validator
+            .iter_errors(message)
+            .map(|e| format!("{}: {e}", e.instance_path))
+            .collect::<Vec<_>>();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+}
+
+impl<C: Codec> ConsumerMiddleware for SchemaValidation<C> {
+    fn handle<'a>(
+        &'a self,
+        _delivery: &'a Delivery,
+        envelope: Envelope<Value>,
+        next: Next<'a>,
+    ) -> LocalBoxFuture<'a, Result<(), ProcessorError>> {
+        Box::pin(async move {
+            self.validate(&envelope.message)
+                .map_err(|e| ProcessorError::PermanentError(format!("invalid message: {e}")))?;
+            next.run(envelope).await
+        })
+    }
+}
+
+impl<C: Codec> PublishInterceptor for SchemaValidation<C> {
+    fn intercept(&self, message: &mut OutgoingMessage) -> Result<(), MqError> {
+        let envelope: Envelope<Value> = self.codec.decode_delivery(&message.properties, &message.payload)?;
+        self.validate(&envelope.message).map_err(|e| {
+            MqError::SchemaError(format!(
+                "invalid message for {:?}, routing key {:?}: {e}",
+                message.exchange, message.routing_key
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin::{acker::Acker, BasicProperties};
+    use serde_json::json;
+
+    use super::*;
+    use crate::mq::consumer::{MessageContext, Processor};
+
+    fn validation() -> SchemaValidation {
+        SchemaValidation::new(&json!({
+            "type": "object",
+            "properties": {"email": {"type": "string"}},
+            "required": ["email"]
+        }))
+        .unwrap()
+    }
+
+    struct Count(usize);
+
+    impl Processor for Count {
+        async fn process(&mut self, _value: Value, _context: &MessageContext) -> Result<(), ProcessorError> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_messages() {
+        let middleware: Vec<Arc<dyn ConsumerMiddleware>> = vec![Arc::new(validation())];
+        let delivery = Delivery {
+            delivery_tag: 1,
+            exchange: "signups".into(),
+            routing_key: "created".into(),
+            redelivered: false,
+            properties: BasicProperties::default(),
+            data: vec![],
+            acker: Acker::default(),
+        };
+        let mut processor = Count(0);
+
+        Next::new(&middleware, &delivery, &mut processor)
+            .run(Envelope::new(json!({"email": "a@b.c"})))
+            .await
+            .unwrap();
+        let invalid = Next::new(&middleware, &delivery, &mut processor)
+            .run(Envelope::new(json!({"email": 1})))
+            .await;
+
+        assert!(matches!(invalid, Err(ProcessorError::PermanentError(e)) if e.contains("/email")));
+        assert_eq!(processor.0, 1);
+    }
+
+    #[test]
+    fn refuses_to_publish_invalid_messages() {
+        let validation = validation();
+        let outgoing = |message: Value| OutgoingMessage {
+            exchange: "signups".into(),
+            routing_key: "created".into(),
+            payload: Json.encode(&Envelope::new(message)).unwrap(),
+            properties: BasicProperties::default().with_content_type(Json.content_type().into()),
+        };
+
+        assert!(validation.intercept(&mut outgoing(json!({"email": "a@b.c"}))).is_ok());
+        assert!(matches!(
+            validation.intercept(&mut outgoing(json!({}))),
+            Err(MqError::SchemaError(_))
+        ));
+    }
+}
@@ -1,8 +1,11 @@
-use std::{cell::RefCell, collections::BTreeMap};
+use std::{cell::RefCell, collections::BTreeMap, fs::File, io::Read, path::Path};
 
 use derive_more::Constructor;
 use lapin::{
-    options::{ExchangeBindOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions},
+    options::{
+        ExchangeBindOptions, ExchangeDeclareOptions, ExchangeDeleteOptions, ExchangeUnbindOptions, QueueBindOptions,
+        QueueDeclareOptions, QueueDeleteOptions,
+    },
     types::{AMQPValue, FieldTable},
     Channel,
 };
@@ -10,11 +13,205 @@ use serde::{Deserialize, Serialize};
 
 use super::MqError;
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/// Exchanges, queues and bindings to declare, named by `Name`, usually `&str` when written out in
+/// code. See [`OwnedTopology`] for one to keep, read from a file, or put together from names of
+/// both kinds.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Topology<Name: Into<String>> {
-    queues: Vec<Queue<Name>>,
-    exchanges: Vec<Exchange<Name>>,
-    bindings: Vec<Binding<Name>>,
+    #[serde(default)]
+    pub(crate) queues: Vec<Queue<Name>>,
+    #[serde(default)]
+    pub(crate) exchanges: Vec<Exchange<Name>>,
+    #[serde(default)]
+    pub(crate) bindings: Vec<Binding<Name>>,
+    /// Topologies for other vhosts than the one connected to, by vhost.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) vhosts: BTreeMap<String, Topology<Name>>,
+}
+
+/// The format of a topology file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopologyFormat {
+    Json,
+    #[cfg(feature = "yaml")]
+    Yaml,
+    #[cfg(feature = "toml")]
+    Toml,
+}
+
+impl TopologyFormat {
+    /// Picks the format from a file extension: `json`, `yaml`/`yml` or `toml`.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "json" => Some(TopologyFormat::Json),
+            #[cfg(feature = "yaml")]
+            "yaml" | "yml" => Some(TopologyFormat::Yaml),
+            #[cfg(feature = "toml")]
+            "toml" => Some(TopologyFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// A topology owning its names, as read by [`Topology::from_reader`], kept by
+/// [`VhostConnections`](super::connection::VhostConnections) and the like, and built up from
+/// borrowed and owned names alike:
+///
+/// ```
+/// use launchpad::mq::setup::{Binding, Exchange, ExchangeType, OwnedTopology, Queue};
+///
+/// let tenant = String::from("acme");
+/// let topology = OwnedTopology::new()
+///     .with_exchange(Exchange::new("orders", ExchangeType::Topic, true))
+///     .with_queue(Queue::new(format!("{tenant}.orders"), vec![]))
+///     .with_binding(Binding::ToQueue {
+///         src_exchange_name: "orders".to_string(),
+///         target_queue_name: format!("{tenant}.orders"),
+///         routing_key: Some(format!("order.{tenant}.*")),
+///     });
+/// ```
+pub type OwnedTopology = Topology<String>;
+pub type OwnedQueue = Queue<String>;
+pub type OwnedExchange = Exchange<String>;
+pub type OwnedBinding = Binding<String>;
+
+impl OwnedTopology {
+    pub fn with_queue(mut self, queue: impl Into<OwnedQueue>) -> Self {
+        self.queues.push(queue.into());
+        self
+    }
+
+    pub fn with_exchange(mut self, exchange: impl Into<OwnedExchange>) -> Self {
+        self.exchanges.push(exchange.into());
+        self
+    }
+
+    pub fn with_binding(mut self, binding: impl Into<OwnedBinding>) -> Self {
+        self.bindings.push(binding.into());
+        self
+    }
+
+    /// See [`TopologyBuilder::with_vhost`].
+    pub fn with_vhost(mut self, vhost: impl Into<String>, topology: impl Into<OwnedTopology>) -> Self {
+        self.vhosts.insert(vhost.into(), topology.into());
+        self
+    }
+
+    /// Adds everything in `other`, e.g. the topology of each part of a service, to this one.
+    pub fn merge(mut self, other: impl Into<OwnedTopology>) -> Self {
+        let other = other.into();
+        self.queues.extend(other.queues);
+        self.exchanges.extend(other.exchanges);
+        self.bindings.extend(other.bindings);
+        for (vhost, topology) in other.vhosts {
+            let merged = self.vhosts.remove(&vhost).unwrap_or_default().merge(topology);
+            self.vhosts.insert(vhost, merged);
+        }
+        self
+    }
+}
+
+impl From<Topology<&str>> for OwnedTopology {
+    fn from(topology: Topology<&str>) -> Self {
+        topology.into_owned()
+    }
+}
+
+impl From<Queue<&str>> for OwnedQueue {
+    fn from(queue: Queue<&str>) -> Self {
+        queue.into_owned()
+    }
+}
+
+impl From<Exchange<&str>> for OwnedExchange {
+    fn from(exchange: Exchange<&str>) -> Self {
+        exchange.into_owned()
+    }
+}
+
+impl From<Binding<&str>> for OwnedBinding {
+    fn from(binding: Binding<&str>) -> Self {
+        binding.into_owned()
+    }
+}
+
+impl Topology<String> {
+    /// Reads a topology, so exchanges, queues and bindings can be managed outside the code. Every
+    /// section may be left out, as may queue `options`, exchange `kind` (`Direct`), `durable`
+    /// (`true`) and `alternate`, and binding `routing_key`. Options and bindings are named as in
+    /// the enums, e.g.:
+    ///
+    /// ```yaml
+    /// exchanges:
+    ///   - name: orders
+    ///     kind: Topic
+    /// queues:
+    ///   - name: orders.billing
+    ///     options:
+    ///       - Type: Quorum
+    ///       - DeadLetterExchange: orders.dlx
+    /// bindings:
+    ///   - ToQueue:
+    ///       src_exchange_name: orders
+    ///       target_queue_name: orders.billing
+    ///       routing_key: order.*
+    /// ```
+    ///
+    /// or in TOML:
+    ///
+    /// ```toml
+    /// [[exchanges]]
+    /// name = "orders"
+    /// kind = "Topic"
+    ///
+    /// [[queues]]
+    /// name = "orders.billing"
+    /// options = [{ Type = "Quorum" }, { DeadLetterExchange = "orders.dlx" }]
+    ///
+    /// [[bindings]]
+    /// ToQueue = { src_exchange_name = "orders", target_queue_name = "orders.billing", routing_key = "order.*" }
+    /// ```
+    ///
+    /// What belongs on other vhosts than the one connected to goes in a topology of its own under
+    /// `vhosts`, e.g. for a queue per tenant:
+    ///
+    /// ```yaml
+    /// vhosts:
+    ///   tenant-a:
+    ///     queues:
+    ///       - name: orders.billing
+    /// ```
+    pub fn from_reader(reader: impl Read, format: TopologyFormat) -> Result<Self, MqError> {
+        let invalid = |e: &dyn std::fmt::Display| MqError::ConfigurationError(format!("invalid topology: {e}"));
+        match format {
+            TopologyFormat::Json => serde_json::from_reader(reader).map_err(|e| invalid(&e)),
+            #[cfg(feature = "yaml")]
+            TopologyFormat::Yaml => {
+                // enum variants are written as single key maps, as in JSON, rather than YAML tags
+                serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Deserializer::from_reader(reader))
+                    .map_err(|e| invalid(&e))
+            }
+            #[cfg(feature = "toml")]
+            TopologyFormat::Toml => {
+                let (mut reader, mut source) = (reader, String::new());
+                reader.read_to_string(&mut source).map_err(|e| invalid(&e))?;
+                toml::from_str(&source).map_err(|e| invalid(&e))
+            }
+        }
+    }
+
+    /// Reads a topology from a file, in the format its extension names.
+    pub fn from_path(path: impl AsRef<Path>) -> Result<Self, MqError> {
+        let path = path.as_ref();
+        let format = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(TopologyFormat::from_extension)
+            .ok_or_else(|| MqError::ConfigurationError(format!("unsupported topology file {}", path.display())))?;
+        let file = File::open(path)
+            .map_err(|e| MqError::ConfigurationError(format!("can't read topology file {}: {e}", path.display())))?;
+        Topology::from_reader(file, format)
+    }
 }
 
 impl<Name: Into<String>> Topology<Name> {
@@ -23,18 +220,74 @@ impl<Name: Into<String>> Topology<Name> {
             queues: Vec::default(),
             exchanges: Vec::default(),
             bindings: Vec::default(),
+            vhosts: BTreeMap::default(),
         }
     }
 
+    /// What's declared on `vhost`, from [`TopologyBuilder::with_vhost`]. Empty if nothing is.
+    pub fn for_vhost(&self, vhost: &str) -> Topology<Name>
+    where
+        Name: Clone,
+    {
+        self.vhosts.get(vhost).cloned().unwrap_or_else(Topology::new)
+    }
+
+    /// The vhosts with something declared on them, besides the one connected to.
+    pub fn vhosts(&self) -> impl Iterator<Item = &str> {
+        self.vhosts.keys().map(String::as_str)
+    }
+
     pub fn builder() -> impl TopologyBuilder<Name> {
         RefCell::new(Topology::<Name>::new())
     }
+
+    /// Alternate exchanges named by the topology's exchanges but not declared in it, along with
+    /// the queue capturing what's sent to each.
+    pub(crate) fn alternates(&self) -> Topology<String>
+    where
+        Name: Clone,
+    {
+        let declared = self.exchanges.iter().map(|e| e.name.clone().into()).collect::<Vec<String>>();
+        let mut alternates = Topology::new();
+        for alternate in self.exchanges.iter().filter_map(|e| e.alternate.clone()) {
+            let alternate: String = alternate.into();
+            if declared.contains(&alternate) || alternates.exchanges.iter().any(|e| e.name == alternate) {
+                continue;
+            }
+            alternates.exchanges.push(Exchange::new(alternate.clone(), ExchangeType::Topic, true));
+            alternates.queues.push(Queue::new(alternate.clone(), vec![QueueOptions::Persistence(true)]));
+            alternates.bindings.push(Binding::ToQueue {
+                src_exchange_name: alternate.clone(),
+                target_queue_name: alternate,
+                routing_key: Some("#".into()),
+            });
+        }
+        alternates
+    }
+
+    /// Converts every name to a `String`, so the topology can be kept and applied again later.
+    pub fn into_owned(self) -> Topology<String> {
+        Topology {
+            queues: self.queues.into_iter().map(Queue::into_owned).collect(),
+            exchanges: self.exchanges.into_iter().map(Exchange::into_owned).collect(),
+            bindings: self.bindings.into_iter().map(Binding::into_owned).collect(),
+            vhosts: self
+                .vhosts
+                .into_iter()
+                .map(|(vhost, topology)| (vhost, topology.into_owned()))
+                .collect(),
+        }
+    }
 }
 
 pub trait TopologyBuilder<Name: Into<String>> {
     fn with_queue(self, queue: Queue<Name>) -> Self;
     fn with_exchange(self, exchange: Exchange<Name>) -> Self;
     fn with_binding(self, binding: Binding<Name>) -> Self;
+    /// Declares `topology` on `vhost` rather than the vhost connected to. It's applied by
+    /// connections to that vhost, e.g. from [`VhostConnections`](super::connection::VhostConnections),
+    /// and left out by [`TopologyOps::apply_topology`] on others.
+    fn with_vhost(self, vhost: impl Into<String>, topology: Topology<Name>) -> Self;
     fn build(self) -> Topology<Name>;
 }
 
@@ -63,42 +316,174 @@ impl<Name: Into<String>> TopologyBuilder<Name> for RefCell<Topology<Name>> {
         self
     }
 
+    fn with_vhost(self, vhost: impl Into<String>, vhost_topology: Topology<Name>) -> Self {
+        {
+            let mut topology = self.borrow_mut();
+            topology.vhosts.insert(vhost.into(), vhost_topology);
+        }
+        self
+    }
+
     fn build(self) -> Topology<Name> {
         self.into_inner()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum QueueOptions {
     Persistence(bool),
     AutoExpire(u32),
     MessageTTL(u32),
     DeadLetterExchange(String),
-    DeadLetterRoutingKey(String)
+    DeadLetterRoutingKey(String),
+    /// Highest message priority the queue orders by; messages above it are treated as this priority.
+    MaxPriority(u8),
+    /// Most messages the queue holds; what happens to more depends on [`QueueOptions::Overflow`].
+    MaxLength(u32),
+    /// Most bytes of message bodies the queue holds. Also bounds how large a stream grows.
+    MaxLengthBytes(u64),
+    /// What to do with messages published to a full queue. Drops the oldest by default.
+    Overflow(Overflow),
+    /// Quorum and stream queues are always declared durable.
+    Type(QueueType),
+    /// How long a stream keeps messages, in seconds. Old segments are discarded as a whole.
+    StreamMaxAge(u32),
+    /// The size of the segment files a stream is stored in, in bytes.
+    StreamMaxSegmentSize(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Overflow {
+    /// Discard or dead letter the oldest messages to make room.
+    DropHead,
+    /// Refuse new messages, nacking them when the publisher uses confirms.
+    RejectPublish,
+    /// Refuse new messages and dead letter them. Not supported by quorum queues.
+    RejectPublishDlx,
 }
 
-#[derive(Debug, Serialize, Deserialize, Constructor)]
+impl Overflow {
+    fn name(&self) -> &'static str {
+        match self {
+            Overflow::DropHead => "drop-head",
+            Overflow::RejectPublish => "reject-publish",
+            Overflow::RejectPublishDlx => "reject-publish-dlx",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QueueType {
+    Classic,
+    /// Replicated across the cluster with Raft, for durable workloads.
+    Quorum,
+    /// An append-only log, read non-destructively from any offset.
+    Stream,
+}
+
+impl QueueType {
+    fn name(&self) -> &'static str {
+        match self {
+            QueueType::Classic => "classic",
+            QueueType::Quorum => "quorum",
+            QueueType::Stream => "stream",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Constructor)]
 pub struct Queue<Name: Into<String>> {
-    name: Name,
-    options: Vec<QueueOptions>,
+    pub(crate) name: Name,
+    #[serde(default)]
+    pub(crate) options: Vec<QueueOptions>,
+}
+
+impl<Name: Into<String>> Queue<Name> {
+    fn into_owned(self) -> Queue<String> {
+        Queue::new(self.name.into(), self.options)
+    }
+
+    /// The `x-` arguments the queue is declared with. Messages rejected without requeueing, or
+    /// expired, are republished to the dead letter exchange when one is set.
+    pub fn arguments(&self) -> FieldTable {
+        self.options.iter().flat_map(|o| {
+            match o {
+                QueueOptions::AutoExpire(ms) => Some(("x-expires".into(), AMQPValue::LongUInt(*ms))),
+                QueueOptions::MessageTTL(ms) => Some(("x-message-ttl".into(), AMQPValue::LongUInt(*ms))),
+                QueueOptions::DeadLetterExchange(dlx) => Some(("x-dead-letter-exchange".into(), AMQPValue::ShortString(dlx.clone().into()))),
+                QueueOptions::DeadLetterRoutingKey(dlx_rk) => Some(("x-dead-letter-routing-key".into(), AMQPValue::ShortString(dlx_rk.clone().into()))),
+                QueueOptions::MaxPriority(max) => Some(("x-max-priority".into(), AMQPValue::ShortShortUInt(*max))),
+                QueueOptions::MaxLength(max) => Some(("x-max-length".into(), AMQPValue::LongUInt(*max))),
+                QueueOptions::MaxLengthBytes(max) => Some(("x-max-length-bytes".into(), AMQPValue::LongLongInt(*max as i64))),
+                QueueOptions::Overflow(overflow) => Some(("x-overflow".into(), AMQPValue::LongString(overflow.name().into()))),
+                QueueOptions::Type(kind) => Some(("x-queue-type".into(), AMQPValue::LongString(kind.name().into()))),
+                QueueOptions::StreamMaxAge(secs) => Some(("x-max-age".into(), AMQPValue::LongString(format!("{secs}s").into()))),
+                QueueOptions::StreamMaxSegmentSize(bytes) => Some(("x-stream-max-segment-size-bytes".into(), AMQPValue::LongLongInt(*bytes as i64))),
+                _ => None
+            }
+        }).collect::<BTreeMap<_, _>>().into()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Constructor)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Exchange<Name: Into<String>> {
-    name: Name,
-    kind: ExchangeType,
-    durable: bool,
+    pub(crate) name: Name,
+    #[serde(default)]
+    pub(crate) kind: ExchangeType,
+    #[serde(default = "durable_by_default")]
+    pub(crate) durable: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) alternate: Option<Name>,
+}
+
+fn durable_by_default() -> bool {
+    true
 }
 
 impl<Name: Into<String>> Exchange<Name> {
+    pub fn new(name: Name, kind: ExchangeType, durable: bool) -> Self {
+        Exchange {
+            name,
+            kind,
+            durable,
+            alternate: None,
+        }
+    }
+
+    fn into_owned(self) -> Exchange<String> {
+        Exchange {
+            name: self.name.into(),
+            kind: self.kind,
+            durable: self.durable,
+            alternate: self.alternate.map(Into::into),
+        }
+    }
+
     pub fn builder(name: Name) -> impl ExchangeBuilder<Name> {
         RefCell::new(Exchange::<Name>::new(name, ExchangeType::Direct, true))
     }
+
+    /// The arguments the exchange is declared with.
+    pub fn arguments(&self) -> FieldTable
+    where
+        Name: Clone,
+    {
+        let mut arguments = FieldTable::default();
+        if let Some(alternate) = &self.alternate {
+            let alternate: String = alternate.clone().into();
+            arguments.insert("alternate-exchange".into(), AMQPValue::LongString(alternate.into()));
+        }
+        arguments
+    }
 }
 
 pub trait ExchangeBuilder<Name: Into<String>> {
     fn with_kind(self, kind: ExchangeType) -> Self;
     fn with_durable(self, durable: bool) -> Self;
+    /// Sends messages the exchange can't route to `alternate` rather than dropping them. Unless
+    /// the topology declares it, `apply_topology` declares it as a topic exchange, along with a
+    /// queue of the same name receiving everything sent to it.
+    fn with_alternate(self, alternate: Name) -> Self;
     fn build(self) -> Exchange<Name>;
 }
 
@@ -112,19 +497,25 @@ impl<Name: Into<String>> ExchangeBuilder<Name> for RefCell<Exchange<Name>> {
         self.borrow_mut().durable = durable;
         self
     }
+
+    fn with_alternate(self, alternate: Name) -> Self {
+        self.borrow_mut().alternate = Some(alternate);
+        self
+    }
     
     fn build(self) -> Exchange<Name> {
         self.into_inner()
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
 pub enum ExchangeType {
+    #[default]
     Direct,
     Topic,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Binding<Name: Into<String>> {
     ToQueue {
         src_exchange_name: Name,
@@ -138,6 +529,55 @@ pub enum Binding<Name: Into<String>> {
     },
 }
 
+impl<Name: Into<String>> Binding<Name> {
+    /// The source exchange, target and routing key, which is empty when not set.
+    pub(crate) fn parts(&self) -> (String, String, String)
+    where
+        Name: Clone,
+    {
+        let (source, target, routing_key) = match self {
+            Binding::ToQueue {
+                src_exchange_name,
+                target_queue_name,
+                routing_key,
+            } => (src_exchange_name, target_queue_name, routing_key),
+            Binding::ToExchange {
+                src_exchange_name,
+                target_exchange_name,
+                routing_key,
+            } => (src_exchange_name, target_exchange_name, routing_key),
+        };
+        (
+            source.clone().into(),
+            target.clone().into(),
+            routing_key.clone().map(Into::into).unwrap_or_default(),
+        )
+    }
+
+    fn into_owned(self) -> Binding<String> {
+        match self {
+            Binding::ToQueue {
+                src_exchange_name,
+                target_queue_name,
+                routing_key,
+            } => Binding::ToQueue {
+                src_exchange_name: src_exchange_name.into(),
+                target_queue_name: target_queue_name.into(),
+                routing_key: routing_key.map(Into::into),
+            },
+            Binding::ToExchange {
+                src_exchange_name,
+                target_exchange_name,
+                routing_key,
+            } => Binding::ToExchange {
+                src_exchange_name: src_exchange_name.into(),
+                target_exchange_name: target_exchange_name.into(),
+                routing_key: routing_key.map(Into::into),
+            },
+        }
+    }
+}
+
 pub trait TopologyOps {
     async fn with_queue<Name: Into<String> + Clone>(
         &self,
@@ -152,6 +592,7 @@ pub trait TopologyOps {
         binding: &Binding<Name>,
     ) -> Result<(), MqError>;
 
+    /// Declares the topology's queues, exchanges and bindings, leaving out those for other vhosts.
     async fn apply_topology<Name: Into<String> + Clone>(
         &self,
         topology: Topology<Name>,
@@ -168,10 +609,70 @@ pub trait TopologyOps {
             self.with_binding(binding).await?
         }
 
+        let alternates = topology.alternates();
+        for queue in alternates.queues.iter() {
+            self.with_queue(queue).await?
+        }
+        for exchange in alternates.exchanges.iter() {
+            self.with_exchange(exchange).await?
+        }
+        for binding in alternates.bindings.iter() {
+            self.with_binding(binding).await?
+        }
+
+        Ok(())
+    }
+
+    async fn delete_queue<Name: Into<String> + Clone>(
+        &self,
+        queue: &Queue<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError>;
+    async fn delete_exchange<Name: Into<String> + Clone>(
+        &self,
+        exchange: &Exchange<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError>;
+    async fn unbind<Name: Into<String> + Clone>(
+        &self,
+        binding: &Binding<Name>,
+    ) -> Result<(), MqError>;
+
+    /// Undoes [`TopologyOps::apply_topology`], removing the bindings, then the exchanges and
+    /// queues. Only what the topology declares itself is deleted: the alternate exchanges it
+    /// declared along with it are left, as other topologies may name them too. Stops at the first
+    /// queue or exchange failing a check in `options`.
+    async fn remove_topology<Name: Into<String> + Clone>(
+        &self,
+        topology: Topology<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError> {
+        for binding in topology.bindings.iter() {
+            self.unbind(binding).await?
+        }
+
+        for exchange in topology.exchanges.iter() {
+            self.delete_exchange(exchange, options).await?
+        }
+
+        for queue in topology.queues.iter() {
+            self.delete_queue(queue, options).await?
+        }
+
         Ok(())
     }
 }
 
+/// Checks made before deleting a queue or exchange, so teardown can't take anything still in use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TeardownOptions {
+    /// Only delete queues without consumers and exchanges nothing is bound from.
+    pub if_unused: bool,
+
+    /// Only delete queues without messages.
+    pub if_empty: bool,
+}
+
 impl TopologyOps for Channel {
     async fn with_queue<Name: Into<String> + Clone>(
         &self,
@@ -180,19 +681,13 @@ impl TopologyOps for Channel {
         let queue_name: String = queue.name.clone().into();
         let mut options = QueueDeclareOptions::default();
 
-        if queue.options.iter().find(|o| matches!(o, QueueOptions::Persistence(true))).is_some() {
+        if queue.options.iter().any(|o| {
+            matches!(o, QueueOptions::Persistence(true) | QueueOptions::Type(QueueType::Quorum | QueueType::Stream))
+        }) {
             options.durable = true
         }
 
-        let arguments: FieldTable = queue.options.iter().flat_map(|o| {
-            match o {
-                QueueOptions::AutoExpire(ms) => Some(("x-expires".into(), AMQPValue::LongUInt(*ms))),
-                QueueOptions::MessageTTL(ms) => Some(("x-message-ttl".into(), AMQPValue::LongUInt(*ms))),
-                QueueOptions::DeadLetterExchange(dlx) => Some(("x-dead-letter-exchange".into(), AMQPValue::ShortString(dlx.clone().into()))),
-                QueueOptions::DeadLetterRoutingKey(dlx_rk) => Some(("x-dead-letter-routing-key".into(), AMQPValue::ShortString(dlx_rk.clone().into()))),
-                _ => None
-            }
-        }).collect::<BTreeMap<_, _>>().into();
+        let arguments = queue.arguments();
 
         self.queue_declare(
             &queue_name,
@@ -222,7 +717,7 @@ impl TopologyOps for Channel {
             &exchange_name,
             exchange_kind,
             options,
-            FieldTable::default(),
+            exchange.arguments(),
         )
         .await?;
         Ok(())
@@ -279,6 +774,63 @@ impl TopologyOps for Channel {
         }
         Ok(())
     }
+
+    /// The broker closes the channel if a check in `options` fails.
+    async fn delete_queue<Name: Into<String> + Clone>(
+        &self,
+        queue: &Queue<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError> {
+        let queue_name: String = queue.name.clone().into();
+        let options = QueueDeleteOptions {
+            if_unused: options.if_unused,
+            if_empty: options.if_empty,
+            ..Default::default()
+        };
+
+        self.queue_delete(&queue_name, options).await?;
+        Ok(())
+    }
+
+    /// The broker closes the channel if a check in `options` fails.
+    async fn delete_exchange<Name: Into<String> + Clone>(
+        &self,
+        exchange: &Exchange<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError> {
+        let exchange_name: String = exchange.name.clone().into();
+        let options = ExchangeDeleteOptions {
+            if_unused: options.if_unused,
+            ..Default::default()
+        };
+
+        self.exchange_delete(&exchange_name, options).await?;
+        Ok(())
+    }
+
+    async fn unbind<Name: Into<String> + Clone>(
+        &self,
+        binding: &Binding<Name>,
+    ) -> Result<(), MqError> {
+        let (src, dest, routing_key) = binding.parts();
+        match binding {
+            Binding::ToQueue { .. } => {
+                self.queue_unbind(&dest, &src, &routing_key, FieldTable::default())
+                    .await?
+            }
+            Binding::ToExchange { .. } => {
+                self.exchange_unbind(
+                    &dest,
+                    &src,
+                    &routing_key,
+                    ExchangeUnbindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -331,6 +883,33 @@ mod tests {
             println!("binding: {} -> {}, rk: {:?}", src_name, dest_name, routing_key);
             Ok(())
         }
+
+        async fn delete_queue<Name: Into<String> + Clone>(
+            &self,
+            queue: &Queue<Name>,
+            options: TeardownOptions,
+        ) -> Result<(), MqError> {
+            println!("delete queue: {} ({:?})", queue.name.clone().into(), options);
+            Ok(())
+        }
+
+        async fn delete_exchange<Name: Into<String> + Clone>(
+            &self,
+            exchange: &Exchange<Name>,
+            options: TeardownOptions,
+        ) -> Result<(), MqError> {
+            println!("delete exchange: {} ({:?})", exchange.name.clone().into(), options);
+            Ok(())
+        }
+
+        async fn unbind<Name: Into<String> + Clone>(
+            &self,
+            binding: &Binding<Name>,
+        ) -> Result<(), MqError> {
+            let (src_name, dest_name, routing_key) = binding.parts();
+            println!("unbind: {} -> {}, rk: {:?}", src_name, dest_name, routing_key);
+            Ok(())
+        }
     }
 
     #[tokio::test]
@@ -348,4 +927,223 @@ mod tests {
         TopologyLogger.apply_topology(topology).await?;
         Ok(())
     }
+
+    #[test]
+    fn into_owned() {
+        let topology = Topology::builder()
+            .with_queue(Queue::new("test.queue", vec![QueueOptions::MessageTTL(1000)]))
+            .with_exchange(Exchange::builder("test.exchange").build())
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "test.exchange",
+                target_queue_name: "test.queue",
+                routing_key: Some("test.#"),
+            })
+            .build();
+        let json = serde_json::to_string(&topology).unwrap();
+
+        let owned: Topology<String> = topology.into_owned();
+        assert_eq!(serde_json::to_string(&owned).unwrap(), json);
+    }
+
+    #[test]
+    fn owned_topology() {
+        let billing = String::from("orders.billing");
+        let topology = OwnedTopology::new()
+            .with_exchange(Exchange::builder("orders").with_kind(ExchangeType::Topic).build())
+            .with_queue(Queue::new(
+                billing.clone(),
+                vec![
+                    QueueOptions::Type(QueueType::Quorum),
+                    QueueOptions::DeadLetterExchange("orders.dlx".into()),
+                ],
+            ))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "orders",
+                target_queue_name: &billing,
+                routing_key: Some("order.*"),
+            });
+        assert_same(Ok(topology), orders_topology());
+
+        let tenant = Topology::builder().with_queue(Queue::new("a.orders", vec![])).build();
+        let merged = OwnedTopology::new()
+            .with_vhost("tenant-a", tenant.clone())
+            .merge(orders_topology())
+            .merge(OwnedTopology::new().with_vhost("tenant-a", tenant));
+        assert_eq!(merged.queues.len(), 1);
+        assert_eq!(merged.for_vhost("tenant-a").queues.len(), 2);
+    }
+
+    #[test]
+    fn topology_by_vhost() {
+        let tenant = |name: &'static str| {
+            Topology::builder()
+                .with_queue(Queue::new(name, vec![QueueOptions::Persistence(true)]))
+                .build()
+        };
+        let topology = Topology::builder()
+            .with_queue(Queue::new("shared", vec![]))
+            .with_vhost("tenant-a", tenant("a.orders"))
+            .with_vhost("tenant-b", tenant("b.orders"))
+            .build()
+            .into_owned();
+
+        assert_eq!(topology.vhosts().collect::<Vec<_>>(), ["tenant-a", "tenant-b"]);
+        assert_eq!(topology.for_vhost("tenant-a").queues[0].name, "a.orders");
+        assert!(topology.for_vhost("tenant-c").queues.is_empty());
+
+        let json = r#"{ "queues": [{ "name": "shared" }], "vhosts": { "tenant-a": { "queues": [{ "name": "a.orders", "options": [{ "Persistence": true }] }] }, "tenant-b": { "queues": [{ "name": "b.orders", "options": [{ "Persistence": true }] }] } } }"#;
+        assert_same(Topology::from_reader(json.as_bytes(), TopologyFormat::Json), topology);
+    }
+
+    fn orders_topology() -> Topology<String> {
+        Topology::builder()
+            .with_exchange(Exchange::builder("orders").with_kind(ExchangeType::Topic).build())
+            .with_queue(Queue::new(
+                "orders.billing",
+                vec![
+                    QueueOptions::Type(QueueType::Quorum),
+                    QueueOptions::DeadLetterExchange("orders.dlx".into()),
+                ],
+            ))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "orders",
+                target_queue_name: "orders.billing",
+                routing_key: Some("order.*"),
+            })
+            .build()
+            .into_owned()
+    }
+
+    fn assert_same(read: Result<Topology<String>, MqError>, expected: Topology<String>) {
+        assert_eq!(
+            serde_json::to_value(read.unwrap()).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    }
+
+    #[test]
+    fn topology_from_json() {
+        let json = r#"{
+            "exchanges": [{ "name": "orders", "kind": "Topic" }],
+            "queues": [{ "name": "orders.billing", "options": [{ "Type": "Quorum" }, { "DeadLetterExchange": "orders.dlx" }] }],
+            "bindings": [{ "ToQueue": { "src_exchange_name": "orders", "target_queue_name": "orders.billing", "routing_key": "order.*" } }]
+        }"#;
+
+        assert_same(Topology::from_reader(json.as_bytes(), TopologyFormat::Json), orders_topology());
+        assert!(Topology::from_reader(r#"{ "queues": [{}] }"#.as_bytes(), TopologyFormat::Json).is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn topology_from_yaml() {
+        let yaml = "
+exchanges:
+  - name: orders
+    kind: Topic
+queues:
+  - name: orders.billing
+    options:
+      - Type: Quorum
+      - DeadLetterExchange: orders.dlx
+bindings:
+  - ToQueue:
+      src_exchange_name: orders
+      target_queue_name: orders.billing
+      routing_key: order.*
+";
+
+        assert_same(Topology::from_reader(yaml.as_bytes(), TopologyFormat::Yaml), orders_topology());
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn topology_from_toml() {
+        let toml = r#"
+[[exchanges]]
+name = "orders"
+kind = "Topic"
+
+[[queues]]
+name = "orders.billing"
+options = [{ Type = "Quorum" }, { DeadLetterExchange = "orders.dlx" }]
+
+[[bindings]]
+ToQueue = { src_exchange_name = "orders", target_queue_name = "orders.billing", routing_key = "order.*" }
+"#;
+
+        assert_same(Topology::from_reader(toml.as_bytes(), TopologyFormat::Toml), orders_topology());
+    }
+
+    #[test]
+    fn dead_letter_arguments() {
+        let queue = Queue::new(
+            "payments",
+            vec![
+                QueueOptions::Persistence(true),
+                QueueOptions::DeadLetterExchange("payments.dlx".into()),
+                QueueOptions::DeadLetterRoutingKey("payments.dead".into()),
+            ],
+        );
+        let arguments = queue.arguments();
+        let arguments = arguments.inner();
+
+        assert_eq!(arguments.len(), 2);
+        assert_eq!(
+            arguments.get("x-dead-letter-exchange"),
+            Some(&AMQPValue::ShortString("payments.dlx".into()))
+        );
+        assert_eq!(
+            arguments.get("x-dead-letter-routing-key"),
+            Some(&AMQPValue::ShortString("payments.dead".into()))
+        );
+    }
+
+    #[test]
+    fn max_priority_argument() {
+        let queue = Queue::new("jobs", vec![QueueOptions::MaxPriority(10)]);
+
+        assert_eq!(
+            queue.arguments().inner().get("x-max-priority"),
+            Some(&AMQPValue::ShortShortUInt(10))
+        );
+    }
+
+    #[test]
+    fn length_limit_arguments() {
+        let queue = Queue::new(
+            "events",
+            vec![
+                QueueOptions::MaxLength(10_000),
+                QueueOptions::MaxLengthBytes(64 * 1024 * 1024),
+                QueueOptions::Overflow(Overflow::RejectPublishDlx),
+            ],
+        );
+        let arguments = queue.arguments();
+        let arguments = arguments.inner();
+
+        assert_eq!(arguments.get("x-max-length"), Some(&AMQPValue::LongUInt(10_000)));
+        assert_eq!(arguments.get("x-max-length-bytes"), Some(&AMQPValue::LongLongInt(64 * 1024 * 1024)));
+        assert_eq!(arguments.get("x-overflow"), Some(&AMQPValue::LongString("reject-publish-dlx".into())));
+    }
+
+    #[test]
+    fn queue_type_arguments() {
+        let queue = Queue::new(
+            "audit",
+            vec![
+                QueueOptions::Type(QueueType::Stream),
+                QueueOptions::StreamMaxAge(7 * 24 * 60 * 60),
+                QueueOptions::StreamMaxSegmentSize(100_000_000),
+            ],
+        );
+        let arguments = queue.arguments();
+        let arguments = arguments.inner();
+
+        assert_eq!(arguments.get("x-queue-type"), Some(&AMQPValue::LongString("stream".into())));
+        assert_eq!(arguments.get("x-max-age"), Some(&AMQPValue::LongString("604800s".into())));
+        assert_eq!(
+            arguments.get("x-stream-max-segment-size-bytes"),
+            Some(&AMQPValue::LongLongInt(100_000_000))
+        );
+    }
 }
@@ -0,0 +1,216 @@
+//! Signs the messages a producer publishes and verifies them as they're consumed, so messages
+//! crossing a trust boundary can't be forged or tampered with on the way.
+//!
+//! The signature covers the payload as sent, after compression and encryption, so add the
+//! [`Signer`] after any other interceptor that changes the payload.
+
+use std::{collections::HashMap, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ed25519_dalek::{Signer as _, Verifier as _};
+use futures::future::LocalBoxFuture;
+use hmac::{Hmac, Mac};
+use lapin::{
+    message::Delivery,
+    types::{AMQPValue, FieldTable},
+};
+use serde_json::Value;
+use sha2::Sha256;
+
+use super::{
+    consumer::ProcessorError,
+    interceptor::{OutgoingMessage, PublishInterceptor},
+    middleware::{ConsumerMiddleware, Next},
+    Envelope, MqError,
+};
+
+/// The header carrying the base64 signature.
+pub const SIGNATURE_HEADER: &str = "x-signature";
+/// The header naming the key a message was signed with.
+pub const KEY_ID_HEADER: &str = "x-signature-key";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub enum SigningKey {
+    /// HMAC-SHA256 with a secret shared by producers and consumers.
+    Hmac(Vec<u8>),
+    /// Ed25519, so consumers only need the public key.
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+#[derive(Clone)]
+pub enum VerifyingKey {
+    Hmac(Vec<u8>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl SigningKey {
+    fn sign(&self, payload: &[u8]) -> Vec<u8> {
+        match self {
+            SigningKey::Hmac(secret) => {
+                let mut mac = HmacSha256::new_from_slice(secret).expect("hmac takes keys of any size");
+                mac.update(payload);
+                mac.finalize().into_bytes().to_vec()
+            }
+            SigningKey::Ed25519(key) => key.sign(payload).to_bytes().to_vec(),
+        }
+    }
+
+    /// The key consumers verify this key's signatures with.
+    pub fn verifying_key(&self) -> VerifyingKey {
+        match self {
+            SigningKey::Hmac(secret) => VerifyingKey::Hmac(secret.clone()),
+            SigningKey::Ed25519(key) => VerifyingKey::Ed25519(key.verifying_key()),
+        }
+    }
+}
+
+impl VerifyingKey {
+    fn verify(&self, payload: &[u8], signature: &[u8]) -> bool {
+        match self {
+            VerifyingKey::Hmac(secret) => {
+                let mut mac = HmacSha256::new_from_slice(secret).expect("hmac takes keys of any size");
+                mac.update(payload);
+                mac.verify_slice(signature).is_ok()
+            }
+            VerifyingKey::Ed25519(key) => ed25519_dalek::Signature::from_slice(signature)
+                .is_ok_and(|signature| key.verify(payload, &signature).is_ok()),
+        }
+    }
+}
+
+/// Looks up the keys messages are verified with, by the id they were signed under.
+pub trait KeyProvider: Send + Sync {
+    fn verifying_key(&self, key_id: &str) -> Option<VerifyingKey>;
+}
+
+impl KeyProvider for HashMap<String, VerifyingKey> {
+    fn verifying_key(&self, key_id: &str) -> Option<VerifyingKey> {
+        self.get(key_id).cloned()
+    }
+}
+
+impl<K: KeyProvider + ?Sized> KeyProvider for Arc<K> {
+    fn verifying_key(&self, key_id: &str) -> Option<VerifyingKey> {
+        K::verifying_key(self, key_id)
+    }
+}
+
+/// Signs each message with `key`, recording `key_id` so consumers know which key to verify it with.
+pub struct Signer {
+    key_id: String,
+    key: SigningKey,
+}
+
+impl Signer {
+    pub fn new(key_id: impl Into<String>, key: SigningKey) -> Self {
+        Signer {
+            key_id: key_id.into(),
+            key,
+        }
+    }
+}
+
+impl PublishInterceptor for Signer {
+    fn intercept(&self, message: &mut OutgoingMessage) -> Result<(), MqError> {
+        let signature = STANDARD.encode(self.key.sign(&message.payload));
+        let mut headers = message.properties.headers().clone().unwrap_or_default();
+        headers.insert(SIGNATURE_HEADER.into(), AMQPValue::LongString(signature.into()));
+        headers.insert(KEY_ID_HEADER.into(), AMQPValue::LongString(self.key_id.as_str().into()));
+        message.properties = message.properties.clone().with_headers(headers);
+        Ok(())
+    }
+}
+
+/// Rejects messages that aren't signed by a key the provider knows, or whose signature doesn't
+/// match, as permanent failures.
+pub struct Verifier<K> {
+    keys: K,
+}
+
+impl<K: KeyProvider> Verifier<K> {
+    pub fn new(keys: K) -> Self {
+        Verifier { keys }
+    }
+
+    fn verify(&self, payload: &[u8], headers: Option<&FieldTable>) -> Result<(), ProcessorError> {
+        let header = |name| match headers.and_then(|h| h.inner().get(name)) {
+            Some(AMQPValue::LongString(s)) => Some(s.to_string()),
+            Some(AMQPValue::ShortString(s)) => Some(s.to_string()),
+            _ => None,
+        };
+        let rejected = |reason: &str| ProcessorError::PermanentError(format!("rejecting message: {reason}"));
+
+        let key_id = header(KEY_ID_HEADER).ok_or_else(|| rejected("not signed"))?;
+        let signature = header(SIGNATURE_HEADER)
+            .and_then(|s| STANDARD.decode(s).ok())
+            .ok_or_else(|| rejected("no valid signature"))?;
+        let key = self
+            .keys
+            .verifying_key(&key_id)
+            .ok_or_else(|| rejected(&format!("unknown signing key {key_id:?}")))?;
+
+        if !key.verify(payload, &signature) {
+            return Err(rejected("signature doesn't match"));
+        }
+        Ok(())
+    }
+}
+
+impl<K: KeyProvider> ConsumerMiddleware for Verifier<K> {
+    fn handle<'a>(
+        &'a self,
+        delivery: &'a Delivery,
+        envelope: Envelope<Value>,
+        next: Next<'a>,
+    ) -> LocalBoxFuture<'a, Result<(), ProcessorError>> {
+        Box::pin(async move {
+            self.verify(&delivery.data, delivery.properties.headers().as_ref())?;
+            next.run(envelope).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lapin::BasicProperties;
+
+    use super::*;
+
+    fn signed(signer: &Signer) -> OutgoingMessage {
+        let mut message = OutgoingMessage {
+            exchange: "events".into(),
+            routing_key: "payment.taken".into(),
+            payload: br#"{"message":{"amount":10}}"#.to_vec(),
+            properties: BasicProperties::default(),
+        };
+        signer.intercept(&mut message).unwrap();
+        message
+    }
+
+    fn check(key: SigningKey) {
+        let signer = Signer::new("k1", key.clone());
+        let verifier = Verifier::new(HashMap::from([("k1".to_string(), key.verifying_key())]));
+
+        let message = signed(&signer);
+        let headers = message.properties.headers().as_ref();
+        assert!(verifier.verify(&message.payload, headers).is_ok());
+        assert!(verifier.verify(br#"{"message":{"amount":1000}}"#, headers).is_err());
+        assert!(verifier.verify(&message.payload, None).is_err());
+
+        let stranger = Signer::new("k2", key);
+        let message = signed(&stranger);
+        assert!(verifier.verify(&message.payload, message.properties.headers().as_ref()).is_err());
+    }
+
+    #[test]
+    fn hmac() {
+        check(SigningKey::Hmac(b"shared secret".to_vec()));
+    }
+
+    #[test]
+    fn ed25519() {
+        check(SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&[7; 32])));
+    }
+}
@@ -0,0 +1,437 @@
+//! SQS and SNS behind the same [`Publisher`] and [`Subscriber`] traits as RabbitMQ. SNS topics play
+//! the part of exchanges and SQS queues the part of queues; subscribe queues to topics with raw
+//! message delivery, so messages arrive as they were published.
+
+use std::{collections::HashMap, future::Future, pin::pin, time::Duration};
+
+use aws_sdk_sqs::{error::DisplayErrorContext, types::MessageSystemAttributeName};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use lapin::{
+    types::{AMQPValue, FieldTable},
+    BasicProperties,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use super::{
+    backend::{Publisher, Subscriber},
+    codec::{Codec, Json},
+    consumer::{process_as, MessageContext, Processor, ProcessorError},
+    Envelope, MqError,
+};
+
+const MESSAGE_ID: &str = "message-id";
+const CORRELATION_ID: &str = "correlation-id";
+const CONTENT_TYPE: &str = "content-type";
+const CONTENT_ENCODING: &str = "content-encoding";
+const ROUTING_KEY: &str = "routing-key";
+// bodies have to be text, so binary payloads are sent base64 encoded
+const TRANSFER_ENCODING: &str = "content-transfer-encoding";
+
+/// Sends messages straight to an SQS queue.
+///
+/// Metadata travels as message attributes, of which SQS allows 10: the message id, correlation
+/// id, content type and routing key take up to 4, leaving 6 for headers.
+pub struct SqsProducer<C: Codec = Json> {
+    client: aws_sdk_sqs::Client,
+    queue_url: String,
+    codec: C,
+}
+
+impl SqsProducer {
+    pub fn new(client: aws_sdk_sqs::Client, queue_url: impl Into<String>) -> Self {
+        SqsProducer {
+            client,
+            queue_url: queue_url.into(),
+            codec: Json,
+        }
+    }
+
+    /// A producer using credentials and region from the environment.
+    pub async fn from_env(queue_url: impl Into<String>) -> Self {
+        let config = sdk_config().await;
+        Self::new(aws_sdk_sqs::Client::new(&config), queue_url)
+    }
+}
+
+impl<C: Codec> SqsProducer<C> {
+    pub fn with_codec<D: Codec>(self, codec: D) -> SqsProducer<D> {
+        SqsProducer {
+            client: self.client,
+            queue_url: self.queue_url,
+            codec,
+        }
+    }
+
+    /// Sends `envelope`, recording `routing_key` as an attribute.
+    pub async fn publish<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+        let properties = envelope.properties().with_content_type(self.codec.content_type().into());
+        let (body, attributes) = encode(self.codec.encode(&envelope)?, &properties, routing_key);
+
+        let mut request = self.client.send_message().queue_url(&self.queue_url).message_body(body);
+        for (name, value) in attributes {
+            request = request.message_attributes(name, sqs_attribute(value)?);
+        }
+        request.send().await.map_err(aws_error)?;
+        Ok(())
+    }
+}
+
+impl<C: Codec> Publisher for SqsProducer<C> {
+    async fn publish_envelope<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+        self.publish(envelope, routing_key).await
+    }
+}
+
+/// Publishes messages to an SNS topic, fanning them out to the queues subscribed to it. The routing
+/// key is sent as the `routing-key` attribute, for subscriptions to filter on.
+pub struct SnsProducer<C: Codec = Json> {
+    client: aws_sdk_sns::Client,
+    topic_arn: String,
+    codec: C,
+}
+
+impl SnsProducer {
+    pub fn new(client: aws_sdk_sns::Client, topic_arn: impl Into<String>) -> Self {
+        SnsProducer {
+            client,
+            topic_arn: topic_arn.into(),
+            codec: Json,
+        }
+    }
+
+    /// A producer using credentials and region from the environment.
+    pub async fn from_env(topic_arn: impl Into<String>) -> Self {
+        let config = sdk_config().await;
+        Self::new(aws_sdk_sns::Client::new(&config), topic_arn)
+    }
+}
+
+impl<C: Codec> SnsProducer<C> {
+    pub fn with_codec<D: Codec>(self, codec: D) -> SnsProducer<D> {
+        SnsProducer {
+            client: self.client,
+            topic_arn: self.topic_arn,
+            codec,
+        }
+    }
+
+    pub async fn publish<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+        let properties = envelope.properties().with_content_type(self.codec.content_type().into());
+        let (body, attributes) = encode(self.codec.encode(&envelope)?, &properties, routing_key);
+
+        let mut request = self.client.publish().topic_arn(&self.topic_arn).message(body);
+        for (name, value) in attributes {
+            let value = aws_sdk_sns::types::MessageAttributeValue::builder()
+                .data_type("String")
+                .string_value(value)
+                .build()
+                .map_err(aws_error)?;
+            request = request.message_attributes(name, value);
+        }
+        request.send().await.map_err(aws_error)?;
+        Ok(())
+    }
+}
+
+impl<C: Codec> Publisher for SnsProducer<C> {
+    async fn publish_envelope<M: Serialize>(&self, envelope: Envelope<M>, routing_key: Option<&str>) -> Result<(), MqError> {
+        self.publish(envelope, routing_key).await
+    }
+}
+
+/// Long polls an SQS queue, deleting each message once it's processed.
+///
+/// While a message is being processed its visibility timeout is extended, so it isn't handed to
+/// another consumer however long processing takes. Messages failing temporarily are made visible
+/// again after `retry_delay`; the queue's redrive policy moves them to its dead letter queue once
+/// they've been received too often. Messages failing permanently are sent to the dead letter queue
+/// straight away if one is set with [`SqsConsumer::with_dead_letter_queue`], and deleted.
+pub struct SqsConsumer<C: Codec = Json> {
+    client: aws_sdk_sqs::Client,
+    queue_url: String,
+    dead_letter_queue_url: Option<String>,
+    visibility_timeout: Duration,
+    retry_delay: Duration,
+    codec: C,
+}
+
+impl SqsConsumer {
+    pub fn new(client: aws_sdk_sqs::Client, queue_url: impl Into<String>) -> Self {
+        SqsConsumer {
+            client,
+            queue_url: queue_url.into(),
+            dead_letter_queue_url: None,
+            visibility_timeout: Duration::from_secs(30),
+            retry_delay: Duration::from_secs(5),
+            codec: Json,
+        }
+    }
+
+    /// A consumer using credentials and region from the environment.
+    pub async fn from_env(queue_url: impl Into<String>) -> Self {
+        let config = sdk_config().await;
+        Self::new(aws_sdk_sqs::Client::new(&config), queue_url)
+    }
+}
+
+impl<C: Codec> SqsConsumer<C> {
+    pub fn with_codec<D: Codec>(self, codec: D) -> SqsConsumer<D> {
+        SqsConsumer {
+            client: self.client,
+            queue_url: self.queue_url,
+            dead_letter_queue_url: self.dead_letter_queue_url,
+            visibility_timeout: self.visibility_timeout,
+            retry_delay: self.retry_delay,
+            codec,
+        }
+    }
+
+    pub fn with_dead_letter_queue(mut self, queue_url: impl Into<String>) -> Self {
+        self.dead_letter_queue_url = Some(queue_url.into());
+        self
+    }
+
+    /// How long a received message stays hidden from other consumers, extended by the same again
+    /// halfway through for as long as it's being processed. Defaults to 30s.
+    pub fn with_visibility_timeout(mut self, visibility_timeout: Duration) -> Self {
+        self.visibility_timeout = visibility_timeout;
+        self
+    }
+
+    /// How long a message failing temporarily stays hidden before it's retried. Defaults to 5s.
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    pub async fn consume_until<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, shutdown: impl Future<Output = ()>) -> Result<(), MqError> {
+        let mut shutdown = pin!(shutdown);
+        loop {
+            let received = self
+                .client
+                .receive_message()
+                .queue_url(&self.queue_url)
+                .max_number_of_messages(10)
+                .wait_time_seconds(20)
+                .visibility_timeout(self.visibility_timeout.as_secs() as i32)
+                .message_attribute_names("All")
+                .message_system_attribute_names(MessageSystemAttributeName::ApproximateReceiveCount)
+                .send();
+            let received = tokio::select! {
+                _ = &mut shutdown => break,
+                received = received => received.map_err(aws_error)?,
+            };
+
+            // messages already received are processed before shutting down, rather than left
+            // hidden until their visibility timeout runs out
+            for message in received.messages() {
+                self.handle_message(processor, message).await?;
+            }
+        }
+
+        debug!("shutting down sqs consumer");
+        Ok(())
+    }
+
+    async fn handle_message<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, message: &aws_sdk_sqs::types::Message) -> Result<(), MqError> {
+        let Some(receipt_handle) = message.receipt_handle() else {
+            return Ok(());
+        };
+        let attributes = message
+            .message_attributes()
+            .into_iter()
+            .flatten()
+            .filter_map(|(name, value)| Some((name.as_str(), value.string_value()?)))
+            .collect::<HashMap<_, _>>();
+
+        let result = match decode(message.body().unwrap_or_default(), &attributes) {
+            Ok((payload, properties)) => match self.codec.decode_delivery::<Envelope<Value>>(&properties, &payload) {
+                Ok(envelope) => {
+                    let receive_count = message
+                        .attributes()
+                        .and_then(|a| a.get(&MessageSystemAttributeName::ApproximateReceiveCount))
+                        .and_then(|count| count.parse().ok())
+                        .unwrap_or(1);
+                    let context = MessageContext {
+                        redelivered: receive_count > 1,
+                        attempt: receive_count,
+                        ..MessageContext::new(attributes.get(ROUTING_KEY).copied().unwrap_or_default(), &properties)
+                    };
+                    let mut processing = pin!(process_as(processor, envelope.with_properties(&properties), &context));
+                    loop {
+                        tokio::select! {
+                            result = &mut processing => break result,
+                            _ = tokio::time::sleep(self.visibility_timeout / 2) => {
+                                self.change_visibility(receipt_handle, self.visibility_timeout).await?
+                            }
+                        }
+                    }
+                }
+                Err(e) => Err(ProcessorError::PermanentError(e.to_string())),
+            },
+            Err(e) => Err(ProcessorError::PermanentError(e.to_string())),
+        };
+
+        match result {
+            Ok(_) => debug!("message successful"),
+            Err(ProcessorError::TemporaryError(e)) => {
+                warn!("message failed temporarily, retrying in {:?}: {e}", self.retry_delay);
+                return self.change_visibility(receipt_handle, self.retry_delay).await;
+            }
+            Err(ProcessorError::PermanentError(e)) => {
+                warn!("message failed permanently: {e}");
+                if let Some(dead_letter_queue_url) = &self.dead_letter_queue_url {
+                    self.client
+                        .send_message()
+                        .queue_url(dead_letter_queue_url)
+                        .message_body(message.body().unwrap_or_default())
+                        .set_message_attributes(message.message_attributes().cloned())
+                        .send()
+                        .await
+                        .map_err(aws_error)?;
+                }
+            }
+        }
+
+        self.client
+            .delete_message()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .send()
+            .await
+            .map_err(aws_error)?;
+        Ok(())
+    }
+
+    async fn change_visibility(&self, receipt_handle: &str, timeout: Duration) -> Result<(), MqError> {
+        self.client
+            .change_message_visibility()
+            .queue_url(&self.queue_url)
+            .receipt_handle(receipt_handle)
+            .visibility_timeout(timeout.as_secs() as i32)
+            .send()
+            .await
+            .map_err(aws_error)?;
+        Ok(())
+    }
+}
+
+impl<C: Codec> Subscriber for SqsConsumer<C> {
+    async fn subscribe_until<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P, shutdown: impl Future<Output = ()>) -> Result<(), MqError> {
+        self.consume_until(processor, shutdown).await
+    }
+}
+
+/// The message body and the attributes carrying its properties.
+fn encode(payload: Vec<u8>, properties: &BasicProperties, routing_key: Option<&str>) -> (String, Vec<(String, String)>) {
+    let mut attributes = Vec::new();
+    let body = match String::from_utf8(payload) {
+        Ok(body) => body,
+        Err(e) => {
+            attributes.push((TRANSFER_ENCODING.to_string(), "base64".to_string()));
+            STANDARD.encode(e.into_bytes())
+        }
+    };
+
+    let named = [
+        (MESSAGE_ID, properties.message_id()),
+        (CORRELATION_ID, properties.correlation_id()),
+        (CONTENT_TYPE, properties.content_type()),
+        (CONTENT_ENCODING, properties.content_encoding()),
+    ];
+    for (name, value) in named {
+        if let Some(value) = value {
+            attributes.push((name.to_string(), value.to_string()));
+        }
+    }
+    if let Some(routing_key) = routing_key.filter(|r| !r.is_empty()) {
+        attributes.push((ROUTING_KEY.to_string(), routing_key.to_string()));
+    }
+    for (name, value) in properties.headers().iter().flat_map(|h| h.inner()) {
+        let value = match value {
+            AMQPValue::LongString(s) => s.to_string(),
+            AMQPValue::ShortString(s) => s.to_string(),
+            _ => continue,
+        };
+        attributes.push((name.to_string(), value));
+    }
+    (body, attributes)
+}
+
+/// The payload and properties of a received message.
+fn decode(body: &str, attributes: &HashMap<&str, &str>) -> Result<(Vec<u8>, BasicProperties), MqError> {
+    let payload = match attributes.get(TRANSFER_ENCODING) {
+        Some(&"base64") => STANDARD
+            .decode(body)
+            .map_err(|e| MqError::CodecError(e.to_string()))?,
+        _ => body.as_bytes().to_vec(),
+    };
+
+    let mut properties = BasicProperties::default();
+    let mut headers = FieldTable::default();
+    for (&name, &value) in attributes {
+        properties = match name {
+            MESSAGE_ID => properties.with_message_id(value.into()),
+            CORRELATION_ID => properties.with_correlation_id(value.into()),
+            CONTENT_TYPE => properties.with_content_type(value.into()),
+            CONTENT_ENCODING => properties.with_content_encoding(value.into()),
+            TRANSFER_ENCODING | ROUTING_KEY => properties,
+            name => {
+                headers.insert(name.into(), AMQPValue::LongString(value.into()));
+                properties
+            }
+        };
+    }
+    if !headers.inner().is_empty() {
+        properties = properties.with_headers(headers);
+    }
+    Ok((payload, properties))
+}
+
+fn sqs_attribute(value: String) -> Result<aws_sdk_sqs::types::MessageAttributeValue, MqError> {
+    aws_sdk_sqs::types::MessageAttributeValue::builder()
+        .data_type("String")
+        .string_value(value)
+        .build()
+        .map_err(aws_error)
+}
+
+async fn sdk_config() -> aws_config::SdkConfig {
+    aws_config::load_defaults(aws_config::BehaviorVersion::latest()).await
+}
+
+fn aws_error(e: impl std::error::Error) -> MqError {
+    MqError::AwsError(DisplayErrorContext(e).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(payload: Vec<u8>) {
+        let properties = Envelope::new(String::new())
+            .with_message_id("m-1")
+            .with_header("tenant", "acme")
+            .properties()
+            .with_content_type("application/cbor".into());
+
+        let (body, attributes) = encode(payload.clone(), &properties, Some("order.created"));
+        let attributes = attributes.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+        let (received, received_properties) = decode(&body, &attributes).unwrap();
+
+        assert_eq!(received, payload);
+        assert_eq!(received_properties, properties);
+    }
+
+    #[test]
+    fn text_payload() {
+        round_trip(br#"{"message":"order"}"#.to_vec());
+    }
+
+    #[test]
+    fn binary_payload() {
+        round_trip(vec![0xa1, 0xff, 0x00, 0x80]);
+    }
+}
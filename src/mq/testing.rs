@@ -0,0 +1,805 @@
+//! A broker living in memory, so processors and topologies can be tested without RabbitMQ.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::{self, Future},
+    pin::pin,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use chrono::Utc;
+use lapin::BasicProperties;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::sync::{broadcast, Notify};
+use tracing::{debug, warn};
+
+use super::{
+    codec::{Codec, Json},
+    consumer::{
+        event_stream, process_as, ConsumerControl, ConsumerEvent, ConsumerResult, ConsumerStream, MessageContext, Processor,
+        ProcessorError,
+    },
+    producer::PublishOptions,
+    routing,
+    setup::{self, Binding, ExchangeType, QueueOptions, TeardownOptions, TopologyOps},
+    ChannelOps, Envelope, Exchange, MqError, Queue,
+};
+
+/// Routes messages like RabbitMQ would: by exact routing key through direct exchanges, by pattern
+/// through topic exchanges, and straight to the queue named by the routing key through the default
+/// exchange. Messages nobody is bound to receive go to the exchange's alternate exchange, if it
+/// has one, or are dropped.
+///
+/// Clones share the same queues. Producers and consumers are made with [`ChannelOps`], as from a
+/// channel, and declare their topology with [`TopologyOps`].
+#[derive(Clone, Default)]
+pub struct InMemoryBroker {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    state: Mutex<State>,
+    published: Notify,
+}
+
+#[derive(Default)]
+struct State {
+    exchanges: HashMap<String, MemoryExchange>,
+    queues: HashMap<String, MemoryQueue>,
+    bindings: Vec<MemoryBinding>,
+}
+
+struct MemoryExchange {
+    kind: ExchangeType,
+    alternate: Option<String>,
+}
+
+#[derive(Default)]
+struct MemoryQueue {
+    messages: VecDeque<Message>,
+    dead_letter_exchange: Option<String>,
+    dead_letter_routing_key: Option<String>,
+}
+
+struct MemoryBinding {
+    source: String,
+    target: Target,
+    routing_key: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Target {
+    Queue(String),
+    Exchange(String),
+}
+
+/// A message waiting in a queue.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub routing_key: String,
+    pub body: Vec<u8>,
+    pub properties: BasicProperties,
+    /// Whether the message was delivered before and requeued.
+    pub redelivered: bool,
+}
+
+impl InMemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many messages are waiting in `queue`.
+    pub fn queue_len(&self, queue: &str) -> usize {
+        self.state().queues.get(queue).map_or(0, |q| q.messages.len())
+    }
+
+    /// The messages waiting in `queue`, oldest first, without consuming them.
+    pub fn messages(&self, queue: &str) -> Vec<Message> {
+        self.state()
+            .queues
+            .get(queue)
+            .map(|q| q.messages.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// The envelopes waiting in `queue`, decoded as `M`, without consuming them.
+    pub fn envelopes<M: DeserializeOwned>(&self, queue: &str) -> Result<Vec<Envelope<M>>, MqError> {
+        self.messages(queue)
+            .iter()
+            .map(|m| Ok(Json.decode_delivery::<Envelope<M>>(&m.properties, &m.body)?.with_properties(&m.properties)))
+            .collect()
+    }
+
+    /// Publishes a message as is, returning how many queues it was routed to.
+    pub fn publish_raw(&self, exchange: &str, routing_key: &str, body: Vec<u8>, properties: BasicProperties) -> Result<usize, MqError> {
+        let mut state = self.state();
+        if !exchange.is_empty() && !state.exchanges.contains_key(exchange) {
+            return Err(MqError::ConfigurationError(format!("no exchange {exchange:?}")));
+        }
+
+        let queues = state.route(exchange, routing_key);
+        for queue in &queues {
+            if let Some(queue) = state.queues.get_mut(queue) {
+                queue.messages.push_back(Message {
+                    routing_key: routing_key.into(),
+                    body: body.clone(),
+                    properties: properties.clone(),
+                    redelivered: false,
+                });
+            }
+        }
+        drop(state);
+
+        if queues.is_empty() {
+            debug!("dropping message to {exchange:?} with routing key {routing_key:?}, no queue is bound");
+        }
+        self.inner.published.notify_waiters();
+        Ok(queues.len())
+    }
+
+    fn pop(&self, queue: &str) -> Option<Message> {
+        self.state().queues.get_mut(queue)?.messages.pop_front()
+    }
+
+    /// Settles a message taken from `queue` the way a consumer would ack or nack it.
+    fn settle(&self, queue: &str, mut message: Message, result: &Result<(), ProcessorError>) -> Result<(), MqError> {
+        match result {
+            Ok(_) => Ok(()),
+            Err(ProcessorError::TemporaryError(e)) => {
+                warn!("message failed temporarily: {e}");
+                message.redelivered = true;
+                if let Some(queue) = self.state().queues.get_mut(queue) {
+                    queue.messages.push_back(message);
+                }
+                self.inner.published.notify_waiters();
+                Ok(())
+            }
+            Err(ProcessorError::PermanentError(e)) => {
+                warn!("message failed permanently: {e}");
+                let dead_letter = self.state().queues.get(queue).and_then(|q| {
+                    let exchange = q.dead_letter_exchange.clone()?;
+                    Some((exchange, q.dead_letter_routing_key.clone()))
+                });
+                if let Some((exchange, routing_key)) = dead_letter {
+                    let routing_key = routing_key.unwrap_or(message.routing_key);
+                    self.publish_raw(&exchange, &routing_key, message.body, message.properties)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    /// The queues a message to `exchange` with `routing_key` ends up in.
+    fn route(&self, exchange: &str, routing_key: &str) -> HashSet<String> {
+        let mut queues = HashSet::new();
+        if exchange.is_empty() {
+            if self.queues.contains_key(routing_key) {
+                queues.insert(routing_key.to_string());
+            }
+            return queues;
+        }
+
+        let mut visited = HashSet::new();
+        let mut pending = vec![exchange.to_string()];
+        while let Some(exchange) = pending.pop() {
+            if !visited.insert(exchange.clone()) {
+                continue;
+            }
+            let Some(declared) = self.exchanges.get(&exchange) else {
+                continue;
+            };
+            let matching = self
+                .bindings
+                .iter()
+                .filter(|b| b.source == exchange && binding_matches(declared.kind, &b.routing_key, routing_key))
+                .collect::<Vec<_>>();
+            if matching.is_empty() {
+                pending.extend(declared.alternate.clone());
+            }
+            for binding in matching {
+                match &binding.target {
+                    Target::Queue(queue) => {
+                        queues.insert(queue.clone());
+                    }
+                    Target::Exchange(exchange) => pending.push(exchange.clone()),
+                }
+            }
+        }
+        queues
+    }
+}
+
+fn binding_matches(kind: ExchangeType, binding_key: &str, routing_key: &str) -> bool {
+    match kind {
+        ExchangeType::Direct => binding_key == routing_key,
+        ExchangeType::Topic => routing::pattern_matches(binding_key, routing_key),
+    }
+}
+
+impl TopologyOps for InMemoryBroker {
+    async fn with_queue<Name: Into<String> + Clone>(&self, queue: &setup::Queue<Name>) -> Result<(), MqError> {
+        let mut state = self.state();
+        let declared = state.queues.entry(queue.name.clone().into()).or_default();
+        for option in &queue.options {
+            match option {
+                QueueOptions::DeadLetterExchange(exchange) => declared.dead_letter_exchange = Some(exchange.clone()),
+                QueueOptions::DeadLetterRoutingKey(routing_key) => {
+                    declared.dead_letter_routing_key = Some(routing_key.clone())
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn with_exchange<Name: Into<String> + Clone>(&self, exchange: &setup::Exchange<Name>) -> Result<(), MqError> {
+        let declared = MemoryExchange {
+            kind: exchange.kind,
+            alternate: exchange.alternate.clone().map(Into::into),
+        };
+        self.state().exchanges.insert(exchange.name.clone().into(), declared);
+        Ok(())
+    }
+
+    async fn with_binding<Name: Into<String> + Clone>(&self, binding: &Binding<Name>) -> Result<(), MqError> {
+        let (source, target, routing_key) = match binding.clone() {
+            Binding::ToQueue {
+                src_exchange_name,
+                target_queue_name,
+                routing_key,
+            } => (src_exchange_name, Target::Queue(target_queue_name.into()), routing_key),
+            Binding::ToExchange {
+                src_exchange_name,
+                target_exchange_name,
+                routing_key,
+            } => (src_exchange_name, Target::Exchange(target_exchange_name.into()), routing_key),
+        };
+        let binding = MemoryBinding {
+            source: source.into(),
+            target,
+            routing_key: routing_key.map(Into::into).unwrap_or_default(),
+        };
+
+        let mut state = self.state();
+        if !state.exchanges.contains_key(&binding.source) {
+            return Err(MqError::ConfigurationError(format!("no exchange {:?}", binding.source)));
+        }
+        let exists = match &binding.target {
+            Target::Queue(queue) => state.queues.contains_key(queue),
+            Target::Exchange(exchange) => state.exchanges.contains_key(exchange),
+        };
+        if !exists {
+            return Err(MqError::ConfigurationError("binding to an undeclared target".into()));
+        }
+        state.bindings.push(binding);
+        Ok(())
+    }
+
+    /// Consumers aren't tracked, so every queue counts as unused.
+    async fn delete_queue<Name: Into<String> + Clone>(
+        &self,
+        queue: &setup::Queue<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError> {
+        let name: String = queue.name.clone().into();
+        let mut state = self.state();
+        if options.if_empty && state.queues.get(&name).is_some_and(|q| !q.messages.is_empty()) {
+            return Err(MqError::ConfigurationError(format!("queue {name:?} isn't empty")));
+        }
+        state.queues.remove(&name);
+        state.bindings.retain(|b| b.target != Target::Queue(name.clone()));
+        drop(state);
+        // wakes its consumers, so they stop
+        self.inner.published.notify_waiters();
+        Ok(())
+    }
+
+    async fn delete_exchange<Name: Into<String> + Clone>(
+        &self,
+        exchange: &setup::Exchange<Name>,
+        options: TeardownOptions,
+    ) -> Result<(), MqError> {
+        let name: String = exchange.name.clone().into();
+        let mut state = self.state();
+        if options.if_unused && state.bindings.iter().any(|b| b.source == name) {
+            return Err(MqError::ConfigurationError(format!("exchange {name:?} is in use")));
+        }
+        state.exchanges.remove(&name);
+        state
+            .bindings
+            .retain(|b| b.source != name && b.target != Target::Exchange(name.clone()));
+        Ok(())
+    }
+
+    async fn unbind<Name: Into<String> + Clone>(&self, binding: &Binding<Name>) -> Result<(), MqError> {
+        let (source, target, routing_key) = binding.parts();
+        let target = match binding {
+            Binding::ToQueue { .. } => Target::Queue(target),
+            Binding::ToExchange { .. } => Target::Exchange(target),
+        };
+        self.state()
+            .bindings
+            .retain(|b| !(b.source == source && b.target == target && b.routing_key == routing_key));
+        Ok(())
+    }
+}
+
+impl ChannelOps for InMemoryBroker {
+    type Producer<'a> = MemoryProducer<'a>;
+    type Consumer<'a> = MemoryConsumer<'a>;
+
+    fn create_producer(self, exchange: Exchange<'_>) -> MemoryProducer<'_> {
+        MemoryProducer { broker: self, exchange }
+    }
+
+    fn create_consumer<'a>(self, consumer_tag: &'a str, queue: Queue<'a>) -> MemoryConsumer<'a> {
+        MemoryConsumer {
+            broker: self,
+            consumer_tag,
+            queue,
+            control: ConsumerControl::default(),
+            events: broadcast::channel(16).0,
+        }
+    }
+}
+
+/// Publishes to an [`InMemoryBroker`], like a [`Producer`](super::producer::Producer).
+#[derive(Clone)]
+pub struct MemoryProducer<'a> {
+    broker: InMemoryBroker,
+    exchange: Exchange<'a>,
+}
+
+impl MemoryProducer<'_> {
+    pub async fn publish<M: Serialize, R: Into<String>>(&self, envelope: Envelope<M>, routing_key: Option<R>) -> Result<(), MqError> {
+        self.publish_with(envelope, routing_key, &PublishOptions::default()).await
+    }
+
+    pub async fn publish_with<M: Serialize, R: Into<String>>(
+        &self,
+        envelope: Envelope<M>,
+        routing_key: Option<R>,
+        options: &PublishOptions,
+    ) -> Result<(), MqError> {
+        let body = Json.encode(&envelope)?;
+        let properties = options
+            .apply(envelope.properties())
+            .with_content_type(Json.content_type().into());
+        let routing_key = routing_key.map(|r| r.into()).unwrap_or_default();
+        self.broker.publish_raw(self.exchange.name, &routing_key, body, properties)?;
+        Ok(())
+    }
+}
+
+/// Consumes from an [`InMemoryBroker`], like a [`Consumer`](super::consumer::Consumer). Messages
+/// failing temporarily are requeued at the back of the queue, and ones failing permanently are
+/// dead-lettered if the queue has a dead letter exchange.
+#[derive(Clone)]
+pub struct MemoryConsumer<'a> {
+    broker: InMemoryBroker,
+    consumer_tag: &'a str,
+    queue: Queue<'a>,
+    control: ConsumerControl,
+    events: broadcast::Sender<ConsumerEvent>,
+}
+
+impl MemoryConsumer<'_> {
+    /// A handle pausing and resuming this consumer and its clones, like
+    /// [`Consumer::control`](super::consumer::Consumer::control). Messages are left in the queue
+    /// while paused.
+    pub fn control(&self) -> ConsumerControl {
+        self.control.clone()
+    }
+
+    pub fn pause(&self) {
+        self.control.pause();
+    }
+
+    pub fn resume(&self) {
+        self.control.resume();
+    }
+
+    /// Every [`ConsumerEvent`] from this consumer and its clones from now on, like
+    /// [`Consumer::events`](super::consumer::Consumer::events). Consuming stops with
+    /// [`ConsumerEvent::Cancelled`] once the queue is deleted.
+    pub fn events(&self) -> ConsumerStream<ConsumerEvent> {
+        event_stream(&self.events)
+    }
+
+    pub async fn consume<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P) -> ConsumerResult<()> {
+        self.consume_until(processor, future::pending()).await
+    }
+
+    /// Processes messages as they arrive until `shutdown` completes.
+    pub async fn consume_until<M: DeserializeOwned, P: Processor<M>>(
+        &self,
+        processor: &mut P,
+        shutdown: impl Future<Output = ()>,
+    ) -> ConsumerResult<()> {
+        let mut shutdown = pin!(shutdown);
+        let mut paused = self.control.subscribe();
+        loop {
+            if *paused.borrow_and_update() {
+                tokio::select! {
+                    _ = &mut shutdown => break,
+                    _ = paused.wait_for(|paused| !paused) => continue,
+                }
+            }
+
+            let published = self.broker.inner.published.notified();
+            if !self.broker.state().queues.contains_key(self.queue.name) {
+                ConsumerEvent::Cancelled {
+                    queue: self.queue.name.to_string(),
+                }
+                .notify(&self.events);
+                return Ok(());
+            }
+            if self.process_next(processor).await?.is_some() {
+                continue;
+            }
+            tokio::select! {
+                _ = &mut shutdown => break,
+                _ = published => {}
+                _ = paused.changed() => {}
+            }
+        }
+        debug!("shutting down consumer {}", self.consumer_tag);
+        Ok(())
+    }
+
+    /// Processes the messages waiting in the queue, each once, returning how many were processed.
+    /// Messages requeued along the way are left for next time.
+    pub async fn drain<M: DeserializeOwned, P: Processor<M>>(&self, processor: &mut P) -> ConsumerResult<usize> {
+        let waiting = self.broker.queue_len(self.queue.name);
+        for processed in 0..waiting {
+            if self.process_next(processor).await?.is_none() {
+                return Ok(processed);
+            }
+        }
+        Ok(waiting)
+    }
+
+    /// Processes the next message in the queue, if there is one, returning how it went.
+    pub async fn process_next<M: DeserializeOwned, P: Processor<M>>(
+        &self,
+        processor: &mut P,
+    ) -> ConsumerResult<Option<Result<(), ProcessorError>>> {
+        let Some(message) = self.broker.pop(self.queue.name) else {
+            return Ok(None);
+        };
+
+        let result = match Json.decode_delivery::<Envelope<Value>>(&message.properties, &message.body) {
+            Ok(envelope) if envelope.is_expired_at(Utc::now()) => {
+                debug!("dropping message that expired at {:?}", envelope.not_after());
+                Ok(())
+            }
+            Ok(envelope) => {
+                let context = MessageContext {
+                    redelivered: message.redelivered,
+                    ..MessageContext::new(message.routing_key.as_str(), &message.properties)
+                };
+                process_as(processor, envelope.with_properties(&message.properties), &context).await
+            }
+            Err(e) => Err(ProcessorError::PermanentError(e.to_string())),
+        };
+        self.broker.settle(self.queue.name, message, &result)?;
+        Ok(Some(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+    use crate::mq::setup::{ExchangeBuilder, Topology, TopologyBuilder};
+
+    #[derive(Default)]
+    struct Recording {
+        seen: Vec<Value>,
+        contexts: Vec<MessageContext>,
+        fail_with: Option<fn(String) -> ProcessorError>,
+    }
+
+    impl Processor for Recording {
+        async fn process(&mut self, value: Value, context: &MessageContext) -> Result<(), ProcessorError> {
+            self.seen.push(value);
+            self.contexts.push(context.clone());
+            match self.fail_with {
+                Some(error) => Err(error("failed".into())),
+                None => Ok(()),
+            }
+        }
+    }
+
+    async fn broker() -> InMemoryBroker {
+        let broker = InMemoryBroker::new();
+        let topology = Topology::builder()
+            .with_exchange(setup::Exchange::new("events", ExchangeType::Topic, true))
+            .with_exchange(setup::Exchange::new("dlx", ExchangeType::Direct, true))
+            .with_queue(setup::Queue::new(
+                "orders",
+                vec![QueueOptions::DeadLetterExchange("dlx".into())],
+            ))
+            .with_queue(setup::Queue::new("everything", vec![]))
+            .with_queue(setup::Queue::new("dead", vec![]))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "events",
+                target_queue_name: "orders",
+                routing_key: Some("order.*"),
+            })
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "events",
+                target_queue_name: "everything",
+                routing_key: Some("#"),
+            })
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "dlx",
+                target_queue_name: "dead",
+                routing_key: Some("order.created"),
+            })
+            .build();
+        broker.apply_topology(topology).await.unwrap();
+        broker
+    }
+
+    #[test]
+    fn topic_patterns() {
+        let matches = |pattern: &str, key: &str| binding_matches(ExchangeType::Topic, pattern, key);
+
+        assert!(matches("order.*", "order.created"));
+        assert!(!matches("order.*", "order.created.eu"));
+        assert!(matches("order.#", "order.created.eu"));
+        assert!(matches("order.#", "order"));
+        assert!(matches("#.eu", "order.created.eu"));
+        assert!(!matches("*.created", "created"));
+    }
+
+    #[tokio::test]
+    async fn captures_unroutable_messages() {
+        let broker = InMemoryBroker::new();
+        let topology = Topology::builder()
+            .with_exchange(setup::Exchange::builder("payments").with_alternate("unrouted").build())
+            .with_queue(setup::Queue::new("payments.taken", vec![]))
+            .with_binding(Binding::ToQueue {
+                src_exchange_name: "payments",
+                target_queue_name: "payments.taken",
+                routing_key: Some("payment.taken"),
+            })
+            .build();
+        broker.apply_topology(topology).await.unwrap();
+        let producer = broker.clone().create_producer("payments".into());
+
+        producer.publish(Envelope::new(json!(1)), Some("payment.taken")).await.unwrap();
+        producer.publish(Envelope::new(json!(2)), Some("payment.tkaen")).await.unwrap();
+
+        assert_eq!(broker.queue_len("payments.taken"), 1);
+        assert_eq!(broker.messages("unrouted")[0].routing_key, "payment.tkaen");
+    }
+
+    #[tokio::test]
+    async fn tears_down_topology() {
+        let broker = broker().await;
+        let producer = broker.clone().create_producer("events".into());
+        producer.publish(Envelope::new(json!(1)), Some("order.created")).await.unwrap();
+
+        let orders = setup::Queue::new("orders", vec![]);
+        let checked = TeardownOptions { if_unused: true, if_empty: true };
+        assert!(broker.delete_queue(&orders, checked).await.is_err());
+        assert!(broker.delete_exchange(&setup::Exchange::new("events", ExchangeType::Topic, true), checked).await.is_err());
+
+        broker
+            .unbind(&Binding::ToQueue {
+                src_exchange_name: "events",
+                target_queue_name: "everything",
+                routing_key: Some("#"),
+            })
+            .await
+            .unwrap();
+        producer.publish(Envelope::new(json!(2)), Some("order.created")).await.unwrap();
+        assert_eq!(broker.queue_len("everything"), 1);
+        assert_eq!(broker.queue_len("orders"), 2);
+
+        let topology = Topology::builder()
+            .with_exchange(setup::Exchange::new("events", ExchangeType::Topic, true))
+            .with_queue(orders)
+            .build();
+        broker.remove_topology(topology, TeardownOptions::default()).await.unwrap();
+        assert_eq!(broker.queue_len("orders"), 0);
+        assert!(producer.publish(Envelope::new(json!(3)), Some("order.created")).await.is_err());
+
+        // alternates declared along with a topology may be shared, so outlive it
+        let topology = Topology::builder()
+            .with_exchange(setup::Exchange::builder("payments").with_alternate("unrouted").build())
+            .build();
+        broker.apply_topology(topology.clone()).await.unwrap();
+        broker.remove_topology(topology, TeardownOptions::default()).await.unwrap();
+        broker.publish_raw("unrouted", "payment.taken", vec![], Default::default()).unwrap();
+        assert_eq!(broker.queue_len("unrouted"), 1);
+    }
+
+    #[tokio::test]
+    async fn routes_by_binding() {
+        let broker = broker().await;
+        let producer = broker.clone().create_producer("events".into());
+
+        producer.publish(Envelope::new(json!(1)), Some("order.created")).await.unwrap();
+        producer.publish(Envelope::new(json!(2)), Some("payment.taken")).await.unwrap();
+
+        assert_eq!(broker.queue_len("orders"), 1);
+        assert_eq!(broker.queue_len("everything"), 2);
+        let orders = broker.envelopes::<i32>("orders").unwrap();
+        assert_eq!(orders[0].message, 1);
+    }
+
+    #[tokio::test]
+    async fn acks_requeues_and_dead_letters() {
+        let broker = broker().await;
+        let producer = broker.clone().create_producer("events".into());
+        let consumer = broker.clone().create_consumer("test", "orders".into());
+        producer.publish(Envelope::new(json!("a")), Some("order.created")).await.unwrap();
+
+        let mut processor = Recording {
+            fail_with: Some(ProcessorError::TemporaryError),
+            ..Default::default()
+        };
+        assert_eq!(consumer.drain(&mut processor).await.unwrap(), 1);
+        assert!(broker.messages("orders")[0].redelivered);
+
+        processor.fail_with = Some(ProcessorError::PermanentError);
+        consumer.drain(&mut processor).await.unwrap();
+        assert_eq!(broker.queue_len("orders"), 0);
+        assert_eq!(processor.contexts[0].routing_key, "order.created");
+        assert_eq!(
+            processor.contexts.iter().map(|c| c.redelivered).collect::<Vec<_>>(),
+            [false, true]
+        );
+        assert_eq!(broker.queue_len("dead"), 1);
+
+        let mut processor = Recording::default();
+        let dead = broker.clone().create_consumer("test", "dead".into());
+        dead.drain(&mut processor).await.unwrap();
+        assert_eq!(processor.seen, [json!("a")]);
+        assert_eq!(broker.queue_len("dead"), 0);
+    }
+
+    #[tokio::test]
+    async fn drops_expired_messages() {
+        let broker = broker().await;
+        let producer = broker.clone().create_producer("events".into());
+        let consumer = broker.clone().create_consumer("test", "orders".into());
+        let now = Utc::now();
+        let stale = Envelope::new(json!("stale")).with_not_after(now - chrono::Duration::seconds(1));
+        let fresh = Envelope::new(json!("fresh")).with_not_after(now + chrono::Duration::minutes(1));
+        producer.publish(stale, Some("order.created")).await.unwrap();
+        producer.publish(fresh, Some("order.created")).await.unwrap();
+
+        let mut processor = Recording::default();
+        assert_eq!(consumer.drain(&mut processor).await.unwrap(), 2);
+        assert_eq!(processor.seen, [json!("fresh")]);
+        assert_eq!(broker.queue_len("orders"), 0);
+        assert_eq!(broker.queue_len("dead"), 0);
+    }
+
+    #[tokio::test]
+    async fn processes_typed_messages() {
+        #[derive(Deserialize)]
+        struct Order {
+            id: u32,
+        }
+
+        #[derive(Default)]
+        struct Orders(Vec<u32>);
+
+        impl Processor<Order> for Orders {
+            async fn process(&mut self, order: Order, _context: &MessageContext) -> Result<(), ProcessorError> {
+                self.0.push(order.id);
+                Ok(())
+            }
+        }
+
+        let broker = broker().await;
+        let producer = broker.clone().create_producer("events".into());
+        let consumer = broker.clone().create_consumer("test", "orders".into());
+        producer.publish(Envelope::new(json!({ "id": 7 })), Some("order.created")).await.unwrap();
+        producer.publish(Envelope::new(json!("not an order")), Some("order.created")).await.unwrap();
+
+        let mut processor = Orders::default();
+        assert_eq!(consumer.drain(&mut processor).await.unwrap(), 2);
+        assert_eq!(processor.0, [7]);
+        // messages that aren't orders can't ever be processed, so are dead lettered
+        assert_eq!(broker.queue_len("orders"), 0);
+        assert_eq!(broker.queue_len("dead"), 1);
+    }
+
+    #[tokio::test]
+    async fn holds_messages_while_paused() {
+        let broker = broker().await;
+        let producer = broker.clone().create_producer("events".into());
+        let consumer = broker.clone().create_consumer("test", "orders".into());
+        let control = consumer.control();
+        let mut processor = Recording::default();
+        control.pause();
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let operating = async {
+            producer.publish(Envelope::new(json!(1)), Some("order.created")).await.unwrap();
+            for _ in 0..10 {
+                tokio::task::yield_now().await;
+            }
+            assert_eq!(broker.queue_len("orders"), 1);
+
+            control.resume();
+            while broker.queue_len("orders") > 0 {
+                tokio::task::yield_now().await;
+            }
+            let _ = stop.send(());
+        };
+        let consuming = consumer.consume_until(&mut processor, async {
+            let _ = stopped.await;
+        });
+        let ((), consumed) = tokio::join!(operating, consuming);
+        consumed.unwrap();
+
+        assert_eq!(processor.seen, [json!(1)]);
+    }
+
+    #[tokio::test]
+    async fn stops_when_the_queue_is_deleted() {
+        let broker = broker().await;
+        let consumer = broker.clone().create_consumer("test", "orders".into());
+        let mut events = consumer.events();
+        let orders = setup::Queue::new("orders", vec![]);
+
+        for _ in 0..2 {
+            let mut processor = Recording::default();
+            let deleting = async {
+                tokio::task::yield_now().await;
+                broker.delete_queue(&orders, TeardownOptions::default()).await.unwrap();
+            };
+            let (consumed, ()) = tokio::join!(consumer.consume(&mut processor), deleting);
+            consumed.unwrap();
+            // e.g. declared again by whoever deleted it
+            broker.with_queue(&orders).await.unwrap();
+        }
+
+        let cancelled = ConsumerEvent::Cancelled { queue: "orders".into() };
+        assert_eq!(events.next().await, Some(cancelled.clone()));
+        assert_eq!(events.next().await, Some(cancelled));
+    }
+
+    #[tokio::test]
+    async fn consumes_until_shutdown() {
+        let broker = broker().await;
+        let producer = broker.clone().create_producer("events".into());
+        let consumer = broker.clone().create_consumer("test", "orders".into());
+        let mut processor = Recording::default();
+
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let publishing = async {
+            for i in 0..3 {
+                producer.publish(Envelope::new(json!(i)), Some("order.created")).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+            let _ = stop.send(());
+        };
+        let consuming = consumer.consume_until(&mut processor, async {
+            let _ = stopped.await;
+        });
+        let ((), consumed) = tokio::join!(publishing, consuming);
+        consumed.unwrap();
+
+        // anything published just before shutdown is left in the queue
+        assert_eq!(processor.seen.len() + broker.queue_len("orders"), 3);
+    }
+}
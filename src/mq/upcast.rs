@@ -0,0 +1,153 @@
+//! Migrating messages published in an older shape to the current one, so consumers can be deployed
+//! before or after the producers that change a message, without every processor reading both.
+
+use std::{collections::BTreeMap, fmt, marker::PhantomData};
+
+use serde_json::Value;
+
+use super::{consumer::ProcessorError, Envelope};
+
+type Step = Box<dyn Fn(Value) -> Result<Value, String> + Send + Sync>;
+
+/// Upcasts messages of `M` to the version it's at, one step at a time, added with
+/// [`Consumer::with_upcaster`](super::consumer::Consumer::with_upcaster). Producers mark the
+/// version of what they publish with [`Envelope::with_version`]; messages without one are taken to
+/// be version 1.
+///
+/// Messages newer than the consumer knows fail temporarily, so they're requeued for a consumer
+/// that's been upgraded. Ones it can't upcast, for want of a step or because the step fails,
+/// fail permanently.
+pub struct Upcaster<M> {
+    version: u32,
+    steps: BTreeMap<u32, Step>,
+    message: PhantomData<fn() -> M>,
+}
+
+impl<M> Upcaster<M> {
+    /// Upcasts to `version`, the version `M` is at.
+    pub fn new(version: u32) -> Self {
+        Upcaster {
+            version,
+            steps: BTreeMap::new(),
+            message: PhantomData,
+        }
+    }
+
+    /// Upcasts messages at version `from` to version `from + 1` with `step`.
+    pub fn with_step(
+        mut self,
+        from: u32,
+        step: impl Fn(Value) -> Result<Value, String> + Send + Sync + 'static,
+    ) -> Self {
+        self.steps.insert(from, Box::new(step));
+        self
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Brings `envelope` up to the current version.
+    pub fn upcast(&self, envelope: Envelope<Value>) -> Result<Envelope<Value>, ProcessorError> {
+        let mut version = envelope.version().unwrap_or(1);
+        if version > self.version {
+            return Err(ProcessorError::TemporaryError(format!(
+                "message version {version} is newer than {}",
+                self.version
+            )));
+        }
+
+        let mut envelope = envelope;
+        while version < self.version {
+            let step = self.steps.get(&version).ok_or_else(|| {
+                ProcessorError::PermanentError(format!("no upcast from message version {version}"))
+            })?;
+            envelope.message = step(envelope.message).map_err(|e| {
+                ProcessorError::PermanentError(format!("upcasting message version {version}: {e}"))
+            })?;
+            version += 1;
+        }
+        Ok(envelope.with_version(version))
+    }
+}
+
+impl<M> fmt::Debug for Upcaster<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upcaster")
+            .field("version", &self.version)
+            .field("steps", &self.steps.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// [`Upcaster`] without its message type, so a consumer can hold one.
+pub(crate) trait ErasedUpcaster: Send + Sync {
+    fn upcast(&self, envelope: Envelope<Value>) -> Result<Envelope<Value>, ProcessorError>;
+}
+
+impl<M> ErasedUpcaster for Upcaster<M> {
+    fn upcast(&self, envelope: Envelope<Value>) -> Result<Envelope<Value>, ProcessorError> {
+        Upcaster::upcast(self, envelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Signup {
+        email: String,
+        plan: String,
+    }
+
+    fn upcaster() -> Upcaster<Signup> {
+        Upcaster::new(3)
+            // v2 renamed `mail` to `email`
+            .with_step(1, |mut message| {
+                let mail = message.as_object_mut().and_then(|m| m.remove("mail")).ok_or("no mail")?;
+                message["email"] = mail;
+                Ok(message)
+            })
+            // v3 added `plan`
+            .with_step(2, |mut message| {
+                message["plan"] = json!("free");
+                Ok(message)
+            })
+    }
+
+    #[test]
+    fn upcasts_older_versions() {
+        let upcaster = upcaster();
+
+        let unversioned = upcaster.upcast(Envelope::new(json!({"mail": "a@b.c"}))).unwrap();
+        assert_eq!(unversioned.version(), Some(3));
+        assert_eq!(
+            unversioned.deserialize::<Signup>().unwrap().message,
+            Signup {
+                email: "a@b.c".into(),
+                plan: "free".into()
+            }
+        );
+
+        let current = Envelope::new(json!({"email": "a@b.c", "plan": "pro"})).with_version(3);
+        assert_eq!(upcaster.upcast(current).unwrap().message["plan"], "pro");
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_upcast() {
+        let upcaster = upcaster();
+
+        let newer = upcaster.upcast(Envelope::new(json!({})).with_version(4));
+        assert!(matches!(newer, Err(ProcessorError::TemporaryError(_))));
+
+        let broken = upcaster.upcast(Envelope::new(json!({})));
+        assert!(matches!(broken, Err(ProcessorError::PermanentError(_))));
+
+        let unknown = upcaster.upcast(Envelope::new(json!({})).with_version(0));
+        assert!(matches!(unknown, Err(ProcessorError::PermanentError(_))));
+    }
+}
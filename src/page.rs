@@ -0,0 +1,37 @@
+use derive_more::Constructor;
+
+/// A window of rows returned by generated `list_<entity>` queries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Constructor)]
+pub struct Page {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl Page {
+    /// The `number`th page of `size` rows, counting from 0.
+    pub fn number(number: i64, size: i64) -> Self {
+        Page::new(size, number * size)
+    }
+
+    pub fn next(self) -> Self {
+        Page::new(self.limit, self.offset + self.limit)
+    }
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page::new(100, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paging() {
+        let page = Page::number(2, 20);
+        assert_eq!(page, Page::new(20, 40));
+        assert_eq!(page.next(), Page::new(20, 60));
+    }
+}
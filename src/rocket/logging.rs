@@ -0,0 +1,65 @@
+//! Changing log filters while running, from the [`FilterHandle`] the Rocket instance manages.
+//! Anyone who can reach these routes can flood the logs, so mount them somewhere only operators
+//! can:
+//!
+//! ```no_run
+//! # use launchpad::{rocket::logging, tracing::configure};
+//! # #[rocket::main]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let logging = configure(None)?;
+//! let _ = rocket::build()
+//!     .manage(logging.filter.clone())
+//!     .mount("/admin", logging::routes())
+//!     .launch()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! after which `curl -X POST -d 'launchpad::mq=debug' localhost:8000/admin/log-filter` turns on
+//! debug logs for `launchpad::mq` in every sink, and `curl -X DELETE ...` turns them off again.
+
+use rocket::{http::Status, response::status::Custom, serde::json::Json, State};
+
+use crate::tracing::{FilterError, FilterHandle};
+
+type FilterResult = Result<Json<Vec<String>>, Custom<String>>;
+
+/// The filter of each sink.
+#[rocket::get("/log-filter")]
+pub fn current(filter: &State<FilterHandle>) -> FilterResult {
+    respond(filter, Ok(()))
+}
+
+/// Filters every sink with the directives in the body, e.g. `info,launchpad::mq=debug`.
+#[rocket::put("/log-filter", data = "<directives>")]
+pub fn set(filter: &State<FilterHandle>, directives: &str) -> FilterResult {
+    respond(filter, filter.set(directives))
+}
+
+/// Adds the directive in the body, e.g. `launchpad::mq=debug`, to the filter of every sink.
+#[rocket::post("/log-filter", data = "<directive>")]
+pub fn add(filter: &State<FilterHandle>, directive: &str) -> FilterResult {
+    respond(filter, filter.add(directive))
+}
+
+/// Filters every sink as it was first.
+#[rocket::delete("/log-filter")]
+pub fn reset(filter: &State<FilterHandle>) -> FilterResult {
+    respond(filter, filter.reset())
+}
+
+/// [`current`], [`set`], [`add`] and [`reset`], at `/log-filter` under wherever they're mounted.
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![current, set, add, reset]
+}
+
+/// The filters after `changed`, or why they weren't.
+fn respond(filter: &FilterHandle, changed: Result<(), FilterError>) -> FilterResult {
+    let error = |e: FilterError| match e {
+        FilterError::ParseError(_) => Custom(Status::BadRequest, e.to_string()),
+        FilterError::ReloadError(_) => Custom(Status::InternalServerError, e.to_string()),
+    };
+    changed.map_err(error)?;
+    filter.current().map(Json).map_err(error)
+}
@@ -6,6 +6,14 @@ use rocket::{
 };
 use tracing::{info, warn};
 
+/// Requests handled, labelled by `method`, `route` and `status`.
+pub const HTTP_REQUESTS: &str = "http_requests_total";
+/// Seconds spent handling each request, labelled by `method` and `route`.
+pub const HTTP_REQUEST_DURATION: &str = "http_request_duration_seconds";
+
+/// Logs how long each request took, and with the `metrics` feature, records it as
+/// [`HTTP_REQUESTS`] and [`HTTP_REQUEST_DURATION`]. Requests are labelled by the route that
+/// handled them, e.g. `/orders/<id>`, rather than their path, to keep the series few.
 pub struct Metrics;
 
 
@@ -29,6 +37,14 @@ impl Fairing for Metrics {
             let path = req.uri().path().to_string();
             let status = res.status().code;
             info!(target: "http.metrics", elapsed, method, path, status);
+
+            #[cfg(feature = "metrics")]
+            {
+                let route = req.route().map_or("unmatched".into(), |route| route.uri.to_string());
+                ::metrics::counter!(HTTP_REQUESTS, "method" => method, "route" => route.clone(), "status" => status.to_string())
+                    .increment(1);
+                ::metrics::histogram!(HTTP_REQUEST_DURATION, "method" => method, "route" => route).record(elapsed as f64);
+            }
         } else {
             warn!("request timer not found.");
         }
@@ -50,3 +66,32 @@ impl RequestTimer {
         Instant::now() - self.start
     }
 }
+
+/// Serves what's recorded to Prometheus, from the [`Prometheus`](crate::metrics::Prometheus)
+/// the Rocket instance manages:
+///
+/// ```no_run
+/// # use launchpad::{metrics::Prometheus, rocket::metrics::{self, Metrics}};
+/// # #[rocket::main]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let prometheus = Prometheus::builder().install()?;
+/// let _ = rocket::build()
+///     .manage(prometheus)
+///     .attach(Metrics)
+///     .mount("/", metrics::routes())
+///     .launch()
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "metrics")]
+#[rocket::get("/metrics")]
+pub fn scrape(prometheus: &rocket::State<crate::metrics::Prometheus>) -> String {
+    prometheus.render()
+}
+
+/// [`scrape`], at `/metrics` under wherever they're mounted.
+#[cfg(feature = "metrics")]
+pub fn routes() -> Vec<rocket::Route> {
+    rocket::routes![scrape]
+}
@@ -1,2 +1,8 @@
 mod auth;
+#[cfg(feature = "tracing")]
+pub mod logging;
 pub mod metrics;
+
+// referenced by the routes the `Entity` derive generates
+#[doc(hidden)]
+pub use ::tracing as __tracing;
@@ -0,0 +1,131 @@
+//! Diagnosing the Tokio runtime, e.g. a consumer that's stopped consuming: what tokio-console
+//! shows of each task, and the runtime's metrics, logged every so often.
+
+use std::{error::Error, net::SocketAddr, time::Duration};
+
+use tokio::runtime::{Handle, RuntimeMetrics};
+use tracing::{info, warn};
+use tracing_subscriber::Layer;
+
+use super::{
+    sink::{BoxedLayer, Sink},
+    Logging,
+};
+
+/// Serves what tokio-console shows, on `127.0.0.1:6669` unless told otherwise. Tasks only show
+/// up in applications built with `RUSTFLAGS="--cfg tokio_unstable"`.
+///
+/// The console is sent Tokio's own spans and events, whatever the filters of other sinks, so it
+/// takes none of its own.
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleOptions {
+    server_addr: Option<SocketAddr>,
+    retention: Option<Duration>,
+    runtime_metrics: Option<Duration>,
+}
+
+impl ConsoleOptions {
+    pub fn new() -> Self {
+        ConsoleOptions::default()
+    }
+
+    pub fn with_server_addr(mut self, address: impl Into<SocketAddr>) -> Self {
+        self.server_addr = Some(address.into());
+        self
+    }
+
+    /// Keeps tasks for the console to show for `retention` after they complete, rather than an
+    /// hour.
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = Some(retention);
+        self
+    }
+
+    /// Also logs the runtime's metrics every `interval`, from a task [`Logging::spawn`] spawns,
+    /// see [`log_runtime_metrics`].
+    pub fn with_runtime_metrics(mut self, interval: Duration) -> Self {
+        self.runtime_metrics = Some(interval);
+        self
+    }
+}
+
+impl Sink for ConsoleOptions {
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>> {
+        let mut builder = console_subscriber::ConsoleLayer::builder().with_default_env();
+        if let Some(address) = self.server_addr {
+            builder = builder.server_addr(address);
+        }
+        if let Some(retention) = self.retention {
+            builder = builder.retention(retention);
+        }
+        logging.runtime_metrics = self.runtime_metrics;
+        Ok(builder.spawn().boxed())
+    }
+}
+
+/// Logs the current runtime's metrics every `interval`, for good: how many workers it has, how
+/// many tasks are alive and how many are queued for any worker to pick up. Workers that haven't
+/// parked since the last time, i.e. have been running tasks for the whole interval, are counted
+/// as stuck, and logged as a warning: they're blocked, e.g. in a synchronous call, or saturated.
+pub async fn log_runtime_metrics(interval: Duration) {
+    let metrics = Handle::current().metrics();
+    let mut ticks = tokio::time::interval(interval);
+    // the first tick is immediate
+    ticks.tick().await;
+    let mut parked = park_counts(&metrics);
+    loop {
+        ticks.tick().await;
+        let now = park_counts(&metrics);
+        // an even count is a worker running tasks, an unchanged one a worker yet to park
+        let stuck_workers = now
+            .iter()
+            .zip(&parked)
+            .filter(|&(now, before)| now % 2 == 0 && now == before)
+            .count();
+        parked = now;
+
+        let workers = metrics.num_workers();
+        let alive_tasks = metrics.num_alive_tasks();
+        let global_queue_depth = metrics.global_queue_depth();
+        if stuck_workers > 0 {
+            warn!(workers, stuck_workers, alive_tasks, global_queue_depth, "runtime workers stuck");
+        } else {
+            info!(workers, stuck_workers, alive_tasks, global_queue_depth, "runtime metrics");
+        }
+    }
+}
+
+fn park_counts(metrics: &RuntimeMetrics) -> Vec<u64> {
+    (0..metrics.num_workers())
+        .map(|worker| metrics.worker_park_unpark_count(worker))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::tracing::{FileOptions, LogFormat, TracingBuilder};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn logs_stuck_workers() {
+        let path = env::temp_dir().join(format!("launchpad-console-{}.json", std::process::id()));
+        let (subscriber, logging) = TracingBuilder::new()
+            .with_sink(FileOptions::new(&path).with_format(LogFormat::Json).with_filter("info"))
+            .build()
+            .unwrap();
+        let _default = ::tracing::subscriber::set_default(subscriber);
+
+        let blocking = tokio::spawn(async { std::thread::sleep(Duration::from_millis(500)) });
+        let _ = tokio::time::timeout(Duration::from_millis(350), log_runtime_metrics(Duration::from_millis(100))).await;
+        blocking.await.unwrap();
+        drop(logging);
+
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        let lines: Vec<serde_json::Value> = logged.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert!(lines.iter().all(|line| line["workers"] == 2));
+        assert!(lines.iter().any(|line| line["level"] == "WARN" && line["stuck_workers"] == 1), "{logged}");
+    }
+}
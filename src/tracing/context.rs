@@ -0,0 +1,185 @@
+use std::fmt;
+
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Subscriber,
+};
+use tracing_subscriber::{
+    layer::Context,
+    registry::{LookupSpan, Registry},
+    Layer,
+};
+use uuid::Uuid;
+
+/// The header W3C trace context is carried in.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// A W3C trace context: the trace a span belongs to, and the span itself.
+///
+/// Every span gets one from [`TraceContextLayer`], continuing its parent's trace, or the trace
+/// in a `traceparent` field recorded on the span, or else starting a new one. Messages carry it
+/// in a `traceparent` header, so work done downstream can be found by the same trace id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+    flags: u8,
+}
+
+impl TraceContext {
+    /// The context of the current span, if it's been assigned one.
+    pub fn current() -> Option<TraceContext> {
+        tracing::Span::current()
+            .with_subscriber(|(id, dispatch)| {
+                let registry = dispatch.downcast_ref::<Registry>()?;
+                let span = registry.span(id)?;
+                let context = span.extensions().get::<TraceContext>().copied();
+                context
+            })
+            .flatten()
+    }
+
+    /// Parses a `traceparent` header, e.g. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn parse(traceparent: &str) -> Option<TraceContext> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return None;
+        }
+        // later versions may append fields, but not to version 00
+        if version == "00" && parts.next().is_some() {
+            return None;
+        }
+
+        let context = TraceContext {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+        (context.trace_id != 0 && context.span_id != 0).then_some(context)
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    pub fn span_id(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+
+    /// The context formatted as a `traceparent` header.
+    pub fn traceparent(&self) -> String {
+        self.to_string()
+    }
+
+    fn child(&self) -> TraceContext {
+        TraceContext {
+            span_id: random_span_id(),
+            ..*self
+        }
+    }
+
+    fn root() -> TraceContext {
+        TraceContext {
+            trace_id: Uuid::new_v4().as_u128(),
+            span_id: random_span_id(),
+            flags: 1,
+        }
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+fn random_span_id() -> u64 {
+    // the low half of a v4 uuid is random bar the variant bits, and never zero
+    Uuid::new_v4().as_u64_pair().1
+}
+
+/// Assigns each span a [`TraceContext`]. Installed by [`configure`](super::configure).
+pub struct TraceContextLayer;
+
+impl<S> Layer<S> for TraceContextLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut remote = RemoteParent(None);
+        attrs.record(&mut remote);
+
+        let parent = remote.0.or_else(|| {
+            span.parent()
+                .and_then(|parent| parent.extensions().get::<TraceContext>().copied())
+        });
+        let context = parent.map_or_else(TraceContext::root, |parent| parent.child());
+        span.extensions_mut().insert(context);
+    }
+}
+
+/// Picks a parent out of a `traceparent` field.
+struct RemoteParent(Option<TraceContext>);
+
+impl Visit for RemoteParent {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == TRACEPARENT {
+            self.0 = TraceContext::parse(value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == TRACEPARENT {
+            self.0 = TraceContext::parse(&format!("{value:?}"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::info_span;
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    const TRACEPARENT_HEADER: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn parse_traceparent() {
+        let context = TraceContext::parse(TRACEPARENT_HEADER).unwrap();
+
+        assert_eq!(context.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(context.span_id(), "00f067aa0ba902b7");
+        assert_eq!(context.traceparent(), TRACEPARENT_HEADER);
+
+        assert_eq!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01"), None);
+        assert_eq!(TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7"), None);
+        assert_eq!(TraceContext::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"), None);
+    }
+
+    #[test]
+    fn spans_share_their_trace() {
+        let subscriber = tracing_subscriber::registry().with(TraceContextLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(TraceContext::current(), None);
+
+            let outer = info_span!("outer").entered();
+            let parent = TraceContext::current().unwrap();
+            let child = info_span!("inner").in_scope(|| TraceContext::current().unwrap());
+            assert_eq!(child.trace_id(), parent.trace_id());
+            assert_ne!(child.span_id(), parent.span_id());
+            drop(outer);
+
+            let remote = info_span!("remote", traceparent = TRACEPARENT_HEADER)
+                .in_scope(|| TraceContext::current().unwrap());
+            assert_eq!(remote.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+            assert_ne!(remote.trace_id(), parent.trace_id());
+        });
+    }
+}
@@ -0,0 +1,185 @@
+//! What each sink is sent, see [`LogFilter`], and changing it while running, e.g. to turn on
+//! debug logs for a module during an incident, see [`Logging::filter`](super::Logging::filter).
+
+use std::{fmt, sync::Arc};
+
+use thiserror::Error;
+use tracing_subscriber::{
+    filter::{Directive, LevelFilter, ParseError},
+    registry::Registry,
+    reload, EnvFilter,
+};
+
+/// The directives a sink is filtered with, built up rather than written out, for any sink's
+/// `with_filter`. Targets match themselves and the modules within, as in `RUST_LOG`:
+///
+/// ```
+/// # use launchpad::tracing::{LogFilter, LokiOptions};
+/// # use tracing::Level;
+/// // warnings and the audit trail to Loki, whatever `RUST_LOG` lets through to stdout
+/// let loki = LokiOptions::new("http://loki:3100", [], [])
+///     .with_filter(LogFilter::new(Level::WARN).with_target("audit", Level::TRACE));
+/// // nothing but the audit trail
+/// let audit = LogFilter::off().with_target("audit", Level::INFO);
+/// assert_eq!(audit.to_string(), "off,audit=info");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFilter {
+    level: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// Lets events and spans at `level` or above through, from any target.
+    pub fn new(level: impl Into<LevelFilter>) -> Self {
+        LogFilter {
+            level: level.into(),
+            targets: Vec::new(),
+        }
+    }
+
+    /// Lets nothing through, but from the targets added.
+    pub fn off() -> Self {
+        LogFilter::new(LevelFilter::OFF)
+    }
+
+    /// Lets events and spans from `target` through at `level` or above instead, be it higher or
+    /// lower.
+    pub fn with_target(mut self, target: impl Into<String>, level: impl Into<LevelFilter>) -> Self {
+        self.targets.push((target.into(), level.into()));
+        self
+    }
+}
+
+impl fmt::Display for LogFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.level)?;
+        for (target, level) in &self.targets {
+            write!(f, ",{target}={level}")?;
+        }
+        Ok(())
+    }
+}
+
+impl From<LogFilter> for String {
+    fn from(filter: LogFilter) -> Self {
+        filter.to_string()
+    }
+}
+
+/// The filter of a sink, which its [`FilterHandle`] can change.
+pub type ReloadableFilter = reload::Layer<EnvFilter, Registry>;
+
+#[derive(Debug, Error)]
+pub enum FilterError {
+    #[error("Parse error: {0}")]
+    ParseError(#[from] ParseError),
+
+    #[error("Reload error: {0}")]
+    ReloadError(#[from] reload::Error),
+}
+
+/// Changes the filters of every sink of the subscriber it came from. Clones change the same
+/// filters, so one can be handed to, say, an admin endpoint, see
+/// [`launchpad::rocket::logging`](crate::rocket::logging).
+#[derive(Debug, Clone, Default)]
+pub struct FilterHandle {
+    sinks: Arc<Vec<SinkFilter>>,
+}
+
+#[derive(Debug, Clone)]
+struct SinkFilter {
+    initial: Option<String>,
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl FilterHandle {
+    /// A filter for `directives`, or `RUST_LOG` without, that this handle changes along with the
+    /// rest.
+    pub(crate) fn register(&mut self, directives: Option<&str>) -> Result<ReloadableFilter, FilterError> {
+        let (filter, handle) = reload::Layer::new(parse(directives)?);
+        let initial = directives.map(Into::into);
+        Arc::make_mut(&mut self.sinks).push(SinkFilter { initial, handle });
+        Ok(filter)
+    }
+
+    /// Filters every sink with `directives`, e.g. `info,launchpad::mq=debug`, in place of what
+    /// it was given.
+    pub fn set(&self, directives: &str) -> Result<(), FilterError> {
+        // parsed up front, so a typo leaves every sink as it was
+        EnvFilter::try_new(directives)?;
+        for sink in self.sinks.iter() {
+            sink.handle.reload(EnvFilter::try_new(directives)?)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `directive`, e.g. `launchpad::mq=debug`, to the filter of every sink, leaving the
+    /// rest of each as it is.
+    pub fn add(&self, directive: &str) -> Result<(), FilterError> {
+        let directive: Directive = directive.parse()?;
+        for sink in self.sinks.iter() {
+            sink.handle
+                .modify(|filter| *filter = std::mem::take(filter).add_directive(directive.clone()))?;
+        }
+        Ok(())
+    }
+
+    /// Filters every sink as it was first, undoing [`FilterHandle::set`] and [`FilterHandle::add`].
+    pub fn reset(&self) -> Result<(), FilterError> {
+        for sink in self.sinks.iter() {
+            sink.handle.reload(parse(sink.initial.as_deref())?)?;
+        }
+        Ok(())
+    }
+
+    /// The filter of each sink, in the order they were added.
+    pub fn current(&self) -> Result<Vec<String>, FilterError> {
+        let mut filters = Vec::with_capacity(self.sinks.len());
+        for sink in self.sinks.iter() {
+            filters.push(sink.handle.with_current(ToString::to_string)?);
+        }
+        Ok(filters)
+    }
+}
+
+fn parse(directives: Option<&str>) -> Result<EnvFilter, ParseError> {
+    match directives {
+        Some(directives) => EnvFilter::try_new(directives),
+        None => Ok(EnvFilter::from_default_env()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use tracing::Level;
+
+    use super::*;
+    use crate::tracing::{FileOptions, TracingBuilder};
+
+    #[test]
+    fn filters_by_target() {
+        let path = env::temp_dir().join(format!("launchpad-filter-{}.log", std::process::id()));
+        let filter = LogFilter::new(Level::WARN).with_target("audit", Level::INFO);
+        assert_eq!(filter.to_string(), "warn,audit=info");
+        let (subscriber, logging) = TracingBuilder::new()
+            .with_sink(FileOptions::new(&path).with_filter(filter))
+            .build()
+            .unwrap();
+
+        ::tracing::subscriber::with_default(subscriber, || {
+            ::tracing::info!(target: "audit", "order refunded");
+            ::tracing::debug!(target: "audit::payments", "card charged");
+            ::tracing::info!("order shipped");
+            ::tracing::warn!("stock low");
+        });
+        drop(logging);
+
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(logged.contains("order refunded") && logged.contains("stock low"));
+        assert!(!logged.contains("card charged") && !logged.contains("order shipped"));
+    }
+}
@@ -1,55 +1,420 @@
-use std::{collections::BTreeMap, error::Error, process};
-
-use tracing_loki::{url::Url, BackgroundTask};
-use tracing_subscriber::{
-    fmt,
-    layer::{Layer, SubscriberExt},
-    prelude::*,
-    registry, EnvFilter,
-};
-
-#[derive(derive_new::new)]
-pub struct LokiOptions {
-    #[new(into)]
-    url: String,
-    #[new(into)]
-    labels: BTreeMap<String, String>,
-    #[new(into)]
-    fields: BTreeMap<String, String>,
-}
+#[cfg(feature = "console")]
+pub mod console;
+pub mod context;
+pub mod filter;
+pub mod rolling;
+pub mod service;
+pub mod sink;
+
+use std::{error::Error, time::Duration};
+
+use context::TraceContextLayer;
+
+use futures::{channel::oneshot, future::BoxFuture, FutureExt};
+use tokio::time::error::Elapsed;
+use tracing::Subscriber;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_loki::{BackgroundTask, BackgroundTaskController};
+use tracing_subscriber::{layer::Layer, prelude::*, registry};
+
+// referenced by the spans the `Entity` derive generates
+#[doc(hidden)]
+pub use ::tracing as __tracing;
+
+#[cfg(feature = "console")]
+pub use console::ConsoleOptions;
+#[cfg(feature = "journald")]
+pub use sink::JournaldOptions;
+#[cfg(feature = "otlp")]
+pub use sink::OtlpOptions;
+#[cfg(feature = "sentry")]
+pub use sink::SentryOptions;
+pub use filter::{FilterError, FilterHandle, LogFilter};
+pub use rolling::Rotation;
+pub use service::ServiceInfo;
+pub use sink::{FileOptions, FmtOptions, LogFormat, LokiOptions, Sink};
 
-#[derive(derive_new::new)]
+/// Sends logs to a Loki sink, until [`Logging::shutdown`].
+pub type LokiTask = BoxFuture<'static, ()>;
+
+/// What has to outlive the subscriber [`TracingBuilder::init`] installs: keep it for as long as
+/// the application runs, then [`Logging::shutdown`] it.
+#[derive(Default)]
+#[must_use]
 pub struct Logging {
-    pub loki_task: Option<BackgroundTask>,
+    /// The tasks sending logs to each Loki sink, to be spawned, e.g. with [`Logging::spawn`].
+    pub loki_tasks: Vec<LokiTask>,
+    /// Keep the file sinks writing; dropping them flushes what's left and stops.
+    pub guards: Vec<WorkerGuard>,
+    /// Changes what each sink is sent, without a restart.
+    pub filter: FilterHandle,
+    service: Option<ServiceInfo>,
+    loki_controllers: Vec<(BackgroundTaskController, oneshot::Receiver<()>)>,
+    #[cfg(feature = "otlp")]
+    pub(crate) tracer_providers: Vec<opentelemetry_sdk::trace::SdkTracerProvider>,
+    #[cfg(feature = "sentry")]
+    pub(crate) sentry: Option<sentry::ClientInitGuard>,
+    #[cfg(feature = "console")]
+    pub(crate) runtime_metrics: Option<Duration>,
 }
 
-pub fn configure(loki: Option<LokiOptions>) -> Result<Logging, Box<dyn Error>> {
-    let log_layer = Some(fmt::layer()).with_filter(EnvFilter::from_default_env());
-    let mut loki_task = None;
-
-    let loki_layer = if let Some(loki) = loki {
-        let mut builder = tracing_loki::builder()
-            .label("host", hostname::get()?.to_string_lossy())?
-            .extra_field("pid", format!("{}", process::id()))?;
+impl Logging {
+    /// The service given to [`TracingBuilder::with_service`], for sinks to describe it with.
+    pub fn service(&self) -> Option<&ServiceInfo> {
+        self.service.as_ref()
+    }
 
-        for (k, v) in loki.labels {
-            builder = builder.label(&k, &v)?;
+    /// Spawns the [`Logging::loki_tasks`] on the current Tokio runtime, and with the `console`
+    /// feature, the task logging its metrics, if asked to.
+    pub fn spawn(&mut self) {
+        for task in self.loki_tasks.drain(..) {
+            tokio::spawn(task);
         }
+        #[cfg(feature = "console")]
+        if let Some(interval) = self.runtime_metrics.take() {
+            tokio::spawn(console::log_runtime_metrics(interval));
+        }
+    }
+
+    /// Sends whatever the sinks haven't yet, waiting up to `timeout` for it to go, then stops
+    /// them. Call it last thing before the process exits, once the application has stopped, as
+    /// anything logged after is lost:
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use launchpad::tracing::{configure, LokiOptions};
+    /// # async fn run(loki: LokiOptions, serve: impl std::future::Future<Output = ()>) -> Result<(), Box<dyn std::error::Error>> {
+    /// let mut logging = configure(Some(loki))?;
+    /// logging.spawn();
+    /// // e.g. an `EventBus::run_until` or Rocket's `launch`, until told to stop
+    /// serve.await;
+    /// logging.shutdown(Duration::from_secs(5)).await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<(), Elapsed> {
+        // tasks never spawned have nothing to wait for
+        self.loki_tasks.clear();
+        let loki = self.loki_controllers.drain(..).map(|(controller, done)| async move {
+            controller.shutdown().await;
+            let _ = done.await;
+        });
+        let loki = futures::future::join_all(loki);
+
+        #[cfg(feature = "otlp")]
+        let otlp = {
+            let providers = std::mem::take(&mut self.tracer_providers);
+            tokio::task::spawn_blocking(move || {
+                for provider in providers {
+                    if let Err(e) = provider.shutdown_with_timeout(timeout) {
+                        tracing::warn!(error = %e, "couldn't export the last spans");
+                    }
+                }
+            })
+        };
+        #[cfg(not(feature = "otlp"))]
+        let otlp = futures::future::ready(());
 
-        for (k, v) in loki.fields {
-            builder = builder.extra_field(&k, &v)?;
+        #[cfg(feature = "sentry")]
+        let sentry = {
+            let sentry = self.sentry.take();
+            tokio::task::spawn_blocking(move || {
+                if let Some(sentry) = sentry {
+                    sentry.close(Some(timeout));
+                }
+            })
+        };
+        #[cfg(not(feature = "sentry"))]
+        let sentry = futures::future::ready(());
+
+        let flushed = tokio::time::timeout(timeout, futures::future::join3(loki, otlp, sentry)).await;
+        // flushes the files, after anything logged above
+        drop(self.guards);
+        flushed.map(|_| ())
+    }
+
+    pub(crate) fn add_loki(&mut self, controller: BackgroundTaskController, task: BackgroundTask) {
+        let (done, finished) = oneshot::channel();
+        self.loki_tasks.push(
+            async move {
+                task.await;
+                let _ = done.send(());
+            }
+            .boxed(),
+        );
+        self.loki_controllers.push((controller, finished));
+    }
+}
+
+/// Installs a subscriber sending logs and spans to any number of [`Sink`]s, each filtered on its
+/// own, `RUST_LOG` by default:
+///
+/// ```no_run
+/// # use launchpad::tracing::{FileOptions, FmtOptions, LokiOptions, Rotation, ServiceInfo, TracingBuilder};
+/// # async fn run() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut logging = TracingBuilder::new()
+///     .with_service(ServiceInfo::new("billing", env!("CARGO_PKG_VERSION")).with_environment("production"))
+///     .with_sink(FmtOptions::new())
+///     .with_sink(LokiOptions::new("http://loki:3100", [], []).with_filter("warn"))
+///     .with_sink(FileOptions::new("billing.log").with_rotation(Rotation::Daily).with_max_files(7))
+///     .init()?;
+/// logging.spawn();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct TracingBuilder {
+    sinks: Vec<Box<dyn Sink>>,
+    service: Option<ServiceInfo>,
+}
+
+impl TracingBuilder {
+    pub fn new() -> Self {
+        TracingBuilder::default()
+    }
+
+    pub fn with_sink(mut self, sink: impl Sink + 'static) -> Self {
+        self.sinks.push(Box::new(sink));
+        self
+    }
+
+    /// Describes the service to every sink, see [`ServiceInfo`].
+    pub fn with_service(mut self, service: ServiceInfo) -> Self {
+        self.service = Some(service);
+        self
+    }
+
+    /// Installs the subscriber globally, failing if there already is one.
+    pub fn init(self) -> Result<Logging, Box<dyn Error>> {
+        let (subscriber, logging) = self.build()?;
+        subscriber.try_init()?;
+        Ok(logging)
+    }
+
+    /// The subscriber, to install as the caller sees fit, e.g. only for a test with
+    /// [`tracing::subscriber::with_default`](::tracing::subscriber::with_default).
+    pub fn build(self) -> Result<(impl Subscriber + Send + Sync, Logging), Box<dyn Error>> {
+        let mut logging = Logging {
+            service: self.service,
+            ..Logging::default()
+        };
+        // unfiltered, so every span has a context to propagate whatever the sinks log
+        let mut layers = vec![TraceContextLayer.boxed()];
+        for sink in self.sinks {
+            layers.push(sink.into_layer(&mut logging)?);
         }
+        Ok((registry().with(layers), logging))
+    }
+}
+
+/// Logs to stdout, and to Loki given `loki`, and with the `console` feature, serves
+/// tokio-console. See [`TracingBuilder`] for any other sinks.
+pub fn configure(loki: Option<LokiOptions>) -> Result<Logging, Box<dyn Error>> {
+    let mut builder = TracingBuilder::new().with_sink(FmtOptions::new());
+    #[cfg(feature = "console")]
+    {
+        builder = builder.with_sink(ConsoleOptions::new());
+    }
+    if let Some(loki) = loki {
+        builder = builder.with_sink(loki);
+    }
+    builder.init()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, env, fs};
+
+    use super::*;
+
+    #[test]
+    fn filters_each_sink() {
+        let dir = env::temp_dir().join(format!("launchpad-tracing-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (everything, warnings) = (dir.join("everything.log"), dir.join("warnings.log"));
+        let (subscriber, logging) = TracingBuilder::new()
+            .with_sink(FileOptions::new(&everything).with_filter("debug"))
+            .with_sink(FileOptions::new(&warnings).with_filter("warn"))
+            .build()
+            .unwrap();
+
+        ::tracing::subscriber::with_default(subscriber, || {
+            ::tracing::debug!("connecting");
+            ::tracing::warn!("connection lost");
+        });
+        drop(logging);
+
+        let everything = fs::read_to_string(everything).unwrap();
+        let warnings = fs::read_to_string(warnings).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert!(everything.contains("connecting") && everything.contains("connection lost"));
+        assert!(!warnings.contains("connecting") && warnings.contains("connection lost"));
+        assert!(TracingBuilder::new().with_sink(FmtOptions::new().with_filter("=")).build().is_err());
+    }
+
+    #[test]
+    fn logs_json_lines() {
+        let path = env::temp_dir().join(format!("launchpad-tracing-{}.json", std::process::id()));
+        let (subscriber, logging) = TracingBuilder::new()
+            .with_sink(FileOptions::new(&path).with_format(LogFormat::Json).with_filter("info"))
+            .build()
+            .unwrap();
+
+        ::tracing::subscriber::with_default(subscriber, || {
+            let _consuming = ::tracing::info_span!("consume", queue = "orders").entered();
+            let _processing = ::tracing::info_span!("process", order_id = 7).entered();
+            ::tracing::info!(attempt = 2, "processed");
+        });
+        drop(logging);
+
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        let line: serde_json::Value = serde_json::from_str(logged.trim()).unwrap();
+        assert_eq!(line["message"], "processed");
+        assert_eq!(line["attempt"], 2);
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], module_path!());
+        assert_eq!(line["span"]["name"], "process");
+        assert_eq!(line["spans"][0]["queue"], "orders");
+        assert_eq!(line["spans"][1]["order_id"], 7);
+        assert_eq!("JSON".parse(), Ok(LogFormat::Json));
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_loki() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let loki = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", loki.local_addr().unwrap());
+        let pushed = tokio::spawn(async move {
+            let (mut stream, _) = loki.accept().await.unwrap();
+            let mut request = vec![0; 64 * 1024];
+            let read = stream.read(&mut request).await.unwrap();
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await.unwrap();
+            String::from_utf8_lossy(&request[..read]).lines().next().map(String::from)
+        });
+        let (subscriber, mut logging) = TracingBuilder::new()
+            .with_sink(LokiOptions::new(url, BTreeMap::new(), BTreeMap::new()).with_filter("info"))
+            .build()
+            .unwrap();
+        logging.spawn();
+
+        ::tracing::subscriber::with_default(subscriber, || ::tracing::info!("stopping"));
+        logging.shutdown(Duration::from_secs(5)).await.unwrap();
+
+        let pushed = tokio::time::timeout(Duration::from_secs(1), pushed).await.unwrap().unwrap();
+        assert_eq!(pushed.as_deref(), Some("POST /loki/api/v1/push HTTP/1.1"));
+    }
+
+    #[cfg(feature = "sentry")]
+    #[test]
+    fn reports_errors_to_sentry() {
+        let (subscriber, _logging) = TracingBuilder::new()
+            .with_sink(SentryOptions::new("https://key@sentry.invalid/1").with_filter("info"))
+            .build()
+            .unwrap();
+
+        let events = sentry::test::with_captured_events(|| {
+            ::tracing::subscriber::with_default(subscriber, || {
+                let _charging = ::tracing::info_span!("charge", order_id = 7).entered();
+                ::tracing::info!("charging");
+                ::tracing::warn!("card expiring");
+                ::tracing::error!("card declined");
+            });
+        });
+
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.level, sentry::Level::Error);
+        assert_eq!(event.message.as_deref(), Some("card declined"));
+        assert_eq!(event.breadcrumbs.len(), 2);
+        assert!(event.contexts.contains_key("trace"), "{:?}", event.contexts);
+        assert!(TracingBuilder::new().with_sink(SentryOptions::new("not a dsn")).build().is_err());
+    }
+
+    #[test]
+    fn describes_the_service() {
+        let json = env::temp_dir().join(format!("launchpad-tracing-{}.service.json", std::process::id()));
+        let text = env::temp_dir().join(format!("launchpad-tracing-{}.service.log", std::process::id()));
+        let service = ServiceInfo::new("billing", "1.4.2").with_instance_id("billing-0");
+        let (subscriber, logging) = TracingBuilder::new()
+            .with_service(service.clone().with_environment("production"))
+            .with_sink(FileOptions::new(&json).with_format(LogFormat::Json).with_filter("info"))
+            .with_sink(FileOptions::new(&text).with_format(LogFormat::Compact).with_filter("info"))
+            .build()
+            .unwrap();
+
+        // outside any span, e.g. in a task spawned on its own
+        ::tracing::subscriber::with_default(subscriber, || ::tracing::info!(attempt = 1, "started"));
+        drop(logging);
+
+        let logged = fs::read_to_string(&json).unwrap();
+        fs::remove_file(json).unwrap();
+        let line: serde_json::Value = serde_json::from_str(logged.trim()).unwrap();
+        assert_eq!(line["message"], "started");
+        assert_eq!(line["attempt"], 1);
+        assert_eq!(line["service.name"], "billing");
+        assert_eq!(line["service.version"], "1.4.2");
+        assert_eq!(line["service.environment"], "production");
+        assert_eq!(line["service.instance_id"], "billing-0");
+
+        let logged = fs::read_to_string(&text).unwrap();
+        fs::remove_file(text).unwrap();
+        assert_eq!(logged.lines().count(), 1);
+        assert!(
+            logged.trim_end().ends_with(
+                "started attempt=1 service.name=billing service.version=1.4.2 service.environment=production service.instance_id=billing-0"
+            ),
+            "{logged}"
+        );
+
+        let (labels, fields) = service.loki_labels();
+        assert_eq!(labels, BTreeMap::from([("service".into(), "billing".into())]));
+        assert_eq!(fields["instance_id"], "billing-0");
+    }
 
-        let (layer, task) = builder.build_url(Url::parse(&loki.url)?)?;
+    #[test]
+    fn traces_spans_the_sinks_filter_out() {
+        let (subscriber, logging) = TracingBuilder::new()
+            .with_sink(FmtOptions::new().with_filter("error"))
+            .build()
+            .unwrap();
 
-        loki_task = Some(task);
+        ::tracing::subscriber::with_default(subscriber, || {
+            let _consuming = ::tracing::info_span!("consume").entered();
+            assert!(context::TraceContext::current().is_some());
+            logging.filter.set("off").unwrap();
+            let _processing = ::tracing::info_span!("process").entered();
+            assert!(context::TraceContext::current().is_some());
+        });
+    }
 
-        Some(layer.with_filter(EnvFilter::from_default_env()))
-    } else {
-        None
-    };
+    #[test]
+    fn reloads_filters() {
+        let path = env::temp_dir().join(format!("launchpad-tracing-{}.reload.log", std::process::id()));
+        let (subscriber, logging) = TracingBuilder::new()
+            .with_sink(FileOptions::new(&path).with_filter("info"))
+            .build()
+            .unwrap();
+        let filter = logging.filter.clone();
 
-    registry().with(log_layer).with(loki_layer).try_init()?;
+        let current = ::tracing::subscriber::with_default(subscriber, || {
+            ::tracing::debug!("before");
+            filter.add("launchpad=debug").unwrap();
+            ::tracing::debug!("added");
+            filter.reset().unwrap();
+            ::tracing::debug!("reset");
+            filter.set("debug").unwrap();
+            ::tracing::debug!("set");
+            assert!(filter.set("=").is_err());
+            filter.current().unwrap()
+        });
+        drop(logging);
 
-    Ok(Logging::new(loki_task))
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(path).unwrap();
+        assert!(!logged.contains("before") && logged.contains("added"));
+        assert!(!logged.contains("reset") && logged.contains("set"));
+        assert_eq!(current.last().map(String::as_str), Some("debug"));
+    }
 }
@@ -0,0 +1,154 @@
+//! Rolling log files over, by time or size, keeping only so many, see
+//! [`FileOptions::with_rotation`](super::FileOptions::with_rotation).
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use tracing_appender::rolling::{self, RollingFileAppender};
+
+/// When a log file is rolled over for a new one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Rotation {
+    /// Never: the one file grows for good.
+    #[default]
+    Never,
+    /// Every minute, hour, day or week, with the date and time the file was started in its name,
+    /// e.g. `billing.2024-06-01.log` for a daily file.
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    /// Once the file would exceed this many bytes, when it's renamed with a `.1` appended, and
+    /// files already rolled over each move up a number, e.g. `billing.log.1` to `billing.log.2`.
+    Size(u64),
+}
+
+/// Opens the log file at `path`, rolled over as `rotation` says, keeping the current file and at
+/// most `max_files - 1` rolled over ones, or all of them if `max_files` is `None`.
+pub(crate) fn writer(
+    path: &Path,
+    rotation: Rotation,
+    max_files: Option<usize>,
+) -> io::Result<Box<dyn Write + Send + Sync>> {
+    let time_based = match rotation {
+        Rotation::Never => rolling::Rotation::NEVER,
+        Rotation::Minutely => rolling::Rotation::MINUTELY,
+        Rotation::Hourly => rolling::Rotation::HOURLY,
+        Rotation::Daily => rolling::Rotation::DAILY,
+        Rotation::Weekly => rolling::Rotation::WEEKLY,
+        Rotation::Size(max_bytes) => return Ok(Box::new(SizeRotatingFile::open(path, max_bytes, max_files)?)),
+    };
+
+    let directory = path.parent().filter(|d| !d.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file_name = |part: Option<&std::ffi::OsStr>| part.map(|p| p.to_string_lossy().into_owned());
+    let never = time_based == rolling::Rotation::NEVER;
+    let mut builder = RollingFileAppender::builder().rotation(time_based);
+    if never {
+        builder = builder.filename_prefix(file_name(path.file_name()).unwrap_or_default());
+    } else {
+        builder = builder.filename_prefix(file_name(path.file_stem()).unwrap_or_default());
+        if let Some(extension) = file_name(path.extension()) {
+            builder = builder.filename_suffix(extension);
+        }
+    }
+    if let Some(max_files) = max_files {
+        builder = builder.max_log_files(max_files);
+    }
+    let appender = builder.build(directory).map_err(io::Error::other)?;
+    Ok(Box::new(appender))
+}
+
+/// A file renamed once it would grow past `max_bytes`, for a new one to take its place.
+struct SizeRotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: Option<usize>,
+    file: File,
+    written: u64,
+}
+
+impl SizeRotatingFile {
+    fn open(path: &Path, max_bytes: u64, max_files: Option<usize>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(SizeRotatingFile {
+            path: path.into(),
+            max_bytes,
+            max_files,
+            written: file.metadata()?.len(),
+            file,
+        })
+    }
+
+    fn rolled(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let mut last = 1;
+        while self.rolled(last).exists() {
+            last += 1;
+        }
+        for n in (1..last).rev() {
+            match self.max_files {
+                Some(max_files) if n + 1 >= max_files => fs::remove_file(self.rolled(n))?,
+                _ => fs::rename(self.rolled(n), self.rolled(n + 1))?,
+            }
+        }
+        if self.max_files == Some(1) {
+            fs::remove_file(&self.path)?;
+        } else {
+            fs::rename(&self.path, self.rolled(1))?;
+        }
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for SizeRotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written > 0 && self.written + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn rotates_by_size() {
+        let dir = env::temp_dir().join(format!("launchpad-rolling-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("billing.log");
+        let mut writer = writer(&path, Rotation::Size(10), Some(3)).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            writer.write_all(line.as_bytes()).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+        let files = (read("billing.log"), read("billing.log.1"), read("billing.log.2"), read("billing.log.3"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            (Some("fourth\n".into()), Some("third\n".into()), Some("second\n".into()), None)
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Which service, and which instance of it, logs and spans come from, see
+//! [`TracingBuilder::with_service`](super::TracingBuilder::with_service).
+
+use std::collections::BTreeMap;
+
+use tracing::{field, info_span, Span};
+
+/// Describes the service to every sink that understands it: Loki streams are labelled with its
+/// `service` and `environment` and have its `version` and `instance_id` as extra fields, OTLP
+/// exports carry them as resource attributes, and Sentry issues are tagged with them. Stdout and
+/// file sinks add them to every event as `service.name`, `service.version`,
+/// `service.environment` and `service.instance_id`, and the journal as `SERVICE_NAME` and so on.
+///
+/// Other sinks only see what's logged in [`ServiceInfo::span`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInfo {
+    /// e.g. `billing`.
+    pub name: String,
+    /// e.g. `env!("CARGO_PKG_VERSION")`.
+    pub version: String,
+    /// e.g. `production`.
+    pub environment: Option<String>,
+    /// Tells replicas apart, e.g. a pod's name.
+    pub instance_id: String,
+}
+
+impl ServiceInfo {
+    /// The service `name` at `version`, in no particular environment, identified by the host it
+    /// runs on.
+    pub fn new(name: impl Into<String>, version: impl Into<String>) -> Self {
+        ServiceInfo {
+            name: name.into(),
+            version: version.into(),
+            environment: None,
+            instance_id: hostname::get().map_or_else(|_| "unknown".into(), |host| host.to_string_lossy().into()),
+        }
+    }
+
+    pub fn with_environment(mut self, environment: impl Into<String>) -> Self {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    pub fn with_instance_id(mut self, instance_id: impl Into<String>) -> Self {
+        self.instance_id = instance_id.into();
+        self
+    }
+
+    /// A span with the service as its fields, for sinks that don't add them to every event, e.g.
+    /// one of your own. Run the application in it for every event to have them, e.g. with
+    /// [`Instrument::instrument`](tracing::Instrument::instrument). Tasks spawned on their own
+    /// have to be instrumented with it too.
+    pub fn span(&self) -> Span {
+        let span = info_span!(
+            "service",
+            service.name = %self.name,
+            service.version = %self.version,
+            service.environment = field::Empty,
+            service.instance_id = %self.instance_id,
+        );
+        if let Some(environment) = &self.environment {
+            span.record("service.environment", environment.as_str());
+        }
+        span
+    }
+
+    /// The fields every event is given by the sinks without labels of their own.
+    pub(crate) fn fields(&self) -> Vec<(&'static str, &str)> {
+        let mut fields = vec![("service.name", self.name.as_str()), ("service.version", self.version.as_str())];
+        if let Some(environment) = &self.environment {
+            fields.push(("service.environment", environment));
+        }
+        fields.push(("service.instance_id", &self.instance_id));
+        fields
+    }
+
+    /// The Loki labels, low in cardinality, and extra fields describing the service.
+    pub(crate) fn loki_labels(&self) -> (BTreeMap<String, String>, BTreeMap<String, String>) {
+        let mut labels = BTreeMap::from([("service".to_string(), self.name.clone())]);
+        if let Some(environment) = &self.environment {
+            labels.insert("environment".into(), environment.clone());
+        }
+        let fields = BTreeMap::from([
+            ("version".to_string(), self.version.clone()),
+            ("instance_id".to_string(), self.instance_id.clone()),
+        ]);
+        (labels, fields)
+    }
+}
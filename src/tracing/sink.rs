@@ -0,0 +1,465 @@
+//! Where logs and spans go, each sink filtered on its own, see
+//! [`TracingBuilder::with_sink`](super::TracingBuilder::with_sink).
+
+use std::{collections::BTreeMap, error::Error, path::PathBuf, process, str::FromStr};
+
+use serde_json::Value;
+use tracing::{Event, Subscriber};
+use tracing_loki::url::Url;
+use tracing_subscriber::{
+    fmt::{
+        self,
+        format::{self, Writer},
+        FmtContext, FormatEvent, FormatFields, MakeWriter,
+    },
+    registry::{LookupSpan, Registry},
+    Layer,
+};
+
+use super::{filter::ReloadableFilter, rolling::Rotation, service::ServiceInfo, Logging};
+
+/// A layer of the subscriber [`TracingBuilder`](super::TracingBuilder) installs.
+pub type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Somewhere to send logs and spans. Implement it to add a sink of your own.
+pub trait Sink {
+    /// The layer writing to the sink, already filtered. Whatever has to outlive the layer, e.g.
+    /// a task sending what it's given, is handed to `logging`.
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>>;
+}
+
+/// The filter a sink is given `directives` for, e.g. `info,launchpad::mq=debug`, or the one in
+/// `RUST_LOG` without, which [`Logging::filter`] can change later.
+pub fn filter(directives: Option<&str>, logging: &mut Logging) -> Result<ReloadableFilter, Box<dyn Error>> {
+    Ok(logging.filter.register(directives)?)
+}
+
+/// How [`FmtOptions`] and [`FileOptions`] write each event.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// A line per event, with its spans and their fields, as tracing-subscriber writes by default.
+    #[default]
+    Full,
+    /// Spread over several lines, for reading at a terminal.
+    Pretty,
+    /// A shorter line per event, with its spans' fields but not their names.
+    Compact,
+    /// A JSON object per line, for log collectors: the event's fields alongside `timestamp`,
+    /// `level`, `target` and `message`, with the current span as `span` and every span it's in,
+    /// outermost first, as `spans`.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    /// Parses `full`, `pretty`, `compact` or `json`, in any case, e.g. from a `LOG_FORMAT`
+    /// environment variable.
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_ascii_lowercase().as_str() {
+            "full" => Ok(LogFormat::Full),
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {format:?}")),
+        }
+    }
+}
+
+/// A layer writing events to `writer` in `format`, with colours unless `ansi` is false, and with
+/// the fields of `service`, if any.
+fn fmt_layer<W>(format: LogFormat, writer: W, ansi: bool, service: Option<&ServiceInfo>) -> BoxedLayer
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let layer = fmt::layer().with_writer(writer).with_ansi(ansi);
+    let events = fmt::format().with_ansi(ansi);
+    match format {
+        LogFormat::Full => layer.event_format(WithService::text(events, service)).boxed(),
+        LogFormat::Pretty => layer
+            .fmt_fields(format::Pretty::default())
+            .event_format(WithService::text(events.pretty(), service))
+            .boxed(),
+        LogFormat::Compact => layer.event_format(WithService::text(events.compact(), service)).boxed(),
+        LogFormat::Json => {
+            let events = events.json().flatten_event(true).with_current_span(true).with_span_list(true);
+            layer
+                .with_ansi(false)
+                .fmt_fields(format::JsonFields::new())
+                .event_format(WithService::json(events, service))
+                .boxed()
+        }
+    }
+}
+
+/// Formats events with `inner`, then adds the service's fields, so every event has them whatever
+/// span it's in, or none: first thing in a JSON object, or last thing on a line otherwise.
+struct WithService<F> {
+    inner: F,
+    json: bool,
+    fields: Option<String>,
+}
+
+impl<F> WithService<F> {
+    fn text(inner: F, service: Option<&ServiceInfo>) -> Self {
+        let fields = service.map(|service| {
+            let fields = service.fields().into_iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>();
+            fields.join(" ")
+        });
+        WithService {
+            inner,
+            json: false,
+            fields,
+        }
+    }
+
+    fn json(inner: F, service: Option<&ServiceInfo>) -> Self {
+        let fields = service.map(|service| {
+            let fields = service
+                .fields()
+                .into_iter()
+                .map(|(k, v)| format!("{}:{}", Value::from(k), Value::from(v)))
+                .collect::<Vec<_>>();
+            fields.join(",")
+        });
+        WithService {
+            inner,
+            json: true,
+            fields,
+        }
+    }
+}
+
+impl<S, N, F> FormatEvent<S, N> for WithService<F>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+    F: FormatEvent<S, N>,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> std::fmt::Result {
+        let Some(fields) = &self.fields else {
+            return self.inner.format_event(ctx, writer, event);
+        };
+
+        let mut line = String::new();
+        self.inner.format_event(ctx, Writer::new(&mut line), event)?;
+        let line = line.trim_end_matches('\n');
+        match line.strip_prefix('{') {
+            Some(rest) if self.json => writeln!(writer, "{{{fields},{rest}"),
+            _ => writeln!(writer, "{line} {fields}"),
+        }
+    }
+}
+
+/// Writes logs to stdout, human readable unless told otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct FmtOptions {
+    format: LogFormat,
+    filter: Option<String>,
+}
+
+impl FmtOptions {
+    pub fn new() -> Self {
+        FmtOptions::default()
+    }
+
+    /// Writes events in `format`, e.g. [`LogFormat::Json`] for a container's log collector.
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Filters what's written with `directives`, or a [`LogFilter`](super::LogFilter), rather than
+    /// `RUST_LOG`.
+    pub fn with_filter(mut self, directives: impl Into<String>) -> Self {
+        self.filter = Some(directives.into());
+        self
+    }
+}
+
+impl Sink for FmtOptions {
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>> {
+        let ansi = self.format != LogFormat::Json;
+        let layer = fmt_layer(self.format, std::io::stdout, ansi, logging.service());
+        Ok(layer.with_filter(filter(self.filter.as_deref(), logging)?).boxed())
+    }
+}
+
+/// Sends logs to Loki, labelled with the host they came from, the [`ServiceInfo`] given, if any,
+/// and `labels`, and with the process id and `fields` as extra fields. Logs are sent by the task
+/// in [`Logging::loki_tasks`], which has to be spawned, e.g. with [`Logging::spawn`].
+#[derive(derive_new::new)]
+pub struct LokiOptions {
+    #[new(into)]
+    url: String,
+    #[new(into)]
+    labels: BTreeMap<String, String>,
+    #[new(into)]
+    fields: BTreeMap<String, String>,
+    #[new(default)]
+    filter: Option<String>,
+}
+
+impl LokiOptions {
+    /// Filters what's sent with `directives`, or a [`LogFilter`](super::LogFilter), rather than
+    /// `RUST_LOG`.
+    pub fn with_filter(mut self, directives: impl Into<String>) -> Self {
+        self.filter = Some(directives.into());
+        self
+    }
+}
+
+impl Sink for LokiOptions {
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>> {
+        let mut builder = tracing_loki::builder()
+            .label("host", hostname::get()?.to_string_lossy())?
+            .extra_field("pid", format!("{}", process::id()))?;
+
+        // those given take precedence over the service's
+        let (mut labels, mut fields) = logging.service().map(ServiceInfo::loki_labels).unwrap_or_default();
+        labels.extend(self.labels);
+        fields.extend(self.fields);
+
+        for (k, v) in labels {
+            builder = builder.label(&k, &v)?;
+        }
+
+        for (k, v) in fields {
+            builder = builder.extra_field(&k, &v)?;
+        }
+
+        let (layer, controller, task) = builder.build_controller_url(Url::parse(&self.url)?)?;
+        logging.add_loki(controller, task);
+        Ok(layer.with_filter(filter(self.filter.as_deref(), logging)?).boxed())
+    }
+}
+
+/// Appends logs to a file, without colours, rolling it over as told. Logs are written from a
+/// thread of their own, until the guard in [`Logging::guards`] is dropped.
+#[derive(Debug, Clone)]
+pub struct FileOptions {
+    path: PathBuf,
+    format: LogFormat,
+    rotation: Rotation,
+    max_files: Option<usize>,
+    filter: Option<String>,
+}
+
+impl FileOptions {
+    /// Appends to the file at `path`, creating it if need be.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileOptions {
+            path: path.into(),
+            format: LogFormat::default(),
+            rotation: Rotation::default(),
+            max_files: None,
+            filter: None,
+        }
+    }
+
+    /// Rolls the file over for a new one every so often, or once it's so big. Files rolled over
+    /// by time are named after `path` with the date in between its stem and extension, e.g.
+    /// `billing.2024-06-01.log`.
+    pub fn with_rotation(mut self, rotation: Rotation) -> Self {
+        self.rotation = rotation;
+        self
+    }
+
+    /// Keeps at most `max_files` files, the one being written to included, deleting the oldest
+    /// when another is started. Every file is kept otherwise.
+    pub fn with_max_files(mut self, max_files: usize) -> Self {
+        self.max_files = Some(max_files).filter(|&n| n > 0);
+        self
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Filters what's written with `directives`, or a [`LogFilter`](super::LogFilter), rather than
+    /// `RUST_LOG`.
+    pub fn with_filter(mut self, directives: impl Into<String>) -> Self {
+        self.filter = Some(directives.into());
+        self
+    }
+}
+
+impl Sink for FileOptions {
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>> {
+        let file = super::rolling::writer(&self.path, self.rotation, self.max_files)?;
+        let (writer, guard) = tracing_appender::non_blocking(file);
+        logging.guards.push(guard);
+        let layer = fmt_layer(self.format, writer, false, logging.service());
+        Ok(layer.with_filter(filter(self.filter.as_deref(), logging)?).boxed())
+    }
+}
+
+/// Sends logs to the systemd journal, with span fields as journal fields.
+#[cfg(feature = "journald")]
+#[derive(Debug, Clone, Default)]
+pub struct JournaldOptions {
+    identifier: Option<String>,
+    filter: Option<String>,
+}
+
+#[cfg(feature = "journald")]
+impl JournaldOptions {
+    pub fn new() -> Self {
+        JournaldOptions::default()
+    }
+
+    /// Logs as `identifier` rather than the executable's name.
+    pub fn with_identifier(mut self, identifier: impl Into<String>) -> Self {
+        self.identifier = Some(identifier.into());
+        self
+    }
+
+    /// Filters what's sent with `directives`, or a [`LogFilter`](super::LogFilter), rather than
+    /// `RUST_LOG`.
+    pub fn with_filter(mut self, directives: impl Into<String>) -> Self {
+        self.filter = Some(directives.into());
+        self
+    }
+}
+
+#[cfg(feature = "journald")]
+impl Sink for JournaldOptions {
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>> {
+        let mut layer = tracing_journald::layer()?;
+        if let Some(identifier) = self.identifier {
+            layer = layer.with_syslog_identifier(identifier);
+        }
+        if let Some(service) = logging.service() {
+            // journal field names are upper case, without dots
+            let fields = service.fields().into_iter().map(|(k, v)| (k.replace('.', "_").to_uppercase(), v.to_string()));
+            layer = layer.with_custom_fields(fields);
+        }
+        Ok(layer.with_filter(filter(self.filter.as_deref(), logging)?).boxed())
+    }
+}
+
+/// Exports spans to an OpenTelemetry collector over OTLP/HTTP, e.g. to Tempo or Jaeger, as
+/// `service_name`, described by the [`ServiceInfo`] given, if any. Spans are exported in batches
+/// from a thread of their own.
+#[cfg(feature = "otlp")]
+#[derive(Debug, Clone)]
+pub struct OtlpOptions {
+    endpoint: String,
+    service_name: String,
+    filter: Option<String>,
+}
+
+#[cfg(feature = "otlp")]
+impl OtlpOptions {
+    /// Exports to `endpoint`, e.g. `http://localhost:4318/v1/traces`.
+    pub fn new(endpoint: impl Into<String>, service_name: impl Into<String>) -> Self {
+        OtlpOptions {
+            endpoint: endpoint.into(),
+            service_name: service_name.into(),
+            filter: None,
+        }
+    }
+
+    /// Filters what's exported with `directives`, or a [`LogFilter`](super::LogFilter), rather than
+    /// `RUST_LOG`.
+    pub fn with_filter(mut self, directives: impl Into<String>) -> Self {
+        self.filter = Some(directives.into());
+        self
+    }
+}
+
+#[cfg(feature = "otlp")]
+impl Sink for OtlpOptions {
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>> {
+        use opentelemetry::{trace::TracerProvider, KeyValue};
+        use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+        use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+        let mut resource = Resource::builder().with_service_name(self.service_name.clone());
+        if let Some(service) = logging.service() {
+            resource = resource.with_attributes([
+                KeyValue::new("service.version", service.version.clone()),
+                KeyValue::new("service.instance.id", service.instance_id.clone()),
+            ]);
+            if let Some(environment) = &service.environment {
+                resource = resource.with_attribute(KeyValue::new("deployment.environment.name", environment.clone()));
+            }
+        }
+
+        let exporter = SpanExporter::builder().with_http().with_endpoint(&self.endpoint).build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build();
+        let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer(self.service_name));
+        logging.tracer_providers.push(provider);
+        Ok(layer.with_filter(filter(self.filter.as_deref(), logging)?).boxed())
+    }
+}
+
+/// Reports ERROR events to Sentry as issues, with the spans they happened in, and the INFO and
+/// WARN events before them as breadcrumbs. Panics are reported too, from the hook it installs.
+#[cfg(feature = "sentry")]
+#[derive(Debug, Clone)]
+pub struct SentryOptions {
+    dsn: String,
+    environment: Option<String>,
+    release: Option<String>,
+    filter: Option<String>,
+}
+
+#[cfg(feature = "sentry")]
+impl SentryOptions {
+    /// Reports to the project `dsn` is the key of, e.g. `https://key@o1.ingest.sentry.io/1`.
+    pub fn new(dsn: impl Into<String>) -> Self {
+        SentryOptions {
+            dsn: dsn.into(),
+            environment: None,
+            release: None,
+            filter: None,
+        }
+    }
+
+    /// Tags issues with the environment they happened in, e.g. `production`, rather than the
+    /// [`ServiceInfo`]'s.
+    pub fn with_environment(mut self, environment: impl Into<String>) -> Self {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    /// Tags issues with the release they happened in, for Sentry to tell which release
+    /// introduced each, rather than the [`ServiceInfo`]'s name and version, e.g. `billing@1.4.2`.
+    pub fn with_release(mut self, release: impl Into<String>) -> Self {
+        self.release = Some(release.into());
+        self
+    }
+
+    /// Filters what's reported with `directives`, or a [`LogFilter`](super::LogFilter), rather than
+    /// `RUST_LOG`.
+    pub fn with_filter(mut self, directives: impl Into<String>) -> Self {
+        self.filter = Some(directives.into());
+        self
+    }
+}
+
+#[cfg(feature = "sentry")]
+impl Sink for SentryOptions {
+    fn into_layer(self: Box<Self>, logging: &mut Logging) -> Result<BoxedLayer, Box<dyn Error>> {
+        let mut options = sentry::ClientOptions::new();
+        options.dsn = Some(self.dsn.parse()?);
+        let service = logging.service();
+        options.environment = self
+            .environment
+            .or_else(|| service.and_then(|service| service.environment.clone()))
+            .map(Into::into);
+        options.release = self
+            .release
+            .or_else(|| service.map(|service| format!("{}@{}", service.name, service.version)))
+            .map(Into::into);
+        options.server_name = service.map(|service| service.instance_id.clone().into());
+        logging.sentry = Some(sentry::init(options));
+        let layer = sentry::integrations::tracing::layer();
+        Ok(layer.with_filter(filter(self.filter.as_deref(), logging)?).boxed())
+    }
+}